@@ -0,0 +1,121 @@
+use bitcoin::block::{self, Block, Content, Header};
+use bitcoin::crypto::address::address_of;
+use bitcoin::crypto::hash::{H160, H256, Hashable, Hashed};
+use bitcoin::crypto::merkle::{self, MerkleTree};
+use bitcoin::transaction::{self, SignedTransaction, State, Transaction, TxIn, TxOut};
+use bitcoin::validation;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+const MERKLE_SIZES: &[usize] = &[1, 16, 256, 4096];
+
+fn hashes(n: usize) -> Vec<H256> {
+    (0..n)
+        .map(|i| {
+            let mut raw = [0u8; 32];
+            raw[24..32].copy_from_slice(&(i as u64).to_be_bytes());
+            raw.into()
+        })
+        .collect()
+}
+
+fn header() -> Header {
+    Header {
+        version: block::VERSION,
+        parent: [1u8; 32].into(),
+        height: 1,
+        nonce: 42,
+        extra_nonce: 0,
+        bits: 0x2100ffff,
+        timestamp: 1581553864000,
+        merkle_root: [2u8; 32].into(),
+    }
+}
+
+/// A state funding `n` outputs of the key, and a block with one transaction spending each.
+fn funded_block(key: &Ed25519KeyPair, n: usize) -> (State, Block) {
+    let owner: H160 = address_of(key.public_key().as_ref());
+    let mut state = State::default();
+    let mut data = Vec::new();
+    for hash in hashes(n) {
+        state.insert_utxo((hash, 0), (100.into(), owner));
+        let input = TxIn { previous_output: hash, index: 0 };
+        let output = TxOut { recipient: owner, value: 90.into() };
+        let tx = Transaction { version: transaction::VERSION, input: vec![input], output: vec![output] };
+        data.push(Hashed::new(SignedTransaction::new(tx, key)));
+    }
+    let mut header = header();
+    header.merkle_root = MerkleTree::new(&data).root();
+    (state, Block { header, content: Content { data } })
+}
+
+fn bench_header_hash(c: &mut Criterion) {
+    let header = header();
+    c.bench_function("header_hash", |b| b.iter(|| header.hash()));
+}
+
+fn bench_merkle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merkle");
+    for &size in MERKLE_SIZES {
+        let data = hashes(size);
+        let tree = MerkleTree::new(&data);
+        let index = size / 2;
+        let proof = tree.proof(index);
+        let leaf = data[index].hash();
+        group.bench_with_input(BenchmarkId::new("new", size), &data, |b, data| {
+            b.iter(|| MerkleTree::new(data))
+        });
+        group.bench_with_input(BenchmarkId::new("proof", size), &tree, |b, tree| {
+            b.iter(|| tree.proof(index))
+        });
+        group.bench_with_input(BenchmarkId::new("verify", size), &proof, |b, proof| {
+            b.iter(|| merkle::verify(&tree.root(), &leaf, proof, index, size))
+        });
+    }
+    group.finish();
+}
+
+fn bench_signature(c: &mut Criterion) {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+    let (state, block) = funded_block(&key, 1);
+    let tx = &block.content.data[0];
+    c.bench_function("check_transaction", |b| {
+        b.iter(|| validation::check_transaction(tx, &state))
+    });
+}
+
+/// Hashing every transaction of a block, and building its Merkle tree, with and without the
+/// cached hashes of `Hashed`.
+fn bench_tx_hash_cache(c: &mut Criterion) {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+    let (_, block) = funded_block(&key, 64);
+    let raw: Vec<SignedTransaction> = block.content.data.iter().map(|tx| (**tx).clone()).collect();
+    let cached = &block.content.data;
+    let mut group = c.benchmark_group("tx_hash_64");
+    group.bench_function("uncached", |b| b.iter(|| raw.iter().map(|tx| tx.hash()).collect::<Vec<H256>>()));
+    group.bench_function("cached", |b| b.iter(|| cached.iter().map(|tx| tx.hash()).collect::<Vec<H256>>()));
+    group.bench_function("merkle_uncached", |b| b.iter(|| MerkleTree::new(&raw)));
+    group.bench_function("merkle_cached", |b| b.iter(|| MerkleTree::new(cached)));
+    group.finish();
+}
+
+fn bench_apply_block(c: &mut Criterion) {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+    let (state, block) = funded_block(&key, 64);
+    c.bench_function("apply_block_64", |b| {
+        b.iter(|| {
+            let mut state = state.clone();
+            state.apply_block(&block)
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_header_hash,
+    bench_merkle,
+    bench_signature,
+    bench_tx_hash_cache,
+    bench_apply_block
+);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+//! Blocks as read from peers and from the block files, then measured and hashed the way
+//! validation does before looking at their transactions.
+#![no_main]
+use bitcoin::block::Block;
+use bitcoin::crypto::hash::Hashable;
+use bitcoin::crypto::merkle::{self, MerkleTree};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let block: Block = match bincode::deserialize(data) {
+        Ok(block) => block,
+        Err(_) => return,
+    };
+    let encoded = bincode::serialize(&block).unwrap();
+    assert_eq!(block.weight(), encoded.len());
+    block.header.target();
+    block.header.work();
+    let data = &block.content.data;
+    let tree = MerkleTree::new(data);
+    for (index, tx) in data.iter().enumerate() {
+        assert!(merkle::verify(&tree.root(), &tx.hash(), &tree.proof(index), index, data.len()));
+    }
+});
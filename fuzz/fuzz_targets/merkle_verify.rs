@@ -0,0 +1,30 @@
+//! Merkle proofs as received in `TxProof` messages: a root, a leaf, its index, the number of
+//! leaves and the siblings, all chosen by the peer.
+#![no_main]
+use bitcoin::crypto::hash::{Hashable, H256};
+use bitcoin::crypto::merkle::{self, MerkleTree};
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryInto;
+
+fn hash(bytes: &[u8]) -> H256 {
+    let raw: [u8; 32] = bytes.try_into().unwrap();
+    raw.into()
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 80 {
+        return;
+    }
+    let root = hash(&data[0..32]);
+    let datum = hash(&data[32..64]);
+    let index = u64::from_le_bytes(data[64..72].try_into().unwrap()) as usize;
+    let leaf_count = u64::from_le_bytes(data[72..80].try_into().unwrap()) as usize;
+    let siblings: Vec<H256> = data[80..].chunks_exact(32).map(hash).collect();
+    merkle::verify(&root, &datum, &siblings, index, leaf_count);
+
+    // the siblings as leaves of a tree: every proof it gives verifies
+    let tree = MerkleTree::new(&siblings);
+    if index < siblings.len() {
+        assert!(merkle::verify(&tree.root(), &siblings[index].hash(), &tree.proof(index), index, siblings.len()));
+    }
+});
@@ -0,0 +1,20 @@
+//! Messages from peers, decoded the way `network::worker` does before dispatching them.
+#![no_main]
+use bitcoin::network::message::{self, Message};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let msg = match message::decode(data) {
+        Ok(msg) => msg,
+        Err(_) => return,
+    };
+    msg.kind();
+    if let Message::Compressed(payload) = &msg {
+        if let Some(raw) = message::decompress(payload) {
+            let _ = message::decode(&raw);
+        }
+    }
+    // whatever decodes encodes back to a message that decodes
+    let encoded = bincode::serialize(&msg).unwrap();
+    message::decode(&encoded).unwrap();
+});
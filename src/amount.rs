@@ -0,0 +1,92 @@
+//! Values of coins, with arithmetic that cannot silently overflow.
+
+use serde::{Deserialize, Serialize};
+
+/// A value of coins, serialized as a plain integer. There is no `+` or `-`: sums go through
+/// `checked_add` or `checked_sum`, which fail past `MAX_MONEY`, and differences through
+/// `checked_sub`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    /// Largest value of an output, and of the outputs of a transaction together. Far below
+    /// `u64::MAX`, so that valid values can be multiplied by fee rate units without overflow.
+    pub const MAX_MONEY: Amount = Amount(21_000_000 * 100_000_000);
+
+    /// An amount fixed at compile time, for constants.
+    pub const fn new(value: u64) -> Amount {
+        Amount(value)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Whether the value is at most `MAX_MONEY`.
+    pub fn is_valid(self) -> bool {
+        self <= Amount::MAX_MONEY
+    }
+
+    /// The sum, `None` past `MAX_MONEY`.
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount).filter(|sum| sum.is_valid())
+    }
+
+    /// The difference, `None` if `other` is larger.
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// The sum of all amounts, `None` past `MAX_MONEY`.
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+
+    /// Value per 1000 bytes of `size` bytes, the unit of `Policy::min_fee_rate`.
+    pub fn per_kilobyte(self, size: usize) -> u64 {
+        self.0.saturating_mul(1000) / size as u64
+    }
+}
+
+impl From<u64> for Amount {
+    fn from(value: u64) -> Amount {
+        Amount(value)
+    }
+}
+
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::str::FromStr for Amount {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u64>().map(Amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic() {
+        let max = Amount::MAX_MONEY;
+        assert_eq!(Amount::from(2).checked_add(3.into()), Some(5.into()));
+        assert_eq!(max.checked_add(Amount::ZERO), Some(max));
+        assert_eq!(max.checked_add(1.into()), None);
+        assert_eq!(Amount::from(u64::MAX).checked_add(2.into()), None);
+        assert_eq!(Amount::from(3).checked_sub(2.into()), Some(1.into()));
+        assert_eq!(Amount::from(2).checked_sub(3.into()), None);
+        assert_eq!(Amount::checked_sum(vec![1.into(), 2.into(), 3.into()]), Some(6.into()));
+        assert_eq!(Amount::checked_sum(vec![max, 1.into()]), None);
+        assert_eq!(Amount::checked_sum(vec![]), Some(Amount::ZERO));
+        assert_eq!(max.per_kilobyte(1000), max.as_u64());
+        assert_eq!(bincode::serialize(&Amount::from(7)).unwrap(), bincode::serialize(&7u64).unwrap());
+    }
+}
@@ -0,0 +1,53 @@
+use super::types::{BlockPage, ChainSummary, SupplyStats};
+use crate::crypto::hash::H256;
+use serde::de::DeserializeOwned;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A minimal client for the API server of another node.
+pub struct Client {
+    addr: SocketAddr,
+}
+
+impl Client {
+    pub fn new(addr: SocketAddr) -> Self {
+        Client { addr: addr }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn summary(&self) -> io::Result<ChainSummary> {
+        self.get("/blockchain/summary")
+    }
+
+    pub fn supply(&self) -> io::Result<SupplyStats> {
+        self.get("/stats/supply")
+    }
+
+    /// Get the hash of the block at a height of the node's longest chain.
+    pub fn hash_at_height(&self, height: usize) -> io::Result<Option<H256>> {
+        let page: BlockPage = self.get(&format!("/blocks?start={}&count=1", height))?;
+        Ok(page.blocks.first().map(|b| b.hash))
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> io::Result<T> {
+        let mut stream = TcpStream::connect_timeout(&self.addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, self.addr)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let body = match response.find("\r\n\r\n") {
+            Some(i) => &response[i + 4..],
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response")),
+        };
+        // errors are reported as an ApiResponse, which does not parse as T
+        serde_json::from_str(body).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response: {}", body.trim()))
+        })
+    }
+}
@@ -1,26 +1,293 @@
-use serde::Serialize;
+use crate::amount::Amount;
 use crate::miner::Handle as MinerHandle;
+use crate::network::banlist;
+use crate::network::download::Scheduler;
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::network::message::Message;
+use crate::network::validator;
+use crate::network::worker::Handle as WorkerHandle;
+use crate::network::message::{Capabilities, Message, PROTOCOL_VERSION};
+use crate::crypto::address::address_of;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainListener, Finality};
+use crate::chainparams::{self, Network};
+use crate::chainstate;
+use crate::clock::{Clock, SystemClock};
+use crate::forks::ForkMonitor;
+use crate::locks::{self, OrderedMutex};
+use crate::logging;
+use crate::policy::Policy;
+use crate::psbt::Psbt;
+use crate::transaction::{
+    self, Mempool, Revalidation, SigHash, SignedTransaction, State, Transaction, TxIn, TxOut, TxPool,
+};
+use crate::validation;
+use crate::wallet::{self, Wallet};
 
-use log::info;
-use std::collections::HashMap;
+use log::{info, LevelFilter};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::io::Read;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+pub mod client;
+mod openapi;
+pub mod types;
+
+use self::types::*;
+
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<Mempool>>,
+    /// The mempool transactions, looked up without the mempool lock
+    txpool: Arc<TxPool>,
+    state: Arc<OrderedMutex<State>>,
+    policy: Policy,
+    wallet: Arc<OrderedMutex<Wallet>>,
+    scheduler: Arc<OrderedMutex<Scheduler>>,
+    workers: WorkerHandle,
+    readiness: Readiness,
+    forks: Arc<Mutex<ForkMonitor>>,
+    tip_watch: Arc<TipWatch>,
+    started: Instant,
+}
+
+/// Wakes up the `/blockchain/tip` requests waiting for the tip to change.
+struct TipWatch {
+    tip: Mutex<H256>,
+    changed: Condvar,
+}
+
+impl TipWatch {
+    /// Wait until the tip differs from `known` or the timeout elapses, and return whether it
+    /// changed.
+    fn wait(&self, known: &H256, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut tip = self.tip.lock().unwrap();
+        while *tip == *known {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            tip = self.changed.wait_timeout(tip, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl ChainListener for TipWatch {
+    fn on_block_connected(&self, block: &Block, _height: usize) {
+        *self.tip.lock().unwrap() = block.hash();
+        self.changed.notify_all();
+    }
+}
+
+/// What `/readyz` requires besides the node being healthy.
+#[derive(Clone, Default)]
+pub struct Readiness {
+    pub min_peers: usize,
+    /// Directory the blocks are archived to, checked for being writable.
+    pub data_dir: Option<PathBuf>,
+}
+
+/// Most transactions `POST /tx/validate` checks at once.
+const MAX_VALIDATE_BATCH: usize = 1000;
+
+/// Most blocks `POST /miner/generate` mines at once.
+const MAX_GENERATE: usize = 1000;
+
+/// Blocks `/stats/hashrate` averages over by default, like `getnetworkhashps`.
+const DEFAULT_HASHRATE_WINDOW: usize = 120;
+
+impl TxDetails {
+    /// Resolve the outputs spent by a transaction through the UTXO set, or through the chain if
+    /// they are already spent.
+    fn new(tx: &SignedTransaction, block: Option<H256>, blockchain: &Blockchain, state: &State) -> Self {
+        let inputs: Vec<InputDetails> = tx
+            .transaction
+            .input
+            .iter()
+            .map(|txin| {
+                let spent = state
+                    .utxo
+                    .get(&(txin.previous_output, txin.index))
+                    .map(|(value, recipient, _)| (*value, *recipient))
+                    .or_else(|| {
+                        let (_, prev) = blockchain.get_transaction(&txin.previous_output)?;
+                        let txout = prev.transaction.output.get(txin.index as usize)?;
+                        Some((txout.value, txout.recipient))
+                    });
+                InputDetails {
+                    previous_output: txin.previous_output,
+                    index: txin.index,
+                    value: spent.map(|(value, _)| value),
+                    address: spent.map(|(_, recipient)| recipient.to_string()),
+                }
+            })
+            .collect();
+        let outputs: Vec<OutputDetails> = tx
+            .transaction
+            .output
+            .iter()
+            .map(|txout| OutputDetails { value: txout.value, address: txout.recipient.to_string() })
+            .collect();
+        let size = tx.serialized_size();
+        let total_in = inputs.iter().map(|i| i.value).collect::<Option<Vec<Amount>>>().and_then(Amount::checked_sum);
+        let total_out = Amount::checked_sum(outputs.iter().map(|o| o.value));
+        let fee = total_in.and_then(|total_in| total_in.checked_sub(total_out?));
+        TxDetails {
+            hash: tx.hash(),
+            block: block,
+            confirmations: block.and_then(|block| blockchain.confirmations(&block)).unwrap_or(0),
+            finality: block.map_or(Finality::Pending, |block| blockchain.finality(&block)),
+            size: size,
+            inputs: inputs,
+            outputs: outputs,
+            total_in: total_in,
+            total_out: total_out,
+            fee: fee,
+            fee_rate: fee.map(|fee| fee.per_kilobyte(size)),
+        }
+    }
+}
+
+/// Default and maximum page size of `/blocks`.
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Longest `/blockchain/tip` waits for the tip to change.
+const MAX_TIP_WAIT: Duration = Duration::from_secs(60);
+
+fn check(name: &'static str, ok: bool, detail: String) -> ProbeCheck {
+    ProbeCheck { name: name, ok: ok, detail: detail }
 }
 
-#[derive(Serialize)]
-struct ApiResponse {
-    success: bool,
-    message: String,
+/// Whether the threads of the node are running: the P2P event loop answers, no worker thread
+/// has exited, and no thread panicked while holding a lock.
+fn health_checks(
+    network: &NetworkServerHandle,
+    workers: &WorkerHandle,
+    blockchain: &OrderedMutex<Blockchain>,
+    mempool: &OrderedMutex<Mempool>,
+    state: &OrderedMutex<State>,
+    wallet: &OrderedMutex<Wallet>,
+    scheduler: &OrderedMutex<Scheduler>,
+) -> Vec<ProbeCheck> {
+    let p2p = match network.peers() {
+        Some(_) => check("p2p", true, "event loop responding".to_string()),
+        None => check("p2p", false, "event loop not responding".to_string()),
+    };
+    let running = workers.running();
+    let workers = check(
+        "workers",
+        running == workers.started(),
+        format!("{} of {} worker threads running, restarted {} times", running, workers.started(), workers.restarts()),
+    );
+    let poisoned: Vec<&str> = [
+        ("blockchain", blockchain.is_poisoned()),
+        ("mempool", mempool.is_poisoned()),
+        ("state", state.is_poisoned()),
+        ("wallet", wallet.is_poisoned()),
+        ("scheduler", scheduler.is_poisoned()),
+    ]
+    .iter()
+    .filter(|(_, poisoned)| *poisoned)
+    .map(|(name, _)| *name)
+    .collect();
+    let locks = if poisoned.is_empty() {
+        check("locks", true, "no thread panicked holding a lock".to_string())
+    } else {
+        check("locks", false, format!("poisoned by a panic: {}", poisoned.join(", ")))
+    };
+    vec![p2p, workers, locks]
+}
+
+/// Create and remove a file in the directory.
+fn check_writable(dir: &Path) -> ProbeCheck {
+    let probe = dir.join(".readyz");
+    match fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+        Ok(()) => check("storage", true, format!("{} is writable", dir.display())),
+        Err(e) => check("storage", false, format!("{} is not writable: {}", dir.display(), e)),
+    }
+}
+
+/// An IP address, or the IP address of a socket address.
+fn parse_ip(addr: &str) -> Result<IpAddr, AddrParseError> {
+    addr.parse::<IpAddr>().or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+}
+
+/// The JSON body of a POST request.
+fn read_json<T: serde::de::DeserializeOwned>(req: &mut tiny_http::Request) -> Result<T, String> {
+    if *req.method() != Method::Post {
+        return Err("use POST".to_string());
+    }
+    let mut body = String::new();
+    req.as_reader().read_to_string(&mut body).map_err(|e| format!("error reading body: {}", e))?;
+    serde_json::from_str(&body).map_err(|e| format!("error parsing body: {}", e))
+}
+
+/// Add a key or a watch-only address to the wallet with `add`, then rescan the longest chain
+/// from `rescan_from` for the transactions of `address`.
+fn import<F: FnOnce(&mut Wallet)>(
+    blockchain: &OrderedMutex<Blockchain>,
+    wallet: &OrderedMutex<Wallet>,
+    address: H160,
+    rescan_from: Option<usize>,
+    add: F,
+) -> Result<WalletImport, String> {
+    let blockchain = blockchain.lock().unwrap();
+    let from = rescan_from.unwrap_or(0);
+    if from > blockchain.height() {
+        return Err(format!("rescan height {} above the tip", from));
+    }
+    let mut wallet = wallet.lock().unwrap();
+    add(&mut wallet);
+    let transactions = wallet.rescan(&blockchain, from);
+    Ok(WalletImport { address: address, scanned: blockchain.height() + 1 - from, transactions: transactions })
+}
+
+/// Pay with the wallet and submit the transaction to the mempool. It is signed with nothing
+/// locked, its key may be held by a device slow to answer.
+fn send(
+    mempool: &OrderedMutex<Mempool>,
+    state: &OrderedMutex<State>,
+    wallet: &OrderedMutex<Wallet>,
+    payments: &[(H160, Amount)],
+    policy: &Policy,
+) -> Result<H256, String> {
+    let funded = {
+        let state = state.lock().unwrap();
+        wallet.lock().unwrap().fund_transaction_many(payments, &state, policy)
+    };
+    let tx = funded.and_then(|funded| funded.sign()).map_err(|e| format!("error creating transaction: {}", e))?;
+    // the mempool notifies the wallet, which is released
+    let mut mempool = mempool.lock().unwrap();
+    let state = state.lock().unwrap();
+    mempool.accept(&tx, &state, policy).map_err(|e| format!("transaction rejected: {}", e))
+}
+
+fn psbt_info(psbt: &Psbt) -> PsbtInfo {
+    PsbtInfo {
+        psbt: psbt.to_hex(),
+        txid: psbt.id(),
+        fee: psbt.fee().unwrap_or_default(),
+        inputs: psbt.inputs.len(),
+        signed: psbt.inputs.iter().filter(|input| input.signature.is_some()).count(),
+        complete: psbt.is_complete(),
+    }
 }
 
 macro_rules! respond_result {
@@ -36,22 +303,87 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$message).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+macro_rules! respond_probe {
+    ( $req:expr, $probe:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let probe = $probe;
+        let status = if probe.ok { 200 } else { 503 };
+        let resp = Response::from_string(serde_json::to_string_pretty(&probe).unwrap())
+            .with_header(content_type)
+            .with_status_code(status);
+        $req.respond(resp).unwrap();
+    }};
+}
+
 impl Server {
+    /// Start serving at `addr` and return the address served at.
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
-    ) {
-        let handle = HTTPServer::http(&addr).unwrap();
+        blockchain: &Arc<OrderedMutex<Blockchain>>,
+        mempool: &Arc<OrderedMutex<Mempool>>,
+        state: &Arc<OrderedMutex<State>>,
+        policy: &Policy,
+        wallet: &Arc<OrderedMutex<Wallet>>,
+        scheduler: &Arc<OrderedMutex<Scheduler>>,
+        workers: &WorkerHandle,
+        readiness: &Readiness,
+        forks: &Arc<Mutex<ForkMonitor>>,
+    ) -> std::io::Result<std::net::SocketAddr> {
+        let handle = HTTPServer::http(&addr).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::Other, format!("cannot listen at {}: {}", addr, e))
+        })?;
+        // the port picked by the system if the address has port 0
+        let addr = handle.server_addr();
+        let tip_watch = {
+            let mut blockchain = blockchain.lock().unwrap();
+            let tip_watch = Arc::new(TipWatch { tip: Mutex::new(blockchain.tip()), changed: Condvar::new() });
+            blockchain.subscribe(tip_watch.clone());
+            tip_watch
+        };
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            txpool: mempool.lock().unwrap().pool(),
+            state: Arc::clone(state),
+            policy: policy.clone(),
+            wallet: Arc::clone(wallet),
+            scheduler: Arc::clone(scheduler),
+            workers: workers.clone(),
+            readiness: readiness.clone(),
+            forks: Arc::clone(forks),
+            tip_watch: tip_watch,
+            started: Instant::now(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
+                let txpool = Arc::clone(&server.txpool);
+                let state = Arc::clone(&server.state);
+                let policy = server.policy.clone();
+                let wallet = Arc::clone(&server.wallet);
+                let scheduler = Arc::clone(&server.scheduler);
+                let workers = server.workers.clone();
+                let readiness = server.readiness.clone();
+                let forks = Arc::clone(&server.forks);
+                let tip_watch = Arc::clone(&server.tip_watch);
+                let started = server.started;
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -63,6 +395,67 @@ impl Server {
                         }
                     };
                     match url.path() {
+                        "/status" => {
+                            let (height, tip) = {
+                                let blockchain = blockchain.lock().unwrap();
+                                (blockchain.height(), blockchain.tip())
+                            };
+                            let sync = {
+                                let scheduler = scheduler.lock().unwrap();
+                                let validating = workers.blocks_validating();
+                                SyncStatus {
+                                    syncing: scheduler.in_flight() + scheduler.queued() + validating > 0,
+                                    blocks_in_flight: scheduler.in_flight(),
+                                    blocks_queued: scheduler.queued(),
+                                    blocks_validating: validating,
+                                }
+                            };
+                            let (mempool_size, orphan_transactions) = {
+                                let mempool = mempool.lock().unwrap();
+                                (mempool.len(), mempool.orphan_count())
+                            };
+                            respond_json!(req, NodeStatus {
+                                version: env!("CARGO_PKG_VERSION"),
+                                network: chainparams::current().network,
+                                protocol: PROTOCOL_VERSION,
+                                capabilities: Capabilities::local(),
+                                uptime: started.elapsed().as_secs(),
+                                height: height,
+                                tip: tip,
+                                peers: network.peers().map(|peers| peers.len()),
+                                mempool_size: mempool_size,
+                                orphan_transactions: orphan_transactions,
+                                worker_restarts: workers.restarts(),
+                                miner: miner.status(),
+                                sync: sync,
+                            });
+                        }
+                        "/healthz" => {
+                            let checks = health_checks(&network, &workers, &blockchain, &mempool, &state, &wallet, &scheduler);
+                            respond_probe!(req, Probe::new(checks));
+                        }
+                        "/readyz" => {
+                            let mut checks = health_checks(&network, &workers, &blockchain, &mempool, &state, &wallet, &scheduler);
+                            // a poisoned lock already fails the health checks, do not panic on it
+                            if let Ok(scheduler) = scheduler.lock() {
+                                let (in_flight, queued) = (scheduler.in_flight(), scheduler.queued());
+                                checks.push(check(
+                                    "sync",
+                                    in_flight + queued == 0,
+                                    format!("{} blocks in flight, {} queued", in_flight, queued),
+                                ));
+                            }
+                            let peers = network.peers().map(|peers| peers.len()).unwrap_or(0);
+                            checks.push(check(
+                                "peers",
+                                peers >= readiness.min_peers,
+                                format!("{} peers connected, {} required", peers, readiness.min_peers),
+                            ));
+                            if let Some(data_dir) = &readiness.data_dir {
+                                checks.push(check_writable(data_dir));
+                            }
+                            respond_probe!(req, Probe::new(checks));
+                        }
                         "/miner/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -87,10 +480,1024 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/generate" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            if chainparams::current().network != Network::Regtest {
+                                respond_result!(req, false, "generate is only available on regtest");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks").map(|v| v.parse::<usize>()) {
+                                None => 1,
+                                Some(Ok(v)) if v <= MAX_GENERATE => v,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, format!("at most {} blocks at once", MAX_GENERATE));
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing blocks: {}", e));
+                                    return;
+                                }
+                            };
+                            let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            match miner.generate(blocks, address) {
+                                Some(hashes) => respond_json!(req, hashes),
+                                None => respond_result!(req, false, "miner stopped"),
+                            }
+                        }
+                        "/miner/set-address" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            match params.get("address").map(|v| v.parse::<H160>()) {
+                                Some(Ok(address)) => {
+                                    miner.set_payout(address);
+                                    info!("Mining rewards go to {}", address);
+                                    respond_result!(req, true, "ok");
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                }
+                            }
+                        }
+                        "/miner/template/preview" => {
+                            match miner.preview() {
+                                Some(preview) => respond_json!(req, preview),
+                                None => respond_result!(req, false, "miner stopped"),
+                            }
+                        }
+                        "/pool/stats" => {
+                            match miner.pool_stats() {
+                                Some(stats) => respond_json!(req, stats),
+                                None => respond_result!(req, false, "not serving a pool"),
+                            }
+                        }
+                        "/admin/loglevel" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let level = match params.get("level").map(|v| v.parse::<LevelFilter>()) {
+                                Some(Ok(level)) => level,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing level: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing level");
+                                    return;
+                                }
+                            };
+                            let target = params.get("target").map(|target| target.as_str());
+                            logging::set_level(target, level);
+                            info!("Logging {} messages of {}", level, target.unwrap_or("the other targets"));
+                            respond_result!(req, true, "ok");
+                        }
+                        "/address/validate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            match address.parse::<H160>() {
+                                Ok(_) => {
+                                    respond_result!(req, true, "ok");
+                                }
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                }
+                            }
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => match network.peer_info() {
+                            Some(peers) => respond_json!(req, peers),
+                            None => respond_result!(req, false, "P2P server not responding"),
+                        },
+                        "/network/ban" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match params.get("addr").map(|v| parse_ip(v)) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let duration = match params.get("duration").map(|v| v.parse::<u64>()) {
+                                None => banlist::DEFAULT_BAN_DURATION,
+                                Some(Ok(v)) => Duration::from_secs(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing duration: {}", e));
+                                    return;
+                                }
+                            };
+                            network.ban(ip, duration);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/bans" => match network.bans() {
+                            Some(bans) => respond_json!(req, bans),
+                            None => respond_result!(req, false, "P2P server not responding"),
+                        },
+                        path if path.starts_with("/network/peers/") && path.ends_with("/stats") => {
+                            let addr = path.trim_start_matches("/network/peers/").trim_end_matches("/stats");
+                            let addr = match addr.parse::<SocketAddr>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            match network.peer_stats(addr) {
+                                Some(Some(stats)) => respond_json!(req, stats),
+                                Some(None) => respond_result!(req, false, "peer not connected"),
+                                None => respond_result!(req, false, "P2P server not responding"),
+                            }
+                        }
+                        path if path.starts_with("/network/ban/") => {
+                            if *req.method() != Method::Delete {
+                                respond_result!(req, false, "use DELETE");
+                                return;
+                            }
+                            let ip = match parse_ip(path.trim_start_matches("/network/ban/")) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing addr: {}", e));
+                                    return;
+                                }
+                            };
+                            match network.unban(ip) {
+                                Some(true) => respond_result!(req, true, "ok"),
+                                Some(false) => respond_result!(req, false, "address not banned"),
+                                None => respond_result!(req, false, "P2P server not responding"),
+                            }
+                        }
+                        "/tx/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let raw = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let raw = match hex::decode(raw) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing tx: {}", e));
+                                    return;
+                                }
+                            };
+                            let tx: SignedTransaction = match bincode::deserialize(&raw) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error decoding tx: {}", e));
+                                    return;
+                                }
+                            };
+                            let result = {
+                                let mut mempool = mempool.lock().unwrap();
+                                let state = state.lock().unwrap();
+                                mempool.accept(&tx, &state, &policy)
+                            };
+                            match result {
+                                Ok(hash) => {
+                                    network.announce(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("transaction rejected: {}", e));
+                                }
+                            }
+                        }
+                        "/tx/validate" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let request: ValidateRequest = match serde_json::from_str(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing body: {}", e));
+                                    return;
+                                }
+                            };
+                            if request.transactions.len() > MAX_VALIDATE_BATCH {
+                                respond_result!(req, false, format!("at most {} transactions per batch", MAX_VALIDATE_BATCH));
+                                return;
+                            }
+                            let decoded: Vec<Result<SignedTransaction, String>> = request
+                                .transactions
+                                .iter()
+                                .map(|raw| {
+                                    let raw = hex::decode(raw).map_err(|e| format!("error parsing tx: {}", e))?;
+                                    bincode::deserialize(&raw).map_err(|e| format!("error decoding tx: {}", e))
+                                })
+                                .collect();
+                            let transactions: Vec<SignedTransaction> = decoded.iter().filter_map(|tx| tx.as_ref().ok()).cloned().collect();
+                            let mut results = {
+                                let mempool = mempool.lock().unwrap();
+                                let state = state.lock().unwrap();
+                                mempool.check_batch(&transactions, &state, &policy)
+                            }
+                            .into_iter();
+                            let validations: Vec<TxValidation> = decoded
+                                .iter()
+                                .map(|tx| match tx {
+                                    Ok(tx) => match results.next().unwrap() {
+                                        Ok(fee) => TxValidation { hash: Some(tx.hash()), valid: true, fee: Some(fee), error: None },
+                                        Err(e) => TxValidation { hash: Some(tx.hash()), valid: false, fee: None, error: Some(e.to_string()) },
+                                    },
+                                    Err(e) => TxValidation { hash: None, valid: false, fee: None, error: Some(e.clone()) },
+                                })
+                                .collect();
+                            respond_json!(req, validations);
+                        }
+                        path if path.starts_with("/tx/") && path.ends_with("/status") => {
+                            let hash = path.trim_start_matches("/tx/").trim_end_matches("/status");
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let status = if let Some(block) = blockchain.find_transaction(&hash) {
+                                let height = blockchain.lengthmap[&block];
+                                TxStatus::Confirmed {
+                                    block: block,
+                                    height: height,
+                                    confirmations: blockchain.confirmations(&block).unwrap(),
+                                    finality: blockchain.finality(&block),
+                                }
+                            } else if let Some(info) = txpool.info(&hash) {
+                                TxStatus::Mempool {
+                                    time_added: info.time_added,
+                                    fee: info.fee,
+                                    finality: Finality::Pending,
+                                }
+                            } else {
+                                TxStatus::Unknown
+                            };
+                            respond_json!(req, status);
+                        }
+                        path if path.starts_with("/tx/") && path.ends_with("/proof") => {
+                            let hash = path.trim_start_matches("/tx/").trim_end_matches("/proof");
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.tx_proof(&hash) {
+                                Some((block, proof)) => {
+                                    respond_json!(req, TxProof { block: block, proof: proof });
+                                }
+                                None => {
+                                    respond_result!(req, false, "transaction not in the longest chain");
+                                }
+                            }
+                        }
+                        path if path.starts_with("/tx/") && path.ends_with("/details") => {
+                            let hash = path.trim_start_matches("/tx/").trim_end_matches("/details");
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            let details = if let Some((block, tx)) = blockchain.get_transaction(&hash) {
+                                TxDetails::new(tx, Some(block), &blockchain, &state)
+                            } else if let Some(tx) = txpool.get(&hash) {
+                                TxDetails::new(&tx, None, &blockchain, &state)
+                            } else {
+                                respond_result!(req, false, "transaction not found");
+                                return;
+                            };
+                            respond_json!(req, details);
+                        }
+                        path if path.starts_with("/block/") && (path.ends_with("/invalidate") || path.ends_with("/reconsider")) => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let invalidate = path.ends_with("/invalidate");
+                            let hash = path.trim_start_matches("/block/").trim_end_matches("/invalidate").trim_end_matches("/reconsider");
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+                            let mut blockchain = blockchain.lock().unwrap();
+                            let mut mempool = mempool.lock().unwrap();
+                            let mut state = state.lock().unwrap();
+                            let change = if invalidate {
+                                chainstate::invalidate_block(&hash, &mut blockchain, &mut state)
+                            } else {
+                                chainstate::reconsider_block(&hash, &mut blockchain, &mut state)
+                            };
+                            match change {
+                                Some(change) => {
+                                    validator::update_mempool(&change, &blockchain, &mut mempool, &state, &policy);
+                                    // transactions spending outputs of the disconnected blocks
+                                    // are no longer valid
+                                    let invalid = Revalidation::new(&mempool, &state).run();
+                                    mempool.evict(&invalid);
+                                    info!("{} block {}, tip now {}", if invalidate { "Invalidated" } else { "Reconsidered" }, hash, blockchain.tip());
+                                    respond_json!(req, change);
+                                }
+                                None if invalidate => respond_result!(req, false, "block not found, or the genesis block, or below the checkpoint"),
+                                None => respond_result!(req, false, "block not found, or not marked invalid"),
+                            }
+                        }
+                        path if path.starts_with("/block/") && path.ends_with("/utxo-commitment") => {
+                            let hash = path.trim_start_matches("/block/").trim_end_matches("/utxo-commitment");
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            match (blockchain.height_of(&hash), state.block_commitment(&hash)) {
+                                (Some(height), Some(commitment)) => {
+                                    respond_json!(req, BlockCommitment {
+                                        block: hash,
+                                        height: height,
+                                        confirmations: blockchain.confirmations(&hash).unwrap(),
+                                        utxo_commitment: commitment,
+                                    });
+                                }
+                                (Some(_), None) => {
+                                    respond_result!(req, false, "no UTXO commitment recorded for the block");
+                                }
+                                (None, _) => {
+                                    respond_result!(req, false, "block not found");
+                                }
+                            }
+                        }
+                        "/mempool/transactions" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let min_fee_rate = match params.get("min_fee_rate").map(|v| v.parse::<u64>()) {
+                                None => None,
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing min_fee_rate: {}", e));
+                                    return;
+                                }
+                            };
+                            let since = match params.get("since").map(|v| v.parse::<u128>()) {
+                                None => None,
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing since: {}", e));
+                                    return;
+                                }
+                            };
+                            let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+                                None => DEFAULT_PAGE_SIZE,
+                                Some(Ok(v)) if v <= MAX_PAGE_SIZE => v,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, format!("limit exceeds {}", MAX_PAGE_SIZE));
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing limit: {}", e));
+                                    return;
+                                }
+                            };
+                            let now = SystemClock.now();
+                            let summaries: Vec<MempoolTxSummary> = txpool
+                                .select(min_fee_rate, since, limit)
+                                .into_iter()
+                                .map(|(tx, info)| {
+                                    let size = tx.serialized_size();
+                                    MempoolTxSummary {
+                                        txid: tx.hash(),
+                                        size: size,
+                                        fee: info.fee,
+                                        fee_rate: info.fee.map(|fee| fee.per_kilobyte(size)),
+                                        age: now.saturating_sub(info.time_added),
+                                        inputs: tx.transaction.input.len(),
+                                        outputs: tx.transaction.output.len(),
+                                    }
+                                })
+                                .collect();
+                            respond_json!(req, summaries);
+                        }
+                        "/mempool/graph" => {
+                            let mempool = mempool.lock().unwrap();
+                            let graph: BTreeMap<H256, MempoolNode> = mempool
+                                .transactions()
+                                .iter()
+                                .map(|tx| {
+                                    let hash = tx.hash();
+                                    let info = mempool.info(&hash).unwrap();
+                                    let size = tx.serialized_size();
+                                    let node = MempoolNode {
+                                        parents: mempool.parents(&hash),
+                                        children: mempool.children(&hash),
+                                        fee: info.fee,
+                                        size: size,
+                                        fee_rate: info.fee.map(|fee| fee.per_kilobyte(size)),
+                                        time_added: info.time_added,
+                                    };
+                                    (hash, node)
+                                })
+                                .collect();
+                            respond_json!(req, graph);
+                        }
+                        "/wallet/send" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let recipient = match params.get("recipient").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing recipient: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing recipient");
+                                    return;
+                                }
+                            };
+                            let value = match params.get("value").map(|v| v.parse::<Amount>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing value: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing value");
+                                    return;
+                                }
+                            };
+                            let result = send(&mempool, &state, &wallet, &[(recipient, value)], &policy);
+                            match result {
+                                Ok(hash) => {
+                                    network.announce(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
+                        }
+                        "/wallet/sendmany" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let request: SendManyRequest = match serde_json::from_str(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing body: {}", e));
+                                    return;
+                                }
+                            };
+                            let mut payments = Vec::new();
+                            for (recipient, value) in &request.recipients {
+                                match recipient.parse::<H160>() {
+                                    Ok(recipient) => payments.push((recipient, *value)),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing recipient {}: {}", recipient, e));
+                                        return;
+                                    }
+                                }
+                            }
+                            let result = send(&mempool, &state, &wallet, &payments, &policy);
+                            match result {
+                                Ok(hash) => {
+                                    network.announce(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
+                        }
+                        "/wallet/psbt/create" => {
+                            let mut req = req;
+                            let request: PsbtCreateRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let mut output = Vec::new();
+                            for (recipient, value) in &request.outputs {
+                                match recipient.parse::<H160>() {
+                                    Ok(recipient) => output.push(TxOut { recipient: recipient, value: *value }),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing recipient {}: {}", recipient, e));
+                                        return;
+                                    }
+                                }
+                            }
+                            let input = request
+                                .inputs
+                                .iter()
+                                .map(|outpoint| TxIn { previous_output: outpoint.txid, index: outpoint.index })
+                                .collect();
+                            let tx = Transaction { version: transaction::VERSION, input: input, output: output };
+                            let result = {
+                                let mempool = mempool.lock().unwrap();
+                                let state = state.lock().unwrap();
+                                Psbt::new(tx, |outpoint| mempool.find_output(outpoint, &state))
+                            };
+                            match result {
+                                Ok(psbt) => {
+                                    respond_json!(req, psbt_info(&psbt));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error creating psbt: {}", e));
+                                }
+                            }
+                        }
+                        "/wallet/psbt/fund" => {
+                            let mut req = req;
+                            let request: SendManyRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let mut payments = Vec::new();
+                            for (recipient, value) in &request.recipients {
+                                match recipient.parse::<H160>() {
+                                    Ok(recipient) => payments.push((recipient, *value)),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing recipient {}: {}", recipient, e));
+                                        return;
+                                    }
+                                }
+                            }
+                            let state = state.lock().unwrap();
+                            match wallet.lock().unwrap().create_psbt(&payments, &state, &policy) {
+                                Ok(psbt) => {
+                                    respond_json!(req, psbt_info(&psbt));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error creating psbt: {}", e));
+                                }
+                            }
+                        }
+                        "/wallet/psbt/sign" => {
+                            let mut req = req;
+                            let request: PsbtSignRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let mut psbt = match Psbt::from_hex(&request.psbt) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            // sign with the wallet released, its keys may be held by devices
+                            let keys = wallet.lock().unwrap().keys();
+                            match wallet::sign_psbt(&keys, &mut psbt, request.sighash.unwrap_or(SigHash::All)) {
+                                Ok(_) => {
+                                    respond_json!(req, psbt_info(&psbt));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error signing psbt: {}", e));
+                                }
+                            }
+                        }
+                        "/wallet/psbt/combine" => {
+                            let mut req = req;
+                            let request: PsbtCombineRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let mut combined: Option<Psbt> = None;
+                            for encoded in &request.psbts {
+                                let result = Psbt::from_hex(encoded).and_then(|psbt| match combined.as_mut() {
+                                    Some(combined) => combined.combine(&psbt),
+                                    None => {
+                                        combined = Some(psbt);
+                                        Ok(())
+                                    }
+                                });
+                                if let Err(e) = result {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            }
+                            match combined {
+                                Some(psbt) => {
+                                    respond_json!(req, psbt_info(&psbt));
+                                }
+                                None => {
+                                    respond_result!(req, false, "no psbt to combine");
+                                }
+                            }
+                        }
+                        "/wallet/psbt/finalize" => {
+                            let mut req = req;
+                            let request: PsbtFinalizeRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match Psbt::from_hex(&request.psbt).and_then(|psbt| psbt.finalize()) {
+                                Ok(tx) => {
+                                    let finalized = FinalizedPsbt {
+                                        txid: tx.hash(),
+                                        tx: hex::encode(bincode::serialize(&tx).unwrap()),
+                                    };
+                                    respond_json!(req, finalized);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
+                        }
+                        "/wallet/import" => {
+                            let mut req = req;
+                            let request: WalletImportRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let key = match hex::decode(&request.seed) {
+                                Ok(ref seed) if seed.len() == 32 => Ed25519KeyPair::from_seed_unchecked(seed).unwrap(),
+                                _ => {
+                                    respond_result!(req, false, "seed must be 32 hex-encoded bytes");
+                                    return;
+                                }
+                            };
+                            let address = address_of(key.public_key().as_ref());
+                            match import(&blockchain, &wallet, address, request.rescan_from, |wallet| wallet.add_key(key)) {
+                                Ok(imported) => {
+                                    info!("Imported key of {}, {} transactions found", address, imported.transactions);
+                                    respond_json!(req, imported);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
+                        }
+                        "/wallet/watch" => {
+                            let mut req = req;
+                            let request: WalletWatchRequest = match read_json(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let address = request.address;
+                            match import(&blockchain, &wallet, address, request.rescan_from, |wallet| wallet.add_watch_only(address)) {
+                                Ok(imported) => {
+                                    info!("Watching {}, {} transactions found", address, imported.transactions);
+                                    respond_json!(req, imported);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
+                        }
+                        "/wallet/balance" => {
+                            let state = state.lock().unwrap();
+                            let wallet = wallet.lock().unwrap();
+                            let v: Vec<AddressBalance> = wallet
+                                .balances(&state)
+                                .into_iter()
+                                .map(|(address, balance)| AddressBalance {
+                                    address: address,
+                                    balance: balance,
+                                    watch_only: !wallet.addresses().contains(&address),
+                                })
+                                .collect();
+                            respond_json!(req, v);
+                        }
+                        "/wallet/transactions" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let wallet = wallet.lock().unwrap();
+                            let v: Vec<WalletTxInfo> = wallet
+                                .transactions
+                                .iter()
+                                .map(|(hash, wtx)| WalletTxInfo {
+                                    hash: *hash,
+                                    state: wtx.state,
+                                    confirmations: wallet.confirmations(hash, &blockchain).unwrap(),
+                                    finality: wallet.finality(hash, &blockchain).unwrap(),
+                                })
+                                .collect();
+                            respond_json!(req, v);
+                        }
+                        "/wallet/label" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let label = params.get("label");
+                            if label.map_or(false, |label| label.len() > wallet::MAX_LABEL_LENGTH) {
+                                respond_result!(req, false, format!("label longer than {} bytes", wallet::MAX_LABEL_LENGTH));
+                                return;
+                            }
+                            let mut wallet = wallet.lock().unwrap();
+                            // without a label, return the current one
+                            let result = match (params.get("address"), params.get("tx")) {
+                                (Some(address), None) => match address.parse::<H160>() {
+                                    Ok(address) => match label {
+                                        Some(label) => wallet.set_address_label(address, label).map(|_| label.clone()),
+                                        None => Ok(wallet.address_label(&address).unwrap_or("").to_string()),
+                                    },
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing address: {}", e));
+                                        return;
+                                    }
+                                },
+                                (None, Some(hash)) => match hash.parse::<H256>() {
+                                    Ok(hash) => match label {
+                                        Some(label) => wallet.set_tx_label(hash, label).map(|_| label.clone()),
+                                        None => Ok(wallet.tx_label(&hash).unwrap_or("").to_string()),
+                                    },
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing hash: {}", e));
+                                        return;
+                                    }
+                                },
+                                _ => {
+                                    respond_result!(req, false, "give either address or tx");
+                                    return;
+                                }
+                            };
+                            match result {
+                                Ok(label) => {
+                                    respond_result!(req, true, label);
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error saving labels: {}", e));
+                                }
+                            }
+                        }
+                        "/wallet/history" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let wallet = wallet.lock().unwrap();
+                            let mut history: Vec<WalletHistoryEntry> = wallet
+                                .transactions
+                                .iter()
+                                .map(|(hash, wtx)| {
+                                    let tx = &wtx.transaction;
+                                    let mut addresses: Vec<H160> =
+                                        tx.signers().iter().map(|signer| address_of(signer)).collect();
+                                    addresses.extend(tx.transaction.output.iter().map(|txout| txout.recipient));
+                                    let mut seen = HashSet::new();
+                                    addresses.retain(|address| seen.insert(*address));
+                                    WalletHistoryEntry {
+                                        hash: *hash,
+                                        state: wtx.state,
+                                        confirmations: wallet.confirmations(hash, &blockchain).unwrap(),
+                                        finality: wallet.finality(hash, &blockchain).unwrap(),
+                                        label: wallet.tx_label(hash).map(|label| label.to_string()),
+                                        address_labels: addresses
+                                            .into_iter()
+                                            .filter_map(|address| {
+                                                let label = wallet.address_label(&address)?.to_string();
+                                                Some(AddressLabel { address: address, label: label })
+                                            })
+                                            .collect(),
+                                    }
+                                })
+                                .collect();
+                            // most recent first, pending ones before confirmed ones
+                            history.sort_by_key(|entry| (entry.confirmations.max(0), entry.hash));
+                            respond_json!(req, history);
+                        }
+                        "/blocks" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let count = match params.get("count").map(|v| v.parse::<usize>()) {
+                                None => DEFAULT_PAGE_SIZE,
+                                Some(Ok(v)) if v <= MAX_PAGE_SIZE => v,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, format!("count exceeds {}", MAX_PAGE_SIZE));
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing count: {}", e));
+                                    return;
+                                }
+                            };
+                            let reverse = match params.get("reverse").map(|v| v.as_str()) {
+                                None | Some("false") => false,
+                                Some("true") => true,
+                                Some(v) => {
+                                    respond_result!(req, false, format!("error parsing reverse: {}", v));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            // a cursor names the first block of the page, so that a reorg between
+                            // two requests is noticed instead of silently skipping blocks
+                            let start = if let Some(cursor) = params.get("cursor") {
+                                let cursor = match cursor.parse::<H256>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing cursor: {}", e));
+                                        return;
+                                    }
+                                };
+                                if !blockchain.is_in_longest_chain(&cursor) {
+                                    respond_result!(req, false, "cursor is not in the longest chain");
+                                    return;
+                                }
+                                blockchain.height_of(&cursor).unwrap()
+                            } else {
+                                match params.get("start").map(|v| v.parse::<usize>()) {
+                                    None if reverse => blockchain.height(),
+                                    None => 0,
+                                    Some(Ok(v)) => v,
+                                    Some(Err(e)) => {
+                                        respond_result!(req, false, format!("error parsing start: {}", e));
+                                        return;
+                                    }
+                                }
+                            };
+                            // fetch one more block than asked for, it starts the next page
+                            let mut hashes = blockchain.longest_chain_range(start, count + 1, reverse);
+                            let next = if hashes.len() > count { hashes.pop() } else { None };
+                            let blocks = hashes
+                                .into_iter()
+                                .enumerate()
+                                .map(|(i, hash)| BlockRef {
+                                    height: if reverse { start - i } else { start + i },
+                                    hash: hash,
+                                })
+                                .collect();
+                            respond_json!(req, BlockPage { blocks: blocks, next: next });
+                        }
+                        "/blockchain/tip" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let wait = match params.get("wait").map(|v| v.parse::<u64>()) {
+                                None => Duration::from_millis(0),
+                                Some(Ok(v)) => Duration::from_millis(v).min(MAX_TIP_WAIT),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing wait: {}", e));
+                                    return;
+                                }
+                            };
+                            let known = match params.get("known").map(|v| v.parse::<H256>()) {
+                                None => None,
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing known: {}", e));
+                                    return;
+                                }
+                            };
+                            if let Some(known) = &known {
+                                tip_watch.wait(known, wait);
+                            }
+                            // read the tip from the chain, which is only released once a reorg
+                            // is complete
+                            let blockchain = blockchain.lock().unwrap();
+                            let tip = blockchain.tip();
+                            respond_json!(req, TipInfo {
+                                tip: tip,
+                                height: blockchain.height(),
+                                changed: known.map_or(true, |known| known != tip),
+                            });
+                        }
+                        "/blockchain/summary" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            respond_json!(req, ChainSummary {
+                                tip: blockchain.tip(),
+                                height: blockchain.height(),
+                                utxo_count: state.utxo.len(),
+                                utxo_commitment: state.commitment(),
+                            });
+                        }
+                        "/stats/supply" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let state = state.lock().unwrap();
+                            let height = blockchain.height();
+                            respond_json!(req, SupplyStats {
+                                height: height,
+                                next_subsidy: validation::block_subsidy(height + 1),
+                                scheduled: validation::scheduled_supply(height),
+                                utxo: state.supply(),
+                            });
+                        }
+                        "/stats/hashrate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let window = match params.get("window").map(|v| v.parse::<usize>()) {
+                                None => DEFAULT_HASHRATE_WINDOW,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing window: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let height = blockchain.height();
+                            respond_json!(req, HashrateStats {
+                                height: height,
+                                window: window.min(height),
+                                hashes_per_second: blockchain.estimated_hashrate(window).map(|rate| rate.to_f64()),
+                            });
+                        }
+                        "/stats/utxo" => {
+                            let stats = state.lock().unwrap().utxo_stats();
+                            respond_json!(req, stats);
+                        }
+                        "/stats/compression" => {
+                            respond_json!(req, workers.compression());
+                        }
+                        "/stats/memory" => {
+                            let memory = mempool.lock().unwrap().memory_budget();
+                            respond_json!(req, memory.stats());
+                        }
+                        "/stats/locks" => {
+                            respond_json!(req, locks::stats());
+                        }
+                        "/stats/forks" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let stats = forks.lock().unwrap().stats(&blockchain);
+                            respond_json!(req, stats);
+                        }
+                        "/blockchain/longest-chain" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let mut v = blockchain.all_blocks_in_longest_chain();
+                            v.reverse();
+                            respond_json!(req, v);
+                        }
+                        "/openapi.json" => {
+                            respond_json!(req, openapi::document());
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -110,5 +1517,6 @@ impl Server {
             }
         });
         info!("API server listening at {}", &addr);
+        Ok(addr)
     }
 }
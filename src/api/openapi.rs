@@ -0,0 +1,332 @@
+//! The OpenAPI document of the API, served at `/openapi.json`. Every endpoint is listed here
+//! with its parameters, and its bodies by their types, whose schemas come from `types`.
+
+use super::types::*;
+use crate::chainstate::TipChange;
+use crate::crypto::hash::{H160, H256};
+use crate::forks::ForkStats;
+use crate::locks::LockStats;
+use crate::memory::MemoryStats;
+use crate::miner::TemplatePreview;
+use crate::pool::PoolStats;
+use crate::network::banlist::Ban;
+use crate::network::message::CompressionSummary;
+use crate::network::peer::MessageStats;
+use crate::network::server::PeerInfo;
+use crate::transaction::UtxoStats;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+pub const OPENAPI_VERSION: &str = "3.0.3";
+
+enum Location {
+    Query,
+    Path,
+}
+
+struct Parameter {
+    name: &'static str,
+    location: Location,
+    required: bool,
+    description: &'static str,
+    schema: Value,
+}
+
+/// An endpoint and what goes in and out of it.
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    parameters: Vec<Parameter>,
+    body: Option<Value>,
+    /// Schema of the answer on success, `ApiResponse` for the endpoints that only acknowledge
+    response: Value,
+    /// Status 503 with the same body when not healthy, for the probes
+    probe: bool,
+    components: Vec<fn(&mut Map<String, Value>)>,
+}
+
+fn endpoint(method: &'static str, path: &'static str, summary: &'static str) -> Endpoint {
+    Endpoint {
+        method: method,
+        path: path,
+        summary: summary,
+        parameters: Vec::new(),
+        body: None,
+        response: ApiResponse::schema(),
+        probe: false,
+        components: vec![ApiResponse::components],
+    }
+}
+
+impl Endpoint {
+    fn query<T: Schema>(mut self, name: &'static str, required: bool, description: &'static str) -> Self {
+        self.parameters.push(Parameter {
+            name: name,
+            location: Location::Query,
+            required: required,
+            description: description,
+            schema: T::schema(),
+        });
+        self
+    }
+
+    fn path<T: Schema>(mut self, name: &'static str, description: &'static str) -> Self {
+        self.parameters.push(Parameter {
+            name: name,
+            location: Location::Path,
+            required: true,
+            description: description,
+            schema: T::schema(),
+        });
+        self
+    }
+
+    fn body<T: Schema>(mut self) -> Self {
+        self.body = Some(T::schema());
+        self.components.push(T::components);
+        self
+    }
+
+    /// The answer on success. Failures are answered with an `ApiResponse` whatever the endpoint.
+    fn returns<T: Schema>(mut self) -> Self {
+        self.response = json!({ "oneOf": [T::schema(), ApiResponse::schema()] });
+        self.components.push(T::components);
+        self
+    }
+
+    fn probe(mut self) -> Self {
+        self.response = Probe::schema();
+        self.probe = true;
+        self.components.push(Probe::components);
+        self
+    }
+
+    fn operation(&self) -> Value {
+        let parameters: Vec<Value> = self
+            .parameters
+            .iter()
+            .map(|parameter| {
+                json!({
+                    "name": parameter.name,
+                    "in": match parameter.location {
+                        Location::Query => "query",
+                        Location::Path => "path",
+                    },
+                    "required": parameter.required,
+                    "description": parameter.description,
+                    "schema": parameter.schema,
+                })
+            })
+            .collect();
+        let content = json!({ "application/json": { "schema": self.response } });
+        let mut responses = Map::new();
+        responses.insert("200".to_string(), json!({ "description": "OK", "content": content }));
+        if self.probe {
+            responses.insert("503".to_string(), json!({ "description": "Not healthy", "content": content }));
+        }
+        let mut operation = json!({ "summary": self.summary, "parameters": parameters, "responses": responses });
+        if let Some(body) = &self.body {
+            operation["requestBody"] =
+                json!({ "required": true, "content": { "application/json": { "schema": body } } });
+        }
+        operation
+    }
+}
+
+fn endpoints() -> Vec<Endpoint> {
+    vec![
+        endpoint("get", "/openapi.json", "This document"),
+        endpoint("get", "/status", "Version, chain, peers, mempool, miner and sync status of the node").returns::<NodeStatus>(),
+        endpoint("get", "/healthz", "Whether the threads of the node are running").probe(),
+        endpoint("get", "/readyz", "Whether the node is healthy, synced and connected to enough peers").probe(),
+        endpoint("get", "/miner/start", "Start mining")
+            .query::<u64>("lambda", true, "Microseconds between two mining attempts"),
+        endpoint("post", "/miner/generate", "Mine blocks right away, on regtest only")
+            .query::<usize>("blocks", false, "Blocks to mine, 1 by default")
+            .query::<String>("address", true, "Address the block rewards pay")
+            .returns::<Vec<H256>>(),
+        endpoint("get", "/miner/set-address", "Pay the rewards of the blocks mined from now on to an address")
+            .query::<String>("address", true, "Address the block rewards pay"),
+        endpoint("get", "/miner/template/preview", "The block the miner would mine next").returns::<TemplatePreview>(),
+        endpoint("get", "/pool/stats", "The shares of the members of the pool this node serves").returns::<PoolStats>(),
+        endpoint("get", "/admin/loglevel", "Change the level of the messages logged, for a module or by default")
+            .query::<String>("target", false, "Module path without the crate name, such as network::worker, the default level if missing")
+            .query::<String>("level", true, "off, error, warn, info, debug or trace"),
+        endpoint("get", "/address/validate", "Check an address").query::<String>("address", true, "Address to check"),
+        endpoint("get", "/network/ping", "Ping every peer"),
+        endpoint("get", "/network/peers", "The connected peers").returns::<Vec<PeerInfo>>(),
+        endpoint("post", "/network/ban", "Ban an IP address")
+            .query::<String>("addr", true, "IP address, or socket address whose IP address to ban")
+            .query::<u64>("duration", false, "Seconds, a day by default"),
+        endpoint("get", "/network/bans", "The banned addresses").returns::<Vec<Ban>>(),
+        endpoint("get", "/network/peers/{addr}/stats", "Traffic with a peer by message kind")
+            .path::<String>("addr", "Socket address of the peer")
+            .returns::<MessageStats>(),
+        endpoint("delete", "/network/ban/{addr}", "Lift the ban of an IP address")
+            .path::<String>("addr", "IP address, or socket address whose IP address to unban"),
+        endpoint("get", "/tx/submit", "Submit a transaction to the mempool, answering with its hash")
+            .query::<String>("tx", true, "Hex-encoded serialized transaction"),
+        endpoint("post", "/tx/validate", "Check transactions against the tip without keeping them")
+            .body::<ValidateRequest>()
+            .returns::<Vec<TxValidation>>(),
+        endpoint("get", "/tx/{hash}/status", "Where a transaction stands")
+            .path::<H256>("hash", "Transaction hash")
+            .returns::<TxStatus>(),
+        endpoint("get", "/tx/{hash}/proof", "Merkle proof of a transaction in its block")
+            .path::<H256>("hash", "Transaction hash")
+            .returns::<TxProof>(),
+        endpoint("get", "/tx/{hash}/details", "A transaction with the outputs it spends")
+            .path::<H256>("hash", "Transaction hash")
+            .returns::<TxDetails>(),
+        endpoint("post", "/block/{hash}/invalidate", "Mark a block invalid, with its descendants")
+            .path::<H256>("hash", "Block hash")
+            .returns::<TipChange>(),
+        endpoint("post", "/block/{hash}/reconsider", "Lift the invalid marks of a block and its ancestors")
+            .path::<H256>("hash", "Block hash")
+            .returns::<TipChange>(),
+        endpoint("get", "/block/{hash}/utxo-commitment", "Commitment to the UTXO set after a block")
+            .path::<H256>("hash", "Block hash")
+            .returns::<BlockCommitment>(),
+        endpoint("get", "/mempool/transactions", "Mempool transactions, highest fee rate first")
+            .query::<u64>("min_fee_rate", false, "Lowest fee per 1000 bytes")
+            .query::<u128>("since", false, "Only transactions added after, in milliseconds since the UNIX epoch")
+            .query::<usize>("limit", false, "Most transactions, 100 by default and at most 1000")
+            .returns::<Vec<MempoolTxSummary>>(),
+        endpoint("get", "/mempool/graph", "Mempool transactions with their parents and children")
+            .returns::<BTreeMap<H256, MempoolNode>>(),
+        endpoint("get", "/wallet/send", "Pay an address from the wallet, answering with the transaction hash")
+            .query::<String>("recipient", true, "Address to pay")
+            .query::<u64>("value", true, "Value to pay"),
+        endpoint("post", "/wallet/sendmany", "Pay several addresses in one transaction, answering with its hash")
+            .body::<SendManyRequest>(),
+        endpoint("post", "/wallet/psbt/create", "Start a partially signed transaction spending outputs of any owner")
+            .body::<PsbtCreateRequest>()
+            .returns::<PsbtInfo>(),
+        endpoint("post", "/wallet/psbt/fund", "Start a partially signed transaction paying out of a watch-only address")
+            .body::<SendManyRequest>()
+            .returns::<PsbtInfo>(),
+        endpoint("post", "/wallet/psbt/sign", "Sign the inputs of a partially signed transaction owned by the wallet")
+            .body::<PsbtSignRequest>()
+            .returns::<PsbtInfo>(),
+        endpoint("post", "/wallet/psbt/combine", "Merge the signatures of partially signed versions of a transaction")
+            .body::<PsbtCombineRequest>()
+            .returns::<PsbtInfo>(),
+        endpoint("post", "/wallet/psbt/finalize", "Assemble a fully signed transaction, to submit with /tx/submit")
+            .body::<PsbtFinalizeRequest>()
+            .returns::<FinalizedPsbt>(),
+        endpoint("post", "/wallet/import", "Add a key to the wallet and rescan the longest chain for its transactions")
+            .body::<WalletImportRequest>()
+            .returns::<WalletImport>(),
+        endpoint("post", "/wallet/watch", "Track an address without its key and rescan the longest chain for its transactions")
+            .body::<WalletWatchRequest>()
+            .returns::<WalletImport>(),
+        endpoint("get", "/wallet/balance", "The unspent value of each address of the wallet").returns::<Vec<AddressBalance>>(),
+        endpoint("get", "/wallet/transactions", "The wallet transactions").returns::<Vec<WalletTxInfo>>(),
+        endpoint("get", "/wallet/label", "Label an address or a transaction, or read its label")
+            .query::<String>("label", false, "New label, the current one is answered without")
+            .query::<H160>("address", false, "Address to label, instead of tx")
+            .query::<H256>("tx", false, "Transaction to label, instead of address"),
+        endpoint("get", "/wallet/history", "The wallet transactions with their labels, most recent first")
+            .returns::<Vec<WalletHistoryEntry>>(),
+        endpoint("get", "/blocks", "A page of the longest chain")
+            .query::<usize>("count", false, "Blocks per page, 100 by default and at most 1000")
+            .query::<bool>("reverse", false, "From the tip down")
+            .query::<H256>("cursor", false, "First block of the page, the `next` of the previous page")
+            .query::<usize>("start", false, "Height of the first block, without cursor")
+            .returns::<BlockPage>(),
+        endpoint("get", "/blockchain/tip", "The tip, waiting for it to change if asked to")
+            .query::<u64>("wait", false, "Seconds to wait for the tip to differ from known, at most 60")
+            .query::<H256>("known", false, "The tip the client knows")
+            .returns::<TipInfo>(),
+        endpoint("get", "/blockchain/summary", "The tip and the UTXO set").returns::<ChainSummary>(),
+        endpoint("get", "/stats/supply", "Coins in existence").returns::<SupplyStats>(),
+        endpoint("get", "/stats/hashrate", "Hash rate estimated from the work and timestamps of the last blocks")
+            .query::<usize>("window", false, "Blocks to average over, 120 by default")
+            .returns::<HashrateStats>(),
+        endpoint("get", "/stats/utxo", "Statistics of the UTXO set").returns::<UtxoStats>(),
+        endpoint("get", "/stats/compression", "Bytes sent and received compressed").returns::<CompressionSummary>(),
+        endpoint("get", "/stats/memory", "Memory held by the mempool, the orphan pools and the block cache")
+            .returns::<MemoryStats>(),
+        endpoint("get", "/stats/locks", "Contention of the locks by rank").returns::<Vec<LockStats>>(),
+        endpoint("get", "/stats/forks", "Reorgs and competing tips").returns::<ForkStats>(),
+        endpoint("get", "/blockchain/longest-chain", "Hashes of the longest chain, from the tip down")
+            .returns::<Vec<H256>>(),
+    ]
+}
+
+/// The OpenAPI document of the API.
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    let mut components = Map::new();
+    for endpoint in endpoints() {
+        let operation = endpoint.operation();
+        let path = paths.entry(endpoint.path).or_insert_with(|| json!({}));
+        path[endpoint.method] = operation;
+        for add_components in &endpoint.components {
+            add_components(&mut components);
+        }
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": { "title": "Bitcoin node API", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": { "schemas": components },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Capabilities;
+
+    /// The references in a schema, recursively.
+    fn references(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::String(reference)) = object.get("$ref") {
+                    found.push(reference.clone());
+                }
+                object.values().for_each(|value| references(value, found));
+            }
+            Value::Array(array) => array.iter().for_each(|value| references(value, found)),
+            _ => {}
+        }
+    }
+
+    /// The properties of a component, sorted.
+    fn properties(document: &Value, name: &str) -> Vec<String> {
+        let mut properties: Vec<String> =
+            document["components"]["schemas"][name]["properties"].as_object().unwrap().keys().cloned().collect();
+        properties.sort();
+        properties
+    }
+
+    /// The fields of a value serialized to JSON, sorted.
+    fn fields<T: serde::Serialize>(value: &T) -> Vec<String> {
+        let mut fields: Vec<String> = serde_json::to_value(value).unwrap().as_object().unwrap().keys().cloned().collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn document_complete() {
+        let document = document();
+        assert_eq!(document["openapi"], OPENAPI_VERSION);
+        let mut found = Vec::new();
+        references(&document, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(document["components"]["schemas"].get(name).is_some(), "{} not described", reference);
+        }
+        assert!(document["paths"]["/tx/{hash}/status"]["get"]["parameters"][0]["in"] == "path");
+        assert!(document["paths"]["/network/ban"]["post"].is_object());
+
+        // the schemas of the types of other modules list the fields they serialize
+        assert_eq!(properties(&document, "MessageStats"), fields(&MessageStats::default()));
+        assert_eq!(properties(&document, "CompressionSummary"), fields(&CompressionSummary::default()));
+        assert_eq!(properties(&document, "TipChange"), fields(&TipChange::default()));
+        assert_eq!(properties(&document, "Capabilities"), fields(&Capabilities::local()));
+    }
+}
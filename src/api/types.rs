@@ -0,0 +1,804 @@
+//! Request and response bodies of the API, and the JSON schemas `/openapi.json` describes them
+//! with. Structs declared with `api_struct!` get their schema from their fields; the types of
+//! other modules the API serves have theirs spelled out at the end of the file.
+
+use crate::amount::Amount;
+use crate::blockchain::Finality;
+use crate::chainparams::Network;
+use crate::chainstate::TipChange;
+use crate::crypto::hash::{H160, H256};
+use crate::crypto::merkle::MerkleProof;
+use crate::forks::{CompetingTip, ForkStats, ReorgEvent};
+use crate::locks::{LockStats, Rank};
+use crate::memory::{MemoryStats, Pool, PoolUsage};
+use crate::miner::{Status as MinerStatus, TemplatePreview, TemplateTransaction};
+use crate::pool::{MemberStats, PoolStats};
+use crate::network::banlist::Ban;
+use crate::network::message::{Capabilities, CompressionSummary};
+use crate::network::peer::{Direction, MessageCount, MessageStats};
+use crate::network::server::PeerInfo;
+use crate::transaction::{SigHash, UtxoStats, ValueBucket};
+use crate::wallet::TxState;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+
+/// Types whose JSON form has a schema in the OpenAPI document.
+pub trait Schema {
+    /// Schema of the JSON form, a reference for the types listed under `components`.
+    fn schema() -> Value;
+
+    /// Add the schemas of this type and the types it is made of, if listed under `components`.
+    fn components(_components: &mut Map<String, Value>) {}
+}
+
+/// Implement `Schema` for a struct serialized field by field, listed under `components`.
+macro_rules! object_schema {
+    ( $name:ident { $( $field:ident: $ty:ty ),* $(,)? } ) => {
+        impl Schema for $name {
+            fn schema() -> Value {
+                json!({ "$ref": concat!("#/components/schemas/", stringify!($name)) })
+            }
+
+            fn components(components: &mut Map<String, Value>) {
+                if components.contains_key(stringify!($name)) {
+                    return;
+                }
+                let mut properties = Map::new();
+                $( properties.insert(stringify!($field).to_string(), <$ty as Schema>::schema()); )*
+                let object = json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": [ $( stringify!($field) ),* ],
+                });
+                components.insert(stringify!($name).to_string(), object);
+                $( <$ty as Schema>::components(components); )*
+            }
+        }
+    };
+}
+
+/// Declare a struct and implement `Schema` for it from its fields.
+macro_rules! api_struct {
+    (
+        $( #[$meta:meta] )*
+        pub struct $name:ident {
+            $( $( #[$field_meta:meta] )* pub $field:ident: $ty:ty, )*
+        }
+    ) => {
+        $( #[$meta] )*
+        pub struct $name {
+            $( $( #[$field_meta] )* pub $field: $ty, )*
+        }
+
+        object_schema!($name { $( $field: $ty ),* });
+    };
+}
+
+/// Schema of a unit-only enum serialized as the names of its variants.
+fn string_enum(names: &[&str]) -> Value {
+    json!({ "type": "string", "enum": names })
+}
+
+/// Schema of an enum serialized with `#[serde(tag = ...)]`: an object per variant, with the
+/// name of the variant under `tag` next to its fields.
+fn tagged_enum(tag: &str, variants: Vec<(&str, Vec<(&str, Value)>)>) -> Value {
+    let variants: Vec<Value> = variants
+        .into_iter()
+        .map(|(name, fields)| {
+            let mut required = vec![tag];
+            let mut properties = Map::new();
+            properties.insert(tag.to_string(), string_enum(&[name]));
+            for (field, schema) in fields {
+                required.push(field);
+                properties.insert(field.to_string(), schema);
+            }
+            json!({ "type": "object", "properties": properties, "required": required })
+        })
+        .collect();
+    json!({ "oneOf": variants })
+}
+
+impl Schema for bool {
+    fn schema() -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+impl Schema for String {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl Schema for &str {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+macro_rules! unsigned_schema {
+    ( $( $ty:ty ),* ) => {
+        $(
+            impl Schema for $ty {
+                fn schema() -> Value {
+                    json!({ "type": "integer", "minimum": 0 })
+                }
+            }
+        )*
+    };
+}
+
+unsigned_schema!(u8, u32, u64, u128, usize, Amount);
+
+impl Schema for i64 {
+    fn schema() -> Value {
+        json!({ "type": "integer" })
+    }
+}
+
+impl Schema for f64 {
+    fn schema() -> Value {
+        json!({ "type": "number" })
+    }
+}
+
+impl Schema for H256 {
+    fn schema() -> Value {
+        json!({ "type": "string", "pattern": "^0x[0-9a-f]{64}$" })
+    }
+}
+
+impl Schema for H160 {
+    fn schema() -> Value {
+        json!({ "type": "string", "pattern": "^0x[0-9a-f]{40}$" })
+    }
+}
+
+impl Schema for IpAddr {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl Schema for SocketAddr {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl<T: Schema> Schema for Option<T> {
+    fn schema() -> Value {
+        let mut schema = T::schema();
+        // siblings of a reference are ignored
+        if schema.get("$ref").is_some() {
+            return json!({ "allOf": [schema], "nullable": true });
+        }
+        schema["nullable"] = json!(true);
+        schema
+    }
+
+    fn components(components: &mut Map<String, Value>) {
+        T::components(components);
+    }
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+
+    fn components(components: &mut Map<String, Value>) {
+        T::components(components);
+    }
+}
+
+impl<K, V: Schema> Schema for BTreeMap<K, V> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": V::schema() })
+    }
+
+    fn components(components: &mut Map<String, Value>) {
+        V::components(components);
+    }
+}
+
+impl<K, V: Schema> Schema for HashMap<K, V> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": V::schema() })
+    }
+
+    fn components(components: &mut Map<String, Value>) {
+        V::components(components);
+    }
+}
+
+api_struct! {
+    /// Answer of the endpoints that only acknowledge, and of every endpoint on failure.
+    #[derive(Serialize)]
+    pub struct ApiResponse {
+        pub success: bool,
+        pub message: String,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum TxStatus {
+    Unknown,
+    Mempool {
+        time_added: u128,
+        fee: Option<Amount>,
+        finality: Finality,
+    },
+    Confirmed {
+        block: H256,
+        height: usize,
+        confirmations: i64,
+        finality: Finality,
+    },
+}
+
+impl Schema for TxStatus {
+    fn schema() -> Value {
+        tagged_enum(
+            "status",
+            vec![
+                ("unknown", vec![]),
+                (
+                    "mempool",
+                    vec![
+                        ("time_added", u128::schema()),
+                        ("fee", Option::<Amount>::schema()),
+                        ("finality", Finality::schema()),
+                    ],
+                ),
+                (
+                    "confirmed",
+                    vec![
+                        ("block", H256::schema()),
+                        ("height", usize::schema()),
+                        ("confirmations", i64::schema()),
+                        ("finality", Finality::schema()),
+                    ],
+                ),
+            ],
+        )
+    }
+}
+
+api_struct! {
+    /// Body of `POST /tx/validate`: hex-encoded serialized transactions.
+    #[derive(Deserialize)]
+    pub struct ValidateRequest {
+        pub transactions: Vec<String>,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct TxValidation {
+        /// `None` when the transaction cannot be decoded.
+        pub hash: Option<H256>,
+        pub valid: bool,
+        pub fee: Option<Amount>,
+        pub error: Option<String>,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/sendmany` and `POST /wallet/psbt/fund`: the value paid to each
+    /// recipient address.
+    #[derive(Deserialize)]
+    pub struct SendManyRequest {
+        pub recipients: BTreeMap<String, Amount>,
+    }
+}
+
+api_struct! {
+    /// An output spent by a partially signed transaction.
+    #[derive(Deserialize)]
+    pub struct Outpoint {
+        pub txid: H256,
+        pub index: u8,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/psbt/create`: the outputs to spend, whoever owns them, and the
+    /// value paid to each recipient address. Whatever the outputs do not pay is the fee.
+    #[derive(Deserialize)]
+    pub struct PsbtCreateRequest {
+        pub inputs: Vec<Outpoint>,
+        pub outputs: BTreeMap<String, Amount>,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/psbt/sign`: a hex-encoded partially signed transaction, with the
+    /// inputs owned by the wallet signed under `sighash`, `all` by default.
+    #[derive(Deserialize)]
+    pub struct PsbtSignRequest {
+        pub psbt: String,
+        pub sighash: Option<SigHash>,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/psbt/combine`: partially signed versions of the same transaction.
+    #[derive(Deserialize)]
+    pub struct PsbtCombineRequest {
+        pub psbts: Vec<String>,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/psbt/finalize`.
+    #[derive(Deserialize)]
+    pub struct PsbtFinalizeRequest {
+        pub psbt: String,
+    }
+}
+
+api_struct! {
+    /// A partially signed transaction, hex-encoded to be passed on to the other signers.
+    #[derive(Serialize)]
+    pub struct PsbtInfo {
+        pub psbt: String,
+        pub txid: H256,
+        pub fee: Amount,
+        pub inputs: usize,
+        /// Inputs signed so far, by any signer.
+        pub signed: usize,
+        pub complete: bool,
+    }
+}
+
+api_struct! {
+    /// A finalized transaction, hex-encoded for `/tx/submit`.
+    #[derive(Serialize)]
+    pub struct FinalizedPsbt {
+        pub txid: H256,
+        pub tx: String,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/import`: the hex-encoded 32-byte seed of an Ed25519 key, and the
+    /// height to look for its transactions from, the genesis block by default.
+    #[derive(Deserialize)]
+    pub struct WalletImportRequest {
+        pub seed: String,
+        pub rescan_from: Option<usize>,
+    }
+}
+
+api_struct! {
+    /// Body of `POST /wallet/watch`: an address to track without its key, and the height to
+    /// look for its transactions from, the genesis block by default.
+    #[derive(Deserialize)]
+    pub struct WalletWatchRequest {
+        pub address: H160,
+        pub rescan_from: Option<usize>,
+    }
+}
+
+api_struct! {
+    /// An imported key or watch-only address, and what the rescan found.
+    #[derive(Serialize)]
+    pub struct WalletImport {
+        pub address: H160,
+        /// Blocks of the longest chain read by the rescan.
+        pub scanned: usize,
+        /// Transactions of the wallet found that it did not know.
+        pub transactions: usize,
+    }
+}
+
+api_struct! {
+    /// The unspent outputs of an address of the wallet, immature ones included.
+    #[derive(Serialize)]
+    pub struct AddressBalance {
+        pub address: H160,
+        pub balance: Amount,
+        /// Tracked without its key, spent through `/wallet/psbt/fund`.
+        pub watch_only: bool,
+    }
+}
+
+api_struct! {
+    /// A transaction listed by `/mempool/transactions`.
+    #[derive(Serialize)]
+    pub struct MempoolTxSummary {
+        pub txid: H256,
+        pub size: usize,
+        /// Only known for transactions accepted through validation.
+        pub fee: Option<Amount>,
+        /// Fee per 1000 bytes, the unit of `Policy::min_fee_rate`.
+        pub fee_rate: Option<u64>,
+        /// Milliseconds since the transaction entered the mempool.
+        pub age: u128,
+        pub inputs: usize,
+        pub outputs: usize,
+    }
+}
+
+api_struct! {
+    /// A transaction of `/mempool/graph`, with the mempool transactions it depends on and that
+    /// depend on it.
+    #[derive(Serialize)]
+    pub struct MempoolNode {
+        pub parents: Vec<H256>,
+        pub children: Vec<H256>,
+        /// Only known for transactions accepted through validation.
+        pub fee: Option<Amount>,
+        pub size: usize,
+        /// Fee per 1000 bytes, the unit of `Policy::min_fee_rate`.
+        pub fee_rate: Option<u64>,
+        /// Milliseconds since the UNIX epoch.
+        pub time_added: u128,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct TxProof {
+        pub block: H256,
+        pub proof: MerkleProof,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct InputDetails {
+        pub previous_output: H256,
+        pub index: u8,
+        /// `None` when the spent output can be found neither in the UTXO set nor in the chain.
+        pub value: Option<Amount>,
+        pub address: Option<String>,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct OutputDetails {
+        pub value: Amount,
+        pub address: String,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct TxDetails {
+        pub hash: H256,
+        /// The containing block of the longest chain, `None` for mempool transactions.
+        pub block: Option<H256>,
+        /// 0 for mempool transactions.
+        pub confirmations: i64,
+        pub finality: Finality,
+        pub size: usize,
+        pub inputs: Vec<InputDetails>,
+        pub outputs: Vec<OutputDetails>,
+        /// `None` when an input is unknown, or the values add up past `Amount::MAX_MONEY`.
+        pub total_in: Option<Amount>,
+        pub total_out: Option<Amount>,
+        pub fee: Option<Amount>,
+        /// Fee per 1000 bytes, the unit of `Policy::min_fee_rate`.
+        pub fee_rate: Option<u64>,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize, Deserialize)]
+    pub struct BlockRef {
+        pub height: usize,
+        pub hash: H256,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize, Deserialize)]
+    pub struct BlockPage {
+        pub blocks: Vec<BlockRef>,
+        /// Pass as `cursor` to get the following page, `None` on the last page.
+        pub next: Option<H256>,
+    }
+}
+
+api_struct! {
+    /// The tip and UTXO set of a node, as served by `/blockchain/summary`.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ChainSummary {
+        pub tip: H256,
+        pub height: usize,
+        pub utxo_count: usize,
+        pub utxo_commitment: H256,
+    }
+}
+
+api_struct! {
+    /// Hash rate estimated from the longest chain, as served by `/stats/hashrate`.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct HashrateStats {
+        pub height: usize,
+        /// Blocks the estimate is based on, fewer than requested near the genesis block
+        pub window: usize,
+        /// See `Blockchain::estimated_hashrate`
+        pub hashes_per_second: Option<f64>,
+    }
+}
+
+api_struct! {
+    /// Coins in existence, as served by `/stats/supply`.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SupplyStats {
+        pub height: usize,
+        /// Subsidy the next block may claim
+        pub next_subsidy: Amount,
+        /// The ICO and the subsidies of the longest chain, the most coins that can exist
+        pub scheduled: Amount,
+        /// Sum of the UTXO set, `None` past `Amount::MAX_MONEY`. Above `scheduled` only if coins
+        /// were created out of thin air.
+        pub utxo: Option<Amount>,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct BlockCommitment {
+        pub block: H256,
+        pub height: usize,
+        /// -1 once the block left the longest chain.
+        pub confirmations: i64,
+        pub utxo_commitment: H256,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct TipInfo {
+        pub tip: H256,
+        pub height: usize,
+        /// Whether the tip differs from the `known` one
+        pub changed: bool,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct SyncStatus {
+        /// Whether blocks announced by peers are still being downloaded or validated.
+        pub syncing: bool,
+        pub blocks_in_flight: usize,
+        pub blocks_queued: usize,
+        /// Received blocks waiting for the validation thread
+        pub blocks_validating: usize,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct NodeStatus {
+        pub version: &'static str,
+        pub network: Network,
+        pub protocol: u32,
+        pub capabilities: Capabilities,
+        /// Seconds since the node started.
+        pub uptime: u64,
+        pub height: usize,
+        pub tip: H256,
+        /// `None` when the P2P event loop does not respond.
+        pub peers: Option<usize>,
+        pub mempool_size: usize,
+        pub orphan_transactions: usize,
+        /// Times a P2P worker thread panicked and was restarted
+        pub worker_restarts: usize,
+        pub miner: MinerStatus,
+        pub sync: SyncStatus,
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct ProbeCheck {
+        pub name: &'static str,
+        pub ok: bool,
+        pub detail: String,
+    }
+}
+
+api_struct! {
+    /// Answer of `/healthz` and `/readyz`, served with status 503 unless `ok`.
+    #[derive(Serialize)]
+    pub struct Probe {
+        pub ok: bool,
+        pub checks: Vec<ProbeCheck>,
+    }
+}
+
+impl Probe {
+    pub fn new(checks: Vec<ProbeCheck>) -> Self {
+        Probe { ok: checks.iter().all(|c| c.ok), checks: checks }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WalletTxInfo {
+    pub hash: H256,
+    #[serde(flatten)]
+    pub state: TxState,
+    pub confirmations: i64,
+    pub finality: Finality,
+}
+
+impl Schema for WalletTxInfo {
+    fn schema() -> Value {
+        json!({ "$ref": "#/components/schemas/WalletTxInfo" })
+    }
+
+    fn components(components: &mut Map<String, Value>) {
+        let fields = json!({
+            "type": "object",
+            "properties": {
+                "hash": H256::schema(),
+                "confirmations": i64::schema(),
+                "finality": Finality::schema(),
+            },
+            "required": ["hash", "confirmations", "finality"],
+        });
+        components.insert("WalletTxInfo".to_string(), json!({ "allOf": [fields, TxState::schema()] }));
+    }
+}
+
+api_struct! {
+    #[derive(Serialize)]
+    pub struct AddressLabel {
+        pub address: H160,
+        pub label: String,
+    }
+}
+
+#[derive(Serialize)]
+pub struct WalletHistoryEntry {
+    pub hash: H256,
+    #[serde(flatten)]
+    pub state: TxState,
+    pub confirmations: i64,
+    pub finality: Finality,
+    pub label: Option<String>,
+    /// Labels of the addresses the transaction is signed by or pays
+    pub address_labels: Vec<AddressLabel>,
+}
+
+impl Schema for WalletHistoryEntry {
+    fn schema() -> Value {
+        json!({ "$ref": "#/components/schemas/WalletHistoryEntry" })
+    }
+
+    fn components(components: &mut Map<String, Value>) {
+        let fields = json!({
+            "type": "object",
+            "properties": {
+                "hash": H256::schema(),
+                "confirmations": i64::schema(),
+                "finality": Finality::schema(),
+                "label": Option::<String>::schema(),
+                "address_labels": Vec::<AddressLabel>::schema(),
+            },
+            "required": ["hash", "confirmations", "finality", "label", "address_labels"],
+        });
+        components.insert("WalletHistoryEntry".to_string(), json!({ "allOf": [fields, TxState::schema()] }));
+        AddressLabel::components(components);
+    }
+}
+
+// the types of other modules served by the API
+
+impl Schema for Finality {
+    fn schema() -> Value {
+        string_enum(&["pending", "confirmed", "final"])
+    }
+}
+
+impl Schema for SigHash {
+    fn schema() -> Value {
+        string_enum(&["all", "single", "all_anyone_can_pay", "single_anyone_can_pay"])
+    }
+}
+
+impl Schema for Network {
+    fn schema() -> Value {
+        string_enum(&["mainnet", "testnet", "regtest"])
+    }
+}
+
+impl Schema for Direction {
+    fn schema() -> Value {
+        string_enum(&["incoming", "outgoing"])
+    }
+}
+
+impl Schema for Pool {
+    fn schema() -> Value {
+        string_enum(&["mempool", "orphan_transactions", "orphan_blocks", "block_cache"])
+    }
+}
+
+impl Schema for Rank {
+    fn schema() -> Value {
+        string_enum(&["chain", "orphan_buffer", "scheduler", "mempool", "state", "wallet"])
+    }
+}
+
+impl Schema for MinerStatus {
+    fn schema() -> Value {
+        tagged_enum("state", vec![("paused", vec![]), ("running", vec![("lambda", u64::schema())]), ("stopped", vec![])])
+    }
+}
+
+impl Schema for TxState {
+    fn schema() -> Value {
+        tagged_enum(
+            "state",
+            vec![
+                ("pending", vec![]),
+                ("confirmed", vec![("block", H256::schema()), ("height", usize::schema())]),
+                ("conflicted", vec![("by", H256::schema())]),
+            ],
+        )
+    }
+}
+
+object_schema!(MerkleProof { siblings: Vec<H256>, index: usize, leaf_count: usize });
+object_schema!(Capabilities {
+    txindex: bool,
+    addrindex: bool,
+    pruning: bool,
+    light: bool,
+    compression: bool,
+    compact_blocks: bool,
+});
+object_schema!(TipChange { disconnected: Vec<H256>, connected: Vec<H256> });
+object_schema!(PeerInfo { addr: SocketAddr, direction: Direction, ping_ms: Option<u64>, ping_pending: bool });
+object_schema!(Ban { addr: IpAddr, until: u128 });
+object_schema!(PoolUsage { pool: Pool, bytes: usize, evicted: usize });
+object_schema!(MemoryStats { limit: Option<usize>, total: usize, pools: Vec<PoolUsage> });
+object_schema!(MemberStats { addr: SocketAddr, shares: u64, blocks: u64, rejected: u64 });
+object_schema!(PoolStats { job: Option<u64>, members: Vec<MemberStats> });
+object_schema!(MessageCount { messages: u64, bytes: u64 });
+object_schema!(MessageStats {
+    sent: BTreeMap<String, MessageCount>,
+    received: BTreeMap<String, MessageCount>,
+    rejected: u64,
+});
+object_schema!(CompressionSummary {
+    sent_raw: u64,
+    sent_compressed: u64,
+    received_raw: u64,
+    received_compressed: u64,
+    bytes_saved: u64,
+});
+object_schema!(LockStats { lock: Rank, acquisitions: u64, total_wait_us: u64, total_hold_us: u64, max_hold_us: u64 });
+object_schema!(ReorgEvent { depth: usize, fork_height: usize, old_tip: H256 });
+object_schema!(CompetingTip { hash: H256, height: usize, fork_height: usize });
+object_schema!(ForkStats {
+    stale_blocks: usize,
+    reorgs: usize,
+    max_reorg_depth: usize,
+    recent_reorgs: Vec<ReorgEvent>,
+    competing_tips: Vec<CompetingTip>,
+});
+object_schema!(ValueBucket { min: u64, max: u64, count: usize });
+object_schema!(UtxoStats { count: usize, total_value: Option<Amount>, histogram: Vec<ValueBucket>, addresses: usize });
+object_schema!(TemplateTransaction { txid: H256, size: usize, fee: Option<Amount>, fee_rate: Option<u64> });
+object_schema!(TemplatePreview {
+    parent: H256,
+    height: u64,
+    payout: Option<H160>,
+    reward: Option<Amount>,
+    transactions: Vec<TemplateTransaction>,
+    total_fee: Amount,
+    transactions_size: usize,
+    block_size: usize,
+});
@@ -0,0 +1,354 @@
+//! Benchmarks of the node's data structures against synthetic data, run with `bench`.
+
+use crate::block::{self, Block, Content, Header};
+use crate::blockchain::Blockchain;
+use crate::crypto::address::address_of;
+use crate::crypto::hash::{H256, Hashable, Hashed};
+use crate::crypto::merkle::MerkleTree;
+use crate::locks::OrderedMutex;
+use crate::miner::{self, BLOCK_SIZE_LIMIT};
+use crate::network::block_cache::{self, BlockCache};
+use crate::policy::Policy;
+use crate::transaction::{self, Mempool, Revalidation, SignedTransaction, State, Transaction, TxIn, TxOut};
+use crate::wallet::{self, Wallet};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of keys owning the outputs spent by the synthetic transactions.
+const KEYS: usize = 16;
+/// Value of each synthetic output.
+const OUTPUT_VALUE: u64 = 1000;
+
+/// Timings of `mempool`.
+pub struct MempoolReport {
+    pub transactions: usize,
+    /// Signing the transactions, not part of the mempool
+    pub generation: Duration,
+    pub insertion: Duration,
+    pub accepted: usize,
+    pub selection: Duration,
+    pub selected: usize,
+    /// Revalidating the mempool after a block spent the inputs of every other transaction
+    pub revalidation: Duration,
+    pub evicted: usize,
+    /// Removing the transactions of a block, one by one
+    pub removal: Duration,
+    pub removed: usize,
+}
+
+fn outpoint(i: usize) -> H256 {
+    let mut bytes = [0u8; 32];
+    bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+    bytes.into()
+}
+
+fn spend(key: &Ed25519KeyPair, i: usize, value: u64) -> SignedTransaction {
+    let input = TxIn { previous_output: outpoint(i), index: 0 };
+    let output = TxOut { recipient: [9u8; 20].into(), value: value.into() };
+    SignedTransaction::new(Transaction { version: transaction::VERSION, input: vec![input], output: vec![output] }, key)
+}
+
+fn keys() -> Vec<Ed25519KeyPair> {
+    (0..KEYS).map(|i| Ed25519KeyPair::from_seed_unchecked(&[i as u8; 32]).unwrap()).collect()
+}
+
+/// `count` transactions paying varied fees, each spending an output added to `state`.
+fn funded_spends(keys: &[Ed25519KeyPair], count: usize, state: &mut State) -> Vec<SignedTransaction> {
+    (0..count)
+        .map(|i| {
+            let key = &keys[i % KEYS];
+            state.insert_utxo((outpoint(i), 0), (OUTPUT_VALUE.into(), address_of(key.public_key().as_ref())));
+            spend(key, i, OUTPUT_VALUE - (i % 100) as u64)
+        })
+        .collect()
+}
+
+/// Fill a mempool with `count` valid transactions paying varied fees, then time template
+/// selection, the revalidation after a block conflicting with half of them, and the removal of
+/// the rest.
+pub fn mempool(count: usize) -> MempoolReport {
+    let keys = keys();
+    let mut state = State::default();
+    let start = Instant::now();
+    let transactions = funded_spends(&keys, count, &mut state);
+    let generation = start.elapsed();
+
+    let policy = Policy::default();
+    let mut mempool = Mempool::new();
+    let start = Instant::now();
+    let accepted = transactions.iter().filter(|tx| mempool.accept(tx, &state, &policy).is_ok()).count();
+    let insertion = start.elapsed();
+
+    let start = Instant::now();
+    let selected = miner::select_transactions(&mempool, &Wallet::new(), 0, BLOCK_SIZE_LIMIT).len();
+    let selection = start.elapsed();
+
+    // a block spending the inputs of every other transaction differently
+    for i in (0..count).step_by(2) {
+        state.apply(&Hashed::new(spend(&keys[i % KEYS], i, OUTPUT_VALUE)));
+    }
+    let start = Instant::now();
+    let invalid = Revalidation::new(&mempool, &state).run();
+    let evicted = mempool.evict(&invalid);
+    let revalidation = start.elapsed();
+
+    let remaining: Vec<Hashed<SignedTransaction>> = mempool.transactions().iter().map(|tx| (**tx).clone()).collect();
+    let start = Instant::now();
+    for tx in &remaining {
+        mempool.remove(tx);
+    }
+    let removal = start.elapsed();
+    debug_assert!(remaining.iter().all(|tx| !mempool.contains(&tx.hash())));
+
+    MempoolReport {
+        transactions: count,
+        generation: generation,
+        insertion: insertion,
+        accepted: accepted,
+        selection: selection,
+        selected: selected,
+        revalidation: revalidation,
+        evicted: evicted,
+        removal: removal,
+        removed: remaining.len(),
+    }
+}
+
+/// Timings of `mempool_contention`.
+pub struct ContentionReport {
+    pub readers: usize,
+    /// Lookups done by each reader
+    pub lookups: usize,
+    /// Readers taking the mempool lock for each lookup
+    pub locked: Duration,
+    /// Readers looking up through the shared `TxPool` instead
+    pub pooled: Duration,
+    /// Transactions the writer accepted during each run
+    pub accepted: (usize, usize),
+}
+
+/// Run `readers` threads looking up `lookups` of `hashes` each, while a writer keeps accepting
+/// and removing `churn`. Returns how long the readers took, and how many transactions the
+/// writer accepted meanwhile.
+fn contended<F>(
+    mempool: &Arc<OrderedMutex<Mempool>>,
+    state: State,
+    churn: Vec<SignedTransaction>,
+    hashes: Arc<Vec<H256>>,
+    readers: usize,
+    lookups: usize,
+    lookup: F,
+) -> (Duration, usize)
+where
+    F: Fn(&H256) -> bool + Send + Sync + 'static,
+{
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let mempool = Arc::clone(mempool);
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let policy = Policy::default();
+            let mut accepted = 0;
+            while !done.load(Ordering::SeqCst) {
+                for tx in &churn {
+                    if mempool.lock().unwrap().accept(tx, &state, &policy).is_ok() {
+                        accepted += 1;
+                    }
+                }
+                for tx in &churn {
+                    mempool.lock().unwrap().remove(&Hashed::new(tx.clone()));
+                }
+            }
+            accepted
+        })
+    };
+    let lookup = Arc::new(lookup);
+    let start = Instant::now();
+    let threads: Vec<_> = (0..readers)
+        .map(|reader| {
+            let hashes = Arc::clone(&hashes);
+            let lookup = Arc::clone(&lookup);
+            thread::spawn(move || (0..lookups).filter(|i| lookup(&hashes[(i * 31 + reader) % hashes.len()])).count())
+        })
+        .collect();
+    for thread in threads {
+        let found = thread.join().unwrap();
+        debug_assert_eq!(found, lookups);
+    }
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::SeqCst);
+    (elapsed, writer.join().unwrap())
+}
+
+/// Fill a mempool with `count` transactions, then time `readers` threads looking them up
+/// `lookups` times each while a writer accepts and removes `count` more, first locking the
+/// mempool for each lookup, then through its shared `TxPool`.
+pub fn mempool_contention(count: usize, readers: usize, lookups: usize) -> ContentionReport {
+    let mut state = State::default();
+    let transactions = funded_spends(&keys(), 2 * count.max(1), &mut state);
+    let (resident, churn) = transactions.split_at(transactions.len() / 2);
+    let hashes: Arc<Vec<H256>> = Arc::new(resident.iter().map(|tx| tx.hash()).collect());
+
+    let run = |pooled: bool| {
+        let mempool = Arc::new(OrderedMutex::new(Mempool::new()));
+        for tx in resident {
+            mempool.lock().unwrap().accept(tx, &state, &Policy::default()).unwrap();
+        }
+        let (state, churn, hashes) = (state.clone(), churn.to_vec(), Arc::clone(&hashes));
+        if pooled {
+            let txpool = mempool.lock().unwrap().pool();
+            contended(&mempool, state, churn, hashes, readers, lookups, move |hash| txpool.contains(hash))
+        } else {
+            let locked = Arc::clone(&mempool);
+            contended(&mempool, state, churn, hashes, readers, lookups, move |hash| locked.lock().unwrap().contains(hash))
+        }
+    };
+    let (locked, locked_accepted) = run(false);
+    let (pooled, pooled_accepted) = run(true);
+    ContentionReport {
+        readers: readers,
+        lookups: lookups,
+        locked: locked,
+        pooled: pooled,
+        accepted: (locked_accepted, pooled_accepted),
+    }
+}
+
+/// Lock hold times of `block_serving`.
+pub struct BlockServingReport {
+    pub blocks: usize,
+    pub requests: usize,
+    /// The chain lock held while cloning the requested blocks out of the chain
+    pub chain_lock: Duration,
+    /// The cache lock held while looking up the serialized blocks
+    pub cache_lock: Duration,
+}
+
+/// Build a chain of `count` full blocks, then time how long serving `requests` requests for
+/// recent blocks holds the chain lock, and the block cache lock instead.
+pub fn block_serving(count: usize, requests: usize) -> BlockServingReport {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+    let mut chain = Blockchain::new();
+    let mut cache = BlockCache::new(block_cache::DEFAULT_CAPACITY);
+    let mut hashes = Vec::new();
+    for i in 0..count {
+        let parent = chain.tip();
+        let mut transactions = Vec::new();
+        let mut size = 0;
+        while size < BLOCK_SIZE_LIMIT {
+            let tx = Hashed::new(spend(&key, i * 100 + transactions.len(), OUTPUT_VALUE));
+            size += tx.serialized_size();
+            transactions.push(tx);
+        }
+        let mut tree = MerkleTree::default();
+        for tx in &transactions {
+            tree.push(tx);
+        }
+        let header = Header {
+            version: block::VERSION,
+            parent: parent,
+            height: chain.blockmap[&parent].header.height + 1,
+            nonce: 0,
+            extra_nonce: 0,
+            bits: chain.blockmap[&parent].header.bits,
+            timestamp: i as u128,
+            merkle_root: tree.root(),
+        };
+        let block = Block { header: header, content: Content { data: transactions } };
+        chain.insert(&block);
+        // as the worker does for the blocks it announces
+        cache.insert(&block);
+        hashes.push(block.hash());
+    }
+    let recent = &hashes[count.saturating_sub(block_cache::DEFAULT_CAPACITY)..];
+
+    let chain = Mutex::new(chain);
+    let mut chain_lock = Duration::default();
+    for hash in recent.iter().cycle().take(requests) {
+        let chain = chain.lock().unwrap();
+        let start = Instant::now();
+        let block = chain.blockmap[hash].clone();
+        chain_lock += start.elapsed();
+        drop(chain);
+        bincode::serialize(&block).unwrap();
+    }
+
+    let cache = Mutex::new(cache);
+    let mut cache_lock = Duration::default();
+    for hash in recent.iter().cycle().take(requests) {
+        let mut cache = cache.lock().unwrap();
+        let start = Instant::now();
+        let block = cache.get(hash);
+        cache_lock += start.elapsed();
+        drop(cache);
+        debug_assert!(block.is_some());
+    }
+
+    BlockServingReport { blocks: count, requests: requests, chain_lock: chain_lock, cache_lock: cache_lock }
+}
+
+/// Total time, and time per item in microseconds.
+fn timing(duration: Duration, items: usize) -> String {
+    let per_item = if items == 0 { 0.0 } else { duration.as_secs_f64() * 1e6 / items as f64 };
+    format!("{:.3} s, {:.1} us each", duration.as_secs_f64(), per_item)
+}
+
+impl std::fmt::Display for MempoolReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Generated {} transactions in {:.3} s", self.transactions, self.generation.as_secs_f64())?;
+        writeln!(f, "Insertion: {} accepted, {}", self.accepted, timing(self.insertion, self.transactions))?;
+        writeln!(f, "Template selection: {} selected, {:.3} ms", self.selected, self.selection.as_secs_f64() * 1e3)?;
+        writeln!(f, "Revalidation: {} evicted, {}", self.evicted, timing(self.revalidation, self.accepted))?;
+        write!(f, "Removal: {} removed, {}", self.removed, timing(self.removal, self.removed))
+    }
+}
+
+impl std::fmt::Display for ContentionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let lookups = self.readers * self.lookups;
+        let rate = |duration: Duration| lookups as f64 / duration.as_secs_f64().max(1e-9);
+        writeln!(f, "{} readers doing {} lookups each", self.readers, self.lookups)?;
+        writeln!(f, "Mempool lock: {:.0} lookups/s, writer accepted {}", rate(self.locked), self.accepted.0)?;
+        write!(f, "Shared pool: {:.0} lookups/s, writer accepted {}", rate(self.pooled), self.accepted.1)
+    }
+}
+
+impl std::fmt::Display for BlockServingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Served {} requests for the last of {} blocks", self.requests, self.blocks)?;
+        writeln!(f, "Chain lock held: {}", timing(self.chain_lock, self.requests))?;
+        write!(f, "Block cache lock held: {}", timing(self.cache_lock, self.requests))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mempool_report() {
+        let report = mempool(40);
+        assert_eq!(report.accepted, 40);
+        assert!(report.selected > 0);
+        assert_eq!(report.evicted, 20);
+        assert_eq!(report.removed, 20);
+    }
+
+    #[test]
+    fn contention_report() {
+        let report = mempool_contention(20, 2, 50);
+        assert_eq!(report.lookups, 50);
+        assert!(report.locked > Duration::default());
+        assert!(report.pooled > Duration::default());
+    }
+
+    #[test]
+    fn block_serving_report() {
+        let report = block_serving(4, 10);
+        assert_eq!(report.requests, 10);
+        assert!(report.chain_lock > Duration::default());
+    }
+}
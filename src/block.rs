@@ -1,17 +1,45 @@
 use serde::{Serialize, Deserialize};
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::{H256, Hashable, Hashed};
 use crate::crypto::merkle::MerkleTree;
+use crate::crypto::u256::{self, U256};
 use super::transaction::{Transaction, SignedTransaction};
 
+/// Version of the blocks produced by this node.
+pub const VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Header {
+	pub version: u32,
 	pub parent: H256,
+	/// Number of blocks between this one and the genesis block, which has height 0
+	pub height: u64,
 	pub nonce: u32,
-	pub difficulty: H256,
+	/// Extends the search space of the nonce, see `miner::Template`
+	pub extra_nonce: u32,
+	/// Compact form of the target, see `H256::from_compact`.
+	pub bits: u32,
 	pub timestamp: u128,
 	pub merkle_root: H256,
 }
 
+impl Header {
+    /// The target the hash of this header must not exceed. An invalid `bits` encoding yields the
+    /// zero target, which no block can meet in practice.
+    pub fn target(&self) -> H256 {
+        H256::from_compact(self.bits).unwrap_or_default()
+    }
+
+    /// Whether the proof of work of this header meets its target.
+    pub fn meets_target(&self) -> bool {
+        self.hash() <= self.target()
+    }
+
+    /// Expected number of hashes needed to mine this header.
+    pub fn work(&self) -> U256 {
+        u256::work_from_target(&self.target())
+    }
+}
+
 impl Hashable for Header {
     fn hash(&self) -> H256 {
         let m = bincode::serialize(&self).unwrap();
@@ -21,7 +49,7 @@ impl Hashable for Header {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
-	pub data: Vec<SignedTransaction>,
+	pub data: Vec<Hashed<SignedTransaction>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -30,6 +58,17 @@ pub struct Block {
 	pub content: Content,
 }
 
+impl Block {
+    /// The weight of the block, counted against `validation::MAX_BLOCK_WEIGHT` and the limit the
+    /// miner fills: the length of its encoding, without witness data to discount. The sizes of
+    /// the transactions are the ones computed when they were wrapped in `Hashed`.
+    pub fn weight(&self) -> usize {
+        let header = bincode::serialized_size(&self.header).unwrap() as usize;
+        // the number of transactions is encoded as a u64
+        header + 8 + self.content.data.iter().map(|tx| tx.serialized_size()).sum::<usize>()
+    }
+}
+
 impl Hashable for Block {
     fn hash(&self) -> H256 {
         self.header.hash()
@@ -47,14 +86,45 @@ pub mod test {
         let nonce: u32 = rng.gen();
         let transactions = Vec::new();
         let timestamp: u128 = rng.gen_range(1581553864000, 1582553864000);
-        let mut bytes32 = [255u8; 32];
-        bytes32[0] = 0;
-        bytes32[1] = 0;
-        let difficulty: H256 = bytes32.into();
+        let bits = 0x1effffff;
         let empty_tree = MerkleTree::new(&transactions);
         let merkle_root = empty_tree.root();
-        let header = Header{ parent: *parent, nonce: nonce, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_root };
+        let header = Header{ version: VERSION, parent: *parent, height: 0, nonce: nonce, extra_nonce: 0, bits: bits, timestamp: timestamp, merkle_root: merkle_root };
         let content = Content{ data: transactions };
         Block{ header: header, content: content }
     }
+
+    /// A random block carrying `count` signed transactions spending made up outputs.
+    pub fn generate_block_with_transactions(parent: &H256, count: u8) -> Block {
+        use crate::crypto::key_pair;
+        use crate::transaction::{self, TxIn, TxOut};
+        let data: Vec<Hashed<SignedTransaction>> = (0..count)
+            .map(|i| {
+                let input = TxIn { previous_output: [i; 32].into(), index: 0 };
+                let output = TxOut { recipient: [i; 20].into(), value: 1.into() };
+                let tx = Transaction { version: transaction::VERSION, input: vec![input], output: vec![output] };
+                Hashed::new(SignedTransaction::new(tx, &key_pair::random()))
+            })
+            .collect();
+        let mut block = generate_random_block(parent);
+        block.header.merkle_root = MerkleTree::new(&data).root();
+        block.content.data = data;
+        block
+    }
+
+    /// A block carrying `data` on top of `parent`, solved for the target of its parent, which
+    /// should be the easy one of `chainparams::REGTEST`. The nonce search starts at random, so
+    /// that blocks on the same parent differ.
+    pub fn generate_mined_block(parent: &Block, data: Vec<Hashed<SignedTransaction>>) -> Block {
+        let mut block = generate_random_block(&parent.hash());
+        block.header.height = parent.header.height + 1;
+        block.header.bits = parent.header.bits;
+        block.header.timestamp = parent.header.timestamp + 1;
+        block.header.merkle_root = MerkleTree::new(&data).root();
+        block.content.data = data;
+        while !block.header.meets_target() {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        block
+    }
 }
@@ -1,39 +1,89 @@
-use crate::block::{Block, Header, Content};
-use crate::crypto::hash::{H256, Hashable};
-use std::collections::HashMap;
-use crate::crypto::merkle::MerkleTree;
+use crate::block::Block;
+use crate::chainparams::{self, ChainParams};
+use crate::crypto::hash::{H256, Hashable, Hashed};
+use crate::crypto::u256::U256;
+use crate::storage::BlockStore;
+use crate::transaction::{SignedTransaction, Undo};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use crate::crypto::merkle::{MerkleProof, MerkleTree};
+use log::error;
+use serde::Serialize;
+
+/// Confirmations after which a block is considered final.
+pub const DEFAULT_FINALITY_DEPTH: usize = 6;
+
+/// How settled a block, and the transactions in it, are.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Finality {
+    /// Not in the longest chain.
+    Pending,
+    /// In the longest chain, but with fewer confirmations than the finality depth.
+    Confirmed,
+    /// Buried under the finality depth, or at or below the checkpoint.
+    Final,
+}
+
+/// Receives notifications about changes to the longest chain and the mempool. Register with
+/// `Blockchain::subscribe` and `Mempool::subscribe`.
+pub trait ChainListener: Send + Sync {
+    /// Called for every block added to the block tree, whether or not it joins the longest chain
+    fn on_block_inserted(&self, _block: &Block) {}
+    /// Called for each block that becomes part of the longest chain, in ascending height order
+    fn on_block_connected(&self, _block: &Block, _height: usize) {}
+    /// Called for each block that is no longer part of the longest chain after a reorg, in
+    /// descending height order
+    fn on_block_disconnected(&self, _block: &Block, _height: usize) {}
+    /// Called when a transaction enters the mempool
+    fn on_tx_accepted(&self, _transaction: &SignedTransaction) {}
+    /// Called with the outputs a block spent, one entry per transaction, when the block is
+    /// applied to the UTXO set
+    fn on_block_undo(&self, _block: &Block, _undo: &[Undo]) {}
+}
 
 pub struct Blockchain {
     pub blockmap: HashMap<H256, Block>,
     pub lengthmap: HashMap<H256, usize>,
+    /// Total work of the chain ending at each block, the genesis block included
+    workmap: HashMap<H256, U256>,
+    /// Blocks containing each transaction, across all forks
+    pub txindex: HashMap<H256, Vec<H256>>,
     tip: H256,
+    /// Hashes of the longest chain, indexed by height
+    canonical: Vec<H256>,
+    /// A block of the longest chain that reorgs may not disconnect, see `set_checkpoint`
+    checkpoint: Option<H256>,
+    /// Confirmations after which a block is final, see `finality`
+    finality_depth: usize,
+    /// Blocks marked by `invalidate`, which cannot be part of the longest chain, nor can their
+    /// descendants
+    invalid: HashSet<H256>,
+    listeners: Vec<Arc<dyn ChainListener>>,
+    /// Where the undo data of the blocks deeper than the state keeps is read back from
+    undo_store: Option<Arc<dyn BlockStore>>,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the selected network, only containing its genesis block
     pub fn new() -> Self {
-        let parent: H256 = [0u8; 32].into();
-        let nonce = 0u32;
-        // let mut bytes32 = [255u8; 32];
-        // bytes32[0] = 0;
-        // bytes32[1] = 0;
-        let mut bytes32 = [0u8; 32];
-        bytes32[2] = 1u8;
-        let difficulty: H256 = bytes32.into();
-        let timestamp = 0u128;
-        let transactions = Vec::new();
-        let empty_tree = MerkleTree::new(&transactions);
-        let merkle_root = empty_tree.root();
-        let header = Header{ parent: parent, nonce: nonce, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_root };
-        let content = Content{ data: transactions };
-        let genesis = Block{ header: header, content: content };
+        Blockchain::with_params(chainparams::current())
+    }
+
+    /// Create a new blockchain, only containing the genesis block of a network
+    pub fn with_params(params: &ChainParams) -> Self {
+        let genesis = params.genesis();
         let mut blockmap = HashMap::new();
         let mut lengthmap = HashMap::new();
         let genesis_hash: H256 = genesis.hash();
         blockmap.insert(genesis_hash, genesis);
         lengthmap.insert(genesis_hash, 0);
+        let mut workmap = HashMap::new();
+        workmap.insert(genesis_hash, blockmap[&genesis_hash].header.work());
         let tip = genesis_hash;
-        Blockchain { blockmap: blockmap, lengthmap: lengthmap, tip: tip }
+        let txindex = HashMap::new();
+        let canonical = vec![genesis_hash];
+        Blockchain { blockmap: blockmap, lengthmap: lengthmap, workmap: workmap, txindex: txindex, tip: tip, canonical: canonical, checkpoint: None, finality_depth: DEFAULT_FINALITY_DEPTH, invalid: HashSet::new(), listeners: Vec::new(), undo_store: None }
     }
 
     /// Insert a block into blockchain
@@ -42,28 +92,388 @@ impl Blockchain {
         let block_hash: H256 = block.hash();
         self.blockmap.insert(block_hash, block.clone());
         self.lengthmap.insert(block_hash, self.lengthmap[&prev] + 1);
-        if self.lengthmap[&self.tip] < self.lengthmap[&block_hash] {
+        // saturates rather than overflows, only reachable with made up targets
+        let work = self.workmap[&prev].checked_add(block.header.work()).unwrap_or(U256::MAX);
+        self.workmap.insert(block_hash, work);
+        for transaction in &block.content.data {
+            self.txindex.entry(transaction.hash()).or_insert_with(Vec::new).push(block_hash);
+        }
+        for listener in &self.listeners {
+            listener.on_block_inserted(block);
+        }
+        if self.lengthmap[&self.tip] < self.lengthmap[&block_hash]
+            && self.includes_checkpoint(&block_hash)
+            && !self.descends_from_invalid(&block_hash)
+        {
+            let old_tip = self.tip;
             self.tip = block_hash;
+            self.update_canonical();
+            self.notify_reorg(old_tip, block_hash);
+        }
+    }
+
+    /// Bring the height index in line with a new tip, rewriting it back to the fork point
+    fn update_canonical(&mut self) {
+        let mut branch = Vec::new();
+        let mut trav = self.tip;
+        loop {
+            let height = self.lengthmap[&trav];
+            if self.canonical.get(height) == Some(&trav) {
+                self.canonical.truncate(height + 1);
+                break;
+            }
+            branch.push(trav);
+            trav = self.blockmap[&trav].header.parent;
+        }
+        self.canonical.extend(branch.into_iter().rev());
+    }
+
+    /// Whether the chain ending at a known block includes the checkpoint, if any
+    fn includes_checkpoint(&self, hash: &H256) -> bool {
+        let checkpoint = match self.checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => return true,
+        };
+        let height = self.lengthmap[&checkpoint];
+        self.lengthmap[hash] >= height && self.ancestor_at(hash, height) == checkpoint
+    }
+
+    /// Make a block of the longest chain final: from then on, longer forks that do not include
+    /// it are stored but never become the longest chain. A checkpoint only moves up the chain.
+    /// Returns whether the block became the checkpoint.
+    pub fn set_checkpoint(&mut self, hash: &H256) -> bool {
+        if !self.is_in_longest_chain(hash) {
+            return false;
+        }
+        if let Some(current) = self.checkpoint {
+            if self.lengthmap[&current] >= self.lengthmap[hash] {
+                return false;
+            }
+        }
+        self.checkpoint = Some(*hash);
+        true
+    }
+
+    /// The ancestor of a known block at a height at most its own
+    fn ancestor_at(&self, hash: &H256, height: usize) -> H256 {
+        let mut trav = *hash;
+        while self.lengthmap[&trav] > height {
+            trav = self.blockmap[&trav].header.parent;
+        }
+        trav
+    }
+
+    /// Whether a known block, or one of its ancestors, is marked by `invalidate`
+    fn descends_from_invalid(&self, hash: &H256) -> bool {
+        self.invalid.iter().any(|invalid| {
+            let height = self.lengthmap[invalid];
+            height <= self.lengthmap[hash] && self.ancestor_at(hash, height) == *invalid
+        })
+    }
+
+    /// Mark a block invalid whatever the validation rules say, like bitcoind's `invalidateblock`,
+    /// so that neither it nor its descendants can be part of the longest chain. If the tip
+    /// descends from it, the chain moves to the longest of the remaining branches. Marks only
+    /// live in memory. The genesis block and the blocks up to the checkpoint cannot be marked.
+    /// Returns whether the block was marked.
+    pub fn invalidate(&mut self, hash: &H256) -> bool {
+        let height = match self.height_of(hash) {
+            Some(height) if height > 0 => height,
+            _ => return false,
+        };
+        if let Some(checkpoint) = self.checkpoint {
+            if self.is_in_longest_chain(hash) && height <= self.lengthmap[&checkpoint] {
+                return false;
+            }
+        }
+        self.invalid.insert(*hash);
+        if self.descends_from_invalid(&self.tip) {
+            self.select_tip();
+        }
+        true
+    }
+
+    /// Lift the marks set by `invalidate` on a block, its ancestors and its descendants, like
+    /// bitcoind's `reconsiderblock`, moving to the branch they were keeping out if it is longer.
+    /// Returns whether any mark was lifted.
+    pub fn reconsider(&mut self, hash: &H256) -> bool {
+        let height = match self.height_of(hash) {
+            Some(height) => height,
+            None => return false,
+        };
+        let related: Vec<H256> = self
+            .invalid
+            .iter()
+            .filter(|invalid| {
+                let invalid_height = self.lengthmap[*invalid];
+                if invalid_height <= height {
+                    self.ancestor_at(hash, invalid_height) == **invalid
+                } else {
+                    self.ancestor_at(invalid, height) == *hash
+                }
+            })
+            .cloned()
+            .collect();
+        if related.is_empty() {
+            return false;
+        }
+        for invalid in &related {
+            self.invalid.remove(invalid);
+        }
+        self.select_tip();
+        true
+    }
+
+    /// Blocks marked by `invalidate`
+    pub fn invalid_blocks(&self) -> Vec<H256> {
+        self.invalid.iter().cloned().collect()
+    }
+
+    /// Move the tip to the end of the longest branch that includes the checkpoint and no marked
+    /// block. The current tip wins ties, other branches of the same length are picked by hash.
+    fn select_tip(&mut self) {
+        let mut blocks: Vec<(H256, usize)> = self.lengthmap.iter().map(|(hash, height)| (*hash, *height)).collect();
+        blocks.sort_by_key(|(hash, height)| (*height, *hash));
+        let mut excluded = HashSet::new();
+        let mut best: Option<(H256, usize)> = None;
+        for (hash, height) in blocks {
+            if self.invalid.contains(&hash) || excluded.contains(&self.blockmap[&hash].header.parent) {
+                excluded.insert(hash);
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some((best_hash, best_height)) => height > best_height || (height == best_height && hash == self.tip && best_hash != self.tip),
+            };
+            if better && self.includes_checkpoint(&hash) {
+                best = Some((hash, height));
+            }
+        }
+        // the genesis block is never marked
+        let (best, _) = best.unwrap();
+        if best != self.tip {
+            let old_tip = self.tip;
+            self.tip = best;
+            self.update_canonical();
+            self.notify_reorg(old_tip, best);
+        }
+    }
+
+    /// The block set by `set_checkpoint`
+    pub fn checkpoint(&self) -> Option<H256> {
+        self.checkpoint
+    }
+
+    /// Register a listener for changes to the longest chain
+    pub fn subscribe(&mut self, listener: Arc<dyn ChainListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Notify listeners about the blocks leaving and joining the longest chain when the tip moves
+    /// from `old_tip` to `new_tip`
+    fn notify_reorg(&self, old_tip: H256, new_tip: H256) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let (disconnected, connected) = self.reorg_path(old_tip, new_tip);
+        for hash in disconnected {
+            for listener in &self.listeners {
+                listener.on_block_disconnected(&self.blockmap[&hash], self.lengthmap[&hash]);
+            }
+        }
+        for hash in connected {
+            for listener in &self.listeners {
+                listener.on_block_connected(&self.blockmap[&hash], self.lengthmap[&hash]);
+            }
+        }
+    }
+
+    /// Pass the undo data of a block applied to the UTXO set on to the listeners
+    pub fn notify_undo(&self, block: &Block, undo: &[Undo]) {
+        for listener in &self.listeners {
+            listener.on_block_undo(block, undo);
+        }
+    }
+
+    /// Read the undo data of blocks deeper than `transaction::UNDO_DEPTH` from this store, which
+    /// should also be subscribed to keep it, so that the UTXO set need not keep it all. Set it
+    /// before replaying the store, whose forks may be deeper.
+    pub fn set_undo_store(&mut self, store: Arc<dyn BlockStore>) {
+        self.undo_store = Some(store);
+    }
+
+    /// Whether an undo store was set, see `set_undo_store`.
+    pub fn has_undo_store(&self) -> bool {
+        self.undo_store.is_some()
+    }
+
+    /// The undo data of a block as kept by the store set by `set_undo_store`.
+    pub fn stored_undo(&self, hash: &H256) -> Option<Vec<Undo>> {
+        match self.undo_store.as_ref()?.load_undo(hash) {
+            Ok(undo) => undo,
+            Err(e) => {
+                error!("Error reading the undo data of block {}: {}", hash, e);
+                None
+            }
         }
     }
 
+    /// The blocks to leave, in descending height order, and to join, in ascending height order,
+    /// to move from the chain ending at `old_tip` to the one ending at `new_tip`
+    pub fn reorg_path(&self, old_tip: H256, new_tip: H256) -> (Vec<H256>, Vec<H256>) {
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        let mut old = old_tip;
+        let mut new = new_tip;
+        while self.lengthmap[&old] > self.lengthmap[&new] {
+            disconnected.push(old);
+            old = self.blockmap[&old].header.parent;
+        }
+        while self.lengthmap[&new] > self.lengthmap[&old] {
+            connected.push(new);
+            new = self.blockmap[&new].header.parent;
+        }
+        while old != new {
+            disconnected.push(old);
+            connected.push(new);
+            old = self.blockmap[&old].header.parent;
+            new = self.blockmap[&new].header.parent;
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         return self.tip;
     }
 
-    /// Get the last block's hash of the longest chain
+    /// Get the height of the longest chain, where the genesis block is at height 0
+    pub fn height(&self) -> usize {
+        self.lengthmap[&self.tip]
+    }
+
+    /// Total work of the longest chain, the expected number of hashes to mine it again
+    pub fn total_work(&self) -> U256 {
+        self.workmap[&self.tip]
+    }
+
+    /// Total work of the chain ending at a known block, whether or not it is the longest chain
+    pub fn chain_work(&self, hash: &H256) -> Option<U256> {
+        self.workmap.get(hash).cloned()
+    }
+
+    /// Get the hash of the block at a height of the longest chain
+    pub fn hash_at_height(&self, height: usize) -> Option<H256> {
+        self.canonical.get(height).cloned()
+    }
+
+    /// Get the height of a known block, whether or not it is part of the longest chain
+    pub fn height_of(&self, hash: &H256) -> Option<usize> {
+        self.lengthmap.get(hash).cloned()
+    }
+
+    /// Check whether a known block is part of the longest chain
+    pub fn is_in_longest_chain(&self, hash: &H256) -> bool {
+        match self.height_of(hash) {
+            Some(height) => self.canonical.get(height) == Some(hash),
+            None => false,
+        }
+    }
+
+    /// Confirmations of a known block, counted like `getblockcount`: one for the tip, one more
+    /// for every block above it, and -1 for blocks outside the longest chain
+    pub fn confirmations(&self, hash: &H256) -> Option<i64> {
+        let height = self.height_of(hash)?;
+        if self.is_in_longest_chain(hash) {
+            Some((self.height() - height + 1) as i64)
+        } else {
+            Some(-1)
+        }
+    }
+
+    pub fn set_finality_depth(&mut self, depth: usize) {
+        self.finality_depth = depth;
+    }
+
+    pub fn finality_depth(&self) -> usize {
+        self.finality_depth
+    }
+
+    /// Finality of a block, `Pending` for unknown blocks
+    pub fn finality(&self, hash: &H256) -> Finality {
+        let confirmations = match self.confirmations(hash) {
+            Some(confirmations) if confirmations > 0 => confirmations as usize,
+            _ => return Finality::Pending,
+        };
+        let below_checkpoint = self.checkpoint.map_or(false, |checkpoint| self.lengthmap[hash] <= self.lengthmap[&checkpoint]);
+        if confirmations >= self.finality_depth || below_checkpoint {
+            Finality::Final
+        } else {
+            Finality::Confirmed
+        }
+    }
+
+    /// Estimate of the hashes per second spent on the last `window` blocks of the longest chain,
+    /// after `getnetworkhashps`: their work over the time from the block before them to the tip.
+    /// The window stops at the genesis block. `None` without blocks above the genesis block, if
+    /// the timestamps do not increase over the window, or if the work overflows.
+    pub fn estimated_hashrate(&self, window: usize) -> Option<U256> {
+        let height = self.height();
+        let window = window.min(height);
+        if window == 0 {
+            return None;
+        }
+        let start = self.blockmap[&self.canonical[height - window]].header.timestamp;
+        let end = self.blockmap[&self.tip].header.timestamp;
+        let elapsed_ms = end.checked_sub(start).filter(|elapsed| *elapsed > 0)?;
+        let mut work = U256::ZERO;
+        for hash in &self.canonical[height - window + 1..] {
+            work = work.checked_add(self.blockmap[hash].header.work())?;
+        }
+        work.checked_mul(U256::from(1000u64))?.checked_div(U256::from(elapsed_ms))
+    }
+
+    /// Find the block of the longest chain that contains a transaction
+    pub fn find_transaction(&self, tx_hash: &H256) -> Option<H256> {
+        let blocks = self.txindex.get(tx_hash)?;
+        blocks.iter().find(|b| self.is_in_longest_chain(b)).cloned()
+    }
+
+    /// Get a transaction of the longest chain together with the hash of its block
+    pub fn get_transaction(&self, tx_hash: &H256) -> Option<(H256, &Hashed<SignedTransaction>)> {
+        let block_hash = self.find_transaction(tx_hash)?;
+        let tx = self.blockmap[&block_hash].content.data.iter().find(|tx| tx.hash() == *tx_hash)?;
+        Some((block_hash, tx))
+    }
+
+    /// Build the inclusion proof of a transaction in the longest chain, returning the hash of the
+    /// containing block and the proof against its Merkle root
+    pub fn tx_proof(&self, tx_hash: &H256) -> Option<(H256, MerkleProof)> {
+        let block_hash = self.find_transaction(tx_hash)?;
+        let data = &self.blockmap[&block_hash].content.data;
+        let index = data.iter().position(|tx| tx.hash() == *tx_hash)?;
+        let proof = MerkleTree::new(data).prove(index)?;
+        Some((block_hash, proof))
+    }
+
+    /// Get up to `count` hashes of the longest chain starting at height `start`, going towards
+    /// the genesis block if `reverse` is set
+    pub fn longest_chain_range(&self, start: usize, count: usize, reverse: bool) -> Vec<H256> {
+        if start >= self.canonical.len() {
+            return Vec::new();
+        }
+        if reverse {
+            self.canonical[..=start].iter().rev().take(count).cloned().collect()
+        } else {
+            self.canonical[start..].iter().take(count).cloned().collect()
+        }
+    }
+
+    /// Get the hashes of the longest chain, from the tip down to the genesis block
     // #[cfg(any(test, test_utilities))]
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        let mut trav = self.tip;
-        let mut longest_chain = Vec::new();
-        let target = [0u8; 32].into();
-        while trav != target {
-            longest_chain.push(trav);
-            let cur_b = &self.blockmap[&trav];
-            trav = cur_b.header.parent;
-        }
-        return longest_chain;
+        self.canonical.iter().rev().cloned().collect()
     }
 }
 
@@ -81,4 +491,237 @@ mod tests {
         blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn longest_chain_membership() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        let next = generate_random_block(&fork.hash());
+        blockchain.insert(&next);
+        assert_eq!(blockchain.height(), 2);
+        assert!(blockchain.is_in_longest_chain(&genesis_hash));
+        assert!(blockchain.is_in_longest_chain(&fork.hash()));
+        assert!(!blockchain.is_in_longest_chain(&block.hash()));
+        assert_eq!(blockchain.hash_at_height(1), Some(fork.hash()));
+        assert_eq!(blockchain.hash_at_height(3), None);
+        assert_eq!(blockchain.height_of(&block.hash()), Some(1));
+        assert_eq!(blockchain.height_of(&[7u8; 32].into()), None);
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![next.hash(), fork.hash(), genesis_hash]);
+    }
+
+    #[test]
+    fn estimated_hashrate() {
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.estimated_hashrate(10), None);
+        let mut parent = blockchain.tip();
+        let mut blocks = Vec::new();
+        for i in 0..4u128 {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = 1_600_000_000_000 + i * 10_000;
+            block.header.bits = 0x1e010000;
+            blockchain.insert(&block);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        // a block every 10 seconds
+        let work = blocks[0].header.work();
+        assert_eq!(blockchain.estimated_hashrate(3), Some(work / U256::from(10u64)));
+        assert_eq!(blockchain.estimated_hashrate(1), Some(work / U256::from(10u64)));
+        assert_eq!(blockchain.estimated_hashrate(0), None);
+
+        // no estimate over a window the timestamps do not move forward in
+        let mut late = generate_random_block(&parent);
+        late.header.bits = 0x1e010000;
+        late.header.timestamp = blocks[2].header.timestamp;
+        blockchain.insert(&late);
+        assert_eq!(blockchain.estimated_hashrate(2), None);
+        assert_eq!(blockchain.estimated_hashrate(3), Some(work * U256::from(3u64) / U256::from(10u64)));
+    }
+
+    #[test]
+    fn chain_work() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let genesis_work = blockchain.total_work();
+        assert_eq!(genesis_work, blockchain.blockmap[&genesis_hash].header.work());
+        let mut strong = generate_random_block(&genesis_hash);
+        strong.header.bits = 0x1e010000;
+        blockchain.insert(&strong);
+        let mut weak = generate_random_block(&genesis_hash);
+        weak.header.bits = 0x207fffff;
+        blockchain.insert(&weak);
+        let mut weak_next = generate_random_block(&weak.hash());
+        weak_next.header.bits = 0x207fffff;
+        blockchain.insert(&weak_next);
+        // the longer fork is the longest chain, though it took less work
+        assert_eq!(blockchain.tip(), weak_next.hash());
+        assert_eq!(blockchain.total_work(), genesis_work + weak.header.work() + weak_next.header.work());
+        assert_eq!(blockchain.chain_work(&strong.hash()), Some(genesis_work + strong.header.work()));
+        assert!(blockchain.chain_work(&strong.hash()).unwrap() > blockchain.total_work());
+        assert_eq!(blockchain.chain_work(&[7u8; 32].into()), None);
+    }
+
+    #[test]
+    fn longest_chain_range() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let next = generate_random_block(&block.hash());
+        blockchain.insert(&next);
+        assert_eq!(blockchain.longest_chain_range(0, 10, false), vec![genesis_hash, block.hash(), next.hash()]);
+        assert_eq!(blockchain.longest_chain_range(1, 1, false), vec![block.hash()]);
+        assert_eq!(blockchain.longest_chain_range(2, 2, true), vec![next.hash(), block.hash()]);
+        assert!(blockchain.longest_chain_range(3, 2, false).is_empty());
+
+        // a longer fork from the genesis block replaces the whole range
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        let fork_next = generate_random_block(&fork.hash());
+        blockchain.insert(&fork_next);
+        assert_eq!(blockchain.longest_chain_range(0, 10, false), vec![genesis_hash, block.hash(), next.hash()]);
+        let fork_tip = generate_random_block(&fork_next.hash());
+        blockchain.insert(&fork_tip);
+        assert_eq!(
+            blockchain.longest_chain_range(0, 10, false),
+            vec![genesis_hash, fork.hash(), fork_next.hash(), fork_tip.hash()]
+        );
+    }
+
+    #[test]
+    fn confirmations() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        assert_eq!(blockchain.confirmations(&genesis_hash), Some(1));
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        assert_eq!(blockchain.confirmations(&genesis_hash), Some(2));
+        assert_eq!(blockchain.confirmations(&block.hash()), Some(1));
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        let next = generate_random_block(&fork.hash());
+        blockchain.insert(&next);
+        assert_eq!(blockchain.confirmations(&block.hash()), Some(-1));
+        assert_eq!(blockchain.confirmations(&fork.hash()), Some(2));
+        assert_eq!(blockchain.confirmations(&[7u8; 32].into()), None);
+    }
+
+    #[test]
+    fn checkpoint() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        assert!(!blockchain.set_checkpoint(&fork.hash()));
+        assert!(blockchain.set_checkpoint(&block.hash()));
+        assert!(!blockchain.set_checkpoint(&genesis_hash));
+        assert_eq!(blockchain.checkpoint(), Some(block.hash()));
+
+        // a longer fork disconnecting the checkpoint is stored but not followed
+        let fork_next = generate_random_block(&fork.hash());
+        blockchain.insert(&fork_next);
+        assert_eq!(blockchain.tip(), block.hash());
+        assert!(blockchain.blockmap.contains_key(&fork_next.hash()));
+        let next = generate_random_block(&block.hash());
+        blockchain.insert(&next);
+        assert_eq!(blockchain.tip(), next.hash());
+    }
+
+    #[test]
+    fn finality() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_finality_depth(3);
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        assert_eq!(blockchain.finality(&block.hash()), Finality::Confirmed);
+        assert_eq!(blockchain.finality(&fork.hash()), Finality::Pending);
+        assert_eq!(blockchain.finality(&[7u8; 32].into()), Finality::Pending);
+
+        let next = generate_random_block(&block.hash());
+        blockchain.insert(&next);
+        assert_eq!(blockchain.finality(&block.hash()), Finality::Confirmed);
+        blockchain.insert(&generate_random_block(&next.hash()));
+        assert_eq!(blockchain.finality(&block.hash()), Finality::Final);
+        assert_eq!(blockchain.finality(&next.hash()), Finality::Confirmed);
+
+        // the checkpoint is final whatever its depth
+        blockchain.set_checkpoint(&next.hash());
+        assert_eq!(blockchain.finality(&next.hash()), Finality::Final);
+    }
+
+    #[test]
+    fn invalidate_and_reconsider() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let next = generate_random_block(&block.hash());
+        blockchain.insert(&next);
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        assert!(!blockchain.invalidate(&genesis_hash));
+        assert!(!blockchain.invalidate(&[7u8; 32].into()));
+
+        // the chain falls back to the fork, and stays there as the invalid branch grows
+        assert!(blockchain.invalidate(&block.hash()));
+        assert_eq!(blockchain.tip(), fork.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![fork.hash(), genesis_hash]);
+        blockchain.insert(&generate_random_block(&next.hash()));
+        assert_eq!(blockchain.tip(), fork.hash());
+        assert_eq!(blockchain.invalid_blocks(), vec![block.hash()]);
+
+        // reconsidering a descendant lifts the mark and moves back to the longer branch
+        assert!(!blockchain.reconsider(&fork.hash()));
+        assert!(blockchain.reconsider(&next.hash()));
+        assert_eq!(blockchain.height(), 3);
+        assert!(blockchain.is_in_longest_chain(&block.hash()));
+        assert!(blockchain.invalid_blocks().is_empty());
+
+        // blocks up to the checkpoint cannot be marked
+        assert!(blockchain.set_checkpoint(&next.hash()));
+        assert!(!blockchain.invalidate(&block.hash()));
+        assert!(blockchain.invalidate(&fork.hash()));
+    }
+
+    struct Recorder(std::sync::Mutex<Vec<(bool, H256)>>);
+
+    impl ChainListener for Recorder {
+        fn on_block_connected(&self, block: &Block, _height: usize) {
+            self.0.lock().unwrap().push((true, block.hash()));
+        }
+
+        fn on_block_disconnected(&self, block: &Block, _height: usize) {
+            self.0.lock().unwrap().push((false, block.hash()));
+        }
+    }
+
+    #[test]
+    fn reorg_notifications() {
+        let mut blockchain = Blockchain::new();
+        let recorder = Arc::new(Recorder(std::sync::Mutex::new(Vec::new())));
+        blockchain.subscribe(recorder.clone());
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.insert(&block);
+        let fork = generate_random_block(&genesis_hash);
+        blockchain.insert(&fork);
+        let next = generate_random_block(&fork.hash());
+        blockchain.insert(&next);
+        let events = recorder.0.lock().unwrap().clone();
+        assert_eq!(events, vec![
+            (true, block.hash()),
+            (false, block.hash()),
+            (true, fork.hash()),
+            (true, next.hash()),
+        ]);
+    }
 }
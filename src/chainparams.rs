@@ -0,0 +1,178 @@
+//! Parameters that set the networks apart: the main network, a public test network, and a
+//! local regression test network whose blocks are mined instantly. Nodes of different networks
+//! do not share a genesis block, so they cannot accept each other's blocks, and their addresses
+//! do not parse on each other's networks.
+
+use crate::block::{self, Block, Content, Header};
+use crate::crypto::hash::H256;
+use crate::crypto::merkle::MerkleTree;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+pub struct ChainParams {
+    pub network: Network,
+    /// Difficulty of the genesis block in compact form. Every block keeps the difficulty of its
+    /// parent, so no block may meet an easier target.
+    pub bits: u32,
+    /// Milliseconds since the UNIX epoch, set apart so that each network has its own genesis
+    pub genesis_timestamp: u128,
+    /// Version byte prepended to addresses
+    pub address_version: u8,
+    pub default_p2p_port: u16,
+    pub default_api_port: u16,
+}
+
+pub const MAINNET: ChainParams = ChainParams {
+    network: Network::Mainnet,
+    // target 0x0000010000...00
+    bits: 0x1e010000,
+    genesis_timestamp: 0,
+    address_version: 0x00,
+    default_p2p_port: 6000,
+    default_api_port: 7000,
+};
+
+pub const TESTNET: ChainParams = ChainParams {
+    network: Network::Testnet,
+    // target 0x0000ffff00...00
+    bits: 0x1f00ffff,
+    genesis_timestamp: 1,
+    address_version: 0x6f,
+    default_p2p_port: 16000,
+    default_api_port: 17000,
+};
+
+/// About one hash in two meets the target, so blocks are mined as soon as they are asked for.
+pub const REGTEST: ChainParams = ChainParams {
+    network: Network::Regtest,
+    // target 0x7fffff00...00
+    bits: 0x207fffff,
+    genesis_timestamp: 2,
+    address_version: 0x6f,
+    default_p2p_port: 26000,
+    default_api_port: 27000,
+};
+
+impl Network {
+    pub fn params(self) -> &'static ChainParams {
+        match self {
+            Network::Mainnet => &MAINNET,
+            Network::Testnet => &TESTNET,
+            Network::Regtest => &REGTEST,
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {}, expected mainnet, testnet or regtest", s)),
+        }
+    }
+}
+
+impl ChainParams {
+    /// The genesis block, which has no transactions.
+    pub fn genesis(&self) -> Block {
+        let transactions = Vec::new();
+        let merkle_root = MerkleTree::new(&transactions).root();
+        let header = Header {
+            version: block::VERSION,
+            parent: H256::default(),
+            height: 0,
+            nonce: 0,
+            extra_nonce: 0,
+            bits: self.bits,
+            timestamp: self.genesis_timestamp,
+            merkle_root: merkle_root,
+        };
+        Block { header: header, content: Content { data: transactions } }
+    }
+}
+
+/// The network of the process, mainnet until `select` is called.
+static SELECTED: AtomicU8 = AtomicU8::new(0);
+
+/// Select the network of the process. Call it at start, before any address is parsed or chain
+/// created.
+pub fn select(network: Network) {
+    let index = match network {
+        Network::Mainnet => 0,
+        Network::Testnet => 1,
+        Network::Regtest => 2,
+    };
+    SELECTED.store(index, Ordering::SeqCst);
+}
+
+/// The parameters of the selected network.
+pub fn current() -> &'static ChainParams {
+    match SELECTED.load(Ordering::SeqCst) {
+        1 => &TESTNET,
+        2 => &REGTEST,
+        _ => &MAINNET,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::clock::SystemClock;
+    use crate::crypto::hash::Hashable;
+    use crate::transaction::State;
+    use crate::validation;
+
+    #[test]
+    fn distinct_networks() {
+        let networks = [Network::Mainnet, Network::Testnet, Network::Regtest];
+        let genesis: Vec<H256> = networks.iter().map(|network| network.params().genesis().hash()).collect();
+        assert_eq!(genesis[0], Blockchain::new().tip());
+        assert_ne!(genesis[0], genesis[1]);
+        assert_ne!(genesis[1], genesis[2]);
+        for network in &networks {
+            assert_eq!(network.to_string().parse::<Network>(), Ok(*network));
+        }
+        assert!("simnet".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn regtest_mines_instantly() {
+        let chain = Blockchain::with_params(&REGTEST);
+        let genesis = chain.blockmap[&chain.tip()].clone();
+        let mut block = Block {
+            header: Header { parent: genesis.hash(), height: 1, timestamp: genesis.header.timestamp + 1, ..genesis.header },
+            content: genesis.content,
+        };
+        let mut attempts = 1;
+        while !block.header.meets_target() {
+            block.header.nonce += 1;
+            attempts += 1;
+        }
+        assert!(attempts < 64);
+        assert_eq!(validation::check_block(&block, &chain, &State::new(), &SystemClock), Ok(()));
+    }
+}
@@ -0,0 +1,299 @@
+//! Keeps the UTXO set in line with the longest chain of the block tree.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::State;
+use crate::validation;
+use log::{error, warn};
+use serde::Serialize;
+
+/// How the longest chain moved when a block was inserted.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct TipChange {
+    /// Blocks that left the longest chain, in descending height order
+    pub disconnected: Vec<H256>,
+    /// Blocks that joined the longest chain, in ascending height order
+    pub connected: Vec<H256>,
+}
+
+/// Insert a block checked by `validation::check_block` into the chain and move the UTXO set to
+/// the new tip. Blocks leaving the longest chain are rolled back with their undo data, and blocks
+/// joining it are applied, their undo data passed on to the chain listeners to be stored. A block
+/// on a shorter fork is only stored: its transactions are checked and applied if and when its
+/// branch becomes the longest.
+pub fn insert_block(block: &Block, chain: &mut Blockchain, state: &mut State) -> TipChange {
+    let old_tip = chain.tip();
+    chain.insert(block);
+    follow_tip(old_tip, Some(block.hash()), chain, state)
+}
+
+/// Mark a block invalid with `Blockchain::invalidate` and move the UTXO set to the new tip.
+/// Returns `None` if the block could not be marked.
+pub fn invalidate_block(hash: &H256, chain: &mut Blockchain, state: &mut State) -> Option<TipChange> {
+    let old_tip = chain.tip();
+    if !chain.invalidate(hash) {
+        return None;
+    }
+    Some(follow_tip(old_tip, None, chain, state))
+}
+
+/// Lift the marks around a block with `Blockchain::reconsider` and move the UTXO set to the new
+/// tip. Returns `None` if no mark was lifted.
+pub fn reconsider_block(hash: &H256, chain: &mut Blockchain, state: &mut State) -> Option<TipChange> {
+    let old_tip = chain.tip();
+    if !chain.reconsider(hash) {
+        return None;
+    }
+    Some(follow_tip(old_tip, None, chain, state))
+}
+
+/// Roll the UTXO set back from `old_tip` and forward to the current tip of the chain. The
+/// transactions of each block joining the longest chain are checked against the UTXO set of its
+/// own branch, but for `validated` when it extends `old_tip`, checked by `check_block` already. A
+/// block failing the check is marked invalid, and the UTXO set follows the chain the longest
+/// without it instead, usually back to `old_tip`. So does a branch that cannot be reached because
+/// the undo data of a block to leave is lost, so that the tip and the UTXO set never part.
+fn follow_tip(old_tip: H256, validated: Option<H256>, chain: &mut Blockchain, state: &mut State) -> TipChange {
+    // deeper blocks are read back from the store, and those connected below are kept until the
+    // next move for `storage::replay` to check
+    if chain.has_undo_store() {
+        state.prune_undo();
+    }
+    // the block the UTXO set is at
+    let mut current = old_tip;
+    'follow: while current != chain.tip() {
+        let (disconnected, connected) = chain.reorg_path(current, chain.tip());
+        for hash in &disconnected {
+            let disconnected_block = &chain.blockmap[hash];
+            if state.disconnect_block(disconnected_block) {
+                current = disconnected_block.header.parent;
+                continue;
+            }
+            if let Some(undo) = chain.stored_undo(hash) {
+                state.disconnect_block_with(disconnected_block, &undo);
+                current = disconnected_block.header.parent;
+                continue;
+            }
+            // keep the tip where the UTXO set can follow: off the branch to join, or back on the
+            // branch of `current` when the tip went down to one of its ancestors
+            let kept = match connected.first() {
+                Some(first) => {
+                    error!("No undo data for block {}, marking block {} invalid to stay at block {}", hash, first, current);
+                    chain.invalidate(first)
+                }
+                None => {
+                    error!("No undo data for block {}, reconsidering block {} to stay on it", hash, current);
+                    chain.reconsider(&current)
+                }
+            };
+            if !kept {
+                panic!("Cannot keep the chain at block {}, the UTXO set has no undo data for block {}", current, hash);
+            }
+            continue 'follow;
+        }
+        for hash in &connected {
+            let connected_block = &chain.blockmap[hash];
+            let checked = if validated == Some(*hash) && connected_block.header.parent == old_tip {
+                Ok(())
+            } else {
+                validation::check_block_transactions(connected_block, state)
+            };
+            if let Err(e) = checked {
+                warn!("Block {} is invalid on its branch, leaving it: {}", hash, e);
+                if !chain.invalidate(hash) {
+                    panic!("Cannot mark block {} invalid, the UTXO set is at block {}", hash, current);
+                }
+                continue 'follow;
+            }
+            let undo = state.connect_block(connected_block);
+            chain.notify_undo(connected_block, &undo);
+            current = *hash;
+        }
+    }
+    let (disconnected, connected) = chain.reorg_path(old_tip, current);
+    TipChange { disconnected: disconnected, connected: connected }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::{generate_mined_block, generate_random_block};
+    use crate::chainparams::REGTEST;
+    use crate::clock::SystemClock;
+    use crate::crypto::hash::Hashed;
+    use crate::storage::{BlockStore, MemoryStore};
+    use crate::transaction::tests::{funded, key, owner, spend};
+    use crate::transaction::UNDO_DEPTH;
+    use std::sync::Arc;
+
+    #[test]
+    fn reorg_rolls_back_the_state() {
+        let mut chain = Blockchain::new();
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let genesis = chain.tip();
+        let initial = state.clone();
+
+        let mut block = generate_random_block(&genesis);
+        block.content.data = vec![Hashed::new(spend(&key(1), [1u8; 32].into(), owner(&key(1)), 10))];
+        let change = insert_block(&block, &mut chain, &mut state);
+        assert_eq!(change, TipChange { disconnected: vec![], connected: vec![block.hash()] });
+        assert_eq!(state.block_undo(&block.hash()).unwrap().len(), 1);
+        let spent = state.clone();
+
+        // a fork of the same length is only stored
+        let mut fork = generate_random_block(&genesis);
+        fork.content.data = vec![Hashed::new(spend(&key(1), [1u8; 32].into(), owner(&key(1)), 7))];
+        assert_eq!(insert_block(&fork, &mut chain, &mut state), TipChange::default());
+        assert_eq!(state, spent);
+
+        // once longer, it replaces the first block, its blocks spending the outputs of the fork
+        let mut next = generate_random_block(&fork.hash());
+        next.content.data = vec![Hashed::new(spend(&key(1), fork.content.data[0].hash(), owner(&key(1)), 6))];
+        let change = insert_block(&next, &mut chain, &mut state);
+        assert_eq!(change, TipChange { disconnected: vec![block.hash()], connected: vec![fork.hash(), next.hash()] });
+        assert!(state.block_undo(&block.hash()).is_none());
+        assert!(!state.utxo.contains_key(&(block.content.data[0].hash(), 0)));
+        assert!(!state.utxo.contains_key(&(fork.content.data[0].hash(), 0)));
+        assert_eq!(state.utxo[&(next.content.data[0].hash(), 0)].0, 6.into());
+
+        // rolling back the fork restores the initial outputs
+        assert!(state.disconnect_block(&next));
+        assert!(state.disconnect_block(&fork));
+        assert_eq!(state.utxo, initial.utxo);
+        assert_eq!(state.commitment(), initial.commitment());
+    }
+
+    #[test]
+    fn fork_checked_against_its_branch() {
+        let mut chain = Blockchain::new();
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let genesis = chain.tip();
+        let mut block = generate_random_block(&genesis);
+        block.content.data = vec![Hashed::new(spend(&key(1), [1u8; 32].into(), owner(&key(1)), 10))];
+        insert_block(&block, &mut chain, &mut state);
+        let tip = generate_random_block(&block.hash());
+        insert_block(&tip, &mut chain, &mut state);
+        let spent = state.clone();
+
+        // the fork spends an output only the first block creates
+        let fork = generate_random_block(&genesis);
+        let mut bad = generate_random_block(&fork.hash());
+        bad.content.data = vec![Hashed::new(spend(&key(1), block.content.data[0].hash(), owner(&key(1)), 10))];
+        let longer = generate_random_block(&bad.hash());
+        for fork_block in &[&fork, &bad] {
+            assert_eq!(insert_block(fork_block, &mut chain, &mut state), TipChange::default());
+        }
+        // once longer, it is checked and left, the first branch staying the longest valid one
+        assert_eq!(insert_block(&longer, &mut chain, &mut state), TipChange::default());
+        assert_eq!(chain.tip(), tip.hash());
+        assert_eq!(state.utxo, spent.utxo);
+        assert_eq!(state.commitment(), spent.commitment());
+
+        // the blocks on top of it are left out too
+        let next = generate_random_block(&longer.hash());
+        assert_eq!(insert_block(&next, &mut chain, &mut state), TipChange::default());
+        assert_eq!(chain.tip(), tip.hash());
+    }
+
+    /// Connect a branch of `UNDO_DEPTH + 2` blocks, the first spending the coins of `funded`, then
+    /// a longer fork of the genesis block. Returns the first block and the tip of the fork.
+    fn deep_fork(chain: &mut Blockchain, state: &mut State) -> (Block, Block) {
+        let genesis = chain.blockmap[&chain.tip()].clone();
+        let first = generate_mined_block(&genesis, vec![Hashed::new(spend(&key(1), [1u8; 32].into(), owner(&key(1)), 10))]);
+        insert_block(&first, chain, state);
+        let mut parent = first.clone();
+        for _ in 1..UNDO_DEPTH + 2 {
+            parent = generate_mined_block(&parent, vec![]);
+            insert_block(&parent, chain, state);
+        }
+        assert_eq!(state.block_undo(&first.hash()).is_some(), !chain.has_undo_store());
+        let mut fork = genesis;
+        for _ in 0..UNDO_DEPTH + 3 {
+            fork = generate_mined_block(&fork, vec![]);
+            insert_block(&fork, chain, state);
+        }
+        (first, fork)
+    }
+
+    #[test]
+    fn deep_reorg_reads_stored_undo() {
+        let mut chain = Blockchain::with_params(&REGTEST);
+        let store = Arc::new(MemoryStore::new());
+        chain.subscribe(store.clone());
+        chain.set_undo_store(store);
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let initial = state.clone();
+        // the fork disconnects the first block with the undo data of the store
+        let (_, fork) = deep_fork(&mut chain, &mut state);
+        assert_eq!(chain.tip(), fork.hash());
+        assert_eq!(state.utxo, initial.utxo);
+        assert_eq!(state.height(), UNDO_DEPTH + 3);
+    }
+
+    #[test]
+    fn deep_reorg_without_store() {
+        let mut chain = Blockchain::with_params(&REGTEST);
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let initial = state.clone();
+        let (_, fork) = deep_fork(&mut chain, &mut state);
+        assert_eq!(chain.tip(), fork.hash());
+        assert_eq!(state.utxo, initial.utxo);
+    }
+
+    #[test]
+    fn replay_deep_reorg() {
+        let store = Arc::new(MemoryStore::new());
+        let mut chain = Blockchain::with_params(&REGTEST);
+        chain.subscribe(store.clone());
+        chain.set_undo_store(store.clone());
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let (_, fork) = deep_fork(&mut chain, &mut state);
+
+        // the stored fork disconnects blocks deeper than the state keeps while loading
+        let mut loaded = Blockchain::with_params(&REGTEST);
+        loaded.set_undo_store(store.clone());
+        let mut loaded_state = funded(owner(&key(1)), &[1], 10);
+        let stats = store.replay(&mut loaded, &mut loaded_state, &SystemClock).unwrap();
+        assert_eq!(stats.connected, 2 * UNDO_DEPTH as usize + 5);
+        assert_eq!(stats.undo_rewritten, 0);
+        assert_eq!(loaded.tip(), fork.hash());
+        assert_eq!(loaded_state.utxo, state.utxo);
+    }
+
+    #[test]
+    fn missing_undo_keeps_the_tip() {
+        let mut chain = Blockchain::with_params(&REGTEST);
+        // a store that lost everything
+        chain.set_undo_store(Arc::new(MemoryStore::new()));
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let (first, fork) = deep_fork(&mut chain, &mut state);
+        // the fork is marked invalid rather than leaving the UTXO set behind the tip
+        assert_ne!(chain.tip(), fork.hash());
+        assert_eq!(chain.height(), UNDO_DEPTH as usize + 2);
+        assert_eq!(state.height(), UNDO_DEPTH + 2);
+        assert!(state.utxo.contains_key(&(first.content.data[0].hash(), 0)));
+        assert_eq!(chain.invalid_blocks().len(), 1);
+    }
+
+    #[test]
+    fn invalidate_rolls_back_the_state() {
+        let mut chain = Blockchain::new();
+        let mut state = funded(owner(&key(1)), &[1], 10);
+        let initial = state.clone();
+        let mut block = generate_random_block(&chain.tip());
+        block.content.data = vec![Hashed::new(spend(&key(1), [1u8; 32].into(), owner(&key(1)), 10))];
+        insert_block(&block, &mut chain, &mut state);
+        let spent = state.clone();
+
+        let change = invalidate_block(&block.hash(), &mut chain, &mut state).unwrap();
+        assert_eq!(change, TipChange { disconnected: vec![block.hash()], connected: vec![] });
+        assert_eq!(state.utxo, initial.utxo);
+        assert_eq!(invalidate_block(&[7u8; 32].into(), &mut chain, &mut state), None);
+
+        let change = reconsider_block(&block.hash(), &mut chain, &mut state).unwrap();
+        assert_eq!(change, TipChange { disconnected: vec![], connected: vec![block.hash()] });
+        assert_eq!(state.utxo, spent.utxo);
+        assert_eq!(reconsider_block(&block.hash(), &mut chain, &mut state), None);
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time. Shared as `Arc<dyn Clock>` by the modules reading the time, so
+/// that tests and simulations can skew or fast-forward it without sleeping.
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the UNIX epoch, the unit of block timestamps.
+    fn now(&self) -> u128;
+}
+
+/// The system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u128 {
+        SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct MockClock {
+    now: Mutex<u128>,
+}
+
+impl MockClock {
+    pub fn new(now: u128) -> Self {
+        MockClock { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: u128) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration.as_millis();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u128 {
+        *self.now.lock().unwrap()
+    }
+}
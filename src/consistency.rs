@@ -0,0 +1,174 @@
+//! Compare the longest chains and UTXO sets of several nodes through their API servers.
+
+use crate::api::client::Client;
+use crate::api::types::ChainSummary;
+use crate::crypto::hash::H256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// What a node reported about its longest chain.
+pub struct NodeView {
+    pub addr: SocketAddr,
+    pub summary: ChainSummary,
+    /// Hash of the block at each of `Report::heights`
+    pub samples: Vec<H256>,
+}
+
+pub struct Report {
+    /// Heights sampled on every node, all at most the lowest tip height
+    pub heights: Vec<usize>,
+    pub nodes: Vec<NodeView>,
+    pub unreachable: Vec<(SocketAddr, String)>,
+}
+
+/// Fetch the summaries of the nodes and the hashes of their longest chains at up to `samples`
+/// heights spread from the genesis block to the lowest tip.
+pub fn check(addrs: &[SocketAddr], samples: usize) -> Report {
+    let mut unreachable = Vec::new();
+    let mut reachable = Vec::new();
+    for addr in addrs {
+        let client = Client::new(*addr);
+        match client.summary() {
+            Ok(summary) => reachable.push((client, summary)),
+            Err(e) => unreachable.push((*addr, e.to_string())),
+        }
+    }
+    let min_height = reachable.iter().map(|(_, s)| s.height).min().unwrap_or(0);
+    let heights = sample_heights(min_height, samples);
+    let mut nodes = Vec::new();
+    for (client, summary) in reachable {
+        let fetched: Result<Option<Vec<H256>>, _> = heights.iter().map(|h| client.hash_at_height(*h)).collect();
+        match fetched {
+            Ok(Some(samples)) => nodes.push(NodeView { addr: client.addr(), summary: summary, samples: samples }),
+            // the chain got shorter since the summary, only possible if the node restarted
+            Ok(None) => unreachable.push((client.addr(), "chain shrank while sampling".to_string())),
+            Err(e) => unreachable.push((client.addr(), e.to_string())),
+        }
+    }
+    Report { heights: heights, nodes: nodes, unreachable: unreachable }
+}
+
+/// Evenly spread heights from 0 to `max`, always including `max`.
+fn sample_heights(max: usize, samples: usize) -> Vec<usize> {
+    match samples {
+        0 => return Vec::new(),
+        1 => return vec![max],
+        _ => {}
+    }
+    let mut heights: Vec<usize> = (0..samples).map(|i| max * i / (samples - 1)).collect();
+    heights.dedup();
+    heights
+}
+
+impl Report {
+    /// The lowest sampled height at which the nodes disagree on the block.
+    pub fn first_divergence(&self) -> Option<usize> {
+        let first = self.nodes.first()?;
+        (0..self.heights.len())
+            .find(|i| self.nodes.iter().any(|n| n.samples[*i] != first.samples[*i]))
+            .map(|i| self.heights[i])
+    }
+
+    /// Groups of nodes that agree on the tip but not on the UTXO set. Nodes with different tips
+    /// may just be catching up, but these have applied the same blocks differently.
+    pub fn state_mismatches(&self) -> Vec<(H256, Vec<&NodeView>)> {
+        let mut by_tip: HashMap<H256, Vec<&NodeView>> = HashMap::new();
+        for node in &self.nodes {
+            by_tip.entry(node.summary.tip).or_insert_with(Vec::new).push(node);
+        }
+        let mut mismatches: Vec<_> = by_tip
+            .into_iter()
+            .filter(|(_, nodes)| nodes.iter().any(|n| n.summary.utxo_commitment != nodes[0].summary.utxo_commitment))
+            .collect();
+        mismatches.sort_by_key(|(tip, _)| *tip);
+        mismatches
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.unreachable.is_empty()
+            && self.nodes.windows(2).all(|w| w[0].summary.tip == w[1].summary.tip)
+            && self.first_divergence().is_none()
+            && self.state_mismatches().is_empty()
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for node in &self.nodes {
+            writeln!(
+                f,
+                "{}: height {} tip {} utxos {} commitment {}",
+                node.addr, node.summary.height, node.summary.tip, node.summary.utxo_count, node.summary.utxo_commitment
+            )?;
+        }
+        for (addr, e) in &self.unreachable {
+            writeln!(f, "{}: unreachable: {}", addr, e)?;
+        }
+        match self.first_divergence() {
+            Some(height) => {
+                writeln!(f, "Longest chains diverge at or below height {}:", height)?;
+                let i = self.heights.iter().position(|h| *h == height).unwrap();
+                for node in &self.nodes {
+                    writeln!(f, "  {}: {}", node.addr, node.samples[i])?;
+                }
+            }
+            None => writeln!(f, "Longest chains agree at the {} sampled heights", self.heights.len())?,
+        }
+        for (tip, nodes) in self.state_mismatches() {
+            writeln!(f, "UTXO sets differ at tip {}:", tip)?;
+            for node in nodes {
+                writeln!(f, "  {}: {}", node.addr, node.summary.utxo_commitment)?;
+            }
+        }
+        write!(f, "{}", if self.is_consistent() { "Consistent" } else { "Inconsistent" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(port: u16, tip: u8, commitment: u8, samples: &[u8]) -> NodeView {
+        NodeView {
+            addr: ([127, 0, 0, 1], port).into(),
+            summary: ChainSummary {
+                tip: [tip; 32].into(),
+                height: 10,
+                utxo_count: 1,
+                utxo_commitment: [commitment; 32].into(),
+            },
+            samples: samples.iter().map(|s| [*s; 32].into()).collect(),
+        }
+    }
+
+    #[test]
+    fn heights() {
+        assert_eq!(sample_heights(10, 0), Vec::<usize>::new());
+        assert_eq!(sample_heights(10, 1), vec![10]);
+        assert_eq!(sample_heights(10, 3), vec![0, 5, 10]);
+        assert_eq!(sample_heights(2, 10), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn divergence() {
+        let mut report = Report {
+            heights: vec![0, 5, 10],
+            nodes: vec![view(7000, 1, 1, &[0, 1, 2]), view(7001, 1, 1, &[0, 1, 2])],
+            unreachable: Vec::new(),
+        };
+        assert!(report.is_consistent());
+
+        report.nodes.push(view(7002, 1, 2, &[0, 1, 2]));
+        assert_eq!(report.first_divergence(), None);
+        let mismatches = report.state_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].1.len(), 3);
+        assert!(!report.is_consistent());
+
+        report.nodes.pop();
+        report.nodes.push(view(7002, 3, 2, &[0, 3, 3]));
+        assert_eq!(report.first_divergence(), Some(5));
+        assert!(report.state_mismatches().is_empty());
+        assert!(!report.is_consistent());
+    }
+}
@@ -0,0 +1,169 @@
+use super::hash::{H160, H256};
+use crate::chainparams;
+use ring::digest;
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AddressError {
+    InvalidCharacter,
+    InvalidLength,
+    InvalidVersion,
+    InvalidChecksum,
+}
+
+impl std::fmt::Display for AddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            AddressError::InvalidCharacter => "invalid base58 character",
+            AddressError::InvalidLength => "invalid address length",
+            AddressError::InvalidVersion => "invalid address version",
+            AddressError::InvalidChecksum => "invalid address checksum",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// First four bytes of the double SHA256 of the payload.
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let once = digest::digest(&digest::SHA256, payload);
+    let twice = digest::digest(&digest::SHA256, once.as_ref());
+    let mut ret = [0u8; 4];
+    ret.copy_from_slice(&twice.as_ref()[0..4]);
+    ret
+}
+
+fn base58_encode(input: &[u8]) -> String {
+    // little endian base58 digits
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in input {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = input.iter().take_while(|b| **b == 0).count();
+    let mut ret = String::with_capacity(zeros + digits.len());
+    for _ in 0..zeros {
+        ret.push(ALPHABET[0] as char);
+    }
+    for digit in digits.iter().rev() {
+        ret.push(ALPHABET[*digit as usize] as char);
+    }
+    ret
+}
+
+fn base58_decode(input: &str) -> Result<Vec<u8>, AddressError> {
+    // little endian bytes
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in input.bytes() {
+        let mut carry = match ALPHABET.iter().position(|a| *a == c) {
+            Some(v) => v as u32,
+            None => return Err(AddressError::InvalidCharacter),
+        };
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = input.bytes().take_while(|c| *c == ALPHABET[0]).count();
+    let mut ret = vec![0u8; zeros];
+    ret.extend(bytes.iter().rev());
+    Ok(ret)
+}
+
+/// The address controlled by a public key.
+pub fn address_of(public_key: &[u8]) -> H160 {
+    let pk_hash: H256 = digest::digest(&digest::SHA256, public_key).into();
+    pk_hash.to_addr().into()
+}
+
+/// Encode a public key hash as a Base58Check address of the selected network.
+pub fn encode(hash: &H160) -> String {
+    encode_with_version(hash, chainparams::current().address_version)
+}
+
+/// Encode a public key hash as a Base58Check address with the given version byte.
+pub fn encode_with_version(hash: &H160, version: u8) -> String {
+    let mut payload = Vec::with_capacity(25);
+    payload.push(version);
+    payload.extend_from_slice(hash.as_ref());
+    let check = checksum(&payload);
+    payload.extend_from_slice(&check);
+    base58_encode(&payload)
+}
+
+/// Parse and validate a Base58Check address of the selected network.
+pub fn decode(address: &str) -> Result<H160, AddressError> {
+    decode_with_version(address, chainparams::current().address_version)
+}
+
+/// Parse and validate a Base58Check address with the given version byte.
+pub fn decode_with_version(address: &str, version: u8) -> Result<H160, AddressError> {
+    let payload = base58_decode(address)?;
+    if payload.len() != 25 {
+        return Err(AddressError::InvalidLength);
+    }
+    if checksum(&payload[0..21]) != payload[21..25] {
+        return Err(AddressError::InvalidChecksum);
+    }
+    if payload[0] != version {
+        return Err(AddressError::InvalidVersion);
+    }
+    let mut raw = [0u8; 20];
+    raw.copy_from_slice(&payload[1..21]);
+    Ok(raw.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_address() {
+        // the genesis coinbase address of Bitcoin
+        let hash: H160 = (hex!("62e907b15cbf27d5425399ebf6f0fb50ebb88f18")).into();
+        assert_eq!(encode(&hash), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        assert_eq!(decode("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"), Ok(hash));
+    }
+
+    #[test]
+    fn round_trip() {
+        let zero: H160 = [0u8; 20].into();
+        assert_eq!(decode(&encode(&zero)), Ok(zero));
+        let ones: H160 = [255u8; 20].into();
+        assert_eq!(decode(&encode(&ones)), Ok(ones));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(decode("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"), Err(AddressError::InvalidChecksum));
+        assert_eq!(decode("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfN0"), Err(AddressError::InvalidCharacter));
+        assert_eq!(decode("1A1zP1eP5QGefi2"), Err(AddressError::InvalidLength));
+    }
+
+    #[test]
+    fn network_versions() {
+        let hash: H160 = (hex!("62e907b15cbf27d5425399ebf6f0fb50ebb88f18")).into();
+        let testnet = chainparams::TESTNET.address_version;
+        let address = encode_with_version(&hash, testnet);
+        assert!(address.starts_with('m') || address.starts_with('n'));
+        assert_eq!(decode_with_version(&address, testnet), Ok(hash));
+        // addresses of one network do not parse on another
+        assert_eq!(decode(&address), Err(AddressError::InvalidVersion));
+        assert_eq!(decode_with_version("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", testnet), Err(AddressError::InvalidVersion));
+    }
+}
@@ -1,4 +1,4 @@
-use serde::{Serialize, Deserialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
 
 /// An object that can be meaningfully hashed.
@@ -7,7 +7,7 @@ pub trait Hashable {
     fn hash(&self) -> H256;
 }
 
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct H160([u8; 20]); // big endian u160
 
 impl Hashable for H160 {
@@ -26,6 +26,20 @@ impl std::fmt::Debug for H160 {
     }
 }
 
+impl std::fmt::Display for H160 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", super::address::encode(self))
+    }
+}
+
+impl std::str::FromStr for H160 {
+    type Err = super::address::AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        super::address::decode(s)
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H160 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -61,7 +75,7 @@ impl std::convert::From<H160> for [u8; 20] {
 }
 
 /// A SHA256 hash.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
 
 impl Hashable for H256 {
@@ -88,6 +102,16 @@ impl std::fmt::Display for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut raw = [0u8; 32];
+        hex::decode_to_slice(s.trim_start_matches("0x"), &mut raw)?;
+        Ok(H256(raw))
+    }
+}
+
 impl std::fmt::Debug for H256 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -140,6 +164,54 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+/// Serialize as 0x-prefixed hex in human-readable formats (JSON), and as raw bytes otherwise
+/// (bincode), so that the wire format is unchanged.
+macro_rules! impl_hex_serde {
+    ($name:ident, $len:expr) => {
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.serialize_str(&format!("0x{}", hex::encode(&self.0)))
+                } else {
+                    serializer.serialize_newtype_struct(stringify!($name), &self.0)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    let s = String::deserialize(deserializer)?;
+                    let digits = s.trim_start_matches("0x");
+                    let mut raw = [0u8; $len];
+                    hex::decode_to_slice(digits, &mut raw).map_err(de::Error::custom)?;
+                    Ok($name(raw))
+                } else {
+                    struct NewtypeVisitor;
+                    impl<'de> de::Visitor<'de> for NewtypeVisitor {
+                        type Value = $name;
+
+                        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                            write!(f, "{} bytes", $len)
+                        }
+
+                        fn visit_newtype_struct<D: Deserializer<'de>>(
+                            self,
+                            deserializer: D,
+                        ) -> Result<Self::Value, D::Error> {
+                            <[u8; $len]>::deserialize(deserializer).map($name)
+                        }
+                    }
+                    deserializer.deserialize_newtype_struct(stringify!($name), NewtypeVisitor)
+                }
+            }
+        }
+    };
+}
+
+impl_hex_serde!(H160, 20);
+impl_hex_serde!(H256, 32);
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -164,6 +236,114 @@ impl H256 {
     pub fn to_addr(&self) -> [u8; 20] {
         self.0[12..32].try_into().unwrap()
     }
+
+    /// Expand a target from its compact "nBits" form, where the high byte is the length of the
+    /// target in bytes and the low three bytes are its most significant digits. Returns `None`
+    /// for negative (sign bit 0x00800000 set) or overflowing encodings.
+    pub fn from_compact(bits: u32) -> Option<H256> {
+        let size = (bits >> 24) as usize;
+        let mantissa = bits & 0x007fffff;
+        if bits & 0x00800000 != 0 && mantissa != 0 {
+            return None;
+        }
+        let mut raw = [0u8; 32];
+        for (i, byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+            // position of this digit counted from the least significant byte
+            let pos = size as isize - 1 - i as isize;
+            if pos < 0 || *byte == 0 {
+                continue;
+            }
+            if pos >= 32 {
+                return None;
+            }
+            raw[31 - pos as usize] = *byte;
+        }
+        Some(H256(raw))
+    }
+
+    /// Compact "nBits" form of a target, keeping only its three most significant bytes.
+    pub fn to_compact(&self) -> u32 {
+        let size = match self.0.iter().position(|b| *b != 0) {
+            Some(first) => 32 - first,
+            None => return 0,
+        };
+        let start = 32 - size;
+        let mut digits = [0u8; 4];
+        for i in 0..3 {
+            if start + i < 32 {
+                digits[i + 1] = self.0[start + i];
+            }
+        }
+        let mut mantissa = u32::from_be_bytes(digits);
+        let mut size = size as u32;
+        // the top bit of the mantissa is a sign bit, so keep it clear
+        if mantissa & 0x00800000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        (size << 24) | mantissa
+    }
+}
+
+/// A value together with its hash and the length of its encoding, computed once when the value
+/// is wrapped. The value can only be read through the wrapper, so neither goes stale.
+/// (De)serializes as the bare value.
+#[derive(Debug, Clone)]
+pub struct Hashed<T> {
+    value: T,
+    hash: H256,
+    size: usize,
+}
+
+impl<T: Hashable + Serialize> Hashed<T> {
+    pub fn new(value: T) -> Self {
+        let hash = value.hash();
+        let size = bincode::serialized_size(&value).unwrap() as usize;
+        Hashed { value: value, hash: hash, size: size }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Hashed<T> {
+    /// The length of the encoding of the value.
+    pub fn serialized_size(&self) -> usize {
+        self.size
+    }
+}
+
+impl<T> Hashable for Hashed<T> {
+    fn hash(&self) -> H256 {
+        self.hash
+    }
+}
+
+impl<T> std::ops::Deref for Hashed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Hashable + Serialize> From<T> for Hashed<T> {
+    fn from(value: T) -> Self {
+        Hashed::new(value)
+    }
+}
+
+impl<T: Serialize> Serialize for Hashed<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl<'de, T: Hashable + Serialize + Deserialize<'de>> Deserialize<'de> for Hashed<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Hashed::new)
+    }
 }
 
 #[cfg(any(test, test_utilities))]
@@ -179,4 +359,67 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn json_hex() {
+        let hash: H256 = [0x0a; 32].into();
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"0x{}\"", "0a".repeat(32)));
+        let back: H256 = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, hash);
+    }
+
+    #[test]
+    fn bincode_raw() {
+        let hash = generate_random_hash();
+        let bytes = bincode::serialize(&hash).unwrap();
+        assert_eq!(bytes, hash.as_ref());
+        let back: H256 = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(back, hash);
+    }
+
+    #[test]
+    fn compact_target() {
+        // Bitcoin's genesis target
+        let target = H256::from_compact(0x1d00ffff).unwrap();
+        let mut expected = [0u8; 32];
+        expected[4] = 0xff;
+        expected[5] = 0xff;
+        assert_eq!(target, expected.into());
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+
+        let mut raw = [0u8; 32];
+        raw[2] = 1;
+        assert_eq!(H256::from(raw).to_compact(), 0x1e010000);
+        assert_eq!(H256::from_compact(0x1e010000), Some(raw.into()));
+
+        // a leading digit with the top bit set moves into the next byte
+        let mut raw = [0u8; 32];
+        raw[31] = 0x80;
+        assert_eq!(H256::from(raw).to_compact(), 0x02008000);
+        assert_eq!(H256::from_compact(0x02008000), Some(raw.into()));
+
+        // digits beyond the three most significant are dropped
+        let truncated = H256::from_compact(H256::from([0xff; 32]).to_compact()).unwrap();
+        assert_eq!(truncated.to_compact(), 0x2100ffff);
+        assert_eq!(H256::from_compact(0).unwrap(), H256::default());
+        assert_eq!(H256::default().to_compact(), 0);
+
+        assert_eq!(H256::from_compact(0x04923456), None);
+        assert_eq!(H256::from_compact(0x23000001), None);
+    }
+
+    #[test]
+    fn hashed_transparent() {
+        use super::{Hashable, Hashed};
+        let inner = generate_random_hash();
+        let hashed = Hashed::new(inner);
+        assert_eq!(hashed.hash(), inner.hash());
+        let bytes = bincode::serialize(&hashed).unwrap();
+        assert_eq!(bytes, bincode::serialize(&inner).unwrap());
+        assert_eq!(hashed.serialized_size(), bytes.len());
+        let back: Hashed<H256> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(back.hash(), inner.hash());
+        assert_eq!(*back, inner);
+    }
+
 }
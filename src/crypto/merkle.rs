@@ -1,126 +1,159 @@
 use super::hash::{Hashable, H256};
 use ring::digest;
+use serde::{Serialize, Deserialize};
 
 /// A Merkle tree.
-#[derive(Debug, Default)]
+///
+/// Levels with an odd number of nodes are completed by pairing the last node with itself, the
+/// same rule Bitcoin uses. The duplicates are not stored.
+#[derive(Debug, Default, Clone)]
 pub struct MerkleTree {
-    tree: Vec<H256>,
-    leaf_num: usize,
+    /// `levels[0]` holds the leaf hashes, the last level holds the root
+    levels: Vec<Vec<H256>>,
 }
 
-fn find_height(in_size: usize) -> u32 {
-    let mut height = 0;
-    let mut cur = 1;
-    while in_size > cur {
-        height += 1;
-        cur *= 2;
+/// A Merkle proof that carries the position of the leaf it proves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub siblings: Vec<H256>,
+    pub index: usize,
+    pub leaf_count: usize,
+}
+
+impl MerkleProof {
+    /// Verify that the datum hash is the leaf at `index` of the tree with the given root.
+    pub fn verify(&self, root: &H256, datum: &H256) -> bool {
+        verify(root, datum, &self.siblings, self.index, self.leaf_count)
     }
-    return height;
+}
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    ctx.finish().into()
 }
 
 impl MerkleTree {
     pub fn new<T>(data: &[T]) -> Self where T: Hashable, {
-        let mut input_len = data.len();
-        let mut tree = Vec::new();
-        if input_len == 0 {
-            let bytes32 = [0u8; 32];
-            tree.push(bytes32.into());
-            return MerkleTree{ tree: tree, leaf_num: 0 };
-        }
-        for i in 0..input_len {
-            let hash = data[i].hash();
-            tree.push(hash);
+        let mut levels = vec![data.iter().map(|d| d.hash()).collect::<Vec<H256>>()];
+        while levels[levels.len()-1].len() > 1 {
+            let below = &levels[levels.len()-1];
+            let mut level = Vec::with_capacity((below.len() + 1) / 2);
+            for pair in below.chunks(2) {
+                let right = if pair.len() == 2 { &pair[1] } else { &pair[0] };
+                level.push(hash_pair(&pair[0], right));
+            }
+            levels.push(level);
         }
-        if input_len % 2 == 1 && input_len != 1 {
-            tree.push(tree[tree.len()-1]);
-            input_len += 1;
+        MerkleTree { levels: levels }
+    }
+
+    /// Append a leaf, recomputing only the nodes on the path from it to the root.
+    pub fn push<T>(&mut self, datum: &T) where T: Hashable, {
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
         }
-        let mut start = 0;
-        let mut cur_len = input_len;
-        while cur_len > 1 {
-            let half = cur_len/2;
-            // println!("{:?} {:?} {:?} {:?}", start, cur_len, half, tree.len());
-            for i in 0..half {
-                let mut ctx = digest::Context::new(&digest::SHA256);
-                ctx.update(tree[start+2*i].as_ref());
-                ctx.update(tree[start+2*i+1].as_ref());
-                tree.push(ctx.finish().into());
-            }
-            if half % 2 == 1 {
-                tree.push(tree[tree.len()-1]);
+        self.levels[0].push(datum.hash());
+        let mut depth = 0;
+        while self.levels[depth].len() > 1 {
+            let below = &self.levels[depth];
+            let index = (below.len() - 1) / 2;
+            let left = below[2*index];
+            let right = if 2*index + 1 < below.len() { below[2*index+1] } else { left };
+            let parent = hash_pair(&left, &right);
+            if depth + 1 == self.levels.len() {
+                self.levels.push(Vec::new());
             }
-            start += cur_len;
-            cur_len /= 2;
-            if cur_len % 2 == 1 && cur_len != 1 {
-                cur_len += 1;
+            let level = &mut self.levels[depth+1];
+            if index < level.len() {
+                level[index] = parent;
+            } else {
+                level.push(parent);
             }
+            depth += 1;
+        }
+    }
+
+    /// Number of leaves in the tree
+    pub fn len(&self) -> usize {
+        match self.levels.first() {
+            Some(leaves) => leaves.len(),
+            None => 0,
         }
-        MerkleTree { tree: tree, leaf_num: input_len }
     }
 
     pub fn root(&self) -> H256 {
-        return self.tree[self.tree.len()-1];
+        match self.levels.last() {
+            Some(level) if !level.is_empty() => level[0],
+            _ => [0u8; 32].into(),
+        }
+    }
+
+    /// Returns the Merkle Proof of data at index i, together with its position
+    pub fn prove(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(MerkleProof { siblings: self.proof(index), index: index, leaf_count: self.len() })
     }
 
     /// Returns the Merkle Proof of data at index i
     pub fn proof(&self, index: usize) -> Vec<H256> {
         let mut proof = Vec::new();
-        if index >= self.leaf_num {
+        if index >= self.len() {
             return proof;
         }
-        let height = find_height(self.leaf_num);
         let mut cur_index = index;
-        let mut sequence = 0;
-        for i in 0..height {
-            // println!("{:?}", cur_index);
-            let group = (cur_index - sequence)/2;
-            if cur_index % 2 == 1 {
-                proof.push(self.tree[cur_index-1]);
-            }
-            else {
-                proof.push(self.tree[cur_index+1]);
-            }
-            if i == 0 {
-                sequence += self.leaf_num;
+        for level in &self.levels[..self.levels.len()-1] {
+            let sibling = cur_index ^ 1;
+            if sibling < level.len() {
+                proof.push(level[sibling]);
+            } else {
+                proof.push(level[cur_index]);
             }
-            else {
-                sequence += 2usize.pow(height - i);
-            }
-            cur_index = sequence + group;
+            cur_index /= 2;
         }
         return proof;
     }
 }
 
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
-/// index of datum and `leaf_size`, the total number of leaves.
+/// index of datum and `leaf_size`, the total number of leaves. All of them may come from a peer:
+/// an index out of range, a proof longer or shorter than the depth of a tree of `leaf_size`
+/// leaves, or a last node of an odd level paired with anything but itself fail the proof.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
-    let height = proof.len();
-    let leaf_num = 2usize.pow(height as u32) - (2usize.pow(height as u32+1) - 1 - leaf_size);
+    if index >= leaf_size || proof.len() != depth(leaf_size) {
+        return false;
+    }
     let mut cur_index = index;
-    let mut sequence = 0;
-    let mut ctx = digest::Context::new(&digest::SHA256);
-    let mut trace = datum.clone();
-    for i in 0..height {
-        let group = (cur_index - sequence)/2;
+    let mut width = leaf_size;
+    let mut trace = *datum;
+    for sibling in proof {
         if cur_index % 2 == 1 {
-            ctx.update(proof[i].as_ref());
-            ctx.update(trace.as_ref());
+            trace = hash_pair(sibling, &trace);
         }
         else {
-            ctx.update(trace.as_ref());
-            ctx.update(proof[i].as_ref());
-        }
-        if i == 0 {
-            sequence += leaf_num;
-        }
-        else {
-            sequence += 2usize.pow(height as u32 - i as u32);
+            if cur_index + 1 == width && *sibling != trace {
+                return false;
+            }
+            trace = hash_pair(&trace, sibling);
         }
-        cur_index = sequence + group;
-        trace = ctx.clone().finish().into();
+        cur_index /= 2;
+        width = width / 2 + width % 2;
     }
-    return trace == *root;
+    trace == *root
+}
+
+/// Number of levels above the leaves of a tree of `leaf_count` leaves.
+fn depth(leaf_count: usize) -> usize {
+    let mut depth = 0;
+    let mut width = leaf_count;
+    while width > 1 {
+        width = width / 2 + width % 2;
+        depth += 1;
+    }
+    depth
 }
 
 #[cfg(test)]
@@ -174,4 +207,103 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn proof_object() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let proof = merkle_tree.prove(1).unwrap();
+        assert_eq!(proof.index, 1);
+        assert_eq!(proof.leaf_count, 2);
+        assert!(proof.verify(&merkle_tree.root(), &input_data[1].hash()));
+        assert!(!proof.verify(&merkle_tree.root(), &input_data[0].hash()));
+        assert!(merkle_tree.prove(2).is_none());
+        let json = serde_json::to_string(&proof).unwrap();
+        let back: MerkleProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, proof);
+    }
+
+    /// Root computed level by level, duplicating the last node of odd levels
+    fn reference_root(leaves: &[H256]) -> H256 {
+        let mut level: Vec<H256> = leaves.to_vec();
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(level[level.len()-1]);
+            }
+            level = level.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+        }
+        level[0]
+    }
+
+    fn leaves(n: usize) -> Vec<H256> {
+        (0..n).map(|i| [i as u8; 32].into()).collect()
+    }
+
+    #[test]
+    fn odd_sizes() {
+        for &n in &[1, 3, 5, 6, 7] {
+            let data = leaves(n);
+            let hashes: Vec<H256> = data.iter().map(|d| d.hash()).collect();
+            let merkle_tree = MerkleTree::new(&data);
+            assert_eq!(merkle_tree.root(), reference_root(&hashes), "{} leaves", n);
+            for i in 0..n {
+                let proof = merkle_tree.proof(i);
+                assert!(verify(&merkle_tree.root(), &hashes[i], &proof, i, n), "{} leaves, index {}", n, i);
+            }
+            assert!(merkle_tree.proof(n).is_empty());
+        }
+    }
+
+    #[test]
+    fn adversarial_proofs() {
+        let data = leaves(5);
+        let hashes: Vec<H256> = data.iter().map(|d| d.hash()).collect();
+        let merkle_tree = MerkleTree::new(&data);
+        let root = merkle_tree.root();
+        let proof = merkle_tree.proof(1);
+        assert!(verify(&root, &hashes[1], &proof, 1, 5));
+
+        // out of range indices and leaf counts
+        assert!(!verify(&root, &hashes[1], &proof, 5, 5));
+        assert!(!verify(&root, &hashes[1], &proof, 1, 0));
+        assert!(!verify(&root, &hashes[1], &proof, usize::MAX, usize::MAX));
+        assert!(!verify(&root, &hashes[1], &proof, 1, usize::MAX));
+        assert_eq!(depth(usize::MAX), 64);
+        // truncated and oversized proofs
+        assert!(!verify(&root, &hashes[1], &proof[..2], 1, 5));
+        let mut longer = proof.clone();
+        longer.push(root);
+        assert!(!verify(&root, &hashes[1], &longer, 1, 5));
+        assert!(!verify(&root, &hashes[1], &vec![root; 10_000], 1, 5));
+
+        // a tree of 4 leaves claimed to have 3: the last leaf may only pair with itself
+        let four = MerkleTree::new(&data[..4]);
+        let proof = four.proof(2);
+        assert!(verify(&four.root(), &hashes[2], &proof, 2, 4));
+        assert!(!verify(&four.root(), &hashes[2], &proof, 2, 3));
+    }
+
+    #[test]
+    fn single_leaf() {
+        let data = leaves(1);
+        let merkle_tree = MerkleTree::new(&data);
+        assert_eq!(merkle_tree.root(), data[0].hash());
+        assert!(merkle_tree.proof(0).is_empty());
+    }
+
+    #[test]
+    fn push_matches_new() {
+        let data = leaves(9);
+        let mut incremental = MerkleTree::new::<H256>(&[]);
+        assert_eq!(incremental.root(), H256::default());
+        for n in 1..=data.len() {
+            incremental.push(&data[n-1]);
+            let batch = MerkleTree::new(&data[..n]);
+            assert_eq!(incremental.root(), batch.root(), "{} leaves", n);
+            assert_eq!(incremental.len(), n);
+            for i in 0..n {
+                assert_eq!(incremental.proof(i), batch.proof(i));
+            }
+        }
+    }
 }
@@ -1,3 +1,6 @@
+pub mod address;
 pub mod hash;
 pub mod merkle;
 pub mod key_pair;
+pub mod signer;
+pub mod u256;
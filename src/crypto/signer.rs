@@ -0,0 +1,265 @@
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use url::Url;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest answer read from an external signer, headers included, far more than a key or a
+/// signature takes.
+const MAX_ANSWER: u64 = 4096;
+
+/// Signature schemes a transaction can be signed with. Transactions name their scheme, and
+/// validation checks the signature with it, so schemes can be added without touching the
+/// transactions signed with the existing ones.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Ed25519,
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::Ed25519
+    }
+}
+
+impl Algorithm {
+    /// Check a signature over the message, returning false for malformed keys and signatures.
+    pub fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            Algorithm::Ed25519 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+                .verify(message, signature)
+                .is_ok(),
+        }
+    }
+
+    /// Length of the encoded public keys of the scheme.
+    pub fn public_key_len(&self) -> usize {
+        match self {
+            Algorithm::Ed25519 => 32,
+        }
+    }
+
+    /// Length of the signatures of the scheme.
+    pub fn signature_len(&self) -> usize {
+        match self {
+            Algorithm::Ed25519 => 64,
+        }
+    }
+}
+
+/// A private key able to sign transactions.
+pub trait Signer {
+    fn algorithm(&self) -> Algorithm;
+
+    /// The encoded public key, as carried by signed transactions.
+    fn public_key_bytes(&self) -> Vec<u8>;
+
+    /// Sign a message. Keys held in memory always do, keys held by a device fail when it does not
+    /// answer.
+    fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+impl Signer for Ed25519KeyPair {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Ed25519
+    }
+
+    fn public_key_bytes(&self) -> Vec<u8> {
+        self.public_key().as_ref().to_vec()
+    }
+
+    fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(Ed25519KeyPair::sign(self, message).as_ref().to_vec())
+    }
+}
+
+/// Where an `ExternalSigner` gets its public key and signatures, hex-encoded.
+enum Device {
+    /// A program run as `<command> pubkey` and `<command> sign <message>`, answering on its
+    /// standard output.
+    Command(Vec<String>),
+    /// A server answering `GET <path>/pubkey`, and `POST <path>/sign` with the message as body.
+    Http { addr: SocketAddr, host: String, path: String },
+}
+
+/// An Ed25519 key held outside the node, like by a hardware wallet, asked for each signature.
+/// The public key is asked once, when the signer is created. Signatures are checked before
+/// being returned, so a device answering garbage fails like one not answering.
+pub struct ExternalSigner {
+    device: Device,
+    public_key: Vec<u8>,
+}
+
+impl ExternalSigner {
+    /// A signer behind an `http://host:port/path` URL, or else behind a command line, split at
+    /// whitespace.
+    pub fn new(spec: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let device = if spec.starts_with("http://") {
+            let url = Url::parse(spec).map_err(|e| invalid(format!("invalid signer URL {}: {}", spec, e)))?;
+            let addr = url
+                .socket_addrs(|| Some(80))?
+                .into_iter()
+                .next()
+                .ok_or_else(|| invalid(format!("signer URL {} resolves to no address", spec)))?;
+            Device::Http {
+                addr: addr,
+                host: url.host_str().unwrap_or_default().to_string(),
+                path: url.path().trim_end_matches('/').to_string(),
+            }
+        } else {
+            let command: Vec<String> = spec.split_whitespace().map(String::from).collect();
+            if command.is_empty() {
+                return Err(invalid("empty signer command".to_string()));
+            }
+            Device::Command(command)
+        };
+        let mut signer = ExternalSigner { device: device, public_key: Vec::new() };
+        signer.public_key = signer.request("pubkey", None)?;
+        if signer.public_key.len() != Algorithm::Ed25519.public_key_len() {
+            return Err(invalid(format!("signer public key of {} bytes", signer.public_key.len())));
+        }
+        Ok(signer)
+    }
+
+    /// Ask the device, decoding its hex answer.
+    fn request(&self, what: &str, message: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let answer = match &self.device {
+            Device::Command(command) => {
+                let mut process = Command::new(&command[0]);
+                process.args(&command[1..]).arg(what);
+                if let Some(message) = message {
+                    process.arg(hex::encode(message));
+                }
+                let mut child = process.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+                let answer = read_answer(child.stdout.take().unwrap());
+                if answer.is_err() {
+                    let _ = child.kill();
+                }
+                let status = child.wait()?;
+                let answer = answer?;
+                if !status.success() {
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("signer command failed: {}", status)));
+                }
+                answer
+            }
+            Device::Http { addr, host, path } => {
+                let mut stream = TcpStream::connect_timeout(addr, TIMEOUT)?;
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                match message {
+                    Some(message) => {
+                        let body = hex::encode(message);
+                        write!(
+                            stream,
+                            "POST {}/{} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+                            path,
+                            what,
+                            host,
+                            body.len(),
+                            body
+                        )?;
+                    }
+                    None => write!(stream, "GET {}/{} HTTP/1.0\r\nHost: {}\r\n\r\n", path, what, host)?,
+                }
+                let response = read_answer(stream)?;
+                let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+                let status = response.split_whitespace().nth(1).ok_or_else(malformed)?;
+                if status != "200" {
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("signer answered {}", status)));
+                }
+                let body = response.find("\r\n\r\n").ok_or_else(malformed)?;
+                response[body + 4..].to_string()
+            }
+        };
+        hex::decode(answer.trim()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("signer answer: {}", e)))
+    }
+}
+
+/// Read the answer of a device, up to `MAX_ANSWER` bytes.
+fn read_answer<R: Read>(reader: R) -> io::Result<String> {
+    let mut answer = String::new();
+    reader.take(MAX_ANSWER + 1).read_to_string(&mut answer)?;
+    if answer.len() as u64 > MAX_ANSWER {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "signer answer too long"));
+    }
+    Ok(answer)
+}
+
+impl Signer for ExternalSigner {
+    fn algorithm(&self) -> Algorithm {
+        Algorithm::Ed25519
+    }
+
+    fn public_key_bytes(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let signature = self.request("sign", Some(message))?;
+        if !self.algorithm().verify(&self.public_key, message, &signature) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "external signer answered an invalid signature"));
+        }
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair;
+
+    #[test]
+    fn sign_verify() {
+        let key = key_pair::random();
+        let signer: &dyn Signer = &key;
+        let signature = signer.sign(b"message").unwrap();
+        let algorithm = signer.algorithm();
+        assert!(algorithm.verify(&signer.public_key_bytes(), b"message", &signature));
+        assert!(!algorithm.verify(&signer.public_key_bytes(), b"other message", &signature));
+        assert!(!algorithm.verify(&[0u8; 3], b"message", &signature));
+        assert_eq!(signature.len(), algorithm.signature_len());
+    }
+
+    #[test]
+    fn external_signer() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/device/", server.server_addr());
+        let key = key_pair::random();
+        let public_key = key.public_key().as_ref().to_vec();
+        let device = std::thread::spawn(move || {
+            // the public key, a signature, then a signature of the wrong message
+            for forge in [false, false, true].iter() {
+                let mut request = server.recv().unwrap();
+                let answer = match request.url() {
+                    "/device/pubkey" => hex::encode(key.public_key()),
+                    "/device/sign" => {
+                        let mut body = String::new();
+                        request.as_reader().read_to_string(&mut body).unwrap();
+                        let message = if *forge { vec![0u8] } else { hex::decode(body).unwrap() };
+                        hex::encode(key.sign(&message))
+                    }
+                    _ => String::new(),
+                };
+                request.respond(tiny_http::Response::from_string(answer)).unwrap();
+            }
+        });
+        let signer = ExternalSigner::new(&url).unwrap();
+        assert_eq!(signer.public_key_bytes(), public_key);
+        let signature = signer.sign(b"message").unwrap();
+        assert!(signer.algorithm().verify(&public_key, b"message", &signature));
+        assert_eq!(signer.sign(b"message").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        device.join().unwrap();
+        // the device is gone
+        assert!(signer.sign(b"message").is_err());
+
+        // a command failing, not answering a key, or answering too much
+        assert!(ExternalSigner::new("false").is_err());
+        assert!(ExternalSigner::new("echo 00").is_err());
+        assert!(ExternalSigner::new("").is_err());
+        assert!(ExternalSigner::new("yes").is_err());
+    }
+}
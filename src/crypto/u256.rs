@@ -0,0 +1,374 @@
+use super::hash::H256;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::ops::{Add, Div, Mul, Not, Rem, Shl, Shr, Sub};
+
+/// An unsigned 256-bit integer, for chain work and target arithmetic. Arithmetic operators panic
+/// on overflow and division by zero, like the primitive integer types in debug builds; use the
+/// `checked_*` methods where the operands are not trusted.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Hash, Default)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|limb| *limb == 0)
+    }
+
+    /// Number of significant bits.
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    /// The lowest 64 bits.
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    /// The value as a u64, if it fits.
+    pub fn as_u64(&self) -> Option<u64> {
+        if self.bits() <= 64 {
+            Some(self.0[0])
+        } else {
+            None
+        }
+    }
+
+    /// The nearest `f64`, for display and rates.
+    pub fn to_f64(&self) -> f64 {
+        self.0.iter().rev().fold(0.0, |value, limb| value * 18_446_744_073_709_551_616.0 + *limb as f64)
+    }
+
+    fn bit(&self, index: u32) -> bool {
+        self.0[(index / 64) as usize] >> (index % 64) & 1 == 1
+    }
+
+    pub fn overflowing_add(self, other: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for i in 0..4 {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            result[i] = sum;
+            carry = c1 || c2;
+        }
+        (U256(result), carry)
+    }
+
+    pub fn overflowing_sub(self, other: U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for i in 0..4 {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            result[i] = diff;
+            borrow = b1 || b2;
+        }
+        (U256(result), borrow)
+    }
+
+    pub fn checked_add(self, other: U256) -> Option<U256> {
+        match self.overflowing_add(other) {
+            (sum, false) => Some(sum),
+            _ => None,
+        }
+    }
+
+    pub fn checked_sub(self, other: U256) -> Option<U256> {
+        match self.overflowing_sub(other) {
+            (diff, false) => Some(diff),
+            _ => None,
+        }
+    }
+
+    pub fn checked_mul(self, other: U256) -> Option<U256> {
+        // schoolbook multiplication on 64-bit limbs, with the product kept in 8 limbs
+        let mut result = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let cur = result[i + j] as u128 + self.0[i] as u128 * other.0[j] as u128 + carry;
+                result[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            result[i + 4] = carry as u64;
+        }
+        if result[4..].iter().any(|limb| *limb != 0) {
+            return None;
+        }
+        Some(U256([result[0], result[1], result[2], result[3]]))
+    }
+
+    /// Quotient and remainder, or `None` when dividing by zero.
+    pub fn checked_div_rem(self, other: U256) -> Option<(U256, U256)> {
+        if other.is_zero() {
+            return None;
+        }
+        if self < other {
+            return Some((U256::ZERO, self));
+        }
+        // binary long division
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            if self.bit(i) {
+                remainder.0[0] |= 1;
+            }
+            if remainder >= other {
+                remainder = remainder - other;
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+        }
+        Some((quotient, remainder))
+    }
+
+    pub fn checked_div(self, other: U256) -> Option<U256> {
+        self.checked_div_rem(other).map(|(quotient, _)| quotient)
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(input: u64) -> U256 {
+        U256([input, 0, 0, 0])
+    }
+}
+
+impl From<u128> for U256 {
+    fn from(input: u128) -> U256 {
+        U256([input as u64, (input >> 64) as u64, 0, 0])
+    }
+}
+
+impl From<&H256> for U256 {
+    fn from(input: &H256) -> U256 {
+        let bytes: &[u8] = input.as_ref();
+        let mut limbs = [0u64; 4];
+        for i in 0..4 {
+            let mut limb = [0u8; 8];
+            limb.copy_from_slice(&bytes[24 - 8 * i..32 - 8 * i]);
+            limbs[i] = u64::from_be_bytes(limb);
+        }
+        U256(limbs)
+    }
+}
+
+impl From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        (&input).into()
+    }
+}
+
+impl From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for i in 0..4 {
+            bytes[24 - 8 * i..32 - 8 * i].copy_from_slice(&input.0[i].to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        self.checked_add(other).expect("attempt to add with overflow")
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: U256) -> U256 {
+        self.checked_sub(other).expect("attempt to subtract with overflow")
+    }
+}
+
+impl Mul for U256 {
+    type Output = U256;
+
+    fn mul(self, other: U256) -> U256 {
+        self.checked_mul(other).expect("attempt to multiply with overflow")
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    fn div(self, other: U256) -> U256 {
+        self.checked_div(other).expect("attempt to divide by zero")
+    }
+}
+
+impl Rem for U256 {
+    type Output = U256;
+
+    fn rem(self, other: U256) -> U256 {
+        self.checked_div_rem(other).expect("attempt to calculate the remainder with a divisor of zero").1
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+/// Shifts discard the bits moved out, and shifting by 256 or more gives zero.
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        for i in limbs..4 {
+            result[i] = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                result[i] |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        for i in 0..4usize.saturating_sub(limbs) {
+            result[i] = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                result[i] |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl std::fmt::Display for U256 {
+    /// Decimal representation.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_zero() {
+            return f.pad("0");
+        }
+        // peel off 19 decimal digits at a time, the most that fit in a u64
+        let chunk = U256::from(10_000_000_000_000_000_000u64);
+        let mut digits = Vec::new();
+        let mut rest = *self;
+        while !rest.is_zero() {
+            let (quotient, remainder) = rest.checked_div_rem(chunk).unwrap();
+            digits.push(remainder.low_u64());
+            rest = quotient;
+        }
+        let mut s = digits.pop().unwrap().to_string();
+        for d in digits.iter().rev() {
+            s.push_str(&format!("{:019}", d));
+        }
+        f.pad(&s)
+    }
+}
+
+impl std::fmt::Debug for U256 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "0x{}", H256::from(*self))
+    }
+}
+
+/// Expected number of hashes to find a hash not exceeding the target, i.e. 2^256 / (target + 1).
+/// The zero target, which only the zero hash meets, saturates at `U256::MAX`.
+pub fn work_from_target(target: &H256) -> U256 {
+    let target = U256::from(target);
+    if target == U256::MAX {
+        return U256::ONE;
+    }
+    if target.is_zero() {
+        return U256::MAX;
+    }
+    // 2^256 does not fit, but 2^256 / (t + 1) = (2^256 - t - 1) / (t + 1) + 1 = !t / (t + 1) + 1
+    (!target / (target + U256::ONE)) + U256::ONE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn h256_round_trip() {
+        let mut raw = [0u8; 32];
+        raw[0] = 0x12;
+        raw[31] = 0x34;
+        raw[15] = 0x56;
+        let value = U256::from(H256::from(raw));
+        assert_eq!(H256::from(value), H256::from(raw));
+        assert_eq!(value.low_u64(), 0x34);
+        assert_eq!(value.bits(), 253);
+        assert_eq!(U256::from(0x1234u64).as_u64(), Some(0x1234));
+        assert_eq!(value.as_u64(), None);
+        assert_eq!(U256::from(0x1234u64).to_f64(), 4660.0);
+        assert_eq!((U256::ONE << 200).to_f64(), 2f64.powi(200));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let a = U256::from(u128::MAX);
+        let b = U256::from(3u64);
+        assert_eq!(a + U256::ONE, U256::ONE << 128);
+        assert_eq!((a + U256::ONE) - U256::ONE, a);
+        assert_eq!(a * b / b, a);
+        assert_eq!((a * b) % U256::from(7u64), U256::from((u128::MAX % 7 * 3 % 7) as u64));
+        assert_eq!((U256::ONE << 255) >> 255, U256::ONE);
+        assert_eq!(U256::ONE << 256, U256::ZERO);
+        assert_eq!(U256::MAX >> 192, U256::from(u64::MAX));
+        assert!(U256::ONE << 200 > U256::from(u128::MAX));
+        assert_eq!(U256::MAX.checked_add(U256::ONE), None);
+        assert_eq!(U256::ZERO.checked_sub(U256::ONE), None);
+        assert_eq!((U256::ONE << 128).checked_mul(U256::ONE << 128), None);
+        assert_eq!(U256::ONE.checked_div(U256::ZERO), None);
+    }
+
+    #[test]
+    fn decimal() {
+        assert_eq!(U256::ZERO.to_string(), "0");
+        assert_eq!(U256::from(10_000_000_000_000_000_000u64).to_string(), "10000000000000000000");
+        assert_eq!(
+            U256::MAX.to_string(),
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935"
+        );
+    }
+
+    #[test]
+    fn work() {
+        assert_eq!(work_from_target(&H256::from([0xff; 32])), U256::ONE);
+        assert_eq!(work_from_target(&H256::default()), U256::MAX);
+        // a target of 2^232 - 1 takes 2^24 hashes on average
+        let target = U256::from(H256::from_compact(0x1e010000).unwrap()) - U256::ONE;
+        assert_eq!(work_from_target(&target.into()), U256::ONE << 24);
+    }
+}
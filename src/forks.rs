@@ -0,0 +1,159 @@
+//! Fork monitoring: reorgs of the longest chain and the competing branches of the block tree.
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainListener};
+use crate::crypto::hash::{H256, Hashable};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Number of reorgs kept for `ForkStats::recent_reorgs`.
+pub const MAX_RECENT_REORGS: usize = 100;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReorgEvent {
+    /// Number of blocks that left the longest chain
+    pub depth: usize,
+    /// Height of the last block the old and the new chain share
+    pub fork_height: usize,
+    /// The tip before the reorg
+    pub old_tip: H256,
+}
+
+/// A leaf of the block tree other than the tip of the longest chain.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompetingTip {
+    pub hash: H256,
+    pub height: usize,
+    /// Height of the last block it shares with the longest chain
+    pub fork_height: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ForkStats {
+    /// Blocks of the block tree outside the longest chain
+    pub stale_blocks: usize,
+    pub reorgs: usize,
+    pub max_reorg_depth: usize,
+    /// The latest reorgs, oldest first
+    pub recent_reorgs: Vec<ReorgEvent>,
+    /// Highest first
+    pub competing_tips: Vec<CompetingTip>,
+}
+
+/// Follows the chain notifications to record reorgs and the leaves of the block tree. Subscribe
+/// it before loading stored blocks to include them.
+#[derive(Default)]
+pub struct ForkMonitor {
+    /// Blocks disconnected since the last block connected, the reorg in progress
+    disconnecting: Option<ReorgEvent>,
+    reorgs: usize,
+    max_reorg_depth: usize,
+    recent_reorgs: VecDeque<ReorgEvent>,
+    leaves: HashSet<H256>,
+}
+
+impl ForkMonitor {
+    pub fn new() -> Self {
+        ForkMonitor::default()
+    }
+
+    pub fn block_inserted(&mut self, block: &Block) {
+        self.leaves.remove(&block.header.parent);
+        self.leaves.insert(block.hash());
+    }
+
+    pub fn block_disconnected(&mut self, block: &Block, height: usize) {
+        // blocks are disconnected from the old tip down
+        let event = self.disconnecting.get_or_insert(ReorgEvent { depth: 0, fork_height: height, old_tip: block.hash() });
+        event.depth += 1;
+        event.fork_height = height - 1;
+    }
+
+    pub fn block_connected(&mut self) {
+        if let Some(event) = self.disconnecting.take() {
+            self.reorgs += 1;
+            self.max_reorg_depth = self.max_reorg_depth.max(event.depth);
+            if self.recent_reorgs.len() == MAX_RECENT_REORGS {
+                self.recent_reorgs.pop_front();
+            }
+            self.recent_reorgs.push_back(event);
+        }
+    }
+
+    pub fn stats(&self, chain: &Blockchain) -> ForkStats {
+        let tip = chain.tip();
+        let mut competing_tips: Vec<CompetingTip> = self
+            .leaves
+            .iter()
+            .filter(|hash| **hash != tip && chain.blockmap.contains_key(hash))
+            .map(|hash| {
+                let mut fork = *hash;
+                while !chain.is_in_longest_chain(&fork) {
+                    fork = chain.blockmap[&fork].header.parent;
+                }
+                CompetingTip { hash: *hash, height: chain.lengthmap[hash], fork_height: chain.lengthmap[&fork] }
+            })
+            .collect();
+        competing_tips.sort_by(|a, b| b.height.cmp(&a.height).then(a.hash.cmp(&b.hash)));
+        ForkStats {
+            stale_blocks: chain.blockmap.len() - (chain.height() + 1),
+            reorgs: self.reorgs,
+            max_reorg_depth: self.max_reorg_depth,
+            recent_reorgs: self.recent_reorgs.iter().cloned().collect(),
+            competing_tips: competing_tips,
+        }
+    }
+}
+
+impl ChainListener for Mutex<ForkMonitor> {
+    fn on_block_inserted(&self, block: &Block) {
+        self.lock().unwrap().block_inserted(block);
+    }
+
+    fn on_block_connected(&self, _block: &Block, _height: usize) {
+        self.lock().unwrap().block_connected();
+    }
+
+    fn on_block_disconnected(&self, block: &Block, height: usize) {
+        self.lock().unwrap().block_disconnected(block, height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use std::sync::Arc;
+
+    #[test]
+    fn reorgs_and_competing_tips() {
+        let mut chain = Blockchain::new();
+        let monitor = Arc::new(Mutex::new(ForkMonitor::new()));
+        chain.subscribe(monitor.clone());
+        let genesis = chain.tip();
+        let a1 = generate_random_block(&genesis);
+        chain.insert(&a1);
+        let a2 = generate_random_block(&a1.hash());
+        chain.insert(&a2);
+        let b1 = generate_random_block(&genesis);
+        chain.insert(&b1);
+
+        let stats = monitor.lock().unwrap().stats(&chain);
+        assert_eq!(stats.stale_blocks, 1);
+        assert_eq!(stats.reorgs, 0);
+        assert_eq!(stats.competing_tips, vec![CompetingTip { hash: b1.hash(), height: 1, fork_height: 0 }]);
+
+        // the b branch overtakes a, two blocks deep
+        let b2 = generate_random_block(&b1.hash());
+        chain.insert(&b2);
+        let b3 = generate_random_block(&b2.hash());
+        chain.insert(&b3);
+        let stats = monitor.lock().unwrap().stats(&chain);
+        assert_eq!(stats.stale_blocks, 2);
+        assert_eq!(stats.reorgs, 1);
+        assert_eq!(stats.max_reorg_depth, 2);
+        assert_eq!(stats.recent_reorgs, vec![ReorgEvent { depth: 2, fork_height: 0, old_tip: a2.hash() }]);
+        assert_eq!(stats.competing_tips, vec![CompetingTip { hash: a2.hash(), height: 2, fork_height: 0 }]);
+    }
+}
@@ -0,0 +1,42 @@
+//! A Bitcoin-like full node. `Node::builder()` assembles and starts a node in-process, the way
+//! the `bitcoin` binary does from its command line; its parts, such as the chain, the mempool,
+//! the UTXO set, the miner and the P2P network, are usable on their own from their modules.
+
+#[cfg(test)]
+#[macro_use]
+extern crate hex_literal;
+
+pub mod amount;
+pub mod api;
+pub mod bench;
+pub mod block;
+pub mod blockchain;
+pub mod chainparams;
+pub mod chainstate;
+pub mod clock;
+pub mod consistency;
+pub mod crypto;
+pub mod forks;
+pub mod locks;
+pub mod logging;
+pub mod memory;
+pub mod miner;
+pub mod network;
+pub mod node;
+pub mod policy;
+pub mod pool;
+pub mod psbt;
+#[cfg(test)]
+mod proptests;
+pub mod sharded;
+pub mod storage;
+#[cfg(test)]
+mod testvectors;
+pub mod transaction;
+pub mod validation;
+pub mod verify;
+pub mod wallet;
+
+pub use crate::blockchain::Blockchain;
+pub use crate::node::{Builder as NodeBuilder, Node};
+pub use crate::transaction::{Mempool, State};
@@ -0,0 +1,258 @@
+//! Mutexes for the shared state of the node, which must always be taken in the same order:
+//! chain, orphan buffer, scheduler, mempool, state, wallet. Two threads taking a pair of them in
+//! opposite orders deadlock the first time they race, so debug builds track the locks each
+//! thread holds and panic as soon as one is taken out of order. All builds record how long each
+//! lock is waited for and held, reported by `/stats/locks`.
+
+use serde::Serialize;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+/// The position of a lock in the lock order, earlier ranks first.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Rank {
+    Chain,
+    OrphanBuffer,
+    Scheduler,
+    Mempool,
+    State,
+    Wallet,
+}
+
+const RANKS: [Rank; 6] = [Rank::Chain, Rank::OrphanBuffer, Rank::Scheduler, Rank::Mempool, Rank::State, Rank::Wallet];
+
+impl std::fmt::Display for Rank {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Rank::Chain => "chain",
+            Rank::OrphanBuffer => "orphan buffer",
+            Rank::Scheduler => "scheduler",
+            Rank::Mempool => "mempool",
+            Rank::State => "state",
+            Rank::Wallet => "wallet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Values shared behind an `OrderedMutex`, each with its place in the lock order.
+pub trait Ranked {
+    const RANK: Rank;
+}
+
+impl Ranked for crate::blockchain::Blockchain {
+    const RANK: Rank = Rank::Chain;
+}
+
+/// The orphan buffer: blocks waiting for their parent, by parent hash
+impl Ranked for std::collections::HashMap<crate::crypto::hash::H256, crate::block::Block> {
+    const RANK: Rank = Rank::OrphanBuffer;
+}
+
+impl Ranked for crate::network::download::Scheduler {
+    const RANK: Rank = Rank::Scheduler;
+}
+
+impl Ranked for crate::transaction::Mempool {
+    const RANK: Rank = Rank::Mempool;
+}
+
+impl Ranked for crate::transaction::State {
+    const RANK: Rank = Rank::State;
+}
+
+impl Ranked for crate::wallet::Wallet {
+    const RANK: Rank = Rank::Wallet;
+}
+
+struct Counters {
+    acquisitions: AtomicU64,
+    wait_nanos: AtomicU64,
+    hold_nanos: AtomicU64,
+    max_hold_nanos: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: Counters = Counters {
+    acquisitions: AtomicU64::new(0),
+    wait_nanos: AtomicU64::new(0),
+    hold_nanos: AtomicU64::new(0),
+    max_hold_nanos: AtomicU64::new(0),
+};
+
+/// Counters of every rank, indexed by rank
+static COUNTERS: [Counters; 6] = [ZERO; 6];
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// Ranks of the locks the thread holds, in the order they were taken
+    static HELD: std::cell::RefCell<Vec<Rank>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Record that the thread takes a lock, panicking if it holds one of a later rank.
+#[cfg(debug_assertions)]
+fn acquire(rank: Rank) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(later) = held.iter().find(|held| **held > rank) {
+            let order: Vec<String> = held.iter().map(|rank| rank.to_string()).collect();
+            panic!("lock order violation: taking the {} lock while holding the {} lock (held: {})", rank, later, order.join(", "));
+        }
+        held.push(rank);
+    });
+}
+
+#[cfg(debug_assertions)]
+fn release(rank: Rank) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        // guards are not always dropped in the reverse order they were taken
+        if let Some(index) = held.iter().rposition(|held| *held == rank) {
+            held.remove(index);
+        }
+    });
+}
+
+#[cfg(not(debug_assertions))]
+fn acquire(_rank: Rank) {}
+
+#[cfg(not(debug_assertions))]
+fn release(_rank: Rank) {}
+
+/// A mutex taking its place in the lock order from the type of its value. Mirrors the parts of
+/// `std::sync::Mutex` the node uses.
+pub struct OrderedMutex<T: Ranked> {
+    inner: Mutex<T>,
+}
+
+impl<T: Ranked> OrderedMutex<T> {
+    pub fn new(value: T) -> Self {
+        OrderedMutex { inner: Mutex::new(value) }
+    }
+
+    /// Take the lock, blocking until it is available. Panics in debug builds if the thread
+    /// holds a lock of a later rank.
+    pub fn lock(&self) -> LockResult<OrderedGuard<'_, T>> {
+        acquire(T::RANK);
+        let started = Instant::now();
+        let (guard, poisoned) = match self.inner.lock() {
+            Ok(guard) => (guard, false),
+            Err(e) => (e.into_inner(), true),
+        };
+        let acquired = Instant::now();
+        let counters = &COUNTERS[T::RANK as usize];
+        counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+        counters.wait_nanos.fetch_add(nanos(acquired - started), Ordering::Relaxed);
+        let guard = OrderedGuard { guard: guard, acquired: acquired };
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+}
+
+/// Access to the value of an `OrderedMutex`, recording the hold time when dropped.
+pub struct OrderedGuard<'a, T: Ranked> {
+    guard: MutexGuard<'a, T>,
+    acquired: Instant,
+}
+
+impl<T: Ranked> Deref for OrderedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: Ranked> DerefMut for OrderedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: Ranked> Drop for OrderedGuard<'_, T> {
+    fn drop(&mut self) {
+        let held = nanos(self.acquired.elapsed());
+        let counters = &COUNTERS[T::RANK as usize];
+        counters.hold_nanos.fetch_add(held, Ordering::Relaxed);
+        counters.max_hold_nanos.fetch_max(held, Ordering::Relaxed);
+        release(T::RANK);
+    }
+}
+
+fn nanos(duration: std::time::Duration) -> u64 {
+    duration.as_nanos() as u64
+}
+
+/// Contention of the locks of a rank since the node started.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockStats {
+    pub lock: Rank,
+    pub acquisitions: u64,
+    /// Microseconds spent waiting for the lock
+    pub total_wait_us: u64,
+    /// Microseconds the lock was held
+    pub total_hold_us: u64,
+    /// Longest the lock was held at once, in microseconds
+    pub max_hold_us: u64,
+}
+
+/// Statistics of every rank, in lock order.
+pub fn stats() -> Vec<LockStats> {
+    RANKS
+        .iter()
+        .map(|rank| {
+            let counters = &COUNTERS[*rank as usize];
+            LockStats {
+                lock: *rank,
+                acquisitions: counters.acquisitions.load(Ordering::Relaxed),
+                total_wait_us: counters.wait_nanos.load(Ordering::Relaxed) / 1000,
+                total_hold_us: counters.hold_nanos.load(Ordering::Relaxed) / 1000,
+                max_hold_us: counters.max_hold_nanos.load(Ordering::Relaxed) / 1000,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::transaction::{Mempool, State};
+    use std::thread;
+
+    #[test]
+    fn lock_order() {
+        let chain = OrderedMutex::new(Blockchain::new());
+        let mempool = OrderedMutex::new(Mempool::new());
+        let state = OrderedMutex::new(State::default());
+        {
+            let chain = chain.lock().unwrap();
+            // guards may be dropped in any order
+            let mempool = mempool.lock().unwrap();
+            let _state = state.lock().unwrap();
+            drop(mempool);
+            assert_eq!(chain.height(), 0);
+        }
+        let mempool = mempool.lock().unwrap();
+        drop(mempool);
+        assert!(stats()[Rank::Mempool as usize].acquisitions >= 2);
+
+        // another thread, as the lock order is tracked per thread
+        let violation = thread::spawn(move || {
+            let _state = state.lock().unwrap();
+            let _chain = chain.lock().unwrap();
+        })
+        .join();
+        assert_eq!(violation.is_err(), cfg!(debug_assertions));
+    }
+}
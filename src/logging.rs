@@ -0,0 +1,111 @@
+//! The logger of the binary: messages go to stderr when their level is enabled for their target,
+//! the module that logged them, or else for the closest enclosing module with a level of its own,
+//! or else by default. Targets are module paths without the crate name, such as
+//! `network::worker`, or the names of dependencies, such as `tiny_http`. Levels can be changed on
+//! a running node through `/admin/loglevel`.
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::RwLock;
+
+/// The levels of the targets, and the level of the others.
+struct Filter {
+    default: LevelFilter,
+    targets: BTreeMap<String, LevelFilter>,
+}
+
+impl Filter {
+    const fn new() -> Self {
+        Filter { default: LevelFilter::Error, targets: BTreeMap::new() }
+    }
+
+    /// The level of a record target, a module path, set for it or for the closest of its parents.
+    fn level(&self, target: &str) -> LevelFilter {
+        let mut path = target.strip_prefix("bitcoin::").unwrap_or(target);
+        loop {
+            if let Some(level) = self.targets.get(path) {
+                return *level;
+            }
+            match path.rfind("::") {
+                Some(end) => path = &path[..end],
+                None => return self.default,
+            }
+        }
+    }
+
+    /// The most verbose level of any target, past which records need not be built at all.
+    fn max_level(&self) -> LevelFilter {
+        self.targets.values().cloned().fold(self.default, Ord::max)
+    }
+}
+
+struct Logger {
+    filter: RwLock<Filter>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.read().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(std::io::stderr(), "{} - {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+static LOGGER: Logger = Logger { filter: RwLock::new(Filter::new()) };
+
+/// Install the logger, logging errors by default, then warnings, information, debug and trace
+/// messages with each increase of the verbosity.
+pub fn init(verbosity: usize) -> Result<(), SetLoggerError> {
+    let level = match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    log::set_logger(&LOGGER)?;
+    set_level(None, level);
+    Ok(())
+}
+
+/// Set the level of a target and the modules in it, or the default level without target.
+pub fn set_level(target: Option<&str>, level: LevelFilter) {
+    let mut filter = LOGGER.filter.write().unwrap();
+    match target {
+        Some(target) => {
+            filter.targets.insert(target.to_string(), level);
+        }
+        None => filter.default = level,
+    }
+    log::set_max_level(filter.max_level());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_target() {
+        let mut filter = Filter::new();
+        filter.targets.insert("network".to_string(), LevelFilter::Info);
+        filter.targets.insert("network::worker".to_string(), LevelFilter::Debug);
+        assert_eq!(filter.level("bitcoin::network::worker::handlers"), LevelFilter::Debug);
+        assert_eq!(filter.level("bitcoin::network::server"), LevelFilter::Info);
+        // a prefix of the name is not a parent
+        assert_eq!(filter.level("bitcoin::networking"), LevelFilter::Error);
+        assert_eq!(filter.level("bitcoin::miner"), LevelFilter::Error);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+        filter.targets.insert("tiny_http".to_string(), LevelFilter::Trace);
+        assert_eq!(filter.level("tiny_http::util"), LevelFilter::Trace);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+}
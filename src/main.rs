@@ -1,31 +1,25 @@
-#[cfg(test)]
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod block;
-pub mod blockchain;
-pub mod crypto;
-pub mod miner;
-pub mod network;
-pub mod transaction;
-
+use bitcoin::{bench, blockchain, chainparams, consistency, logging, policy, verify, wallet};
+use bitcoin::amount::Amount;
+use bitcoin::chainparams::Network;
+use bitcoin::clock::SystemClock;
+use bitcoin::crypto::hash::H160;
+use bitcoin::network::addresses::Family;
+use bitcoin::network::identity::{Identity, NodeId};
+use bitcoin::network::server;
+use bitcoin::node::Node;
+use bitcoin::storage::{self, BlockFiles};
+use bitcoin::transaction::State;
 use clap::clap_app;
-use crossbeam::channel;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use log::{error, info};
-use api::Server as ApiServer;
-use network::{server, worker};
 use std::net;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::process;
-use std::thread;
 use std::time;
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use ring::digest;
-use ring::signature::{self, Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
-use crypto::hash::{H160, H256, Hashable};
-use network::message::Message;
-use transaction::{TxIn, TxOut, Transaction, SignedTransaction, State};
 
 fn main() {
     // parse command line arguments
@@ -33,45 +27,251 @@ fn main() {
      (version: "0.1")
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg network: --network [NAME] default_value("mainnet") "Sets the network: mainnet, testnet, or regtest, whose blocks are mined instantly, for integration tests")
+     (@arg peer_addr: --p2p ... [ADDR] "Sets the IP addresses and the ports the P2P server listens at, IPv4 or IPv6 [default: 127.0.0.1 at the network's P2P port, 6000 on mainnet]")
+     (@arg api_addr: --api [ADDR] "Sets the IP address and the port of the API server [default: 127.0.0.1 at the network's API port, 7000 on mainnet]")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start, as addresses or host names with a port")
+     (@arg prefer_family: --("prefer-family") [FAMILY] "Sets the address family, ipv4 or ipv6, tried first when a peer has addresses of both")
+     (@arg proxy: --proxy [ADDR] "Sets the SOCKS5 proxy, such as Tor, that outbound peer connections go through")
+     (@arg no_direct: --("no-direct") requires[proxy] "Never reaches peers without the proxy: failed proxy connections are not retried directly, and incoming connections are not accepted")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg identity: --identity [FILE] default_value("node.key") "Sets the file holding the node identity key, created if missing")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where received blocks are archived, and loaded from at start")
+     (@arg sign_announcements: --("sign-announcements") "Signs the announcements of blocks and transactions created by this node")
+     (@arg min_relay_fee_rate: --("min-relay-fee-rate") [FEE] "Sets the minimum fee per 1000 bytes of the transactions relayed and created by the wallet")
+     (@arg dust_limit: --("dust-limit") [VALUE] "Sets the smallest output value of the transactions relayed and created by the wallet")
+     (@arg coin_selection: --("coin-selection") [STRATEGY] default_value("largest-first") "Sets how the wallet picks the coins it spends: largest-first, branch-and-bound or random-improve")
+     (@arg external_signer: --("external-signer") [SIGNER] "Adds to the wallet a key held outside the node, asked for signatures at an http:// URL or by running a command")
+     (@arg mining_address: --("mining-address") [ADDRESS] "Sets the address the mined blocks pay their rewards to, the first key generated by the wallet, saved in --data-dir, by default")
+     (@arg pool: --pool "Hands out mining jobs to the peers asking for them, connecting the blocks their shares complete")
+     (@arg join_pool: --("join-pool") [ADDR] "Mines the jobs of the pool node at this address instead of blocks of our own")
+     (@arg maturity: --maturity [BLOCKS] default_value("1") "Sets the confirmations an output needs before the wallet spends it")
+     (@arg finality_depth: --("finality-depth") [BLOCKS] default_value("6") "Sets the confirmations after which the API reports transactions as final")
+     (@arg own_tx_quota: --("own-tx-quota") [BYTES] default_value("0") "Sets the bytes of each mined block reserved for the wallet's own transactions, ahead of fee ordering")
+     (@arg template_refresh: --("template-refresh") [MS] default_value("1000") "Sets how often, in milliseconds, the miner checks the mempool for transactions paying better than the block it is mining")
+     (@arg checkpoint_authority: --("checkpoint-authority") [NODE_ID] "Trusts the checkpoints signed by this node, refusing reorgs past them; the node with this id signs its tip as a checkpoint")
+     (@arg checkpoint_interval: --("checkpoint-interval") [SECS] default_value("30") "Sets how often, in seconds, the checkpoint authority signs its tip")
+     (@arg min_peers: --("min-peers") [INT] default_value("1") "Sets the number of peers required for /readyz to report the node ready")
+     (@arg max_memory: --("max-memory") [MB] "Caps the memory of the mempool, the orphan pools and the block cache together, evicting their least valuable entries beyond it")
+     (@arg deterministic: --deterministic [SEED] "Seeds the miner nonces and the wallet's coin selection so that runs are reproducible; give each node its own seed")
+     (@subcommand reindex =>
+      (about: "Rebuilds the block index from the block files in --data-dir, and replays them to check the chain")
+     )
+     (@subcommand export_blocks =>
+      (name: "export-blocks")
+      (about: "Writes the longest chain of the blocks in --data-dir to a file, in height order, to be imported by other nodes")
+      (@arg file: <FILE> "Sets the file to write")
+     )
+     (@subcommand import_blocks =>
+      (name: "import-blocks")
+      (about: "Validates the blocks of a file written by export-blocks and stores them in --data-dir")
+      (@arg file: <FILE> "Sets the file to read")
+     )
+     (@subcommand verify_chain =>
+      (name: "verify-chain")
+      (about: "Rolls the chain in --data-dir back over its last blocks with the stored undo data, then validates and applies them again, reporting where the block files, indices and UTXO set disagree")
+      (@arg depth: --depth [N] default_value("6") "Sets the number of blocks below the tip to check")
+     )
+     (@subcommand check_consistency =>
+      (name: "check-consistency")
+      (about: "Compares the longest chains and UTXO sets of running nodes through their API servers")
+      (@arg peers: --peers <ADDRS> "Sets the comma-separated API addresses of the nodes to compare")
+      (@arg samples: --samples [INT] default_value("10") "Sets the number of heights at which to compare blocks")
+     )
+     (@subcommand bench =>
+      (about: "Benchmarks the node's data structures against synthetic data")
+      (@subcommand mempool =>
+       (about: "Times mempool insertion, block template selection, revalidation and removal")
+       (@arg transactions: --transactions [N] default_value("10000") "Sets the number of synthetic transactions")
+      )
+      (@subcommand contention =>
+       (about: "Compares mempool lookups from several threads through the mempool lock and through the shared transaction pool")
+       (@arg transactions: --transactions [N] default_value("1000") "Sets the number of synthetic transactions looked up, and churned by a writer")
+       (@arg readers: --readers [N] default_value("4") "Sets the number of reader threads")
+       (@arg lookups: --lookups [N] default_value("100000") "Sets the number of lookups of each reader")
+      )
+      (@subcommand blocks =>
+       (about: "Times how long serving recent blocks to peers holds the chain lock, and the block cache lock instead")
+       (@arg blocks: --blocks [N] default_value("1000") "Sets the number of synthetic blocks")
+       (@arg requests: --requests [N] default_value("10000") "Sets the number of block requests")
+      )
+     )
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
-    stderrlog::new().verbosity(verbosity).init().unwrap();
+    logging::init(verbosity).unwrap();
 
-    // parse p2p server address
-    let p2p_addr = matches
-        .value_of("peer_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P server address: {}", e);
+    // select the network before anything parses addresses or creates a chain
+    let network = matches.value_of("network").unwrap().parse::<Network>().unwrap_or_else(|e| {
+        error!("Error parsing network: {}", e);
+        process::exit(1);
+    });
+    chainparams::select(network);
+    let params = chainparams::current();
+    if network != Network::Mainnet {
+        info!("Running on {}", network);
+    }
+
+    if matches.subcommand_matches("reindex").is_some() {
+        let data_dir = matches.value_of("data_dir").unwrap_or_else(|| {
+            error!("Reindexing requires --data-dir");
+            process::exit(1);
+        });
+        reindex(Path::new(data_dir));
+        return;
+    }
+
+    for (name, export) in &[("export-blocks", true), ("import-blocks", false)] {
+        if let Some(sub_matches) = matches.subcommand_matches(name) {
+            let data_dir = matches.value_of("data_dir").unwrap_or_else(|| {
+                error!("{} requires --data-dir", name);
+                process::exit(1);
+            });
+            let file = Path::new(sub_matches.value_of("file").unwrap());
+            if *export {
+                export_blocks(Path::new(data_dir), file);
+            } else {
+                import_blocks(Path::new(data_dir), file);
+            }
+            return;
+        }
+    }
+
+    if let Some(sub_matches) = matches.subcommand_matches("verify-chain") {
+        let data_dir = matches.value_of("data_dir").unwrap_or_else(|| {
+            error!("verify-chain requires --data-dir");
             process::exit(1);
         });
+        let depth = sub_matches
+            .value_of("depth")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing depth: {}", e);
+                process::exit(1);
+            });
+        verify_chain(Path::new(data_dir), depth);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        if let Some(matches) = matches.subcommand_matches("mempool") {
+            let transactions = matches
+                .value_of("transactions")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap_or_else(|e| {
+                    error!("Error parsing transactions: {}", e);
+                    process::exit(1);
+                });
+            println!("{}", bench::mempool(transactions));
+        } else if let Some(matches) = matches.subcommand_matches("contention") {
+            let parse = |name: &str| {
+                matches.value_of(name).unwrap().parse::<usize>().unwrap_or_else(|e| {
+                    error!("Error parsing {}: {}", name, e);
+                    process::exit(1);
+                })
+            };
+            println!("{}", bench::mempool_contention(parse("transactions"), parse("readers"), parse("lookups")));
+        } else if let Some(matches) = matches.subcommand_matches("blocks") {
+            let parse = |name: &str| {
+                matches.value_of(name).unwrap().parse::<usize>().unwrap_or_else(|e| {
+                    error!("Error parsing {}: {}", name, e);
+                    process::exit(1);
+                })
+            };
+            println!("{}", bench::block_serving(parse("blocks"), parse("requests")));
+        } else {
+            error!("Missing benchmark, try bench mempool, bench contention or bench blocks");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check-consistency") {
+        let peers: Vec<net::SocketAddr> = matches
+            .value_of("peers")
+            .unwrap()
+            .split(',')
+            .map(|addr| addr.trim().parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                error!("Error parsing node address {}: {}", addr, e);
+                process::exit(1);
+            }))
+            .collect();
+        let samples = matches
+            .value_of("samples")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing samples: {}", e);
+                process::exit(1);
+            });
+        let report = consistency::check(&peers, samples);
+        println!("{}", report);
+        if !report.is_consistent() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // parse p2p server addresses
+    let p2p_addrs: Vec<net::SocketAddr> = match matches.values_of("peer_addr") {
+        Some(addrs) => addrs
+            .map(|addr| {
+                addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                    error!("Error parsing P2P server address {}: {}", addr, e);
+                    process::exit(1);
+                })
+            })
+            .collect(),
+        None => vec![net::SocketAddr::from(([127, 0, 0, 1], params.default_p2p_port))],
+    };
+
+    // parse the address family preference
+    let prefer_family = matches.value_of("prefer_family").map(|family| {
+        family.parse::<Family>().unwrap_or_else(|e| {
+            error!("Error parsing address family: {}", e);
+            process::exit(1);
+        })
+    });
+
+    // parse the outbound connection settings
+    let outbound = server::Outbound {
+        proxy: matches.value_of("proxy").map(|proxy| {
+            proxy.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                error!("Error parsing proxy address: {}", e);
+                process::exit(1);
+            })
+        }),
+        direct: !matches.is_present("no_direct"),
+    };
 
     // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
+    let api_addr = match matches.value_of("api_addr") {
+        Some(addr) => addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
             error!("Error parsing API server address: {}", e);
             process::exit(1);
-        });
+        }),
+        None => net::SocketAddr::from(([127, 0, 0, 1], params.default_api_port)),
+    };
 
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::unbounded();
+    // parse the seed of deterministic mode
+    let seed = matches.value_of("deterministic").map(|seed| {
+        seed.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing seed: {}", e);
+            process::exit(1);
+        })
+    });
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    // load the node identity
+    let identity_path = matches.value_of("identity").unwrap();
+    let identity = Identity::load_or_generate(Path::new(identity_path)).unwrap_or_else(|e| {
+        error!("Error loading node identity from {}: {}", identity_path, e);
+        process::exit(1);
+    });
 
-    // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -80,139 +280,237 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+    let coin_selection = matches
+        .value_of("coin_selection")
+        .unwrap()
+        .parse::<wallet::CoinSelection>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing coin selection: {}", e);
+            process::exit(1);
+        });
+    let maturity = matches
+        .value_of("maturity")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing maturity: {}", e);
+            process::exit(1);
+        });
+    let finality_depth = matches
+        .value_of("finality_depth")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing finality depth: {}", e);
+            process::exit(1);
+        });
+    let mut relay_policy = policy::Policy::default();
+    if let Some(rate) = matches.value_of("min_relay_fee_rate") {
+        relay_policy.min_fee_rate = rate.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing minimum relay fee rate: {}", e);
+            process::exit(1);
+        });
+    }
+    if let Some(limit) = matches.value_of("dust_limit") {
+        relay_policy.dust_limit = limit.parse::<Amount>().unwrap_or_else(|e| {
+            error!("Error parsing dust limit: {}", e);
+            process::exit(1);
+        });
+    }
 
-    let the_chain = blockchain::Blockchain::new();
-    let chain_lock = Arc::new(Mutex::new(the_chain));
-    let buffer = HashMap::new();
-    let buffer_lock = Arc::new(Mutex::new(buffer));
-    let the_mempool = transaction::Mempool::new();
-    let mempool_lock = Arc::new(Mutex::new(the_mempool));
-    let the_state = State::new();
-    let state_lock = Arc::new(Mutex::new(the_state));
-
-    let worker_ctx = worker::new(
-        p2p_workers,
-        msg_rx,
-        &server,
-        &chain_lock,
-        &buffer_lock,
-        &mempool_lock,
-        &state_lock,
-    );
-    worker_ctx.start();
-
-    let server_ = server.clone();
-    let mempool_lock_ = mempool_lock.clone();
-    thread::spawn(move || {
-        loop {
-            // use rand::Rng;
-            // let mut rng = rand::thread_rng();
-            // use crate::crypto::key_pair;
-
-            thread::sleep(time::Duration::from_millis(10000));
-            // let mut map_key = 0;
-            // let mut map_val = 0;
-            // for key in state_lock.utxo.keys() {
-            //     map_key = key.clone();
-            //     val = state_lock.utxo[&key].clone();
-            //     break;
-            // }
-
-            // let key = key_pair::random();
-            // let public_key = key.public_key();
-            // let pk_hash: H256 = digest::digest(&digest::SHA256, public_key.as_ref()).into();
-            // let recipient: H160 = pk_hash.to_addr().into();
-            // let value: u64 = map_val.0;
-            // let tx_out = TxOut { recipient: recipient, value: value };
-
-            // let previous_output: H256 = map_key.0;
-            // let index: u8 = map_key.1;
-            // let tx_in = TxIn { previous_output: previous_output, index: index };
-
-            let seed = [255u8; 32];
-            let key = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
-            let public_key = key.public_key();
-            let pk_hash: H256 = digest::digest(&digest::SHA256, public_key.as_ref()).into();
-            let recipient: H160 = pk_hash.to_addr().into();
-            let value: u64 = 10000;
-            let tx_out = TxOut { recipient: recipient, value: value };
-
-            let previous_output: H256 = [0u8; 32].into();
-            let index: u8 = 0;
-            let tx_in = TxIn { previous_output: previous_output, index: index };
-
-            let inputs = vec![tx_in];
-            let outputs = vec![tx_out];
-            let tx = Transaction { input: inputs, output: outputs };
-            let seed_sender = [0u8; 32];
-            let key_sender = Ed25519KeyPair::from_seed_unchecked(&seed_sender).unwrap();
-            let pk_sender = key_sender.public_key();
-            let m = bincode::serialize(&tx).unwrap();
-            let txid = digest::digest(&digest::SHA256, digest::digest(&digest::SHA256, m.as_ref()).as_ref());
-            let sig = key_sender.sign(txid.as_ref());
-            let signed_tx = SignedTransaction { transaction: tx, public_key: pk_sender.as_ref().to_vec(), signature: sig.as_ref().to_vec() };
-
-            let mut mempool_un = mempool_lock_.lock().unwrap();
-            mempool_un.insert(&signed_tx);
-            let mut hash: H256 = signed_tx.hash();
-            let pk_sender_hash: H256 = digest::digest(&digest::SHA256, pk_sender.as_ref()).into();
-            let sender: H160 = pk_sender_hash.to_addr().into();
-            println!("New transaction generated. Sending from {:?} to {:?}.", sender, recipient);
-            server_.broadcast(Message::NewTransactionHashes(vec![hash]));
-        }
+    // federated checkpoints
+    let checkpoint_authority = matches.value_of("checkpoint_authority").map(|authority| {
+        authority.parse::<NodeId>().unwrap_or_else(|e| {
+            error!("Error parsing checkpoint authority: {}", e);
+            process::exit(1);
+        })
     });
+    let checkpoint_interval = matches
+        .value_of("checkpoint_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing checkpoint interval: {}", e);
+            process::exit(1);
+        });
 
-    // start the miner
-    let (miner_ctx, miner) = miner::new(
-        &server,
-        &chain_lock,
-        &mempool_lock,
-        &state_lock,
-    );
-    miner_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
+    let own_tx_quota = matches
+        .value_of("own_tx_quota")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing own transaction quota: {}", e);
+            process::exit(1);
         });
+    let template_refresh = matches
+        .value_of("template_refresh")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing template refresh interval: {}", e);
+            process::exit(1);
+        });
+    let known_peers: Vec<String> = matches
+        .values_of("known_peer")
+        .map(|known_peers| known_peers.map(|x| x.to_owned()).collect())
+        .unwrap_or_default();
+    let min_peers = matches
+        .value_of("min_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing minimum peers: {}", e);
+            process::exit(1);
+        });
+    let mining_address = matches.value_of("mining_address").map(|address| {
+        address.parse::<H160>().unwrap_or_else(|e| {
+            error!("Error parsing mining address: {}", e);
+            process::exit(1);
+        })
+    });
+    // the key of a new address would be lost when the node stops
+    if mining_address.is_none() && seed.is_none() && !matches.is_present("data_dir") {
+        error!("--mining-address is required without --data-dir or --deterministic");
+        process::exit(1);
     }
+    let join_pool = matches.value_of("join_pool").map(|addr| {
+        addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing pool address: {}", e);
+            process::exit(1);
+        })
+    });
+    let max_memory = matches.value_of("max_memory").map(|mb| {
+        mb.parse::<usize>().map(|mb| mb * 1024 * 1024).unwrap_or_else(|e| {
+            error!("Error parsing memory budget: {}", e);
+            process::exit(1);
+        })
+    });
 
+    let mut builder = Node::builder()
+        .p2p(&p2p_addrs)
+        .api(Some(api_addr))
+        .connect(&known_peers)
+        .prefer_family(prefer_family)
+        .outbound(outbound)
+        .p2p_workers(p2p_workers)
+        .identity(identity)
+        .sign_announcements(matches.is_present("sign_announcements"))
+        .policy(relay_policy)
+        .coin_selection(coin_selection)
+        .external_signer(matches.value_of("external_signer"))
+        .mining_address(mining_address)
+        .pool(matches.is_present("pool"))
+        .join_pool(join_pool)
+        .maturity(maturity)
+        .finality_depth(finality_depth)
+        .own_tx_quota(own_tx_quota)
+        .template_refresh(time::Duration::from_millis(template_refresh))
+        .checkpoint_authority(checkpoint_authority, time::Duration::from_secs(checkpoint_interval))
+        .min_peers(min_peers)
+        .max_memory(max_memory)
+        .demo_transactions(true);
+    if let Some(seed) = seed {
+        builder = builder.rng(StdRng::seed_from_u64(seed));
+    }
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        builder = builder.data_dir(Path::new(data_dir));
+    }
+    let node = builder.start().unwrap_or_else(|e| {
+        error!("Error starting the node: {}", e);
+        process::exit(1);
+    });
+    node.run();
+}
 
-    // start the API server
-    ApiServer::start(
-        api_addr,
-        &miner,
-        &server,
+fn open_block_files(data_dir: &Path) -> BlockFiles {
+    BlockFiles::open(&data_dir.join("blocks")).unwrap_or_else(|e| {
+        error!("Error opening block files in {}: {}", data_dir.display(), e);
+        process::exit(1);
+    })
+}
+
+/// Rebuild the block index from the block files, then rebuild the chain, the transaction index
+/// and the UTXO set from scratch by replaying every stored block through validation, repairing
+/// the stored undo data along the way.
+fn reindex(data_dir: &Path) {
+    let mut block_files = open_block_files(data_dir);
+    let count = block_files.reindex().unwrap_or_else(|e| {
+        error!("Error scanning block files: {}", e);
+        process::exit(1);
+    });
+    info!("Found {} blocks in {}", count, data_dir.display());
+    let mut chain = blockchain::Blockchain::new();
+    let mut state = State::new();
+    let stats = storage::replay(&mut block_files, &mut chain, &mut state, &SystemClock).unwrap_or_else(|e| {
+        error!("Error replaying block files: {}", e);
+        process::exit(1);
+    });
+    println!(
+        "Reindexed {} blocks: {} connected, {} invalid, {} orphaned, {} undo records rewritten. Tip {} at height {}, {} unspent outputs.",
+        count, stats.connected, stats.invalid, stats.orphaned, stats.undo_rewritten, chain.tip(), chain.height(), state.utxo.len()
     );
+}
+
+/// Load the chain stored in the data directory, exiting on failure.
+fn load_chain(block_files: &mut BlockFiles, data_dir: &Path) -> (blockchain::Blockchain, State) {
+    let mut chain = blockchain::Blockchain::new();
+    let mut state = State::new();
+    let stats = storage::replay(block_files, &mut chain, &mut state, &SystemClock).unwrap_or_else(|e| {
+        error!("Error loading blocks from {}, try reindexing: {}", data_dir.display(), e);
+        process::exit(1);
+    });
+    info!("Loaded {} blocks from {}, height {}", stats.connected, data_dir.display(), chain.height());
+    (chain, state)
+}
 
-    loop {
-        std::thread::park();
+/// Re-validate the last blocks stored in the data directory, exiting with an error status if
+/// anything is inconsistent.
+fn verify_chain(data_dir: &Path, depth: usize) {
+    let block_files = open_block_files(data_dir);
+    let mut chain = blockchain::Blockchain::new();
+    let mut state = State::new();
+    // loading without replay, which would rewrite the undo data being checked
+    let stats = storage::load(&block_files, &mut chain, &mut state, &SystemClock).unwrap_or_else(|e| {
+        error!("Error loading blocks from {}, try reindexing: {}", data_dir.display(), e);
+        process::exit(1);
+    });
+    info!("Loaded {} blocks from {}, height {}", stats.connected, data_dir.display(), chain.height());
+    let report = verify::verify_chain(&block_files, &chain, &state, &SystemClock, depth);
+    println!("{}", report);
+    if !report.is_consistent() {
+        process::exit(1);
     }
 }
+
+/// Write the longest chain stored in the data directory to a file.
+fn export_blocks(data_dir: &Path, file: &Path) {
+    let mut block_files = open_block_files(data_dir);
+    let (chain, _) = load_chain(&mut block_files, data_dir);
+    let written = fs::File::create(file)
+        .and_then(|f| storage::export_chain(&chain, &mut io::BufWriter::new(f)))
+        .unwrap_or_else(|e| {
+            error!("Error exporting blocks to {}: {}", file.display(), e);
+            process::exit(1);
+        });
+    println!("Exported {} blocks to {}. Tip {} at height {}.", written, file.display(), chain.tip(), chain.height());
+}
+
+/// Validate the blocks of an exported file on top of the chain stored in the data directory,
+/// storing the new ones.
+fn import_blocks(data_dir: &Path, file: &Path) {
+    let mut block_files = open_block_files(data_dir);
+    let (mut chain, mut state) = load_chain(&mut block_files, data_dir);
+    let block_files = Arc::new(Mutex::new(block_files));
+    chain.set_undo_store(block_files.clone());
+    chain.subscribe(block_files);
+    let stats = fs::File::open(file)
+        .and_then(|f| storage::import_chain(&mut io::BufReader::new(f), &mut chain, &mut state, &SystemClock))
+        .unwrap_or_else(|e| {
+            error!("Error importing blocks from {}: {}", file.display(), e);
+            process::exit(1);
+        });
+    println!(
+        "Imported {} blocks from {}, {} already known. Tip {} at height {}.",
+        stats.imported, file.display(), stats.known, chain.tip(), chain.height()
+    );
+}
@@ -0,0 +1,145 @@
+//! Accounting of the memory held by the pools and caches of a node against one budget, so that
+//! a long run on a small machine evicts entries instead of running out of memory. Each pool
+//! reports the bytes its entries take, counted as their serialized size, and on insertion evicts
+//! its least valuable entries while the budget is exceeded. A pool only evicts while it holds
+//! more than an even share of the budget, so that a growing cache does not drain the mempool.
+//! Reported by `/stats/memory`.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A pool of entries accounted against the budget.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pool {
+    Mempool,
+    OrphanTransactions,
+    OrphanBlocks,
+    BlockCache,
+}
+
+const POOLS: [Pool; 4] = [Pool::Mempool, Pool::OrphanTransactions, Pool::OrphanBlocks, Pool::BlockCache];
+
+#[derive(Default)]
+struct Usage {
+    bytes: AtomicUsize,
+    evicted: AtomicUsize,
+}
+
+/// The bytes held by each pool, and the most all of them may hold together.
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    usage: [Usage; 4],
+}
+
+impl MemoryBudget {
+    /// Account without ever evicting.
+    pub fn unlimited() -> Self {
+        MemoryBudget { limit: None, usage: Default::default() }
+    }
+
+    /// Evict once the pools hold more than `limit` bytes together.
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget { limit: Some(limit), usage: Default::default() }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn add(&self, pool: Pool, bytes: usize) {
+        self.usage[pool as usize].bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, pool: Pool, bytes: usize) {
+        self.usage[pool as usize].bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Count an entry the pool evicted to stay within the budget, besides removing its bytes.
+    pub fn evicted(&self, pool: Pool) {
+        self.usage[pool as usize].evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn usage(&self, pool: Pool) -> usize {
+        self.usage[pool as usize].bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        POOLS.iter().map(|pool| self.usage(*pool)).sum()
+    }
+
+    /// Whether the pool should evict an entry: the pools hold more than the budget together,
+    /// and this one more than its share.
+    pub fn over_budget(&self, pool: Pool) -> bool {
+        match self.limit {
+            Some(limit) => self.total() > limit && self.usage(pool) > limit / POOLS.len(),
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            limit: self.limit,
+            total: self.total(),
+            pools: POOLS
+                .iter()
+                .map(|pool| PoolUsage {
+                    pool: *pool,
+                    bytes: self.usage(*pool),
+                    evicted: self.usage[*pool as usize].evicted.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget::unlimited()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub pool: Pool,
+    pub bytes: usize,
+    /// Entries evicted to stay within the budget since the node started
+    pub evicted: usize,
+}
+
+/// The memory held by the pools, as served by `/stats/memory`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// `None` without budget
+    pub limit: Option<usize>,
+    pub total: usize,
+    pub pools: Vec<PoolUsage>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn even_shares() {
+        let budget = MemoryBudget::new(4000);
+        budget.add(Pool::Mempool, 900);
+        budget.add(Pool::BlockCache, 3000);
+        assert!(!budget.over_budget(Pool::BlockCache));
+        budget.add(Pool::OrphanBlocks, 200);
+        // the block cache holds more than its share, the others have to wait for it
+        assert!(budget.over_budget(Pool::BlockCache));
+        assert!(!budget.over_budget(Pool::Mempool));
+        assert!(!budget.over_budget(Pool::OrphanBlocks));
+        budget.remove(Pool::BlockCache, 1000);
+        budget.evicted(Pool::BlockCache);
+        assert!(!budget.over_budget(Pool::BlockCache));
+        assert_eq!(budget.total(), 3100);
+        let stats = budget.stats();
+        assert_eq!(stats.pools[Pool::BlockCache as usize], PoolUsage { pool: Pool::BlockCache, bytes: 2000, evicted: 1 });
+
+        let unlimited = MemoryBudget::unlimited();
+        unlimited.add(Pool::Mempool, usize::MAX / 2);
+        assert!(!unlimited.over_budget(Pool::Mempool));
+    }
+}
@@ -1,22 +1,55 @@
-use crate::network::server::Handle as ServerHandle;
-use crate::blockchain::Blockchain;
+use crate::amount::Amount;
+use crate::network::server::{self, Handle as ServerHandle};
+use crate::blockchain::{Blockchain, ChainListener};
+use crate::clock::Clock;
+use crate::locks::OrderedMutex;
 use crate::crypto::merkle::MerkleTree;
-use crate::block::{Block, Header, Content};
-use crate::transaction::{Transaction, SignedTransaction, Mempool, State};
+use crate::block::{self, Block, Header, Content};
+use crate::transaction::{Transaction, SignedTransaction, Mempool, State, TxOut};
+use crate::validation;
 
-use log::{info, debug};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use rand::rngs::StdRng;
+use rand::{FromEntropy, Rng, SeedableRng};
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::time;
 
 use std::thread;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::{H160, H256, Hashable};
 use crate::network::message::Message;
+use crate::network::peer;
+use crate::network::validator;
+use crate::pool::{Coordinator, PoolJob, PoolShare, PoolStats};
+use crate::policy::Policy;
+use crate::wallet::Wallet;
+use crate::crypto::hash::Hashed;
+use std::cmp::Reverse;
+use std::net::SocketAddr;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Maximum size of the transactions of a mined block, in serialized bytes.
+pub const BLOCK_SIZE_LIMIT: usize = 2048;
+/// How much more a fresh template must pay in fees, in percent, to replace the current one.
+pub const TEMPLATE_FEE_GAIN_PERCENT: u64 = 10;
+/// How often a pool member checks that it is still connected to its pool.
+const POOL_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(1000);
+/// Misbehavior score of a pool member for an invalid share, see `ShareError::is_invalid`.
+const INVALID_SHARE_PENALTY: u32 = server::BAN_THRESHOLD / 10;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    NewTip(H256), // the longest chain moved, so the current template is stale
+    Generate(usize, H160, Sender<Vec<H256>>), // mine blocks right away, answering with their hashes
+    Preview(Sender<TemplatePreview>), // describe the block that would be mined next, without mining it
+    ServePool, // hand out jobs to the peers asking for work
+    JoinPool(peer::Handle), // mine the jobs of this peer instead of our own blocks
+    PoolMember(peer::Handle), // a peer asked for work
+    PoolWork(SocketAddr, PoolJob), // a job from a pool
+    PoolShare(SocketAddr, PoolShare), // nonces a member found for a job
+    PoolStats(Sender<Option<PoolStats>>),
     Exit,
 }
 
@@ -31,21 +64,169 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     server: ServerHandle,
-    chain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<Mempool>>,
-    state: Arc<Mutex<State>>,
+    chain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<Mempool>>,
+    state: Arc<OrderedMutex<State>>,
+    policy: Policy,
+    wallet: Arc<OrderedMutex<Wallet>>,
+    /// Bytes of each block reserved for transactions signed by the wallet
+    own_tx_quota: usize,
+    /// How often the mempool is checked for transactions paying better than the template
+    template_refresh: time::Duration,
+    /// The block being mined, `None` until the first attempt or after a block is found
+    template: Option<Template>,
+    /// Source of the nonces
+    rng: StdRng,
+    clock: Arc<dyn Clock>,
+    /// Shared with the handles
+    status: Arc<Mutex<Status>>,
+    /// Address the coinbase pays, shared with the handles, see `Handle::set_payout`
+    payout: Arc<Mutex<Option<H160>>>,
+    /// The members of the pool this node serves, if it does, and their connections
+    pool: Option<(Coordinator, HashMap<SocketAddr, peer::Handle>)>,
+    /// The pool this node mines for, if any, and its current job
+    member_of: Option<(peer::Handle, Option<Work>)>,
+    /// When the connection to the pool was last checked, see `check_pool_connection`
+    pool_checked_at: u128,
+}
+
+/// A job of the pool this node mines for, and the nonces to try next.
+struct Work {
+    job: PoolJob,
+    nonce: u32,
+    extra_nonce: u32,
+}
+
+impl Work {
+    fn new(job: PoolJob) -> Self {
+        Work { nonce: 0, extra_nonce: job.extra_nonces.0, job: job }
+    }
+
+    /// Try the next nonce, then the next extra nonce of the range, starting over past its end.
+    fn next(&mut self) {
+        let (nonce, overflow) = self.nonce.overflowing_add(1);
+        self.nonce = nonce;
+        if overflow {
+            self.extra_nonce += 1;
+            if self.extra_nonce == self.job.extra_nonces.1 {
+                self.extra_nonce = self.job.extra_nonces.0;
+            }
+        }
+    }
+}
+
+/// What the miner is doing, as reported by `Handle::status`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum Status {
+    Paused,
+    Running { lambda: u64 },
+    Stopped,
+}
+
+/// A transaction of a block template, as listed by `Handle::preview`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TemplateTransaction {
+    pub txid: H256,
+    pub size: usize,
+    /// `None` for transactions not accepted through `Mempool::accept`
+    pub fee: Option<Amount>,
+    /// Fee per kilobyte
+    pub fee_rate: Option<u64>,
+}
+
+/// The block the miner would mine next on the tip, as shown by `Handle::preview`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TemplatePreview {
+    pub parent: H256,
+    pub height: u64,
+    /// Address the coinbase pays, `None` if none is set, the wallet has none and the block has
+    /// no coinbase
+    pub payout: Option<H160>,
+    /// Subsidy and fees claimed by the coinbase
+    pub reward: Option<Amount>,
+    /// The transactions in block order, coinbase aside
+    pub transactions: Vec<TemplateTransaction>,
+    pub total_fee: Amount,
+    /// Size of the transactions, coinbase aside, out of `BLOCK_SIZE_LIMIT`
+    pub transactions_size: usize,
+    /// Size of the whole block, serialized
+    pub block_size: usize,
+}
+
+/// The parts of the block being mined that stay the same between attempts. Each attempt tries
+/// the next nonce, then the next extra nonce once every nonce has been tried.
+struct Template {
+    parent: H256,
+    height: u64,
+    bits: u32,
+    /// Claims the subsidy and fees for the payout address, `None` if there is none
+    coinbase: Option<Hashed<SignedTransaction>>,
+    transactions: Vec<Hashed<SignedTransaction>>,
+    merkle_root: H256,
+    /// Total fee of the transactions
+    fee: Amount,
+    nonce: u32,
+    extra_nonce: u32,
+    /// When the mempool was last checked for better-paying transactions, in milliseconds
+    checked_at: u128,
+}
+
+impl Template {
+    fn header(&self, timestamp: u128) -> Header {
+        Header {
+            version: block::VERSION,
+            parent: self.parent,
+            height: self.height,
+            nonce: self.nonce,
+            extra_nonce: self.extra_nonce,
+            bits: self.bits,
+            timestamp: timestamp,
+            merkle_root: self.merkle_root,
+        }
+    }
+
+    /// The coinbase, if any, followed by the transactions.
+    fn content(self) -> Content {
+        Content { data: self.coinbase.into_iter().chain(self.transactions).collect() }
+    }
+
+    fn next(&mut self) {
+        let (nonce, overflow) = self.nonce.overflowing_add(1);
+        self.nonce = nonce;
+        if overflow {
+            self.extra_nonce = self.extra_nonce.wrapping_add(1);
+        }
+    }
+}
+
+/// Whether a template paying `candidate_fee` is worth switching to from one paying
+/// `current_fee`, that is whether it pays at least `TEMPLATE_FEE_GAIN_PERCENT` more.
+pub fn is_better_template(current_fee: Amount, candidate_fee: Amount) -> bool {
+    let current_fee = current_fee.as_u64();
+    candidate_fee.as_u64() > current_fee.saturating_add(current_fee.saturating_mul(TEMPLATE_FEE_GAIN_PERCENT) / 100)
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
+    payout: Arc<Mutex<Option<H160>>>,
 }
 
+/// Create the miner. With a seed, the nonces tried are the same on every run. Up to
+/// `own_tx_quota` bytes of each block go to the wallet's own transactions before any other.
+/// Every `template_refresh`, the block being mined is replaced if the mempool now pays
+/// significantly more.
 pub fn new(
-    server: &ServerHandle, blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, state: &Arc<Mutex<State>>,
+    server: &ServerHandle, blockchain: &Arc<OrderedMutex<Blockchain>>, mempool: &Arc<OrderedMutex<Mempool>>, state: &Arc<OrderedMutex<State>>,
+    policy: &Policy, wallet: &Arc<OrderedMutex<Wallet>>, own_tx_quota: usize, template_refresh: time::Duration, seed: Option<u64>,
+    clock: &Arc<dyn Clock>,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let status = Arc::new(Mutex::new(Status::Paused));
+    let payout = Arc::new(Mutex::new(None));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -54,10 +235,27 @@ pub fn new(
         chain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
         state: Arc::clone(state),
+        policy: policy.clone(),
+        wallet: Arc::clone(wallet),
+        own_tx_quota: own_tx_quota,
+        template_refresh: template_refresh,
+        template: None,
+        rng: match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        },
+        clock: Arc::clone(clock),
+        status: Arc::clone(&status),
+        payout: Arc::clone(&payout),
+        pool: None,
+        member_of: None,
+        pool_checked_at: 0,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status: status,
+        payout: payout,
     };
 
     (ctx, handle)
@@ -74,6 +272,180 @@ impl Handle {
             .unwrap();
     }
 
+    pub fn status(&self) -> Status {
+        *self.status.lock().unwrap()
+    }
+
+    /// Pay the rewards of the blocks mined from now on to `address`, instead of the first
+    /// address of the wallet. The block being mined switches to it right away.
+    pub fn set_payout(&self, address: H160) {
+        *self.payout.lock().unwrap() = Some(address);
+    }
+
+    /// The address set with `set_payout`, if any.
+    pub fn payout(&self) -> Option<H160> {
+        *self.payout.lock().unwrap()
+    }
+
+    /// Act as a mining pool: hand out jobs to the peers sending `GetPoolWork`, and connect the
+    /// blocks their shares complete. See `pool`.
+    pub fn serve_pool(&self) {
+        self.control_chan.send(ControlSignal::ServePool).unwrap();
+    }
+
+    /// Mine the jobs of the pool at the other end of a connection, instead of blocks of our own.
+    pub fn join_pool(&self, pool: peer::Handle) {
+        self.control_chan.send(ControlSignal::JoinPool(pool)).unwrap();
+    }
+
+    /// A peer asked for work, see `serve_pool`.
+    pub fn add_pool_member(&self, peer: peer::Handle) {
+        let _ = self.control_chan.send(ControlSignal::PoolMember(peer));
+    }
+
+    /// A peer sent a job, mined if it is the pool this node joined.
+    pub fn set_pool_work(&self, from: SocketAddr, job: PoolJob) {
+        let _ = self.control_chan.send(ControlSignal::PoolWork(from, job));
+    }
+
+    /// A peer sent a share, counted if it is a member of the pool this node serves.
+    pub fn submit_share(&self, from: SocketAddr, share: PoolShare) {
+        let _ = self.control_chan.send(ControlSignal::PoolShare(from, share));
+    }
+
+    /// The shares of the members of the pool, `None` if the node serves no pool or the miner has
+    /// stopped.
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        let (sender, receiver) = unbounded();
+        self.control_chan.send(ControlSignal::PoolStats(sender)).ok()?;
+        receiver.recv().ok()?
+    }
+
+    /// Mine `count` blocks on the tip right away, whatever the lambda, paying their rewards to
+    /// `recipient`. Each block takes as long as its proof of work, so this is meant for regtest.
+    /// Returns the hashes of the blocks, up to the first that fails validation, `None` if the
+    /// miner has stopped.
+    pub fn generate(&self, count: usize, recipient: H160) -> Option<Vec<H256>> {
+        let (sender, receiver) = unbounded();
+        self.control_chan.send(ControlSignal::Generate(count, recipient, sender)).ok()?;
+        receiver.recv().ok()
+    }
+
+    /// The block the miner would mine next, picked from the mempool like any block it mines.
+    /// Nothing is mined. Returns `None` if the miner has stopped.
+    pub fn preview(&self) -> Option<TemplatePreview> {
+        let (sender, receiver) = unbounded();
+        self.control_chan.send(ControlSignal::Preview(sender)).ok()?;
+        receiver.recv().ok()
+    }
+}
+
+/// Wakes the miner up as soon as the longest chain changes, so that it does not keep working on
+/// a template whose parent is no longer the tip.
+impl ChainListener for Handle {
+    fn on_block_connected(&self, block: &Block, _height: usize) {
+        // the miner may have exited, in which case there is nobody left to notify
+        let _ = self.control_chan.send(ControlSignal::NewTip(block.hash()));
+    }
+}
+
+/// A mempool transaction and its ancestors not in `included`, each after its parents.
+fn package(mempool: &Mempool, tx_hash: &H256, included: &HashSet<H256>) -> Vec<H256> {
+    let mut package = Vec::new();
+    let mut seen = HashSet::new();
+    // depth first, a transaction is pushed once all its parents are
+    let mut stack = vec![(*tx_hash, false)];
+    while let Some((hash, expanded)) = stack.pop() {
+        if expanded {
+            package.push(hash);
+            continue;
+        }
+        if included.contains(&hash) || !seen.insert(hash) {
+            continue;
+        }
+        stack.push((hash, true));
+        for parent in mempool.parents(&hash) {
+            stack.push((parent, false));
+        }
+    }
+    package
+}
+
+/// Pick the transactions of a block of at most `block_limit` bytes: first the ones signed by
+/// the wallet, oldest first, up to `own_tx_quota` bytes, then the rest by decreasing fee rate.
+/// Payments to the wallet get no priority, or anyone could jump the queue by paying us.
+/// A transaction comes with the ancestors it spends outputs of, and the fee rate it competes
+/// with is that of the whole package, so a child paying a high fee pulls in its parents.
+pub fn select_transactions(
+    mempool: &Mempool,
+    wallet: &Wallet,
+    own_tx_quota: usize,
+    block_limit: usize,
+) -> Vec<Hashed<SignedTransaction>> {
+    let transaction = |hash: &H256| mempool.get(hash).unwrap();
+    let size = |hash: &H256| transaction(hash).serialized_size();
+    // transactions not accepted through `Mempool::accept` have no known fee
+    let fee = |hash: &H256| mempool.info(hash).unwrap().fee.unwrap_or(Amount::ZERO);
+    let mut own: Vec<_> = mempool.transactions().into_iter().filter(|tx| wallet.is_from_me(tx)).collect();
+    own.sort_by_key(|tx| (mempool.info(&tx.hash()).unwrap().time_added, tx.hash()));
+
+    let mut selected = Vec::new();
+    let mut included = HashSet::new();
+    let mut block_size = 0;
+    let mut own_size = 0;
+    for tx in own {
+        let package = package(mempool, &tx.hash(), &included);
+        let s: usize = package.iter().map(|hash| size(hash)).sum();
+        if own_size + s > own_tx_quota || block_size + s > block_limit {
+            // the transactions not fitting the quota compete on fees
+            continue;
+        }
+        own_size += s;
+        block_size += s;
+        for hash in package {
+            included.insert(hash);
+            selected.push((*transaction(&hash)).clone());
+        }
+    }
+
+    // the fee rate of each package, updated when some of its ancestors get selected
+    let package_rate = |hash: &H256, included: &HashSet<H256>| {
+        let package = package(mempool, hash, included);
+        let package_size: usize = package.iter().map(|hash| size(hash)).sum();
+        // valid transactions cannot pay more, the fees being part of their input values
+        let package_fee = Amount::checked_sum(package.iter().map(|hash| fee(hash))).unwrap_or(Amount::MAX_MONEY);
+        package_fee.per_kilobyte(package_size)
+    };
+    let mut rates: HashMap<H256, u64> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    for hash in mempool.hashes().into_iter().filter(|hash| !included.contains(hash)) {
+        let rate = package_rate(&hash, &included);
+        rates.insert(hash, rate);
+        queue.push((rate, Reverse(hash)));
+    }
+    while let Some((rate, Reverse(hash))) = queue.pop() {
+        if included.contains(&hash) || rates[&hash] != rate {
+            continue;
+        }
+        let package = package(mempool, &hash, &included);
+        let s: usize = package.iter().map(|hash| size(hash)).sum();
+        if block_size + s > block_limit {
+            continue;
+        }
+        block_size += s;
+        for hash in &package {
+            included.insert(*hash);
+            selected.push((*transaction(hash)).clone());
+        }
+        for hash in package.iter().flat_map(|hash| mempool.descendants(hash)).collect::<HashSet<H256>>() {
+            if !included.contains(&hash) {
+                let rate = package_rate(&hash, &included);
+                rates.insert(hash, rate);
+                queue.push((rate, Reverse(hash)));
+            }
+        }
+    }
+    selected
 }
 
 impl Context {
@@ -82,11 +454,129 @@ impl Context {
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
+                *self.status.lock().unwrap() = Status::Stopped;
             })
             .unwrap();
         info!("Miner initialized into paused mode");
     }
 
+    /// The address set with `Handle::set_payout`, else the first address of the wallet.
+    fn payout(&self) -> Option<H160> {
+        let payout = *self.payout.lock().unwrap();
+        payout.or_else(|| self.wallet.lock().unwrap().addresses().first().cloned())
+    }
+
+    /// Build a template on the tip paying the payout address, starting from a random nonce.
+    fn build_template(&mut self, chain: &Blockchain, mempool: &Mempool, now: u128) -> Template {
+        let payout = self.payout();
+        self.build_template_paying(chain, mempool, now, payout)
+    }
+
+    /// Build a template on the tip whose coinbase pays `payout`, if any, starting from a random
+    /// nonce.
+    fn build_template_paying(&mut self, chain: &Blockchain, mempool: &Mempool, now: u128, payout: Option<H160>) -> Template {
+        let template = self.assemble(chain, mempool, now, payout);
+        Template { nonce: self.rng.gen(), ..template }
+    }
+
+    /// Pick the transactions of a block on the tip whose coinbase pays `payout`, if any.
+    fn assemble(&self, chain: &Blockchain, mempool: &Mempool, now: u128, payout: Option<H160>) -> Template {
+        let parent = chain.tip();
+        let height = chain.blockmap[&parent].header.height + 1;
+        let transactions = {
+            let wallet = self.wallet.lock().unwrap();
+            select_transactions(mempool, &wallet, self.own_tx_quota, BLOCK_SIZE_LIMIT)
+        };
+        // transactions not accepted through `Mempool::accept` have no known fee
+        let fees = Amount::checked_sum(transactions.iter().map(|tx| mempool.info(&tx.hash()).unwrap().fee.unwrap_or(Amount::ZERO)));
+        let fee = fees.unwrap_or(Amount::MAX_MONEY);
+        let subsidy = validation::block_subsidy(height as usize);
+        let reward = fees.and_then(|fees| fees.checked_add(subsidy)).unwrap_or(subsidy);
+        let coinbase = payout.map(|recipient| {
+            let output = TxOut { recipient: recipient, value: reward };
+            Hashed::new(SignedTransaction::coinbase(Transaction::coinbase(height, vec![output])))
+        });
+        let mut tree = MerkleTree::default();
+        for val in coinbase.iter().chain(transactions.iter()) {
+            tree.push(val);
+        }
+        Template {
+            parent: parent,
+            height: height,
+            bits: chain.blockmap[&parent].header.bits,
+            merkle_root: tree.root(),
+            coinbase: coinbase,
+            transactions: transactions,
+            fee: fee,
+            nonce: 0,
+            extra_nonce: 0,
+            checked_at: now,
+        }
+    }
+
+    /// Make sure the template builds on the tip with transactions still in the mempool and pays
+    /// the payout address set, and switch to a better-paying one if it is time to check.
+    fn refresh_template(&mut self, chain: &Blockchain, mempool: &Mempool, now: u128) {
+        let payout = *self.payout.lock().unwrap();
+        let stale = match &self.template {
+            None => true,
+            Some(template) => {
+                template.parent != chain.tip()
+                    || template.transactions.iter().any(|tx| !mempool.contains(&tx.hash()))
+                    || payout.map_or(false, |payout| {
+                        template.coinbase.as_ref().map(|coinbase| coinbase.transaction.output[0].recipient) != Some(payout)
+                    })
+            }
+        };
+        if stale {
+            self.template = Some(self.build_template(chain, mempool, now));
+            return;
+        }
+        let checked_at = self.template.as_ref().unwrap().checked_at;
+        if now < checked_at + self.template_refresh.as_millis() {
+            return;
+        }
+        let candidate = self.build_template(chain, mempool, now);
+        let template = self.template.as_mut().unwrap();
+        if is_better_template(template.fee, candidate.fee) {
+            debug!("Refreshing block template, fees {} -> {}", template.fee, candidate.fee);
+            *template = candidate;
+        } else {
+            template.checked_at = now;
+        }
+    }
+
+    /// Describe the block a fresh template would mine, paying the payout address.
+    fn preview(&self) -> TemplatePreview {
+        let chain_un = self.chain.lock().unwrap();
+        let mempool_un = self.mempool.lock().unwrap();
+        let payout = self.payout();
+        let template = self.assemble(&chain_un, &mempool_un, self.clock.now(), payout);
+        let transactions: Vec<TemplateTransaction> = template
+            .transactions
+            .iter()
+            .map(|tx| {
+                let size = tx.serialized_size();
+                let fee = mempool_un.info(&tx.hash()).and_then(|info| info.fee);
+                TemplateTransaction { txid: tx.hash(), size: size, fee: fee, fee_rate: fee.map(|fee| fee.per_kilobyte(size)) }
+            })
+            .collect();
+        let block = Block {
+            header: template.header(self.clock.now()),
+            content: Content { data: template.coinbase.iter().chain(template.transactions.iter()).cloned().collect() },
+        };
+        TemplatePreview {
+            parent: template.parent,
+            height: template.height,
+            payout: payout,
+            reward: template.coinbase.as_ref().map(|coinbase| coinbase.transaction.output[0].value),
+            transactions_size: transactions.iter().map(|tx| tx.size).sum(),
+            transactions: transactions,
+            total_fee: template.fee,
+            block_size: block.weight(),
+        }
+    }
+
     fn handle_control_signal(&mut self, signal: ControlSignal) {
         match signal {
             ControlSignal::Exit => {
@@ -96,17 +586,230 @@ impl Context {
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
+                *self.status.lock().unwrap() = Status::Running { lambda: i };
+            }
+            ControlSignal::NewTip(hash) => {
+                debug!("New tip {}, refreshing block template", hash);
+                self.refresh_pool_job();
+            }
+            ControlSignal::Generate(count, recipient, result_chan) => {
+                let hashes = self.generate(count, recipient);
+                info!("Generated {} blocks paying {}", hashes.len(), recipient);
+                // the caller may have given up waiting
+                let _ = result_chan.send(hashes);
+            }
+            ControlSignal::Preview(result_chan) => {
+                let _ = result_chan.send(self.preview());
+            }
+            ControlSignal::ServePool => {
+                info!("Serving a mining pool");
+                self.pool = Some((Coordinator::new(), HashMap::new()));
+            }
+            ControlSignal::JoinPool(pool) => {
+                info!("Mining for the pool at {}", pool.addr());
+                pool.write(Message::GetPoolWork);
+                self.member_of = Some((pool, None));
+            }
+            ControlSignal::PoolMember(peer) => self.add_pool_member(peer),
+            ControlSignal::PoolWork(from, job) => match &mut self.member_of {
+                Some((pool, work)) if pool.addr() == from => {
+                    debug!("Pool job {} on block {}", job.id, job.header.parent);
+                    *work = Some(Work::new(job));
+                }
+                _ => debug!("Ignoring pool job from peer {}, which is not our pool", from),
+            },
+            ControlSignal::PoolShare(from, share) => self.submit_share(from, share),
+            ControlSignal::PoolStats(result_chan) => {
+                let _ = result_chan.send(self.pool.as_ref().map(|(coordinator, _)| coordinator.stats()));
+            }
+        }
+    }
+
+    fn add_pool_member(&mut self, peer: peer::Handle) {
+        let joined = match &mut self.pool {
+            Some((coordinator, peers)) => {
+                let joined = coordinator.join(peer.addr());
+                if joined {
+                    peers.insert(peer.addr(), peer.clone());
+                }
+                joined
+            }
+            None => {
+                debug!("Peer {} asked for pool work, but we serve no pool", peer.addr());
+                return;
+            }
+        };
+        if !joined {
+            warn!("Pool full, peer {} cannot join", peer.addr());
+            return;
+        }
+        info!("Peer {} joined the pool", peer.addr());
+        // a fresh job goes to every member, the others get the current one
+        if !self.refresh_pool_job() {
+            if let Some(job) = self.pool.as_ref().and_then(|(coordinator, _)| coordinator.job(&peer.addr())) {
+                peer.write(Message::PoolWork(job));
+            }
+        }
+    }
+
+    /// Start a pool job on the tip if the current one builds on another block, and send it to
+    /// the members still connected. Returns whether a new job was sent.
+    fn refresh_pool_job(&mut self) -> bool {
+        if self.pool.is_none() {
+            return false;
+        }
+        let block = {
+            let chain_un = self.chain.lock().unwrap();
+            let current = self.pool.as_ref().and_then(|(coordinator, _)| coordinator.current().map(|block| block.header.parent));
+            if current == Some(chain_un.tip()) {
+                return false;
+            }
+            let mempool_un = self.mempool.lock().unwrap();
+            let now = self.clock.now();
+            let template = self.assemble(&chain_un, &mempool_un, now, self.payout());
+            Block { header: template.header(now), content: template.content() }
+        };
+        let connected = self.server.peers();
+        let (coordinator, peers) = self.pool.as_mut().unwrap();
+        if let Some(connected) = connected {
+            for addr in coordinator.members() {
+                if !connected.contains(&addr) {
+                    info!("Peer {} left the pool", addr);
+                    coordinator.leave(&addr);
+                    peers.remove(&addr);
+                }
+            }
+        }
+        let id = coordinator.new_job(block);
+        debug!("Pool job {} for {} members", id, peers.len());
+        for (addr, peer) in peers.iter() {
+            peer.write(Message::PoolWork(coordinator.job(addr).unwrap()));
+        }
+        true
+    }
+
+    /// Count the share of a member, and connect the block it completes, if it does.
+    fn submit_share(&mut self, from: SocketAddr, share: PoolShare) {
+        let result = match &mut self.pool {
+            Some((coordinator, peers)) => {
+                let result = coordinator.submit(&from, share);
+                if let (Err(e), Some(peer)) = (&result, peers.get(&from)) {
+                    if e.is_invalid() {
+                        peer.record_rejected();
+                        self.server.misbehaving(from, INVALID_SHARE_PENALTY);
+                    }
+                }
+                result
             }
+            None => {
+                debug!("Ignoring share from peer {}, we serve no pool", from);
+                return;
+            }
+        };
+        match result {
+            Ok(Some(block)) => {
+                let chain = Arc::clone(&self.chain);
+                let mut chain_un = chain.lock().unwrap();
+                let mempool = Arc::clone(&self.mempool);
+                let mut mempool_un = mempool.lock().unwrap();
+                if self.connect(&block, &mut chain_un, &mut mempool_un) {
+                    info!("Pool member {} found block {}", from, block.hash());
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("Rejected share of job {} from peer {}: {}", share.job, from, e),
+        }
+    }
+
+    /// Leave the pool this node mines for once the connection to it is gone, to mine blocks of
+    /// its own again. Checked every `POOL_CHECK_INTERVAL` at most.
+    fn check_pool_connection(&mut self) {
+        let now = self.clock.now();
+        if now < self.pool_checked_at + POOL_CHECK_INTERVAL.as_millis() {
+            return;
+        }
+        self.pool_checked_at = now;
+        let addr = match &self.member_of {
+            Some((pool, _)) => pool.addr(),
+            None => return,
+        };
+        if let Some(connected) = self.server.peers() {
+            if !connected.contains(&addr) {
+                warn!("Lost the connection to the pool at {}, mining blocks of our own", addr);
+                self.member_of = None;
+            }
+        }
+    }
+
+    /// Try the next nonces of the pool job, sending the header to the pool if it is a share.
+    /// Returns false without a job to work on.
+    fn mine_pool_work(&mut self) -> bool {
+        let (pool, work) = match &mut self.member_of {
+            Some((pool, Some(work))) => (pool, work),
+            _ => return false,
+        };
+        let header = work.job.header(work.nonce, work.extra_nonce);
+        work.next();
+        if header.hash() <= work.job.share_target {
+            pool.write(Message::PoolShare(PoolShare { job: work.job.id, nonce: header.nonce, extra_nonce: header.extra_nonce }));
         }
+        true
+    }
+
+    /// Mine blocks one after the other on the tip, trying nonces until each meets its target.
+    fn generate(&mut self, count: usize, recipient: H160) -> Vec<H256> {
+        let mut hashes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let chain = Arc::clone(&self.chain);
+            let mut chain_un = chain.lock().unwrap();
+            let mempool = Arc::clone(&self.mempool);
+            let mut mempool_un = mempool.lock().unwrap();
+            let timestamp = self.clock.now();
+            let mut template = self.build_template_paying(&chain_un, &mempool_un, timestamp, Some(recipient));
+            let header = loop {
+                let header = template.header(timestamp);
+                if header.meets_target() {
+                    break header;
+                }
+                template.next();
+            };
+            let block = Block { header: header, content: template.content() };
+            if !self.connect(&block, &mut chain_un, &mut mempool_un) {
+                break;
+            }
+            hashes.push(block.hash());
+        }
+        // the template being mined, if any, builds on an old tip
+        self.template = None;
+        hashes
+    }
+
+    /// Validate a block mined by this node like a block received from a peer, then connect and
+    /// announce it. Returns whether the block was valid: if not, it is dropped, so that peers
+    /// never see a block the template got wrong.
+    fn connect(&self, block: &Block, chain: &mut Blockchain, mempool: &mut Mempool) -> bool {
+        let mut state_un = self.state.lock().unwrap();
+        let compact = match validator::connect_block(block, chain, mempool, &mut state_un, &self.policy, &*self.clock) {
+            Ok(compact) => compact,
+            Err(e) => {
+                error!("Mined block {} is invalid, not announcing it: {}", block.hash(), e);
+                return false;
+            }
+        };
+        let parents: Vec<H256> = block.content.data.iter().map(|tx| tx.hash()).collect();
+        let accepted = mempool.retry_orphans(&parents, &state_un, &self.policy);
+        if !accepted.is_empty() {
+            self.server.broadcast(Message::NewTransactionHashes(accepted));
+        }
+        self.server.announce_block(compact);
+        true
     }
 
     fn miner_loop(&mut self) {
         // main mining loop
         let mut num_blocks = 0;
         let mut cnt = 0;
-        let mut total_size = 0;
-        let start_time = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs();
-        let block_limit = 2048;
+        // the loop also serves the control signals, so it runs until shut down however long
         loop {
             // check and react to control signals
             match self.operating_state {
@@ -130,63 +833,250 @@ impl Context {
                 return;
             }
 
-            // TODO: actual mining
-            use rand::Rng;
-            let mut rng = rand::thread_rng();
-            let mut chain_un = self.chain.lock().unwrap();
-            let parent = chain_un.tip();
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
-            let difficulty = chain_un.blockmap[&parent].header.difficulty;
-            let mut transactions = Vec::new();
-            let mut mempool_un = self.mempool.lock().unwrap();
-            let mut block_size = 0;
-            for key in mempool_un.txmap.keys() {
-                let val = mempool_un.txmap[&key].clone();
-                let m = bincode::serialize(&val).unwrap();
-                if block_size + m.len() > block_limit {
-                    break;
+            self.check_pool_connection();
+            if self.member_of.is_some() {
+                // mine for the pool, waiting for its first job
+                if self.mine_pool_work() {
+                    self.wait();
+                } else {
+                    match self.control_chan.recv_timeout(POOL_CHECK_INTERVAL) {
+                        Ok(signal) => self.handle_control_signal(signal),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => panic!("Miner control channel detached"),
+                    }
                 }
-                transactions.push(val);
-                block_size += m.len();
-            }
-            let empty_tree = MerkleTree::new(&transactions);
-            let merkle_root = empty_tree.root();
-            let nonce = rng.gen();
-            let header = Header{ parent: parent, nonce: nonce, difficulty: difficulty, timestamp: timestamp, merkle_root: merkle_root };
-            let content = Content{ data: transactions };
-            let cur_block = Block{ header: header, content: content };
+                continue;
+            }
+
+            let chain = Arc::clone(&self.chain);
+            let mut chain_un = chain.lock().unwrap();
+            let mempool = Arc::clone(&self.mempool);
+            let mut mempool_un = mempool.lock().unwrap();
+            let timestamp = self.clock.now();
+            self.refresh_template(&chain_un, &mempool_un, timestamp);
+            let template = self.template.as_mut().unwrap();
+            let header = template.header(timestamp);
+            template.next();
             cnt += 1;
             if cnt % 100000 == 0 {
                 println!("time: {:?}, tip: {:?}, blocksnum: {:?}", timestamp, chain_un.tip(), chain_un.blockmap.len());
             }
 
-            if cur_block.hash() <= difficulty {
-                for transaction in cur_block.clone().content.data {
-                    let mut state_un = self.state.lock().unwrap();
-                    mempool_un.remove(&transaction);
-                    state_un.update(&transaction);
+            if header.meets_target() {
+                let template = self.template.take().unwrap();
+                let cur_block = Block{ header: header, content: template.content() };
+                if self.connect(&cur_block, &mut chain_un, &mut mempool_un) {
+                    num_blocks += 1;
+                    info!("{:?} blocks mined", num_blocks);
                 }
-                chain_un.insert(&cur_block);
-                num_blocks += 1;
-                total_size += bincode::serialize(&cur_block).unwrap().len();
-                info!("{:?} blocks mined", num_blocks);
-                let mut blockhashes = Vec::new();
-                blockhashes.push(cur_block.hash());
-                self.server.broadcast(Message::NewBlockHashes(blockhashes));
-            }
-
-            let cur_time = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs();
-            if cur_time - start_time > 300 {
-                info!("{:?} blocks mined in {:?} seconds. The longest chain has {:?} blocks. The size of all blocks is {:?}.", num_blocks, 300, chain_un.all_blocks_in_longest_chain().len(), total_size);
-                break;
             }
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+            drop(mempool_un);
+            drop(chain_un);
+            self.wait();
+        }
+    }
+
+    /// Wait for the next attempt, unless a new tip makes the template stale first.
+    fn wait(&mut self) {
+        if let OperatingState::Run(i) = self.operating_state {
+            if i != 0 {
+                let interval = time::Duration::from_micros(i as u64);
+                match self.control_chan.recv_timeout(interval) {
+                    Ok(signal) => {
+                        self.handle_control_signal(signal);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => panic!("Miner control channel detached"),
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::{funded, key, owner, spend};
+    use crate::chainparams;
+    use crate::clock::SystemClock;
+    use crate::network::server;
+
+    /// A miner on a fresh regtest chain, with the node state it is given.
+    fn regtest(server: &ServerHandle, mempool: Mempool, state: State, wallet: Wallet, clock: &Arc<dyn Clock>) -> (Context, Handle) {
+        new(
+            server,
+            &Arc::new(OrderedMutex::new(Blockchain::with_params(&chainparams::REGTEST))),
+            &Arc::new(OrderedMutex::new(mempool)),
+            &Arc::new(OrderedMutex::new(state)),
+            &Policy::default(),
+            &Arc::new(OrderedMutex::new(wallet)),
+            0,
+            time::Duration::from_secs(1),
+            Some(1),
+            clock,
+        )
+    }
+
+    #[test]
+    fn own_transactions_first() {
+        let own_key = key(1);
+        let other_key = key(2);
+        let mut state = funded(owner(&other_key), &[2, 3], 100);
+        state.insert_utxo(([1u8; 32].into(), 0), (100.into(), owner(&own_key)));
+        let own = spend(&own_key, [1u8; 32].into(), [9u8; 20].into(), 100);
+        let rich = spend(&other_key, [2u8; 32].into(), [9u8; 20].into(), 50);
+        let poor = spend(&other_key, [3u8; 32].into(), [9u8; 20].into(), 90);
+        let mut mempool = Mempool::new();
+        for tx in &[&poor, &own, &rich] {
+            mempool.accept(tx, &state, &Policy::default()).unwrap();
+        }
+        let mut wallet = Wallet::new();
+        wallet.add_key(own_key);
+        let size = own.serialized_size();
+        let hashes = |selected: Vec<Hashed<SignedTransaction>>| -> Vec<H256> { selected.iter().map(|tx| tx.hash()).collect() };
+
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, 0, 2048)), vec![rich.hash(), poor.hash(), own.hash()]);
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, size, 2048)), vec![own.hash(), rich.hash(), poor.hash()]);
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, size, 2 * size)), vec![own.hash(), rich.hash()]);
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, size - 1, 2 * size)), vec![rich.hash(), poor.hash()]);
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        let key = key(2);
+        let address = owner(&key);
+        let state = funded(address, &[1, 2], 100);
+        let parent = spend(&key, [1u8; 32].into(), address, 100);
+        let child = spend(&key, parent.hash(), [9u8; 20].into(), 20);
+        let other = spend(&key, [2u8; 32].into(), [9u8; 20].into(), 70);
+        let mut mempool = Mempool::new();
+        for tx in &[&parent, &child, &other] {
+            mempool.accept(tx, &state, &Policy::default()).unwrap();
+        }
+        let size = other.serialized_size();
+        let hashes = |selected: Vec<Hashed<SignedTransaction>>| -> Vec<H256> { selected.iter().map(|tx| tx.hash()).collect() };
+
+        // 80 for two transactions beats 30 for one, the parent comes first
+        let wallet = Wallet::new();
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, 0, 2 * size)), vec![parent.hash(), child.hash()]);
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, 0, 3 * size)), vec![parent.hash(), child.hash(), other.hash()]);
+        // the package does not fit, the other transaction does
+        assert_eq!(hashes(select_transactions(&mempool, &wallet, 0, size)), vec![other.hash()]);
+    }
+
+    #[test]
+    fn generate_on_regtest() {
+        let (server, _control_rx) = server::test::handle();
+        let (mut context, _handle) =
+            regtest(&server, Mempool::new(), State::default(), Wallet::new(), &(Arc::new(SystemClock) as Arc<dyn Clock>));
+        let chain = Arc::clone(&context.chain);
+        let recipient: H160 = [5u8; 20].into();
+        let hashes = context.generate(3, recipient);
+        let chain = chain.lock().unwrap();
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.tip(), hashes[2]);
+        for (height, hash) in hashes.iter().enumerate() {
+            let block = &chain.blockmap[hash];
+            assert_eq!(block.header.height as usize, height + 1);
+            assert_eq!(block.content.data[0].transaction.output[0].recipient, recipient);
+        }
+    }
+
+    #[test]
+    fn keeps_serving_control_signals() {
+        use crate::clock::MockClock;
+        let (server, _control_rx) = server::test::handle();
+        let genesis = chainparams::REGTEST.genesis().header.timestamp;
+        let clock = Arc::new(MockClock::new(genesis + 1000));
+        let (context, handle) = regtest(&server, Mempool::new(), State::default(), Wallet::new(), &(clock.clone() as Arc<dyn Clock>));
+        context.start();
+        handle.set_payout([5u8; 20].into());
+        handle.start(1000);
+        // a clock stepping back, then well past any time limit
+        clock.set(genesis);
+        assert!(handle.preview().is_some());
+        clock.advance(time::Duration::from_secs(3600));
+        assert!(handle.preview().is_some());
+        assert_eq!(handle.status(), Status::Running { lambda: 1000 });
+        handle.exit();
+    }
+
+    #[test]
+    fn invalid_block_not_connected() {
+        let (server, control_rx) = server::test::handle();
+        // a transaction spending an output the chain does not have
+        let key = key(2);
+        let other_state = funded(owner(&key), &[1], 100);
+        let mut mempool = Mempool::new();
+        mempool.accept(&spend(&key, [1u8; 32].into(), [9u8; 20].into(), 90), &other_state, &Policy::default()).unwrap();
+        let (mut context, _handle) = regtest(&server, mempool, State::default(), Wallet::new(), &(Arc::new(SystemClock) as Arc<dyn Clock>));
+        let (chain, mempool) = (Arc::clone(&context.chain), Arc::clone(&context.mempool));
+        assert!(context.generate(1, [5u8; 20].into()).is_empty());
+        assert_eq!(chain.lock().unwrap().height(), 0);
+        assert_eq!(mempool.lock().unwrap().len(), 1);
+        assert!(control_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn preview_template() {
+        let (server, _control_rx) = server::test::handle();
+        let key = key(2);
+        let address = owner(&key);
+        let state = funded(address, &[1], 100);
+        let tx = spend(&key, [1u8; 32].into(), [9u8; 20].into(), 90);
+        let mut mempool = Mempool::new();
+        mempool.accept(&tx, &state, &Policy::default()).unwrap();
+        let mut wallet = Wallet::new();
+        wallet.add_key(key);
+        let (context, handle) = regtest(&server, mempool, state, wallet, &(Arc::new(SystemClock) as Arc<dyn Clock>));
+        let (chain, mempool) = (Arc::clone(&context.chain), Arc::clone(&context.mempool));
+        let preview = context.preview();
+        let size = tx.serialized_size();
+        assert_eq!(preview.parent, chain.lock().unwrap().tip());
+        assert_eq!(preview.height, 1);
+        assert_eq!(preview.payout, Some(address));
+        assert_eq!(
+            preview.transactions,
+            vec![TemplateTransaction { txid: tx.hash(), size: size, fee: Some(10.into()), fee_rate: Some(Amount::from(10).per_kilobyte(size)) }]
+        );
+        assert_eq!(preview.total_fee, 10.into());
+        assert_eq!(preview.reward, validation::block_subsidy(1).checked_add(10.into()));
+        assert_eq!(preview.transactions_size, size);
+        assert!(preview.block_size > size);
+        // nothing was mined
+        assert_eq!(chain.lock().unwrap().height(), 0);
+        assert_eq!(mempool.lock().unwrap().len(), 1);
+
+        let payout: H160 = [7u8; 20].into();
+        handle.set_payout(payout);
+        assert_eq!(handle.payout(), Some(payout));
+        assert_eq!(context.preview().payout, Some(payout));
+    }
+
+    #[test]
+    fn template_refresh() {
+        assert!(is_better_template(Amount::ZERO, 1.into()));
+        assert!(!is_better_template(100.into(), 110.into()));
+        assert!(is_better_template(100.into(), 111.into()));
+
+        // the extra nonce moves on once the nonces run out
+        let mut template = Template {
+            parent: [0u8; 32].into(),
+            height: 1,
+            bits: 0,
+            coinbase: None,
+            transactions: vec![],
+            merkle_root: [0u8; 32].into(),
+            fee: Amount::ZERO,
+            nonce: std::u32::MAX - 1,
+            extra_nonce: 0,
+            checked_at: 0,
+        };
+        template.next();
+        assert_eq!((template.nonce, template.extra_nonce), (std::u32::MAX, 0));
+        template.next();
+        assert_eq!((template.nonce, template.extra_nonce), (0, 1));
+        assert_ne!(template.header(0).hash(), Template { extra_nonce: 0, ..template }.header(0).hash());
+    }
+}
@@ -0,0 +1,160 @@
+//! Addresses peers accept connections at, as gossiped in Addr messages.
+
+use super::identity::NodeId;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// File in the data directory the address book is saved to.
+pub const PEERS_FILE: &str = "peers.dat";
+
+/// Most addresses kept for one node.
+pub const MAX_ADDRESSES_PER_NODE: usize = 8;
+
+/// Address family tried first when a peer is reachable over both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(addr: &SocketAddr) -> Family {
+        match addr {
+            SocketAddr::V4(_) => Family::V4,
+            SocketAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+impl std::str::FromStr for Family {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4" => Ok(Family::V4),
+            "ipv6" => Ok(Family::V6),
+            _ => Err(format!("unknown address family {}, expected ipv4 or ipv6", s)),
+        }
+    }
+}
+
+/// Order addresses so that those of the preferred family come first, keeping the order
+/// within each family.
+pub fn sort_by_preference(addrs: &mut [SocketAddr], prefer: Option<Family>) {
+    if let Some(prefer) = prefer {
+        addrs.sort_by_key(|addr| Family::of(addr) != prefer);
+    }
+}
+
+/// Whether an address can be handed to other nodes to connect to.
+pub fn is_advertisable(addr: &SocketAddr) -> bool {
+    !addr.ip().is_unspecified() && addr.port() != 0
+}
+
+/// The listen addresses other nodes advertised, by node id. Only nodes that completed the
+/// version handshake are recorded, so these are the peers known to be good.
+#[derive(Default)]
+pub struct AddressBook {
+    prefer: Option<Family>,
+    nodes: HashMap<NodeId, Vec<SocketAddr>>,
+}
+
+impl AddressBook {
+    pub fn new(prefer: Option<Family>) -> Self {
+        AddressBook { prefer: prefer, nodes: HashMap::new() }
+    }
+
+    /// Record the addresses a node advertised, replacing those it advertised before.
+    pub fn insert(&mut self, node: NodeId, addrs: &[SocketAddr]) {
+        let mut addrs: Vec<SocketAddr> = addrs.iter().cloned().filter(is_advertisable).collect();
+        addrs.dedup();
+        addrs.truncate(MAX_ADDRESSES_PER_NODE);
+        if addrs.is_empty() {
+            self.nodes.remove(&node);
+        } else {
+            self.nodes.insert(node, addrs);
+        }
+    }
+
+    /// Addresses of a node in the order to try them.
+    pub fn addresses(&self, node: &NodeId) -> Vec<SocketAddr> {
+        let mut addrs = self.nodes.get(node).cloned().unwrap_or_default();
+        sort_by_preference(&mut addrs, self.prefer);
+        addrs
+    }
+
+    pub fn nodes(&self) -> Vec<NodeId> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    /// Write the address book to `path`, replacing the file at once.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let entries: Vec<(&NodeId, &Vec<SocketAddr>)> = self.nodes.iter().collect();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &bincode::serialize(&entries).unwrap())?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Add the nodes saved to `path` and return their number, 0 if the file does not exist.
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let entries: Vec<(NodeId, Vec<SocketAddr>)> = bincode::deserialize(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (node, addrs) in &entries {
+            self.insert(*node, addrs);
+        }
+        Ok(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preferred_family_first() {
+        let node: NodeId = [1u8; 32].into();
+        let v4: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6000".parse().unwrap();
+        let unspecified: SocketAddr = "0.0.0.0:6000".parse().unwrap();
+
+        let mut book = AddressBook::new(Some(Family::V6));
+        book.insert(node, &[v4, unspecified, v6]);
+        assert_eq!(book.addresses(&node), vec![v6, v4]);
+        let mut book = AddressBook::new(Some(Family::V4));
+        book.insert(node, &[v6, v4]);
+        assert_eq!(book.addresses(&node), vec![v4, v6]);
+        let mut book = AddressBook::new(None);
+        book.insert(node, &[v6, v4]);
+        assert_eq!(book.addresses(&node), vec![v6, v4]);
+
+        // a node advertising nothing reachable is forgotten
+        book.insert(node, &[unspecified]);
+        assert!(book.nodes().is_empty());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("peers-{}.dat", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut book = AddressBook::new(None);
+        assert_eq!(book.load(&path).unwrap(), 0);
+
+        let node: NodeId = [1u8; 32].into();
+        let other: NodeId = [2u8; 32].into();
+        book.insert(node, &["10.0.0.1:6000".parse().unwrap(), "[2001:db8::1]:6000".parse().unwrap()]);
+        book.insert(other, &["10.0.0.2:6000".parse().unwrap()]);
+        book.save(&path).unwrap();
+
+        let mut loaded = AddressBook::new(Some(Family::V6));
+        assert_eq!(loaded.load(&path).unwrap(), 2);
+        assert_eq!(loaded.addresses(&node), vec!["[2001:db8::1]:6000".parse().unwrap(), "10.0.0.1:6000".parse().unwrap()]);
+        assert_eq!(loaded.addresses(&other), book.addresses(&other));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+//! Misbehavior scores and bans of peer IP addresses, saved so that a restarted node does not
+//! let abusive peers straight back in.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// File in the data directory the ban list is saved to.
+pub const BANS_FILE: &str = "bans.dat";
+
+/// How long peers stay banned, whether for misbehaving or by hand.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A banned address, as listed by `BanList::bans`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub addr: IpAddr,
+    /// End of the ban, in milliseconds since the Unix epoch
+    pub until: u128,
+}
+
+/// Misbehavior scores and ban expirations by IP address.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BanList {
+    scores: HashMap<IpAddr, u32>,
+    /// End of each ban, in milliseconds since the Unix epoch
+    bans: HashMap<IpAddr, u128>,
+}
+
+impl BanList {
+    /// Add to the misbehavior score of an address, returning its total.
+    pub fn add_score(&mut self, addr: IpAddr, score: u32) -> u32 {
+        let total = self.scores.entry(addr).or_insert(0);
+        *total = total.saturating_add(score);
+        *total
+    }
+
+    /// Ban an address until a time, in milliseconds since the Unix epoch, clearing its score
+    /// for when the ban ends.
+    pub fn ban(&mut self, addr: IpAddr, until: u128) {
+        self.scores.remove(&addr);
+        self.bans.insert(addr, until);
+    }
+
+    /// Lift the ban of an address and clear its score. Returns whether it was banned.
+    pub fn unban(&mut self, addr: &IpAddr) -> bool {
+        self.scores.remove(addr);
+        self.bans.remove(addr).is_some()
+    }
+
+    pub fn is_banned(&self, addr: &IpAddr, now: u128) -> bool {
+        self.bans.get(addr).map_or(false, |until| *until > now)
+    }
+
+    /// Forget the bans that ended, returning how many.
+    pub fn expire(&mut self, now: u128) -> usize {
+        let before = self.bans.len();
+        self.bans.retain(|_, until| *until > now);
+        before - self.bans.len()
+    }
+
+    /// The bans, ended or not, by address.
+    pub fn bans(&self) -> Vec<Ban> {
+        let mut bans: Vec<Ban> = self.bans.iter().map(|(addr, until)| Ban { addr: *addr, until: *until }).collect();
+        bans.sort_by_key(|ban| ban.addr);
+        bans
+    }
+
+    /// Write the ban list to `path`, replacing the file at once.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &bincode::serialize(self).unwrap())?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Add the scores and bans saved to `path` and return the number of bans, 0 if the file does
+    /// not exist. Of two bans of an address, the longer wins.
+    pub fn load(&mut self, path: &Path) -> io::Result<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+        let saved: BanList =
+            bincode::deserialize(&fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for (addr, score) in saved.scores {
+            self.add_score(addr, score);
+        }
+        for (addr, until) in &saved.bans {
+            let current = self.bans.entry(*addr).or_insert(*until);
+            *current = (*current).max(*until);
+        }
+        Ok(saved.bans.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_expire_and_persist() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut list = BanList::default();
+        assert_eq!(list.add_score(peer, 40), 40);
+        assert_eq!(list.add_score(peer, 40), 80);
+        list.add_score(other, 10);
+        list.ban(peer, 1000);
+        assert!(list.is_banned(&peer, 999));
+        assert!(!list.is_banned(&peer, 1000));
+        assert!(!list.is_banned(&other, 0));
+        // the score starts over once banned
+        assert_eq!(list.add_score(peer, 1), 1);
+
+        let path = std::env::temp_dir().join(format!("bans-{}.dat", std::process::id()));
+        list.save(&path).unwrap();
+        let mut loaded = BanList::default();
+        assert_eq!(loaded.load(&path).unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, list);
+        assert_eq!(loaded.bans(), vec![Ban { addr: peer, until: 1000 }]);
+
+        assert_eq!(loaded.expire(1000), 1);
+        assert!(loaded.bans().is_empty());
+        list.ban(other, 2000);
+        assert!(list.unban(&other));
+        assert!(!list.unban(&other));
+        assert_eq!(list.add_score(other, 1), 1);
+    }
+}
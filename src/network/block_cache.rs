@@ -0,0 +1,119 @@
+//! Recently announced or requested blocks, kept serialized so that serving them to peers does
+//! not hold the chain lock.
+
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use crate::memory::{MemoryBudget, Pool};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Blocks kept by the cache of a node.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Serialized blocks by hash, dropping the least recently used beyond the capacity, or while
+/// over its share of the memory budget.
+pub struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<H256, Arc<Vec<u8>>>,
+    /// Least recently used first
+    order: VecDeque<H256>,
+    memory: Arc<MemoryBudget>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity: capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+            memory: Arc::new(MemoryBudget::unlimited()),
+        }
+    }
+
+    /// Account the blocks against a budget shared with other pools. Set it while empty.
+    pub fn set_memory_budget(&mut self, memory: &Arc<MemoryBudget>) {
+        self.memory = Arc::clone(memory);
+    }
+
+    /// Serialize a block into the cache. Returns the serialized block.
+    pub fn insert(&mut self, block: &Block) -> Arc<Vec<u8>> {
+        let hash = block.hash();
+        if let Some(serialized) = self.get(&hash) {
+            return serialized;
+        }
+        let serialized = Arc::new(bincode::serialize(block).unwrap());
+        self.blocks.insert(hash, Arc::clone(&serialized));
+        self.memory.add(Pool::BlockCache, serialized.len());
+        self.order.push_back(hash);
+        while self.order.len() > self.capacity {
+            self.evict_oldest();
+        }
+        while self.memory.over_budget(Pool::BlockCache) && !self.order.is_empty() {
+            self.evict_oldest();
+            self.memory.evicted(Pool::BlockCache);
+        }
+        serialized
+    }
+
+    fn evict_oldest(&mut self) {
+        let evicted = self.order.pop_front().unwrap();
+        let serialized = self.blocks.remove(&evicted).unwrap();
+        self.memory.remove(Pool::BlockCache, serialized.len());
+    }
+
+    /// The serialized block, which becomes the most recently used.
+    pub fn get(&mut self, hash: &H256) -> Option<Arc<Vec<u8>>> {
+        let serialized = self.blocks.get(hash)?;
+        let index = self.order.iter().position(|h| h == hash).unwrap();
+        self.order.remove(index);
+        self.order.push_back(*hash);
+        Some(Arc::clone(serialized))
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let mut cache = BlockCache::new(2);
+        let blocks: Vec<Block> = (0..3).map(|i| generate_random_block(&[i as u8; 32].into())).collect();
+        cache.insert(&blocks[0]);
+        cache.insert(&blocks[1]);
+        assert_eq!(*cache.get(&blocks[0].hash()).unwrap(), bincode::serialize(&blocks[0]).unwrap());
+        cache.insert(&blocks[2]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&blocks[1].hash()).is_none());
+        assert!(cache.get(&blocks[0].hash()).is_some());
+        assert!(cache.get(&blocks[2].hash()).is_some());
+    }
+
+    #[test]
+    fn memory_budget() {
+        let blocks: Vec<Block> = (0..3).map(|i| generate_random_block(&[i as u8; 32].into())).collect();
+        let size = blocks[0].weight();
+        // a share of the budget fits two blocks
+        let memory = Arc::new(MemoryBudget::new(4 * (2 * size + 1)));
+        memory.add(Pool::Mempool, 3 * (2 * size + 1));
+        let mut cache = BlockCache::new(10);
+        cache.set_memory_budget(&memory);
+        cache.insert(&blocks[0]);
+        cache.insert(&blocks[1]);
+        assert_eq!(memory.usage(Pool::BlockCache), 2 * size);
+        cache.insert(&blocks[2]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&blocks[0].hash()).is_none());
+        assert_eq!(memory.usage(Pool::BlockCache), 2 * size);
+        assert_eq!(memory.stats().pools[Pool::BlockCache as usize].evicted, 1);
+    }
+}
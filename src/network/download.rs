@@ -0,0 +1,374 @@
+use super::peer;
+use crate::crypto::hash::H256;
+use crate::crypto::u256::U256;
+use log::{debug, warn};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of blocks requested from a single peer at any time.
+pub const DEFAULT_WINDOW: usize = 16;
+/// A request that is not answered within this time is considered stalled.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// A peer that stalls this many times in a row is no longer asked for blocks, and is
+/// disconnected.
+const MAX_STALLS: u32 = 3;
+
+struct PeerState {
+    handle: peer::Handle,
+    in_flight: usize,
+    stalls: u32,
+    /// Total work of the longest chain of the peer, as of its Version
+    chain_work: U256,
+}
+
+/// Schedules block downloads across all peers that announced the blocks.
+pub struct Scheduler {
+    /// The connected peers, until they disconnect or stall too often
+    peers: HashMap<SocketAddr, PeerState>,
+    pending: VecDeque<H256>,
+    /// The blocks in `pending`, to check announcements against
+    queued: HashSet<H256>,
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    sources: HashMap<H256, HashSet<SocketAddr>>,
+    window: usize,
+    stall_timeout: Duration,
+    /// Peers that stalled too often, until `take_stalled_peers`
+    stalled_peers: Vec<SocketAddr>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_params(DEFAULT_WINDOW, DEFAULT_STALL_TIMEOUT)
+    }
+
+    pub fn with_params(window: usize, stall_timeout: Duration) -> Self {
+        Scheduler {
+            peers: HashMap::new(),
+            pending: VecDeque::new(),
+            queued: HashSet::new(),
+            in_flight: HashMap::new(),
+            sources: HashMap::new(),
+            window: window,
+            stall_timeout: stall_timeout,
+            stalled_peers: Vec::new(),
+        }
+    }
+
+    fn peer_state(&mut self, from: &peer::Handle) -> &mut PeerState {
+        let state = self.peers.entry(from.addr()).or_insert(PeerState {
+            handle: from.clone(),
+            in_flight: 0,
+            stalls: 0,
+            chain_work: U256::ZERO,
+        });
+        state.handle = from.clone();
+        state
+    }
+
+    /// Record that `from` has the given blocks, and queue the ones we are not downloading yet.
+    pub fn announce(&mut self, hashes: &[H256], from: &peer::Handle) {
+        let addr = from.addr();
+        self.peer_state(from);
+        for hash in hashes {
+            // a stalled request leaves the block without sources, though queued again
+            if !self.in_flight.contains_key(hash) && self.queued.insert(*hash) {
+                self.pending.push_back(*hash);
+            }
+            self.sources.entry(*hash).or_insert_with(HashSet::new).insert(addr);
+        }
+    }
+
+    /// Record the total work of the longest chain `from` advertised.
+    pub fn set_chain_work(&mut self, from: &peer::Handle, chain_work: U256) {
+        self.peer_state(from).chain_work = chain_work;
+    }
+
+    /// The peer to sync from: the connected peer advertising the most work, not the longest
+    /// chain, which a peer could stretch with blocks of low difficulty. `None` without peers.
+    pub fn sync_peer(&self) -> Option<SocketAddr> {
+        self.peers.iter().max_by_key(|(addr, state)| (state.chain_work, Reverse(**addr))).map(|(addr, _)| *addr)
+    }
+
+    /// Mark a block as received, freeing a slot in the window of the peer it was assigned to.
+    pub fn received(&mut self, hash: &H256) {
+        self.sources.remove(hash);
+        if let Some((addr, _)) = self.in_flight.remove(hash) {
+            if let Some(state) = self.peers.get_mut(&addr) {
+                state.in_flight -= 1;
+                state.stalls = 0;
+            }
+        }
+    }
+
+    /// Whether the block is queued or being downloaded.
+    pub fn is_scheduled(&self, hash: &H256) -> bool {
+        self.sources.contains_key(hash)
+    }
+
+    /// Number of blocks requested but not yet received.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Number of announced blocks waiting to be requested.
+    pub fn queued(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Move requests that timed out back into the queue, and penalize the peers that stalled.
+    fn reassign_stalled(&mut self, now: Instant) {
+        let timeout = self.stall_timeout;
+        let stalled: Vec<H256> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, sent))| now.duration_since(*sent) >= timeout)
+            .map(|(hash, _)| *hash)
+            .collect();
+        let mut stalled_peers = HashSet::new();
+        for hash in stalled {
+            let (addr, _) = self.in_flight.remove(&hash).unwrap();
+            if let Some(state) = self.peers.get_mut(&addr) {
+                state.in_flight -= 1;
+            }
+            stalled_peers.insert(addr);
+            // prefer someone else next time
+            if let Some(sources) = self.sources.get_mut(&hash) {
+                sources.remove(&addr);
+            }
+            self.requeue(hash);
+        }
+        for addr in stalled_peers {
+            let remove = match self.peers.get_mut(&addr) {
+                Some(state) => {
+                    state.stalls += 1;
+                    warn!("Peer {} stalled block download ({} times)", addr, state.stalls);
+                    state.stalls >= MAX_STALLS
+                }
+                None => false,
+            };
+            if remove {
+                warn!("Peer {} stalled too often, no longer downloading from it", addr);
+                self.remove_peer(&addr);
+                self.stalled_peers.push(addr);
+            }
+        }
+    }
+
+    /// Queue a block again, ahead of the others.
+    fn requeue(&mut self, hash: H256) {
+        if self.queued.insert(hash) {
+            self.pending.push_front(hash);
+        }
+    }
+
+    /// Forget a peer that disconnected, was banned or stalled too often, queueing again the
+    /// blocks requested from it.
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        if self.peers.remove(addr).is_none() {
+            return;
+        }
+        let requested: Vec<H256> =
+            self.in_flight.iter().filter(|(_, (a, _))| a == addr).map(|(hash, _)| *hash).collect();
+        for hash in requested {
+            self.in_flight.remove(&hash);
+            self.requeue(hash);
+        }
+        for sources in self.sources.values_mut() {
+            sources.remove(addr);
+        }
+    }
+
+    /// The peers given up on since the last call, to be disconnected.
+    pub fn take_stalled_peers(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.stalled_peers)
+    }
+
+    /// Pick a peer with a free window, preferring peers that announced the block, then peers
+    /// advertising more work, then the least loaded.
+    fn pick_peer(&self, hash: &H256) -> Option<SocketAddr> {
+        let window = self.window;
+        let free = |addr: &SocketAddr| match self.peers.get(addr) {
+            Some(state) => state.in_flight < window,
+            None => false,
+        };
+        let preference = |addr: &&SocketAddr| {
+            let state = &self.peers[*addr];
+            (state.chain_work, Reverse(state.in_flight))
+        };
+        let announced = match self.sources.get(hash) {
+            Some(sources) => sources.iter().filter(|a| free(a)).max_by_key(preference).cloned(),
+            None => None,
+        };
+        announced.or_else(|| self.peers.keys().filter(|a| free(a)).max_by_key(preference).cloned())
+    }
+
+    /// Assign queued blocks to peers. Returns the `GetBlocks` requests that should be sent.
+    pub fn schedule(&mut self) -> Vec<(peer::Handle, Vec<H256>)> {
+        self.schedule_at(Instant::now())
+    }
+
+    fn schedule_at(&mut self, now: Instant) -> Vec<(peer::Handle, Vec<H256>)> {
+        self.reassign_stalled(now);
+        let mut requests: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        let mut deferred = VecDeque::new();
+        while let Some(hash) = self.pending.pop_front() {
+            self.queued.remove(&hash);
+            if !self.sources.contains_key(&hash) || self.in_flight.contains_key(&hash) {
+                // received or requested in the meantime
+                continue;
+            }
+            match self.pick_peer(&hash) {
+                Some(addr) => {
+                    self.peers.get_mut(&addr).unwrap().in_flight += 1;
+                    self.in_flight.insert(hash, (addr, now));
+                    requests.entry(addr).or_insert_with(Vec::new).push(hash);
+                }
+                None => deferred.push_back(hash),
+            }
+        }
+        self.queued = deferred.iter().cloned().collect();
+        self.pending = deferred;
+        let mut ret = Vec::new();
+        for (addr, hashes) in requests {
+            debug!("Requesting {} blocks from peer {}", hashes.len(), addr);
+            ret.push((self.peers[&addr].handle.clone(), hashes));
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    fn requested(requests: &[(peer::Handle, Vec<H256>)], addr: SocketAddr) -> usize {
+        requests.iter().filter(|(h, _)| h.addr() == addr).map(|(_, v)| v.len()).sum()
+    }
+
+    #[test]
+    fn split_across_peers() {
+        let mut scheduler = Scheduler::with_params(4, DEFAULT_STALL_TIMEOUT);
+        let (a, _a_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (b, _b_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        let hashes: Vec<H256> = (0..8).map(|_| generate_random_hash()).collect();
+        scheduler.announce(&hashes, &a);
+        scheduler.announce(&hashes, &b);
+        let requests = scheduler.schedule();
+        assert_eq!(requested(&requests, a.addr()), 4);
+        assert_eq!(requested(&requests, b.addr()), 4);
+        assert_eq!(scheduler.in_flight(), 8);
+        for hash in &hashes {
+            scheduler.received(hash);
+        }
+        assert_eq!(scheduler.in_flight(), 0);
+        assert!(scheduler.schedule().is_empty());
+    }
+
+    #[test]
+    fn reassign_stalled_peer() {
+        let mut scheduler = Scheduler::with_params(4, Duration::from_secs(1));
+        let (a, _a_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (b, _b_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        let hash = generate_random_hash();
+        scheduler.announce(&[hash], &a);
+        let now = Instant::now();
+        let requests = scheduler.schedule_at(now);
+        assert_eq!(requested(&requests, a.addr()), 1);
+        scheduler.announce(&[hash], &b);
+        // nothing new to request while a's request is still fresh
+        assert!(scheduler.schedule_at(now).is_empty());
+        let requests = scheduler.schedule_at(now + Duration::from_secs(2));
+        assert_eq!(requested(&requests, b.addr()), 1);
+        assert_eq!(requested(&requests, a.addr()), 0);
+    }
+
+    #[test]
+    fn announce_after_stall() {
+        let mut scheduler = Scheduler::with_params(4, Duration::from_secs(1));
+        let (a, _a_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (b, _b_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        let hash = generate_random_hash();
+        scheduler.announce(&[hash], &a);
+        let now = Instant::now();
+        assert_eq!(requested(&scheduler.schedule_at(now), a.addr()), 1);
+        // a stalls, and is asked again for lack of other peers
+        let later = now + Duration::from_secs(2);
+        assert_eq!(requested(&scheduler.schedule_at(later), a.addr()), 1);
+        // the block is announced again while requested, it is not requested twice
+        scheduler.announce(&[hash], &b);
+        scheduler.announce(&[hash], &b);
+        assert!(scheduler.schedule_at(later).is_empty());
+        assert_eq!(scheduler.in_flight(), 1);
+        assert_eq!(scheduler.queued(), 0);
+        scheduler.received(&hash);
+        assert_eq!(scheduler.peers[&a.addr()].in_flight, 0);
+    }
+
+    #[test]
+    fn give_up_on_stalling_peer() {
+        let mut scheduler = Scheduler::with_params(1, Duration::from_secs(1));
+        let (a, _a_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (b, _b_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        let mut now = Instant::now();
+        for _ in 0..MAX_STALLS {
+            scheduler.announce(&[generate_random_hash()], &a);
+            assert_eq!(requested(&scheduler.schedule_at(now), a.addr()), 1);
+            assert!(scheduler.take_stalled_peers().is_empty());
+            now += Duration::from_secs(2);
+        }
+        // the last stall gives up on a, its blocks wait for another peer
+        assert!(scheduler.schedule_at(now).is_empty());
+        assert_eq!(scheduler.take_stalled_peers(), vec![a.addr()]);
+        assert!(scheduler.take_stalled_peers().is_empty());
+        assert_eq!(scheduler.queued(), MAX_STALLS as usize);
+        scheduler.announce(&[], &b);
+        assert_eq!(requested(&scheduler.schedule_at(now), b.addr()), 1);
+    }
+
+    #[test]
+    fn prefer_most_work() {
+        let mut scheduler = Scheduler::with_params(4, DEFAULT_STALL_TIMEOUT);
+        assert_eq!(scheduler.sync_peer(), None);
+        let (long, _long_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (heavy, _heavy_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        scheduler.set_chain_work(&long, U256::from(100u64));
+        scheduler.set_chain_work(&heavy, U256::from(200u64));
+        assert_eq!(scheduler.sync_peer(), Some(heavy.addr()));
+        // blocks both announced go to the peer with the most work until its window is full
+        let hashes: Vec<H256> = (0..6).map(|_| generate_random_hash()).collect();
+        scheduler.announce(&hashes, &long);
+        scheduler.announce(&hashes, &heavy);
+        let requests = scheduler.schedule();
+        assert_eq!(requested(&requests, heavy.addr()), 4);
+        assert_eq!(requested(&requests, long.addr()), 2);
+        scheduler.set_chain_work(&long, U256::from(300u64));
+        assert_eq!(scheduler.sync_peer(), Some(long.addr()));
+    }
+
+    #[test]
+    fn remove_disconnected_peer() {
+        let mut scheduler = Scheduler::with_params(4, DEFAULT_STALL_TIMEOUT);
+        let (liar, _liar_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (honest, _honest_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        scheduler.set_chain_work(&liar, U256::MAX);
+        scheduler.set_chain_work(&honest, U256::from(100u64));
+        let hashes: Vec<H256> = (0..2).map(|_| generate_random_hash()).collect();
+        scheduler.announce(&hashes, &liar);
+        assert_eq!(requested(&scheduler.schedule(), liar.addr()), 2);
+        assert_eq!(scheduler.sync_peer(), Some(liar.addr()));
+
+        // its requests are queued again, for the remaining peer to be asked
+        scheduler.remove_peer(&liar.addr());
+        scheduler.remove_peer(&liar.addr());
+        assert_eq!(scheduler.sync_peer(), Some(honest.addr()));
+        assert_eq!(scheduler.in_flight(), 0);
+        assert_eq!(scheduler.queued(), 2);
+        assert!(hashes.iter().all(|hash| scheduler.is_scheduled(hash)));
+        scheduler.announce(&hashes, &honest);
+        assert_eq!(scheduler.queued(), 2);
+        assert_eq!(requested(&scheduler.schedule(), honest.addr()), 2);
+    }
+}
@@ -0,0 +1,157 @@
+use super::message::{Capabilities, Message, Version, PROTOCOL_VERSION};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Identifies a node across connections and restarts. This is its Ed25519 public key, so
+/// signatures can be checked against the id alone.
+pub type NodeId = H256;
+
+/// The long-lived key pair of this node.
+pub struct Identity {
+    key: Ed25519KeyPair,
+}
+
+/// Proof that an announcement was originated by the node `origin`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Provenance {
+    pub origin: NodeId,
+    pub signature: Vec<u8>,
+}
+
+/// A block of the longest chain made final by the checkpoint authority: nodes trusting the
+/// authority refuse reorgs that would disconnect it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedCheckpoint {
+    pub block: H256,
+    pub authority: NodeId,
+    pub signature: Vec<u8>,
+}
+
+impl Identity {
+    /// Generate a fresh, not persisted, identity.
+    pub fn random() -> Self {
+        Identity { key: crate::crypto::key_pair::random() }
+    }
+
+    /// The identity whose key is derived from the seed, so that simulated nodes keep their ids
+    /// across runs.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        Identity { key: Ed25519KeyPair::from_seed_unchecked(seed).unwrap() }
+    }
+
+    /// Load the PKCS#8-encoded key from the file, creating it with a fresh key if it does not
+    /// exist.
+    pub fn load_or_generate(path: &Path) -> std::io::Result<Self> {
+        let pkcs8 = if path.exists() {
+            std::fs::read(path)?
+        } else {
+            let rng = ring::rand::SystemRandom::new();
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            std::fs::write(path, pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        };
+        let key = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid identity key")
+        })?;
+        Ok(Identity { key: key })
+    }
+
+    pub fn id(&self) -> NodeId {
+        let mut raw = [0u8; 32];
+        raw.copy_from_slice(self.key.public_key().as_ref());
+        raw.into()
+    }
+
+    /// The Version message introducing this node, with the longest chain of `chain`, to a peer.
+    pub fn version(&self, chain: &Blockchain) -> Message {
+        Message::Version(Version {
+            protocol: PROTOCOL_VERSION,
+            node_id: self.id(),
+            software: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: Capabilities::local(),
+            tip: chain.tip(),
+            height: chain.height() as u64,
+            chain_work: chain.total_work(),
+        })
+    }
+
+    /// Wrap an announcement originated by this node with a signature over it.
+    pub fn originate(&self, msg: Message) -> Message {
+        let signature = self.key.sign(&bincode::serialize(&msg).unwrap());
+        let provenance = Provenance {
+            origin: self.id(),
+            signature: signature.as_ref().to_vec(),
+        };
+        Message::Originated(Box::new(msg), provenance)
+    }
+
+    /// Sign a checkpoint on a block, as the checkpoint authority.
+    pub fn sign_checkpoint(&self, block: H256) -> SignedCheckpoint {
+        SignedCheckpoint {
+            block: block,
+            authority: self.id(),
+            signature: self.key.sign(block.as_ref()).as_ref().to_vec(),
+        }
+    }
+}
+
+impl SignedCheckpoint {
+    /// Check that `authority` signed the checkpoint.
+    pub fn verify(&self, authority: &NodeId) -> bool {
+        let public_key = signature::UnparsedPublicKey::new(&signature::ED25519, authority.as_ref());
+        self.authority == *authority && public_key.verify(self.block.as_ref(), &self.signature).is_ok()
+    }
+}
+
+impl Provenance {
+    /// Check that the origin signed the announcement.
+    pub fn verify(&self, msg: &Message) -> bool {
+        let public_key = signature::UnparsedPublicKey::new(&signature::ED25519, self.origin.as_ref());
+        public_key
+            .verify(&bincode::serialize(msg).unwrap(), &self.signature)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_announcement() {
+        let identity = Identity::random();
+        let announcement = Message::NewBlockHashes(vec![[1u8; 32].into()]);
+        match identity.originate(announcement.clone()) {
+            Message::Originated(inner, provenance) => {
+                assert_eq!(provenance.origin, identity.id());
+                assert!(provenance.verify(&inner));
+                let forged = Message::NewBlockHashes(vec![[2u8; 32].into()]);
+                assert!(!provenance.verify(&forged));
+            }
+            _ => panic!("expected originated announcement"),
+        }
+    }
+
+    #[test]
+    fn signed_checkpoint() {
+        let authority = Identity::random();
+        let checkpoint = authority.sign_checkpoint([1u8; 32].into());
+        assert!(checkpoint.verify(&authority.id()));
+        assert!(!checkpoint.verify(&Identity::random().id()));
+        let forged = SignedCheckpoint { block: [2u8; 32].into(), ..checkpoint };
+        assert!(!forged.verify(&authority.id()));
+    }
+
+    #[test]
+    fn persistent() {
+        let path = std::env::temp_dir().join(format!("identity-{}.key", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let first = Identity::load_or_generate(&path).unwrap();
+        let second = Identity::load_or_generate(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(first.id(), second.id());
+    }
+}
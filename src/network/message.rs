@@ -1,7 +1,72 @@
+use serde::de::{Error, Deserializer};
 use serde::{Serialize, Deserialize};
 use crate::block::{Block, Header, Content};
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::{H256, Hashable, Hashed};
+use crate::crypto::merkle::{MerkleProof, MerkleTree};
+use crate::crypto::u256::U256;
+use crate::pool::{PoolJob, PoolShare};
 use crate::transaction::SignedTransaction;
+use super::identity::{NodeId, Provenance, SignedCheckpoint};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Version of the P2P protocol spoken by this node. Version 2 added the software version and
+/// capabilities to the Version message, version 3 the compression of Blocks messages, version 4
+/// compact block relay, version 5 the longest chain to the Version message, version 6 the
+/// messages of pooled mining, version 7 the explicit witness of signed transactions.
+pub const PROTOCOL_VERSION: u32 = 7;
+/// Largest message a `Compressed` message may expand to.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+/// Most `Originated` messages a message may be wrapped in. A signed announcement wraps a plain
+/// one; deeper nesting would only serve to exhaust the stack of the decoder.
+const MAX_NESTING: usize = 1;
+
+/// What a node keeps and can serve to its peers.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Finds the block of any confirmed transaction
+    pub txindex: bool,
+    /// Finds the transactions paying any address
+    pub addrindex: bool,
+    /// Dropped the bodies of old blocks
+    pub pruning: bool,
+    /// Keeps block headers only
+    pub light: bool,
+    /// Accepts `Compressed` messages
+    pub compression: bool,
+    /// Accepts new blocks as `CompactBlock` messages instead of hash announcements
+    pub compact_blocks: bool,
+}
+
+impl Capabilities {
+    /// The capabilities of this node: every block is kept and transactions are indexed.
+    pub fn local() -> Self {
+        Capabilities { txindex: true, addrindex: false, pruning: false, light: false, compression: true, compact_blocks: true }
+    }
+
+    /// Whether the node can serve the bodies of the blocks it announces.
+    pub fn serves_blocks(&self) -> bool {
+        !self.pruning && !self.light
+    }
+}
+
+/// Sent by the connecting side when a connection opens, and answered with the same by the
+/// accepting side.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol: u32,
+    pub node_id: NodeId,
+    /// Version of the node software
+    pub software: String,
+    pub capabilities: Capabilities,
+    /// Longest chain of the sender: its tip, height and total work
+    pub tip: H256,
+    pub height: u64,
+    pub chain_work: U256,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -13,4 +78,278 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// Ask for the inclusion proof of a transaction in the longest chain
+    GetTxProof(H256),
+    /// Transaction hash, the block containing it, and the proof against the block's Merkle root
+    TxProof(H256, H256, MerkleProof),
+    Version(Version),
+    /// A NewBlockHashes or NewTransactionHashes announcement signed by the node that created
+    /// the announced objects
+    Originated(#[serde(deserialize_with = "deserialize_nested")] Box<Message>, Provenance),
+    /// The addresses the sender accepts connections at
+    Addr(Vec<SocketAddr>),
+    /// A block the checkpoint authority made final
+    SignedCheckpoint(SignedCheckpoint),
+    /// A serialized message compressed with snappy, sent only to peers advertising the
+    /// `compression` capability
+    Compressed(Vec<u8>),
+    /// A new block, sent in place of its hash announcement to peers advertising the
+    /// `compact_blocks` capability
+    CompactBlock(CompactBlock),
+    /// Join the mining pool of the receiver, asking for a job, see `pool`
+    GetPoolWork,
+    /// A job of the mining pool of the sender, which replaces the previous one
+    PoolWork(PoolJob),
+    /// Nonces found for a job of the mining pool of the receiver
+    PoolShare(PoolShare),
+}
+
+/// A block announced by its header and the hashes of its transactions, which the receiver
+/// looks up in its mempool, saving the round trip of a `GetBlocks` when it has them all. The
+/// transactions the sender did not relay before the block, like the coinbase, are sent whole.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub tx_hashes: Vec<H256>,
+    pub prefilled: Vec<SignedTransaction>,
+}
+
+impl CompactBlock {
+    /// Compact a block, sending whole the transactions for which `relayed` is false.
+    pub fn new<F: Fn(&H256) -> bool>(block: &Block, relayed: F) -> Self {
+        CompactBlock {
+            header: block.header.clone(),
+            tx_hashes: block.content.data.iter().map(|tx| tx.hash()).collect(),
+            prefilled: block.content.data.iter().filter(|tx| !relayed(&tx.hash())).map(|tx| SignedTransaction::clone(tx)).collect(),
+        }
+    }
+
+    /// Rebuild the block from the prefilled transactions and those `lookup` finds. `None` if
+    /// any is missing, or if they do not match the Merkle root of the header.
+    pub fn reconstruct<F: Fn(&H256) -> Option<Hashed<SignedTransaction>>>(&self, lookup: F) -> Option<Block> {
+        let prefilled: HashMap<H256, &SignedTransaction> = self.prefilled.iter().map(|tx| (tx.hash(), tx)).collect();
+        let mut data = Vec::with_capacity(self.tx_hashes.len());
+        for hash in &self.tx_hashes {
+            match prefilled.get(hash) {
+                Some(tx) => data.push(Hashed::new(SignedTransaction::clone(tx))),
+                None => data.push(lookup(hash)?),
+            }
+        }
+        if MerkleTree::new(&data).root() != self.header.merkle_root {
+            return None;
+        }
+        Some(Block { header: self.header.clone(), content: Content { data: data } })
+    }
+}
+
+impl Message {
+    /// Name of the variant, under which the message is counted in `peer::MessageStats`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::NewBlockHashes(_) => "new_block_hashes",
+            Message::GetBlocks(_) => "get_blocks",
+            Message::Blocks(_) => "blocks",
+            Message::NewTransactionHashes(_) => "new_transaction_hashes",
+            Message::GetTransactions(_) => "get_transactions",
+            Message::Transactions(_) => "transactions",
+            Message::GetTxProof(_) => "get_tx_proof",
+            Message::TxProof(..) => "tx_proof",
+            Message::Version(_) => "version",
+            Message::Originated(..) => "originated",
+            Message::Addr(_) => "addr",
+            Message::SignedCheckpoint(_) => "signed_checkpoint",
+            Message::Compressed(_) => "compressed",
+            Message::CompactBlock(_) => "compact_block",
+            Message::GetPoolWork => "get_pool_work",
+            Message::PoolWork(_) => "pool_work",
+            Message::PoolShare(_) => "pool_share",
+        }
+    }
+
+    /// Whether the message may be sent compressed. Only bulk block data is worth the effort.
+    pub fn is_compressible(&self) -> bool {
+        match self {
+            Message::Blocks(_) => true,
+            _ => false,
+        }
+    }
+}
+
+thread_local! {
+    /// Depth of the `Originated` message being decoded on this thread.
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Decode the message wrapped in an `Originated` one, failing past `MAX_NESTING` levels instead
+/// of recursing further.
+fn deserialize_nested<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<Message>, D::Error> {
+    let depth = NESTING.with(|nesting| nesting.get()) + 1;
+    if depth > MAX_NESTING {
+        return Err(D::Error::custom("originated messages nested too deep"));
+    }
+    NESTING.with(|nesting| nesting.set(depth));
+    let inner = Message::deserialize(deserializer);
+    NESTING.with(|nesting| nesting.set(depth - 1));
+    inner.map(Box::new)
+}
+
+/// Decode a message received from a peer. Any input, however malformed, yields an error rather
+/// than a panic or an unbounded allocation or recursion; `fuzz/` exercises this.
+pub fn decode(bytes: &[u8]) -> bincode::Result<Message> {
+    bincode::deserialize(bytes)
+}
+
+/// Wrap a serialized message into a serialized `Compressed` message.
+pub fn compress(serialized: &[u8]) -> Vec<u8> {
+    let compressed = snap::raw::Encoder::new().compress_vec(serialized).unwrap();
+    bincode::serialize(&Message::Compressed(compressed)).unwrap()
+}
+
+/// The serialized message in the payload of a `Compressed` message, `None` if the payload is
+/// not valid snappy or expands past `MAX_DECOMPRESSED_SIZE`.
+pub fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    match snap::raw::decompress_len(payload) {
+        Ok(len) if len <= MAX_DECOMPRESSED_SIZE => snap::raw::Decoder::new().decompress_vec(payload).ok(),
+        _ => None,
+    }
+}
+
+/// Bytes of the messages sent and received compressed, before and after compression.
+#[derive(Default)]
+pub struct CompressionStats {
+    sent_raw: AtomicU64,
+    sent_compressed: AtomicU64,
+    received_raw: AtomicU64,
+    received_compressed: AtomicU64,
+}
+
+/// A snapshot of `CompressionStats`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSummary {
+    pub sent_raw: u64,
+    pub sent_compressed: u64,
+    pub received_raw: u64,
+    pub received_compressed: u64,
+    /// Bytes that did not cross the network thanks to compression, both ways
+    pub bytes_saved: u64,
+}
+
+impl CompressionStats {
+    pub fn record_sent(&self, raw: usize, compressed: usize) {
+        self.sent_raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.sent_compressed.fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, raw: usize, compressed: usize) {
+        self.received_raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.received_compressed.fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> CompressionSummary {
+        let sent_raw = self.sent_raw.load(Ordering::Relaxed);
+        let sent_compressed = self.sent_compressed.load(Ordering::Relaxed);
+        let received_raw = self.received_raw.load(Ordering::Relaxed);
+        let received_compressed = self.received_compressed.load(Ordering::Relaxed);
+        CompressionSummary {
+            sent_raw: sent_raw,
+            sent_compressed: sent_compressed,
+            received_raw: received_raw,
+            received_compressed: received_compressed,
+            bytes_saved: (sent_raw + received_raw).saturating_sub(sent_compressed + received_compressed),
+        }
+    }
+}
+
+/// The serialization of a `Blocks` message carrying blocks that are already serialized, so that
+/// cached blocks are not decoded and encoded again.
+pub fn serialize_blocks(blocks: &[Arc<Vec<u8>>]) -> Vec<u8> {
+    // the variant tag, then the length of the vector, then its items
+    let mut buffer = bincode::serialize(&Message::Blocks(Vec::new())).unwrap();
+    let length = std::mem::size_of::<u64>();
+    buffer.truncate(buffer.len() - length);
+    buffer.extend_from_slice(&(blocks.len() as u64).to_le_bytes());
+    for block in blocks {
+        buffer.extend_from_slice(block);
+    }
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::{generate_block_with_transactions, generate_random_block};
+
+    #[test]
+    fn nested_originated() {
+        let announcement = Message::NewBlockHashes(vec![[1u8; 32].into()]);
+        let provenance = Provenance { origin: [2u8; 32].into(), signature: vec![3u8; 64] };
+        let originated = Message::Originated(Box::new(announcement), provenance.clone());
+        assert!(decode(&bincode::serialize(&originated).unwrap()).is_ok());
+        let twice = Message::Originated(Box::new(originated), provenance);
+        assert!(decode(&bincode::serialize(&twice).unwrap()).is_err());
+
+        // a long run of Originated tags used to overflow the stack of the decoder
+        let originated_tag = bincode::serialize(&twice).unwrap()[..4].to_vec();
+        assert!(decode(&originated_tag.repeat(1_000_000)).is_err());
+        // the depth is reset after a failure
+        assert!(decode(&bincode::serialize(&Message::Ping(String::new())).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn preserialized_blocks() {
+        let blocks: Vec<Block> = (0..3).map(|i| generate_random_block(&[i as u8; 32].into())).collect();
+        let serialized: Vec<Arc<Vec<u8>>> = blocks.iter().map(|block| Arc::new(bincode::serialize(block).unwrap())).collect();
+        assert_eq!(serialize_blocks(&serialized), bincode::serialize(&Message::Blocks(blocks)).unwrap());
+        assert_eq!(serialize_blocks(&[]), bincode::serialize(&Message::Blocks(vec![])).unwrap());
+    }
+
+    #[test]
+    fn compact_block_reconstruction() {
+        let block = generate_block_with_transactions(&[1u8; 32].into(), 3);
+        let pool: HashMap<H256, Hashed<SignedTransaction>> = block.content.data.iter().map(|tx| (tx.hash(), tx.clone())).collect();
+        let first = block.content.data[0].hash();
+
+        // the first transaction sent whole, the others found in the pool
+        let compact = CompactBlock::new(&block, |hash| *hash != first);
+        assert_eq!(compact.prefilled.len(), 1);
+        let rebuilt = compact.reconstruct(|hash| if *hash == first { None } else { pool.get(hash).cloned() }).unwrap();
+        assert_eq!(bincode::serialize(&rebuilt).unwrap(), bincode::serialize(&block).unwrap());
+
+        // a transaction missing from the pool, or transactions not matching the Merkle root
+        assert!(compact.reconstruct(|_| None).is_none());
+        let mut shuffled = CompactBlock::new(&block, |_| false);
+        shuffled.tx_hashes.reverse();
+        assert!(shuffled.reconstruct(|_| None).is_none());
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        // blocks with the same parent compress well
+        let parent: H256 = [7u8; 32].into();
+        let blocks: Vec<Block> = (0..20).map(|_| generate_random_block(&parent)).collect();
+        let serialized = bincode::serialize(&Message::Blocks(blocks.clone())).unwrap();
+        let compressed = compress(&serialized);
+        assert!(compressed.len() < serialized.len());
+        let payload = match bincode::deserialize(&compressed).unwrap() {
+            Message::Compressed(payload) => payload,
+            _ => panic!("expected compressed message"),
+        };
+        let inner: Message = bincode::deserialize(&decompress(&payload).unwrap()).unwrap();
+        assert!(inner.is_compressible());
+        match inner {
+            Message::Blocks(received) => assert_eq!(received.len(), blocks.len()),
+            _ => panic!("expected blocks"),
+        }
+        assert_eq!(decompress(b"not snappy"), None);
+        assert!(!Message::Ping(String::new()).is_compressible());
+
+        let stats = CompressionStats::default();
+        stats.record_sent(100, 40);
+        stats.record_received(50, 30);
+        let summary = stats.summary();
+        assert_eq!((summary.sent_raw, summary.received_compressed), (100, 30));
+        assert_eq!(summary.bytes_saved, 80);
+    }
 }
@@ -1,4 +1,11 @@
+pub mod addresses;
+pub mod banlist;
+pub mod block_cache;
+pub mod download;
+pub mod identity;
 pub mod message;
 pub mod peer;
 pub mod server;
+pub mod socks5;
+pub mod validator;
 pub mod worker;
@@ -1,10 +1,22 @@
-use super::message;
+use super::message::{self, Message};
+use serde::Serialize;
+use crate::crypto::hash::{H256, Hashable};
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of block and transaction hashes remembered per peer, the oldest are forgotten first.
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+/// How often peers are pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a peer has to answer a ping before it is disconnected.
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(30);
 
 enum DecodeState {
     Length,
@@ -157,11 +169,11 @@ impl WriteContext {
 
 pub fn new(
     stream: mio::net::TcpStream,
+    addr: std::net::SocketAddr,
     direction: Direction,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
-    let addr = stream.peer_addr()?;
     let bufreader = std::io::BufReader::new(reader_stream);
     let read_ctx = ReadContext {
         reader: bufreader,
@@ -184,6 +196,11 @@ pub fn new(
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        known: Arc::new(Mutex::new(KnownInventory::default())),
+        liveness: Arc::new(Mutex::new(Liveness::default())),
+        compact_blocks: Arc::new(AtomicBool::new(false)),
+        stats: Arc::new(Mutex::new(MessageStats::default())),
     };
     let ctx = Context {
         addr,
@@ -196,7 +213,8 @@ pub fn new(
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -211,18 +229,386 @@ pub struct Context {
     pub direction: Direction,
 }
 
+/// Messages of one kind exchanged with a peer, and their bytes.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MessageCount {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl MessageCount {
+    fn add(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Traffic with a peer by message kind since it connected, as served by
+/// `/network/peers/{addr}/stats`. Sent messages are counted when queued; compressed messages
+/// under the kind of the message inside, with their compressed size.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MessageStats {
+    pub sent: BTreeMap<&'static str, MessageCount>,
+    pub received: BTreeMap<&'static str, MessageCount>,
+    /// Messages refused as malformed or invalid
+    pub rejected: u64,
+}
+
+/// Hashes of the blocks and transactions a peer is known to have, because it sent or announced
+/// them to us, asked us for them, or we announced them to it.
+#[derive(Default)]
+struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl KnownInventory {
+    /// Remember a hash, returning whether it was new.
+    fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+}
+
+/// The ping awaiting an answer from a peer, and how fast it answered the last one.
+#[derive(Default)]
+struct Liveness {
+    /// Nonce and send time of the unanswered ping
+    outstanding: Option<(u64, Instant)>,
+    last_sent: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+/// What the server has to do to keep a connection alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keepalive {
+    Idle,
+    Ping,
+    /// The last ping went unanswered for `PONG_TIMEOUT`
+    TimedOut,
+}
+
+impl Liveness {
+    fn check(&self, now: Instant) -> Keepalive {
+        match (self.outstanding, self.last_sent) {
+            (Some((_, sent)), _) if now >= sent + PONG_TIMEOUT => Keepalive::TimedOut,
+            (Some(_), _) => Keepalive::Idle,
+            (None, Some(sent)) if now < sent + PING_INTERVAL => Keepalive::Idle,
+            (None, _) => Keepalive::Ping,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: channel::Sender<Vec<u8>>,
+    direction: Direction,
+    known: Arc<Mutex<KnownInventory>>,
+    liveness: Arc<Mutex<Liveness>>,
+    /// Whether the peer accepts new blocks as compact blocks, as its Version says
+    compact_blocks: Arc<AtomicBool>,
+    stats: Arc<Mutex<MessageStats>>,
 }
 
 impl Handle {
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+        self.write_serialized(msg.kind(), bincode::serialize(&msg).unwrap());
+    }
+
+    /// Queue a message that is already serialized, counted as a message of the given kind.
+    pub fn write_serialized(&self, kind: &'static str, buffer: Vec<u8>) {
+        self.stats.lock().unwrap().sent.entry(kind).or_default().add(buffer.len());
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
+
+    /// Whether the peer is due a ping, or has failed to answer the last one in time.
+    pub fn keepalive(&self, now: Instant) -> Keepalive {
+        self.liveness.lock().unwrap().check(now)
+    }
+
+    /// Send a ping with a fresh nonce, to be answered with a pong carrying the same nonce.
+    pub fn ping(&self, now: Instant) {
+        let nonce: u64 = rand::random();
+        {
+            let mut liveness = self.liveness.lock().unwrap();
+            liveness.outstanding = Some((nonce, now));
+            liveness.last_sent = Some(now);
+        }
+        self.write(Message::Ping(nonce.to_string()));
+    }
+
+    /// Record a pong, returning whether it answers the outstanding ping.
+    pub fn pong(&self, nonce: &str, now: Instant) -> bool {
+        let mut liveness = self.liveness.lock().unwrap();
+        match liveness.outstanding {
+            Some((expected, sent)) if nonce.parse::<u64>().ok() == Some(expected) => {
+                liveness.outstanding = None;
+                liveness.latency = Some(now.saturating_duration_since(sent));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Round trip time of the last answered ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.liveness.lock().unwrap().latency
+    }
+
+    /// Whether a ping is waiting for an answer.
+    pub fn ping_pending(&self) -> bool {
+        self.liveness.lock().unwrap().outstanding.is_some()
+    }
+
+    /// Count a message received from the peer, of `bytes` as it came over the wire.
+    pub fn record_received(&self, kind: &'static str, bytes: usize) {
+        self.stats.lock().unwrap().received.entry(kind).or_default().add(bytes);
+    }
+
+    /// Count a message of the peer refused as malformed or invalid.
+    pub fn record_rejected(&self) {
+        self.stats.lock().unwrap().rejected += 1;
+    }
+
+    pub fn message_stats(&self) -> MessageStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Relay new blocks to the peer as compact blocks rather than hash announcements.
+    pub fn set_compact_blocks(&self, enabled: bool) {
+        self.compact_blocks.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record that the peer has the blocks or transactions, so they are not announced to it.
+    pub fn mark_known(&self, hashes: &[H256]) {
+        let mut known = self.known.lock().unwrap();
+        for hash in hashes {
+            known.insert(*hash);
+        }
+    }
+
+    /// Narrow a broadcast message down to what the peer does not know yet, marking the
+    /// announced hashes as known. Returns `None` when there is nothing left to send. Signed
+    /// announcements cannot be narrowed, so they are sent whole unless the peer knows every hash.
+    /// Compact blocks become hash announcements for peers that do not accept them.
+    pub fn filter_announcement(&self, msg: &Message) -> Option<Message> {
+        let mut known = self.known.lock().unwrap();
+        let mut unknown = |hashes: &[H256]| -> Vec<H256> {
+            hashes.iter().filter(|hash| known.insert(**hash)).cloned().collect()
+        };
+        match msg {
+            Message::NewBlockHashes(hashes) => {
+                let hashes = unknown(hashes);
+                if hashes.is_empty() { None } else { Some(Message::NewBlockHashes(hashes)) }
+            }
+            Message::NewTransactionHashes(hashes) => {
+                let hashes = unknown(hashes);
+                if hashes.is_empty() { None } else { Some(Message::NewTransactionHashes(hashes)) }
+            }
+            Message::CompactBlock(compact) => {
+                let hash = compact.header.hash();
+                if !known.insert(hash) {
+                    None
+                } else if self.compact_blocks.load(Ordering::Relaxed) {
+                    Some(msg.clone())
+                } else {
+                    Some(Message::NewBlockHashes(vec![hash]))
+                }
+            }
+            Message::Originated(inner, _) => match &**inner {
+                Message::NewBlockHashes(hashes) | Message::NewTransactionHashes(hashes) => {
+                    if unknown(hashes).is_empty() { None } else { Some(msg.clone()) }
+                }
+                _ => Some(msg.clone()),
+            },
+            _ => Some(msg.clone()),
+        }
+    }
+}
+
+#[cfg(any(test, test_utilities))]
+pub mod test {
+    use super::*;
+
+    /// Create a handle that is not backed by a socket, together with its write queue.
+    pub fn handle(addr: std::net::SocketAddr) -> (Handle, channel::Receiver<Vec<u8>>) {
+        handle_with_direction(addr, Direction::Incoming)
+    }
+
+    fn handle_with_direction(addr: std::net::SocketAddr, direction: Direction) -> (Handle, channel::Receiver<Vec<u8>>) {
+        let (write_sender, write_receiver) = channel::channel();
+        let handle = Handle {
+            write_queue: write_sender,
+            addr,
+            direction,
+            known: Arc::new(Mutex::new(KnownInventory::default())),
+            liveness: Arc::new(Mutex::new(Liveness::default())),
+            compact_blocks: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(Mutex::new(MessageStats::default())),
+        };
+        (handle, write_receiver)
+    }
+
+    /// A peer that is not backed by a socket. The messages written to it are kept until they
+    /// are read back or relayed to another node.
+    pub struct MockPeer {
+        pub handle: Handle,
+        writes: channel::Receiver<Vec<u8>>,
+    }
+
+    impl MockPeer {
+        pub fn new(addr: std::net::SocketAddr, direction: Direction) -> Self {
+            let (handle, writes) = handle_with_direction(addr, direction);
+            MockPeer { handle: handle, writes: writes }
+        }
+
+        /// The next message written to the peer, decompressed, if any.
+        pub fn next(&self) -> Option<Message> {
+            let msg = bincode::deserialize(&self.writes.try_recv().ok()?).unwrap();
+            match msg {
+                Message::Compressed(payload) => {
+                    Some(bincode::deserialize(&message::decompress(&payload).unwrap()).unwrap())
+                }
+                msg => Some(msg),
+            }
+        }
+
+        /// The messages written to the peer and not read yet.
+        pub fn sent(&self) -> Vec<Message> {
+            std::iter::from_fn(|| self.next()).collect()
+        }
+
+        /// Deliver the messages written to the peer to the message queue of a node, as received
+        /// from `from`, the peer on the side of that node. Returns how many were delivered.
+        pub fn relay(&self, sink: &crossbeam::channel::Sender<(Vec<u8>, Handle)>, from: &MockPeer) -> usize {
+            let mut delivered = 0;
+            while let Ok(msg) = self.writes.try_recv() {
+                // the node may be gone, then so is the connection
+                if sink.send((msg, from.handle.clone())).is_err() {
+                    break;
+                }
+                delivered += 1;
+            }
+            delivered
+        }
+    }
+
+    /// An in-memory connection between a node that connected to `listener` and a node that
+    /// accepted the connection from `connector`. The first peer stands for the listening node on
+    /// the side of the connecting one, the second for the connecting node on the other side.
+    pub fn link(connector: std::net::SocketAddr, listener: std::net::SocketAddr) -> (MockPeer, MockPeer) {
+        (MockPeer::new(listener, Direction::Outgoing), MockPeer::new(connector, Direction::Incoming))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::network::identity::Identity;
+    use crate::network::message::CompactBlock;
+
+    #[test]
+    fn announce_only_unknown() {
+        let (peer, _) = test::handle("127.0.0.1:6001".parse().unwrap());
+        let a: H256 = [1u8; 32].into();
+        let b: H256 = [2u8; 32].into();
+        let c: H256 = [3u8; 32].into();
+        // the peer sent us a, so only b is announced to it, and only once
+        peer.mark_known(&[a]);
+        match peer.filter_announcement(&Message::NewBlockHashes(vec![a, b])) {
+            Some(Message::NewBlockHashes(hashes)) => assert_eq!(hashes, vec![b]),
+            _ => panic!("expected announcement"),
+        }
+        assert!(peer.filter_announcement(&Message::NewTransactionHashes(vec![a, b])).is_none());
+        assert!(peer.filter_announcement(&Message::Ping(String::new())).is_some());
+
+        let identity = Identity::random();
+        assert!(peer.filter_announcement(&identity.originate(Message::NewBlockHashes(vec![a]))).is_none());
+        match peer.filter_announcement(&identity.originate(Message::NewBlockHashes(vec![a, c]))) {
+            Some(Message::Originated(inner, provenance)) => assert!(provenance.verify(&inner)),
+            _ => panic!("expected signed announcement"),
+        }
+    }
+
+    #[test]
+    fn compact_blocks_for_capable_peers() {
+        let (peer, _) = test::handle("127.0.0.1:6001".parse().unwrap());
+        let block = generate_random_block(&[1u8; 32].into());
+        let compact = Message::CompactBlock(CompactBlock::new(&block, |_| false));
+        match peer.filter_announcement(&compact) {
+            Some(Message::NewBlockHashes(hashes)) => assert_eq!(hashes, vec![block.hash()]),
+            _ => panic!("expected hash announcement"),
+        }
+        assert!(peer.filter_announcement(&compact).is_none());
+
+        let (peer, _) = test::handle("127.0.0.1:6002".parse().unwrap());
+        peer.set_compact_blocks(true);
+        match peer.filter_announcement(&compact) {
+            Some(Message::CompactBlock(received)) => assert_eq!(received.header.hash(), block.hash()),
+            _ => panic!("expected compact block"),
+        }
+    }
+
+    #[test]
+    fn forget_oldest() {
+        let hash = |i: usize| -> H256 {
+            let mut raw = [0u8; 32];
+            raw[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            raw.into()
+        };
+        let mut known = KnownInventory::default();
+        for i in 0..=MAX_KNOWN_INVENTORY {
+            assert!(known.insert(hash(i)));
+        }
+        assert_eq!(known.hashes.len(), MAX_KNOWN_INVENTORY);
+        assert!(!known.insert(hash(1)));
+        assert!(known.insert(hash(0)));
+    }
+
+    #[test]
+    fn ping_pong() {
+        let (peer, write_rx) = test::handle("127.0.0.1:6001".parse().unwrap());
+        let start = Instant::now();
+        assert_eq!(peer.keepalive(start), Keepalive::Ping);
+        peer.ping(start);
+        let nonce = match bincode::deserialize(&write_rx.try_recv().unwrap()).unwrap() {
+            Message::Ping(nonce) => nonce,
+            _ => panic!("expected ping"),
+        };
+        assert!(peer.ping_pending());
+        assert_eq!(peer.keepalive(start + PONG_TIMEOUT / 2), Keepalive::Idle);
+
+        // only the pong carrying the nonce answers the ping
+        assert!(!peer.pong("unrelated", start + PONG_TIMEOUT / 2));
+        assert!(peer.pong(&nonce, start + Duration::from_millis(40)));
+        assert!(!peer.pong(&nonce, start + Duration::from_millis(50)));
+        assert_eq!(peer.latency(), Some(Duration::from_millis(40)));
+        assert_eq!(peer.keepalive(start + PING_INTERVAL / 2), Keepalive::Idle);
+        assert_eq!(peer.keepalive(start + PING_INTERVAL), Keepalive::Ping);
+
+        // an unanswered ping times out
+        peer.ping(start + PING_INTERVAL);
+        assert_eq!(peer.keepalive(start + PING_INTERVAL + PONG_TIMEOUT), Keepalive::TimedOut);
+        assert_eq!(peer.latency(), Some(Duration::from_millis(40)));
+    }
 }
@@ -1,31 +1,119 @@
-use super::message;
-use super::peer::{self, ReadResult, WriteResult};
+use super::addresses;
+use super::banlist::{self, Ban, BanList};
+use super::download::Scheduler;
+use super::identity::Identity;
+use super::message::{self, Message};
+use super::peer::{self, MessageStats, ReadResult, WriteResult};
+use super::socks5;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::hash::{H256, Hashable};
+use crate::locks::OrderedMutex;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
+use serde::Serialize;
 use mio_extras::channel;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
+/// Pending connections each listener queues before they are accepted.
+const LISTEN_BACKLOG: i32 = 1024;
+/// Peers whose misbehavior score reaches this value are disconnected and banned for
+/// `banlist::DEFAULT_BAN_DURATION`.
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long transaction announcements are held back to be sent together.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of held back transaction hashes that triggers sending them right away.
+const ANNOUNCE_BATCH_SIZE: usize = 500;
+/// How long `Handle::peers` waits for the event loop to answer.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Idle time after which the operating system probes a connection, to notice dead peers even
+/// between pings.
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+/// How often the peers are checked for being due a ping or having missed a pong.
+const KEEPALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
+/// How the server reaches peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outbound {
+    /// SOCKS5 proxy all outbound connections go through
+    pub proxy: Option<std::net::SocketAddr>,
+    /// Whether peers may be connected to directly, when there is no proxy or connecting through
+    /// it fails, and whether incoming connections are accepted
+    pub direct: bool,
+}
+
+impl Default for Outbound {
+    fn default() -> Self {
+        Outbound { proxy: None, direct: true }
+    }
+}
+
+/// Bind a listener, keeping IPv6 listeners off IPv4 so that both families can be bound to the
+/// same port.
+fn bind(addr: &std::net::SocketAddr) -> std::io::Result<net::TcpListener> {
+    let builder = match addr {
+        std::net::SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        std::net::SocketAddr::V6(_) => {
+            let builder = net2::TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?;
+    net::TcpListener::from_std(builder.listen(LISTEN_BACKLOG)?)
+}
+
+/// Create a server listening at each of `addrs`, or at none of them if direct connections are
+/// disabled.
 pub fn new(
-    addr: std::net::SocketAddr,
+    addrs: &[std::net::SocketAddr],
+    outbound: Outbound,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    signer: Option<Arc<Identity>>,
 ) -> std::io::Result<(Context, Handle)> {
+    let mut listeners = vec![];
+    if outbound.direct {
+        for addr in addrs {
+            listeners.push(bind(addr).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("cannot listen at {}: {}", addr, e))
+            })?);
+        }
+    }
+    let mut advertised = vec![];
+    for listener in &listeners {
+        let addr = listener.local_addr()?;
+        if addresses::is_advertisable(&addr) {
+            advertised.push(addr);
+        }
+    }
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
         control_chan: control_signal_sender,
+        signer: signer,
+        advertised: Arc::new(advertised),
     };
     let ctx = Context {
         peers: slab::Slab::new(),
         peer_list: vec![],
-        addr,
+        listeners: listeners,
+        outbound: outbound,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
         _handle: handle.clone(),
+        bans: BanList::default(),
+        bans_path: None,
+        scheduler: None,
+        tx_announcements: Batch::default(),
+        next_keepalive: Instant::now(),
     };
     Ok((ctx, handle))
 }
@@ -33,14 +121,95 @@ pub fn new(
 pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
-    addr: std::net::SocketAddr,
+    listeners: Vec<net::TcpListener>,
+    outbound: Outbound,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
     _handle: Handle,
+    bans: BanList,
+    /// Where the ban list is saved whenever it changes, if anywhere
+    bans_path: Option<PathBuf>,
+    /// Told about the peers that leave, so that blocks are no longer expected from them
+    scheduler: Option<Arc<OrderedMutex<Scheduler>>>,
+    tx_announcements: Batch,
+    next_keepalive: Instant,
+}
+
+/// A connected peer, as listed by `Handle::peer_info`.
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    /// Round trip time of the last answered ping, in milliseconds
+    pub ping_ms: Option<u64>,
+    /// Whether a ping is waiting for an answer
+    pub ping_pending: bool,
+}
+
+/// Hashes held back to be announced together, once the first has waited `ANNOUNCE_INTERVAL`
+/// or `ANNOUNCE_BATCH_SIZE` are collected.
+#[derive(Default)]
+struct Batch {
+    hashes: Vec<H256>,
+    since: Option<Instant>,
+}
+
+impl Batch {
+    /// Add hashes, returning the whole batch if it is full.
+    fn push(&mut self, hashes: &[H256], now: Instant) -> Option<Vec<H256>> {
+        self.hashes.extend_from_slice(hashes);
+        self.since.get_or_insert(now);
+        if self.hashes.len() >= ANNOUNCE_BATCH_SIZE {
+            return self.take();
+        }
+        None
+    }
+
+    /// Take the batch if it has waited long enough.
+    fn take_due(&mut self, now: Instant) -> Option<Vec<H256>> {
+        match self.since {
+            Some(since) if now >= since + ANNOUNCE_INTERVAL => self.take(),
+            _ => None,
+        }
+    }
+
+    fn take(&mut self) -> Option<Vec<H256>> {
+        self.since = None;
+        if self.hashes.is_empty() {
+            None
+        } else {
+            Some(std::mem::replace(&mut self.hashes, Vec::new()))
+        }
+    }
+
+    /// Time left until the batch is due, `None` when it is empty.
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.since.map(|since| (since + ANNOUNCE_INTERVAL).saturating_duration_since(now))
+    }
 }
 
 impl Context {
+    /// Add the bans saved to a file by a previous run, and save the ban list there from now
+    /// on. Returns the number of bans loaded.
+    pub fn load_bans(&mut self, path: PathBuf) -> std::io::Result<usize> {
+        let loaded = self.bans.load(&path);
+        self.bans.expire(SystemClock.now());
+        self.bans_path = Some(path);
+        loaded
+    }
+
+    /// Remove the peers that disconnect or get banned from the download scheduler.
+    pub fn set_scheduler(&mut self, scheduler: &Arc<OrderedMutex<Scheduler>>) {
+        self.scheduler = Some(Arc::clone(scheduler));
+    }
+
+    /// The addresses the server listens at, with the ports picked by the system for the
+    /// addresses given port 0.
+    pub fn local_addrs(&self) -> Vec<std::net::SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.local_addr().ok()).collect()
+    }
+
     /// Start a new server context.
     pub fn start(mut self) -> std::io::Result<()> {
         thread::spawn(move || {
@@ -55,6 +224,7 @@ impl Context {
     fn register(
         &mut self,
         stream: net::TcpStream,
+        addr: std::net::SocketAddr,
         direction: peer::Direction,
     ) -> std::io::Result<peer::Handle> {
         // get a new slot in the connection set
@@ -72,6 +242,7 @@ impl Context {
         let socket_token = mio::Token(key * 2);
         let writer_token = mio::Token(key * 2 + 1);
 
+        stream.set_keepalive(Some(TCP_KEEPALIVE))?;
         // register the new connection
         self.poll.register(
             &stream,
@@ -79,7 +250,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, addr, direction)?;
 
         // register the writer queue
         self.poll.register(
@@ -101,9 +272,31 @@ impl Context {
     fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         // we need to estabilsh a stdlib tcp stream, since we need it to block
         debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
+        if self.bans.is_banned(&addr.ip(), SystemClock.now()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "peer is banned",
+            ));
+        }
+        let stream = match self.outbound.proxy {
+            Some(proxy) => match socks5::connect(&proxy, addr) {
+                Ok(stream) => stream,
+                Err(e) if self.outbound.direct => {
+                    warn!("Error connecting to peer {} through proxy {}, connecting directly: {}", addr, proxy, e);
+                    std::net::TcpStream::connect(addr)?
+                }
+                Err(e) => return Err(e),
+            },
+            None if self.outbound.direct => std::net::TcpStream::connect(addr)?,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "direct connections are disabled",
+                ));
+            }
+        };
         let mio_stream = net::TcpStream::from_stream(stream)?;
-        self.register(mio_stream, peer::Direction::Outgoing)
+        self.register(mio_stream, *addr, peer::Direction::Outgoing)
     }
 
     /// Accept an incoming peer and register it
@@ -113,7 +306,11 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
-        match self.register(stream, peer::Direction::Incoming) {
+        if self.bans.is_banned(&addr.ip(), SystemClock.now()) {
+            info!("Refusing connection from banned peer {}", addr);
+            return Ok(());
+        }
+        match self.register(stream, addr, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
             }
@@ -131,16 +328,171 @@ impl Context {
                 let handle = self.connect(&req.addr);
                 req.result_chan.send(handle).unwrap();
             }
+            ControlSignal::BroadcastMessage(Message::NewTransactionHashes(hashes)) => {
+                trace!("Processing BroadcastMessage command, holding back transaction hashes");
+                if let Some(batch) = self.tx_announcements.push(&hashes, Instant::now()) {
+                    self.broadcast(Message::NewTransactionHashes(batch));
+                }
+            }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
-                for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                self.broadcast(msg);
+            }
+            ControlSignal::Misbehaving(addr, score) => {
+                trace!("Processing Misbehaving command");
+                self.misbehaving(addr, score);
+            }
+            ControlSignal::Disconnect(addr) => {
+                trace!("Processing Disconnect command");
+                self.disconnect(addr);
+            }
+            ControlSignal::Ban(ip, duration) => {
+                trace!("Processing Ban command");
+                self.ban(ip, duration);
+            }
+            ControlSignal::Unban(ip, result_chan) => {
+                trace!("Processing Unban command");
+                let unbanned = self.bans.unban(&ip);
+                if unbanned {
+                    info!("Unbanned peer {}", ip);
                 }
+                self.save_bans();
+                let _ = result_chan.send(unbanned);
+            }
+            ControlSignal::ListBans(result_chan) => {
+                trace!("Processing ListBans command");
+                self.bans.expire(SystemClock.now());
+                let _ = result_chan.send(self.bans.bans());
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self.peer_list.iter().map(|peer_id| self.peers[*peer_id].addr).collect();
+                // the caller may have given up waiting
+                let _ = result_chan.send(peers);
+            }
+            ControlSignal::PeerInfo(result_chan) => {
+                trace!("Processing PeerInfo command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| {
+                        let peer = &self.peers[*peer_id];
+                        PeerInfo {
+                            addr: peer.addr,
+                            direction: peer.direction,
+                            ping_ms: peer.handle.latency().map(|latency| latency.as_millis() as u64),
+                            ping_pending: peer.handle.ping_pending(),
+                        }
+                    })
+                    .collect();
+                let _ = result_chan.send(peers);
+            }
+            ControlSignal::PeerStats(addr, result_chan) => {
+                trace!("Processing PeerStats command");
+                let stats = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| &self.peers[*peer_id])
+                    .find(|peer| peer.addr == addr)
+                    .map(|peer| peer.handle.message_stats());
+                let _ = result_chan.send(stats);
             }
         }
         Ok(())
     }
 
+    /// Ping the peers that are due, and disconnect those that did not answer the last ping.
+    fn keepalive(&mut self, now: Instant) {
+        let mut unresponsive = vec![];
+        for peer_id in &self.peer_list {
+            let peer = &self.peers[*peer_id];
+            match peer.handle.keepalive(now) {
+                peer::Keepalive::Idle => {}
+                peer::Keepalive::Ping => peer.handle.ping(now),
+                peer::Keepalive::TimedOut => unresponsive.push(peer.addr),
+            }
+        }
+        for addr in unresponsive {
+            warn!("Peer {} did not answer ping within {:?}", addr, peer::PONG_TIMEOUT);
+            self.disconnect(addr);
+        }
+    }
+
+    /// Time left until the next deadline of the event loop.
+    fn poll_timeout(&self, now: Instant) -> Duration {
+        let keepalive = self.next_keepalive.saturating_duration_since(now);
+        match self.tx_announcements.timeout(now) {
+            Some(announce) => announce.min(keepalive),
+            None => keepalive,
+        }
+    }
+
+    /// Send a message to all peers, leaving out what each of them already knows.
+    fn broadcast(&self, msg: Message) {
+        for peer_id in &self.peer_list {
+            let handle = &self.peers[*peer_id].handle;
+            if let Some(msg) = handle.filter_announcement(&msg) {
+                handle.write(msg);
+            }
+        }
+    }
+
+    /// Add to the misbehavior score of a peer, and ban it once the score reaches the threshold.
+    fn misbehaving(&mut self, addr: std::net::SocketAddr, score: u32) {
+        let total = self.bans.add_score(addr.ip(), score);
+        warn!("Peer {} misbehaving, score {}", addr, total);
+        if total < BAN_THRESHOLD {
+            self.save_bans();
+            return;
+        }
+        self.ban(addr.ip(), banlist::DEFAULT_BAN_DURATION);
+    }
+
+    /// Ban an address for a while, disconnecting its peers.
+    fn ban(&mut self, ip: IpAddr, duration: Duration) {
+        warn!("Banning peer {} for {:?}", ip, duration);
+        self.bans.ban(ip, SystemClock.now() + duration.as_millis());
+        self.save_bans();
+        let banned: Vec<usize> = self
+            .peer_list
+            .iter()
+            .cloned()
+            .filter(|peer_id| self.peers[*peer_id].addr.ip() == ip)
+            .collect();
+        for peer_id in banned {
+            self.remove_peer(peer_id);
+        }
+    }
+
+    /// Drop the connection to a peer, and tell the download scheduler.
+    fn remove_peer(&mut self, peer_id: usize) {
+        let peer = self.peers.remove(peer_id);
+        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+        self.peer_list.swap_remove(index);
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.lock().unwrap().remove_peer(&peer.addr);
+        }
+    }
+
+    fn save_bans(&mut self) {
+        self.bans.expire(SystemClock.now());
+        if let Some(path) = &self.bans_path {
+            if let Err(e) = self.bans.save(path) {
+                warn!("Error saving bans to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Close the connection to a peer, without banning it.
+    fn disconnect(&mut self, addr: std::net::SocketAddr) {
+        let peer_id = match self.peer_list.iter().cloned().find(|peer_id| self.peers[*peer_id].addr == addr) {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        info!("Disconnecting peer {}", addr);
+        self.remove_peer(peer_id);
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
         let peer = &mut self.peers[peer_id];
@@ -164,9 +516,7 @@ impl Context {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -187,9 +537,7 @@ impl Context {
                         break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
+                        self.remove_peer(peer_id);
                         break;
                     }
                 }
@@ -224,9 +572,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.remove_peer(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,9 +592,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.remove_peer(peer_id);
                 }
             }
         }
@@ -257,17 +601,20 @@ impl Context {
 
     /// The main event loop of the server.
     fn listen(&mut self) -> std::io::Result<()> {
-        // bind server to passed addr and register to the poll
-        let server = net::TcpListener::bind(&self.addr)?;
-
-        // token for new incoming connection
+        // token for new incoming connection, on any of the listeners
         const INCOMING: mio::Token = mio::Token(std::usize::MAX - 1);
-        self.poll.register(
-            &server,
-            INCOMING,
-            mio::Ready::readable(),
-            mio::PollOpt::edge(),
-        )?;
+        for listener in &self.listeners {
+            self.poll.register(
+                listener,
+                INCOMING,
+                mio::Ready::readable(),
+                mio::PollOpt::edge(),
+            )?;
+            info!("P2P server listening at {}", listener.local_addr()?);
+        }
+        if self.listeners.is_empty() {
+            info!("P2P server not listening, direct connections are disabled");
+        }
 
         // token for new control signal from the handle
         const CONTROL: mio::Token = mio::Token(std::usize::MAX - 2);
@@ -278,13 +625,20 @@ impl Context {
             mio::PollOpt::edge(),
         )?;
 
-        info!("P2P server listening at {}", server.local_addr()?);
-
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
         loop {
-            self.poll.poll(&mut events, None)?;
+            // wake up in time to send the held back announcements and check on the peers
+            self.poll.poll(&mut events, Some(self.poll_timeout(Instant::now())))?;
+            let now = Instant::now();
+            if let Some(batch) = self.tx_announcements.take_due(now) {
+                self.broadcast(Message::NewTransactionHashes(batch));
+            }
+            if now >= self.next_keepalive {
+                self.keepalive(now);
+                self.next_keepalive = now + KEEPALIVE_CHECK_INTERVAL;
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -310,20 +664,23 @@ impl Context {
                     }
                     INCOMING => {
                         trace!("P2P server listener readable");
-                        // we have a new connection
-                        // we are using edge-triggered events, loop until block
-                        loop {
-                            // accept the connection
-                            match server.accept() {
-                                Ok((stream, client_addr)) => {
-                                    self.accept(stream, client_addr).unwrap();
-                                }
-                                Err(e) => {
-                                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                                        // socket is not ready anymore, stop reading here
-                                        break;
-                                    } else {
-                                        return Err(e);
+                        // we have a new connection on one of the listeners
+                        // we are using edge-triggered events, loop until each blocks
+                        for listener_id in 0..self.listeners.len() {
+                            loop {
+                                // accept the connection
+                                let accepted = self.listeners[listener_id].accept();
+                                match accepted {
+                                    Ok((stream, client_addr)) => {
+                                        self.accept(stream, client_addr).unwrap();
+                                    }
+                                    Err(e) => {
+                                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                                            // socket is not ready anymore, stop reading here
+                                            break;
+                                        } else {
+                                            return Err(e);
+                                        }
                                     }
                                 }
                             }
@@ -367,6 +724,10 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    /// Signs the announcements passed to `announce`, if set.
+    signer: Option<Arc<Identity>>,
+    /// Listen addresses advertised to peers
+    advertised: Arc<Vec<std::net::SocketAddr>>,
 }
 
 impl Handle {
@@ -387,14 +748,147 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Broadcast an announcement of blocks or transactions created by this node, signed with
+    /// the node identity if signing is enabled.
+    pub fn announce(&self, msg: message::Message) {
+        match &self.signer {
+            Some(identity) => self.broadcast(identity.originate(msg)),
+            None => self.broadcast(msg),
+        }
+    }
+
+    /// Announce a block created by this node, as a compact block to the peers accepting them.
+    /// Only hash announcements can carry a provenance, so blocks are announced by hash when
+    /// signing is enabled.
+    pub fn announce_block(&self, compact: message::CompactBlock) {
+        match &self.signer {
+            Some(_) => self.announce(Message::NewBlockHashes(vec![compact.header.hash()])),
+            None => self.broadcast(Message::CompactBlock(compact)),
+        }
+    }
+
+    /// The addresses the server listens at that peers can connect to.
+    pub fn advertised(&self) -> &[std::net::SocketAddr] {
+        &self.advertised
+    }
+
+    /// Addresses of the connected peers, `None` if the event loop does not answer in time,
+    /// which means it has stopped or is stuck.
+    pub fn peers(&self) -> Option<Vec<std::net::SocketAddr>> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan.send(ControlSignal::ListPeers(sender)).ok()?;
+        receiver.recv_timeout(CONTROL_TIMEOUT).ok()
+    }
+
+    /// The connected peers with their direction and ping latency, `None` if the event loop
+    /// does not answer in time.
+    pub fn peer_info(&self) -> Option<Vec<PeerInfo>> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan.send(ControlSignal::PeerInfo(sender)).ok()?;
+        receiver.recv_timeout(CONTROL_TIMEOUT).ok()
+    }
+
+    /// The messages exchanged with a connected peer, `Some(None)` if no peer has the address,
+    /// `None` if the event loop does not answer in time.
+    pub fn peer_stats(&self, addr: std::net::SocketAddr) -> Option<Option<MessageStats>> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan.send(ControlSignal::PeerStats(addr, sender)).ok()?;
+        receiver.recv_timeout(CONTROL_TIMEOUT).ok()
+    }
+
+    /// Report a peer for misbehaving. It is banned once its accumulated score reaches
+    /// `BAN_THRESHOLD`.
+    pub fn misbehaving(&self, addr: std::net::SocketAddr, score: u32) {
+        self.control_chan
+            .send(ControlSignal::Misbehaving(addr, score))
+            .unwrap();
+    }
+
+    /// Close the connection to a peer, which may connect again.
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::Disconnect(addr))
+            .unwrap();
+    }
+
+    /// Ban an address for a while, disconnecting its peers.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.control_chan
+            .send(ControlSignal::Ban(ip, duration))
+            .unwrap();
+    }
+
+    /// Lift the ban of an address and clear its misbehavior score. Returns whether it was
+    /// banned, `None` if the event loop does not answer in time.
+    pub fn unban(&self, ip: IpAddr) -> Option<bool> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan.send(ControlSignal::Unban(ip, sender)).ok()?;
+        receiver.recv_timeout(CONTROL_TIMEOUT).ok()
+    }
+
+    /// The addresses currently banned, `None` if the event loop does not answer in time.
+    pub fn bans(&self) -> Option<Vec<Ban>> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan.send(ControlSignal::ListBans(sender)).ok()?;
+        receiver.recv_timeout(CONTROL_TIMEOUT).ok()
+    }
 }
 
-enum ControlSignal {
+pub(crate) enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    Misbehaving(std::net::SocketAddr, u32),
+    Disconnect(std::net::SocketAddr),
+    Ban(IpAddr, Duration),
+    Unban(IpAddr, cbchannel::Sender<bool>),
+    ListBans(cbchannel::Sender<Vec<Ban>>),
+    ListPeers(cbchannel::Sender<Vec<std::net::SocketAddr>>),
+    PeerInfo(cbchannel::Sender<Vec<PeerInfo>>),
+    PeerStats(std::net::SocketAddr, cbchannel::Sender<Option<MessageStats>>),
 }
 
-struct ConnectRequest {
+pub(crate) struct ConnectRequest {
     addr: std::net::SocketAddr,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(any(test, test_utilities))]
+pub mod test {
+    use super::*;
+
+    /// Create a handle that is not backed by a running server, together with its control queue.
+    pub(crate) fn handle() -> (Handle, channel::Receiver<ControlSignal>) {
+        let (control_signal_sender, control_signal_receiver) = channel::channel();
+        let handle = Handle {
+            control_chan: control_signal_sender,
+            signer: None,
+            advertised: Arc::new(vec![]),
+        };
+        (handle, control_signal_receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_announcements() {
+        let start = Instant::now();
+        let mut batch = Batch::default();
+        assert_eq!(batch.timeout(start), None);
+        assert_eq!(batch.push(&[[1u8; 32].into()], start), None);
+        assert_eq!(batch.push(&[[2u8; 32].into()], start + ANNOUNCE_INTERVAL / 2), None);
+        assert_eq!(batch.timeout(start + ANNOUNCE_INTERVAL / 2), Some(ANNOUNCE_INTERVAL / 2));
+        assert_eq!(batch.take_due(start + ANNOUNCE_INTERVAL / 2), None);
+        assert_eq!(batch.take_due(start + ANNOUNCE_INTERVAL), Some(vec![[1u8; 32].into(), [2u8; 32].into()]));
+        assert_eq!(batch.timeout(start + ANNOUNCE_INTERVAL), None);
+
+        // a full batch goes out right away
+        let hashes: Vec<H256> = (0..ANNOUNCE_BATCH_SIZE).map(|i| [i as u8; 32].into()).collect();
+        assert_eq!(batch.push(&hashes[..1], start), None);
+        assert_eq!(batch.push(&hashes[1..], start).map(|b| b.len()), Some(ANNOUNCE_BATCH_SIZE));
+        assert_eq!(batch.take_due(start + ANNOUNCE_INTERVAL), None);
+    }
+}
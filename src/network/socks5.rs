@@ -0,0 +1,143 @@
+//! Outbound connections through a SOCKS5 proxy, such as Tor (RFC 1928, CONNECT without
+//! authentication).
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// How long connecting to the proxy, and the proxy connecting to the peer, may take.
+pub const PROXY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+const SUCCEEDED: u8 = 0;
+
+fn error(kind: std::io::ErrorKind, msg: &str) -> std::io::Error {
+    std::io::Error::new(kind, format!("SOCKS5 proxy: {}", msg))
+}
+
+/// Open a blocking TCP stream to `target` through the proxy at `proxy`.
+pub fn connect(proxy: &SocketAddr, target: &SocketAddr) -> std::io::Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(proxy, PROXY_TIMEOUT)
+        .map_err(|e| error(e.kind(), &format!("cannot reach proxy {}: {}", proxy, e)))?;
+    stream.set_read_timeout(Some(PROXY_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROXY_TIMEOUT))?;
+    handshake(&mut stream, target)?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+/// Ask the proxy to connect to `target`, leaving `stream` relaying to it on success.
+fn handshake<S: Read + Write>(stream: &mut S, target: &SocketAddr) -> std::io::Result<()> {
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(error(std::io::ErrorKind::InvalidData, "not a SOCKS5 server"));
+    }
+    if reply[1] == NO_ACCEPTABLE_METHOD {
+        return Err(error(std::io::ErrorKind::PermissionDenied, "authentication required"));
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(error(std::io::ErrorKind::InvalidData, "not a SOCKS5 server"));
+    }
+    if reply[1] != SUCCEEDED {
+        let (kind, msg) = match reply[1] {
+            2 => (std::io::ErrorKind::PermissionDenied, "connection not allowed by ruleset"),
+            3 => (std::io::ErrorKind::Other, "network unreachable"),
+            4 => (std::io::ErrorKind::Other, "host unreachable"),
+            5 => (std::io::ErrorKind::ConnectionRefused, "connection refused"),
+            6 => (std::io::ErrorKind::TimedOut, "TTL expired"),
+            7 => (std::io::ErrorKind::Other, "command not supported"),
+            8 => (std::io::ErrorKind::Other, "address type not supported"),
+            _ => (std::io::ErrorKind::Other, "general failure"),
+        };
+        return Err(error(kind, msg));
+    }
+    // skip the address the proxy bound for the connection
+    let bound_length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length)?;
+            length[0] as usize
+        }
+        _ => return Err(error(std::io::ErrorKind::InvalidData, "unknown address type")),
+    };
+    let mut bound = vec![0u8; bound_length + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Run a proxy answering one connection with `status`, and relaying "pong" on success.
+    fn proxy(status: u8) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 1, NO_AUTHENTICATION]);
+            stream.write_all(&[VERSION, NO_AUTHENTICATION]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&[VERSION, status, 0, IPV4, 127, 0, 0, 1, 0, 80]).unwrap();
+            if status == SUCCEEDED {
+                stream.write_all(b"pong").unwrap();
+            }
+            request.to_vec()
+        });
+        (addr, proxy)
+    }
+
+    #[test]
+    fn connect_through_proxy() {
+        let (addr, proxy_thread) = proxy(SUCCEEDED);
+        let target: SocketAddr = "10.1.2.3:6000".parse().unwrap();
+        let mut stream = connect(&addr, &target).unwrap();
+        let mut relayed = [0u8; 4];
+        stream.read_exact(&mut relayed).unwrap();
+        assert_eq!(&relayed, b"pong");
+        let request = proxy_thread.join().unwrap();
+        assert_eq!(request, vec![VERSION, CONNECT, 0, IPV4, 10, 1, 2, 3, 0x17, 0x70]);
+    }
+
+    #[test]
+    fn proxy_refuses() {
+        let (addr, proxy_thread) = proxy(5);
+        let target: SocketAddr = "10.1.2.3:6000".parse().unwrap();
+        let e = connect(&addr, &target).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused);
+        proxy_thread.join().unwrap();
+    }
+}
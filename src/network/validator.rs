@@ -0,0 +1,347 @@
+//! Validation of the blocks received from peers on a dedicated thread. Workers queue the
+//! blocks and go back to handling messages, and blocks are connected one at a time in the order
+//! they arrived.
+
+use super::block_cache::BlockCache;
+use super::download::Scheduler;
+use super::message::{CompactBlock, Message};
+use super::peer;
+use super::worker;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::chainstate::{self, TipChange};
+use crate::clock::Clock;
+use crate::crypto::hash::{H256, Hashable};
+use crate::locks::OrderedMutex;
+use crate::memory::{MemoryBudget, Pool};
+use crate::network::server::Handle as ServerHandle;
+use crate::policy::Policy;
+use crate::transaction::{Mempool, Revalidation, State};
+use crate::validation::{self, BlockError};
+use crossbeam::channel;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A block received from a peer, waiting for validation.
+pub struct Candidate {
+    pub block: Block,
+    pub peer: peer::Handle,
+    /// Whether the peer can be asked for the parent if it is missing
+    pub fetch_parent: bool,
+}
+
+/// Queues candidate blocks for the validation thread.
+#[derive(Clone)]
+pub struct Handle {
+    queue: channel::Sender<Candidate>,
+}
+
+impl Handle {
+    pub fn submit(&self, candidate: Candidate) {
+        // the queue only closes once the validation thread is gone, with the node
+        let _ = self.queue.send(candidate);
+    }
+
+    /// Blocks waiting for validation.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+/// A handle and the queue it feeds, to be drained by `Context::start`.
+pub fn queue() -> (Handle, channel::Receiver<Candidate>) {
+    let (sender, receiver) = channel::unbounded();
+    (Handle { queue: sender }, receiver)
+}
+
+/// Validate a block and connect it, moving the mempool along with the tip, see
+/// `update_mempool`. Every block the node connects goes through here, whether received from a
+/// peer or mined. Returns the block in compact form, leaving out the transactions the mempool
+/// had, to announce it.
+pub fn connect_block(
+    block: &Block,
+    chain: &mut Blockchain,
+    mempool: &mut Mempool,
+    state: &mut State,
+    policy: &Policy,
+    clock: &dyn Clock,
+) -> Result<CompactBlock, BlockError> {
+    validation::check_block(block, chain, state, clock)?;
+    let compact = CompactBlock::new(block, |hash| mempool.contains(hash));
+    let change = chainstate::insert_block(block, chain, state);
+    update_mempool(&change, chain, mempool, state, policy);
+    Ok(compact)
+}
+
+/// Take the transactions of the blocks joining the longest chain out of the mempool, and put
+/// back those of the blocks leaving it, checked against the new UTXO set. A block stored on a
+/// shorter fork changes neither. Mempool transactions the new blocks spent the inputs of are
+/// left to a `Revalidation`.
+pub fn update_mempool(change: &TipChange, chain: &Blockchain, mempool: &mut Mempool, state: &State, policy: &Policy) {
+    let mut confirmed = HashSet::new();
+    for hash in &change.connected {
+        for transaction in &chain.blockmap[hash].content.data {
+            mempool.remove(transaction);
+            confirmed.insert(transaction.hash());
+        }
+    }
+    // from the lowest block up, so that parents are back before their children
+    let mut restored = 0;
+    for hash in change.disconnected.iter().rev() {
+        for transaction in &chain.blockmap[hash].content.data {
+            if transaction.transaction.coinbase_height().is_some() || confirmed.contains(&transaction.hash()) {
+                continue;
+            }
+            if mempool.restore(transaction, state, policy).is_ok() {
+                restored += 1;
+            }
+        }
+    }
+    if restored > 0 {
+        debug!("Put back {} transactions of disconnected blocks in the mempool", restored);
+    }
+}
+
+pub struct Context {
+    queue: channel::Receiver<Candidate>,
+    server: ServerHandle,
+    chain: Arc<OrderedMutex<Blockchain>>,
+    orphan_buffer: Arc<OrderedMutex<HashMap<H256, Block>>>,
+    mempool: Arc<OrderedMutex<Mempool>>,
+    state: Arc<OrderedMutex<State>>,
+    scheduler: Arc<OrderedMutex<Scheduler>>,
+    policy: Policy,
+    clock: Arc<dyn Clock>,
+    block_cache: Arc<Mutex<BlockCache>>,
+    /// Accounts the orphan buffer
+    memory: Arc<MemoryBudget>,
+}
+
+impl Context {
+    pub fn new(
+        queue: channel::Receiver<Candidate>,
+        server: &ServerHandle,
+        chain: &Arc<OrderedMutex<Blockchain>>,
+        orphan_buffer: &Arc<OrderedMutex<HashMap<H256, Block>>>,
+        mempool: &Arc<OrderedMutex<Mempool>>,
+        state: &Arc<OrderedMutex<State>>,
+        scheduler: &Arc<OrderedMutex<Scheduler>>,
+        policy: &Policy,
+        clock: &Arc<dyn Clock>,
+        block_cache: &Arc<Mutex<BlockCache>>,
+        memory: &Arc<MemoryBudget>,
+    ) -> Context {
+        Context {
+            queue: queue,
+            server: server.clone(),
+            chain: Arc::clone(chain),
+            orphan_buffer: Arc::clone(orphan_buffer),
+            mempool: Arc::clone(mempool),
+            state: Arc::clone(state),
+            scheduler: Arc::clone(scheduler),
+            policy: policy.clone(),
+            clock: Arc::clone(clock),
+            block_cache: Arc::clone(block_cache),
+            memory: Arc::clone(memory),
+        }
+    }
+
+    pub fn start(self) {
+        thread::spawn(move || {
+            self.validator_loop();
+            warn!("Validation thread exited");
+        });
+    }
+
+    fn validator_loop(&self) {
+        while let Ok(candidate) = self.queue.recv() {
+            self.process(candidate);
+        }
+    }
+
+    /// Connect a block, then the orphans waiting for it, or keep it as an orphan if its parent
+    /// is missing.
+    fn process(&self, candidate: Candidate) {
+        let Candidate { block, peer, fetch_parent } = candidate;
+        let mut chain_un = self.chain.lock().unwrap();
+        if chain_un.blockmap.contains_key(&block.hash()) {
+            return;
+        }
+        let mut buffer = self.orphan_buffer.lock().unwrap();
+        if !chain_un.blockmap.contains_key(&block.header.parent) {
+            // fetch the missing parent from the peer that sent the orphan
+            if fetch_parent {
+                let mut scheduler = self.scheduler.lock().unwrap();
+                if !scheduler.is_scheduled(&block.header.parent) {
+                    scheduler.announce(&[block.header.parent], &peer);
+                }
+            }
+            self.memory.add(Pool::OrphanBlocks, block.weight());
+            if let Some(replaced) = buffer.insert(block.header.parent, block) {
+                self.memory.remove(Pool::OrphanBlocks, replaced.weight());
+            }
+            // the orphans highest above the chain are the furthest from connecting
+            while self.memory.over_budget(Pool::OrphanBlocks) {
+                let parent = match buffer.iter().max_by_key(|(parent, orphan)| (orphan.header.height, **parent)) {
+                    Some((parent, _)) => *parent,
+                    None => break,
+                };
+                let evicted = buffer.remove(&parent).unwrap();
+                debug!("Orphan buffer over its memory budget, dropping block {}", evicted.hash());
+                self.memory.remove(Pool::OrphanBlocks, evicted.weight());
+                self.memory.evicted(Pool::OrphanBlocks);
+            }
+            drop(buffer);
+            drop(chain_un);
+            worker::request_blocks(&self.scheduler, &self.server);
+            return;
+        }
+        let mut new_blocks = Vec::new();
+        let mut next = Some(block);
+        while let Some(block) = next.take() {
+            let hash = block.hash();
+            let mut mempool_un = self.mempool.lock().unwrap();
+            let mut state_un = self.state.lock().unwrap();
+            let compact = match connect_block(&block, &mut chain_un, &mut mempool_un, &mut state_un, &self.policy, &*self.clock) {
+                Ok(compact) => compact,
+                Err(e) => {
+                    info!("Invalid block {} from peer {}: {}", hash, peer.addr(), e);
+                    peer.record_rejected();
+                    break;
+                }
+            };
+            new_blocks.push(hash);
+            // peers will ask for the block once it is announced
+            self.block_cache.lock().unwrap().insert(&block);
+            next = buffer.remove(&hash);
+            if let Some(orphan) = &next {
+                self.memory.remove(Pool::OrphanBlocks, orphan.weight());
+            }
+            // a compact block saves peers a round trip on the new tip, the blocks of a catch up
+            // are announced by hash
+            if next.is_none() && chain_un.tip() == hash {
+                self.server.broadcast(Message::CompactBlock(compact));
+            } else {
+                self.server.broadcast(Message::NewBlockHashes(vec![hash]));
+            }
+        }
+        drop(buffer);
+        if new_blocks.is_empty() {
+            return;
+        }
+        // transactions waiting for outputs of the new blocks may be valid now
+        let parents: Vec<H256> = new_blocks
+            .iter()
+            .flat_map(|hash| chain_un.blockmap[hash].content.data.iter().map(|tx| tx.hash()))
+            .collect();
+        let mut mempool_un = self.mempool.lock().unwrap();
+        let state_un = self.state.lock().unwrap();
+        let accepted = mempool_un.retry_orphans(&parents, &state_un, &self.policy);
+        if !accepted.is_empty() {
+            self.server.broadcast(Message::NewTransactionHashes(accepted));
+        }
+        // transactions whose inputs the new blocks spent are evicted in the background, the
+        // signature checks need no lock
+        let revalidation = Revalidation::new(&mempool_un, &state_un);
+        let mempool = Arc::clone(&self.mempool);
+        rayon::spawn(move || {
+            let invalid = revalidation.run();
+            let evicted = mempool.lock().unwrap().evict(&invalid);
+            if evicted > 0 {
+                debug!("Evicted {} mempool transactions invalidated by new blocks", evicted);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::clock::SystemClock;
+    use crate::crypto::hash::Hashed;
+    use crate::network::server;
+    use crate::transaction::tests::{funded, key, owner, spend};
+
+    #[test]
+    fn orphan_waits_for_parent() {
+        let (server, _control_rx) = server::test::handle();
+        let (peer, peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let chain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let orphan_buffer = Arc::new(OrderedMutex::new(HashMap::new()));
+        let (_validator, queue) = super::queue();
+        let context = Context::new(
+            queue,
+            &server,
+            &chain,
+            &orphan_buffer,
+            &Arc::new(OrderedMutex::new(Mempool::new())),
+            &Arc::new(OrderedMutex::new(State::new())),
+            &Arc::new(OrderedMutex::new(Scheduler::new())),
+            &Policy::default(),
+            &(Arc::new(SystemClock) as Arc<dyn Clock>),
+            &Arc::new(Mutex::new(BlockCache::new(1))),
+            &Arc::new(MemoryBudget::unlimited()),
+        );
+        let parent: H256 = [1u8; 32].into();
+        let orphan = generate_random_block(&parent);
+        context.process(Candidate { block: orphan.clone(), peer: peer.clone(), fetch_parent: true });
+        assert_eq!(orphan_buffer.lock().unwrap()[&parent].hash(), orphan.hash());
+        match bincode::deserialize(&peer_rx.try_recv().unwrap()).unwrap() {
+            Message::GetBlocks(hashes) => assert_eq!(hashes, vec![parent]),
+            _ => panic!("expected block request"),
+        }
+
+        // a block failing validation is not connected, nor are the orphans waiting for it
+        let invalid = generate_random_block(&chain.lock().unwrap().tip());
+        orphan_buffer.lock().unwrap().insert(invalid.hash(), generate_random_block(&invalid.hash()));
+        context.process(Candidate { block: invalid.clone(), peer: peer, fetch_parent: true });
+        assert!(!chain.lock().unwrap().blockmap.contains_key(&invalid.hash()));
+        assert_eq!(chain.lock().unwrap().height(), 0);
+    }
+
+    #[test]
+    fn mempool_follows_reorg() {
+        let key = key(1);
+        let owner = owner(&key);
+        let mut chain = Blockchain::new();
+        let mut state = funded(owner, &[1, 2], 10);
+        let mut mempool = Mempool::new();
+        let policy = Policy::default();
+        let genesis = chain.tip();
+
+        let parent = spend(&key, [1u8; 32].into(), owner, 10);
+        let child = spend(&key, parent.hash(), owner, 10);
+        let other = spend(&key, [2u8; 32].into(), owner, 10);
+        for transaction in &[&parent, &child, &other] {
+            mempool.accept(transaction, &state, &policy).unwrap();
+        }
+        let mut block = generate_random_block(&genesis);
+        block.content.data = vec![Hashed::new(parent.clone()), Hashed::new(child.clone()), Hashed::new(other.clone())];
+        let change = chainstate::insert_block(&block, &mut chain, &mut state);
+        update_mempool(&change, &chain, &mut mempool, &state, &policy);
+        assert_eq!(mempool.transactions().len(), 0);
+
+        // a fork stored on the side leaves the mempool alone
+        let conflict = spend(&key, [2u8; 32].into(), owner, 9);
+        let mut fork = generate_random_block(&genesis);
+        fork.content.data = vec![Hashed::new(parent.clone()), Hashed::new(conflict.clone())];
+        let change = chainstate::insert_block(&fork, &mut chain, &mut state);
+        update_mempool(&change, &chain, &mut mempool, &state, &policy);
+        assert_eq!(mempool.transactions().len(), 0);
+
+        // once it is the longest, the transactions only the old branch confirmed are back, unless
+        // the new branch spent their inputs
+        let next = generate_random_block(&fork.hash());
+        let change = chainstate::insert_block(&next, &mut chain, &mut state);
+        assert_eq!(change.disconnected, vec![block.hash()]);
+        update_mempool(&change, &chain, &mut mempool, &state, &policy);
+        assert!(mempool.contains(&child.hash()));
+        assert!(!mempool.contains(&parent.hash()));
+        assert!(!mempool.contains(&other.hash()));
+        assert!(!mempool.contains(&conflict.hash()));
+        assert_eq!(mempool.orphan_count(), 0);
+    }
+}
@@ -0,0 +1,543 @@
+//! Handlers of the messages peers send, one per message type. Each is given the context of the
+//! worker, the peer the message came from and the content of the message. `dispatch` is the
+//! table from message types to handlers: it matches every variant of `Message`, so a new
+//! message type does not build until it has a handler.
+
+use super::{Context, BAD_PROVENANCE_PENALTY};
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable, Hashed};
+use crate::crypto::merkle::MerkleProof;
+use crate::network::identity::SignedCheckpoint;
+use crate::network::message::{self, CompactBlock, Message, Version};
+use crate::network::peer;
+use crate::network::validator::Candidate;
+use crate::pool::{PoolJob, PoolShare};
+use crate::transaction::{AcceptError, SignedTransaction};
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Hand a decoded message to its handler. Compressed and signed messages are unwrapped by the
+/// worker loop before they get here.
+pub(super) fn dispatch(ctx: &mut Context, peer: &peer::Handle, msg: Message) {
+    match msg {
+        Message::Version(version) => on_version(ctx, peer, version),
+        Message::Addr(addrs) => on_addr(ctx, peer, addrs),
+        Message::SignedCheckpoint(checkpoint) => on_signed_checkpoint(ctx, peer, checkpoint),
+        Message::Originated(..) => unreachable!("signed announcements are unwrapped before dispatch"),
+        Message::Compressed(..) => unreachable!("compressed messages are unwrapped before dispatch"),
+        Message::Ping(nonce) => on_ping(peer, nonce),
+        Message::Pong(nonce) => on_pong(peer, nonce),
+        Message::NewBlockHashes(hashes) => on_new_block_hashes(ctx, peer, hashes),
+        Message::GetBlocks(hashes) => on_get_blocks(ctx, peer, hashes),
+        Message::Blocks(blocks) => on_blocks(ctx, peer, blocks),
+        Message::CompactBlock(compact) => on_compact_block(ctx, peer, compact),
+        Message::NewTransactionHashes(hashes) => on_new_transaction_hashes(ctx, peer, hashes),
+        Message::GetTransactions(hashes) => on_get_transactions(ctx, peer, hashes),
+        Message::GetTxProof(tx_hash) => on_get_tx_proof(ctx, peer, tx_hash),
+        Message::TxProof(tx_hash, block_hash, proof) => on_tx_proof(ctx, peer, tx_hash, block_hash, proof),
+        Message::Transactions(transactions) => on_transactions(ctx, peer, transactions),
+        Message::GetPoolWork => on_get_pool_work(ctx, peer),
+        Message::PoolWork(job) => on_pool_work(ctx, peer, job),
+        Message::PoolShare(share) => on_pool_share(ctx, peer, share),
+    }
+}
+
+fn on_version(ctx: &Context, peer: &peer::Handle, version: Version) {
+    info!(
+        "Peer {} is node {} running version {}, speaking protocol {}, with {:?}, at height {}",
+        peer.addr(), version.node_id, version.software, version.protocol, version.capabilities, version.height
+    );
+    peer.set_compact_blocks(version.capabilities.compact_blocks);
+    let (tip, chain_work) = (version.tip, version.chain_work);
+    ctx.versions.lock().unwrap().insert(peer.addr(), version);
+    // the connecting side speaks first
+    let (our_work, known_tip) = {
+        let chain_un = ctx.chain.lock().unwrap();
+        if peer.direction() == peer::Direction::Incoming {
+            peer.write(ctx.identity.version(&chain_un));
+        }
+        (chain_un.total_work(), chain_un.blockmap.contains_key(&tip))
+    };
+    if !ctx.server.advertised().is_empty() {
+        peer.write(Message::Addr(ctx.server.advertised().to_vec()));
+    }
+    if !ctx.serves_blocks(&peer.addr()) {
+        return;
+    }
+    // catch up from the peer with the most work, fetching its tip then the parents it lacks,
+    // so that a longer chain of less work does not draw the download
+    let mut scheduler = ctx.scheduler.lock().unwrap();
+    scheduler.set_chain_work(peer, chain_work);
+    if chain_work > our_work && !known_tip && scheduler.sync_peer() == Some(peer.addr()) {
+        info!("Syncing from peer {}, whose chain has {} work against our {}", peer.addr(), chain_work, our_work);
+        scheduler.announce(&[tip], peer);
+        drop(scheduler);
+        ctx.request_blocks();
+    }
+}
+
+fn on_addr(ctx: &Context, peer: &peer::Handle, addrs: Vec<SocketAddr>) {
+    match ctx.node_id(&peer.addr()) {
+        Some(node_id) => {
+            debug!("Node {} listens at {:?}", node_id, addrs);
+            ctx.addresses.lock().unwrap().insert(node_id, &addrs);
+        }
+        None => debug!("Ignoring addresses from peer {} before its Version", peer.addr()),
+    }
+}
+
+fn on_signed_checkpoint(ctx: &Context, peer: &peer::Handle, checkpoint: SignedCheckpoint) {
+    let authority = match ctx.checkpoint_authority {
+        Some(authority) if authority == checkpoint.authority => authority,
+        _ => {
+            debug!("Ignoring checkpoint of untrusted node {} from peer {}", checkpoint.authority, peer.addr());
+            return;
+        }
+    };
+    if !checkpoint.verify(&authority) {
+        warn!("Invalid checkpoint signature from peer {}", peer.addr());
+        ctx.reject(peer, BAD_PROVENANCE_PENALTY);
+        return;
+    }
+    let mut chain_un = ctx.chain.lock().unwrap();
+    if !chain_un.blockmap.contains_key(&checkpoint.block) {
+        drop(chain_un);
+        // the authority signs again later, by then the block is here
+        if ctx.serves_blocks(&peer.addr()) {
+            ctx.scheduler.lock().unwrap().announce(&[checkpoint.block], peer);
+            ctx.request_blocks();
+        }
+        return;
+    }
+    if chain_un.set_checkpoint(&checkpoint.block) {
+        info!("Checkpoint at block {}, height {}", checkpoint.block, chain_un.height_of(&checkpoint.block).unwrap());
+        drop(chain_un);
+        ctx.server.broadcast(Message::SignedCheckpoint(checkpoint));
+    } else if !chain_un.is_in_longest_chain(&checkpoint.block) {
+        warn!("Checkpoint at block {} outside the longest chain", checkpoint.block);
+    }
+}
+
+fn on_ping(peer: &peer::Handle, nonce: String) {
+    debug!("Ping: {}", nonce);
+    peer.write(Message::Pong(nonce));
+}
+
+fn on_pong(peer: &peer::Handle, nonce: String) {
+    if peer.pong(&nonce, Instant::now()) {
+        debug!("Pong from peer {}, latency {:?}", peer.addr(), peer.latency().unwrap());
+    } else {
+        debug!("Unsolicited pong from peer {}: {}", peer.addr(), nonce);
+    }
+}
+
+fn on_new_block_hashes(ctx: &Context, peer: &peer::Handle, blockhashes: Vec<H256>) {
+    println!("Received NewBlockHashes");
+    peer.mark_known(&blockhashes);
+    let unknown: Vec<H256> = {
+        let chain_un = ctx.chain.lock().unwrap();
+        blockhashes.into_iter().filter(|hash| !chain_un.blockmap.contains_key(hash)).collect()
+    };
+    if !ctx.serves_blocks(&peer.addr()) {
+        debug!("Not asking peer {} for blocks it does not keep", peer.addr());
+        return;
+    }
+    ctx.scheduler.lock().unwrap().announce(&unknown, peer);
+    ctx.request_blocks();
+}
+
+fn on_get_blocks(ctx: &Context, peer: &peer::Handle, blockhashes: Vec<H256>) {
+    println!("Received GetBlocks");
+    peer.mark_known(&blockhashes);
+    let mut found: Vec<Option<Arc<Vec<u8>>>> = {
+        let mut cache = ctx.block_cache.lock().unwrap();
+        blockhashes.iter().map(|hash| cache.get(hash)).collect()
+    };
+    // only the blocks missing from the cache need the chain
+    if found.iter().any(|block| block.is_none()) {
+        let chain_un = ctx.chain.lock().unwrap();
+        let mut cache = ctx.block_cache.lock().unwrap();
+        for (hash, block) in blockhashes.iter().zip(found.iter_mut()) {
+            if block.is_none() {
+                *block = chain_un.blockmap.get(hash).map(|b| cache.insert(b));
+            }
+        }
+    }
+    let valid_blocks: Vec<Arc<Vec<u8>>> = found.into_iter().flatten().collect();
+    ctx.write_compressible(peer, "blocks", message::serialize_blocks(&valid_blocks));
+}
+
+fn on_blocks(ctx: &mut Context, peer: &peer::Handle, blocks: Vec<Block>) {
+    println!("Received Blocks");
+    peer.mark_known(&blocks.iter().map(|block| block.hash()).collect::<Vec<H256>>());
+    {
+        let mut scheduler = ctx.scheduler.lock().unwrap();
+        for block in &blocks {
+            scheduler.received(&block.hash());
+        }
+    }
+    let fetch_parent = ctx.serves_blocks(&peer.addr());
+    for block in blocks {
+        ctx.num_blocks += 1;
+        // blocks from peers with a fast clock may appear to come from the future
+        ctx.delay_sum += ctx.clock.now().saturating_sub(block.header.timestamp);
+        println!("{:?} received by the worker. The sum of block delay is {:?} milliseconds.", ctx.num_blocks, ctx.delay_sum);
+        ctx.validator.submit(Candidate { block: block, peer: peer.clone(), fetch_parent: fetch_parent });
+    }
+    ctx.request_blocks();
+}
+
+fn on_compact_block(ctx: &Context, peer: &peer::Handle, compact: CompactBlock) {
+    let hash = compact.header.hash();
+    peer.mark_known(&[hash]);
+    peer.mark_known(&compact.tx_hashes);
+    let parent_known = {
+        let chain_un = ctx.chain.lock().unwrap();
+        if chain_un.blockmap.contains_key(&hash) {
+            return;
+        }
+        chain_un.blockmap.contains_key(&compact.header.parent)
+    };
+    let block = if parent_known {
+        compact.reconstruct(|tx_hash| ctx.txpool.get(tx_hash).map(|tx| Hashed::clone(&tx)))
+    } else {
+        None
+    };
+    let fetch_parent = ctx.serves_blocks(&peer.addr());
+    match block {
+        Some(block) => {
+            debug!("Rebuilt compact block {} from peer {}", hash, peer.addr());
+            ctx.validator.submit(Candidate { block: block, peer: peer.clone(), fetch_parent: fetch_parent });
+        }
+        None if fetch_parent => {
+            // missing transactions or parent, download the body like an announced block
+            debug!("Fetching the body of compact block {} from peer {}", hash, peer.addr());
+            ctx.scheduler.lock().unwrap().announce(&[hash], peer);
+            ctx.request_blocks();
+        }
+        None => debug!("Not asking peer {} for blocks it does not keep", peer.addr()),
+    }
+}
+
+fn on_new_transaction_hashes(ctx: &Context, peer: &peer::Handle, txhashes: Vec<H256>) {
+    peer.mark_known(&txhashes);
+    let unknown: Vec<H256> = txhashes.into_iter().filter(|hash| !ctx.txpool.contains(hash)).collect();
+    peer.write(Message::GetTransactions(unknown));
+}
+
+fn on_get_transactions(ctx: &Context, peer: &peer::Handle, txhashes: Vec<H256>) {
+    peer.mark_known(&txhashes);
+    let valid_txs: Vec<SignedTransaction> =
+        txhashes.iter().filter_map(|hash| ctx.txpool.get(hash)).map(|tx| SignedTransaction::clone(&tx)).collect();
+    peer.write(Message::Transactions(valid_txs));
+}
+
+fn on_get_tx_proof(ctx: &Context, peer: &peer::Handle, tx_hash: H256) {
+    let chain_un = ctx.chain.lock().unwrap();
+    if let Some((block_hash, proof)) = chain_un.tx_proof(&tx_hash) {
+        peer.write(Message::TxProof(tx_hash, block_hash, proof));
+    }
+}
+
+fn on_tx_proof(ctx: &Context, peer: &peer::Handle, tx_hash: H256, block_hash: H256, proof: MerkleProof) {
+    let chain_un = ctx.chain.lock().unwrap();
+    match chain_un.blockmap.get(&block_hash) {
+        Some(block) if proof.verify(&block.header.merkle_root, &tx_hash) => {
+            debug!("Transaction {} proven in block {}", tx_hash, block_hash);
+        }
+        Some(_) => {
+            warn!("Invalid proof for transaction {} from peer {}", tx_hash, peer.addr());
+            peer.record_rejected();
+        }
+        None => {
+            debug!("Proof for transaction {} in unknown block {}", tx_hash, block_hash);
+        }
+    }
+}
+
+fn on_transactions(ctx: &Context, peer: &peer::Handle, transactions: Vec<SignedTransaction>) {
+    peer.mark_known(&transactions.iter().map(|tx| tx.hash()).collect::<Vec<H256>>());
+    let mut mempool_un = ctx.mempool.lock().unwrap();
+    let state_un = ctx.state.lock().unwrap();
+    for transaction in transactions {
+        match mempool_un.accept(&transaction, &state_un, &ctx.policy) {
+            Ok(hash) => {
+                let mut accepted = vec![hash];
+                accepted.extend(mempool_un.retry_orphans(&[hash], &state_un, &ctx.policy));
+                ctx.server.broadcast(Message::NewTransactionHashes(accepted));
+            }
+            Err(AcceptError::Duplicate) => {}
+            Err(AcceptError::Orphan) => {
+                debug!("Holding orphan transaction {} from peer {}", transaction.hash(), peer.addr());
+            }
+            Err(e) => {
+                println!("Invalid transaction received! Not adding to the mempool: {}", e);
+                peer.record_rejected();
+            }
+        }
+    }
+}
+
+fn on_get_pool_work(ctx: &Context, peer: &peer::Handle) {
+    match &ctx.miner {
+        Some(miner) => miner.add_pool_member(peer.clone()),
+        None => debug!("Peer {} asked for pool work, but no miner runs", peer.addr()),
+    }
+}
+
+fn on_pool_work(ctx: &Context, peer: &peer::Handle, job: PoolJob) {
+    match &ctx.miner {
+        Some(miner) => miner.set_pool_work(peer.addr(), job),
+        None => debug!("Pool job {} from peer {}, but no miner runs", job.id, peer.addr()),
+    }
+}
+
+fn on_pool_share(ctx: &Context, peer: &peer::Handle, share: PoolShare) {
+    match &ctx.miner {
+        Some(miner) => miner.submit_share(peer.addr(), share),
+        None => debug!("Pool share from peer {}, but no miner runs", peer.addr()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::context;
+    use super::*;
+    use crate::block::test::{generate_block_with_transactions, generate_random_block};
+    use crate::blockchain::Blockchain;
+    use crate::locks::OrderedMutex;
+    use crate::network::addresses::AddressBook;
+    use crate::network::identity::Identity;
+    use crate::network::peer::test::MockPeer;
+    use crate::network::server::{self, ControlSignal};
+    use crossbeam::channel;
+    use std::sync::Mutex;
+
+    /// A worker context, and a peer whose messages it handles.
+    struct Fixture {
+        ctx: Context,
+        identity: Arc<Identity>,
+        chain: Arc<OrderedMutex<Blockchain>>,
+        addresses: Arc<Mutex<AddressBook>>,
+        control_rx: mio_extras::channel::Receiver<ControlSignal>,
+        peer: MockPeer,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let (_msg_tx, msg_rx) = channel::unbounded();
+            let (server, control_rx) = server::test::handle();
+            let peer = MockPeer::new("127.0.0.1:6001".parse().unwrap(), peer::Direction::Incoming);
+            let chain = Arc::new(OrderedMutex::new(Blockchain::new()));
+            let ctx = context(msg_rx, &server, &chain);
+            Fixture {
+                identity: Arc::clone(&ctx.identity),
+                chain: chain,
+                addresses: Arc::clone(&ctx.addresses),
+                ctx: ctx,
+                control_rx: control_rx,
+                peer: peer,
+            }
+        }
+
+        fn handle(&mut self, msg: Message) {
+            dispatch(&mut self.ctx, &self.peer.handle, msg);
+        }
+
+        fn misbehaving_reports(&self) -> usize {
+            let mut reports = 0;
+            while let Ok(signal) = self.control_rx.try_recv() {
+                if let ControlSignal::Misbehaving(..) = signal {
+                    reports += 1;
+                }
+            }
+            reports
+        }
+    }
+
+    #[test]
+    fn version_answered_and_recorded() {
+        let mut fixture = Fixture::new();
+        let remote = Identity::random();
+        fixture.handle(remote.version(&Blockchain::new()));
+        assert_eq!(fixture.ctx.node_id(&fixture.peer.handle.addr()), Some(remote.id()));
+        match fixture.peer.next() {
+            Some(Message::Version(version)) => assert_eq!(version.node_id, fixture.identity.id()),
+            _ => panic!("expected version"),
+        }
+        // nothing to advertise
+        assert!(fixture.peer.next().is_none());
+    }
+
+    #[test]
+    fn sync_from_peer_with_most_work() {
+        let mut fixture = Fixture::new();
+        let extend = |chain: &mut Blockchain, blocks: usize, bits: u32| {
+            for _ in 0..blocks {
+                let mut block = generate_random_block(&chain.tip());
+                block.header.bits = bits;
+                chain.insert(&block);
+            }
+        };
+        extend(&mut fixture.chain.lock().unwrap(), 1, 0x1e010000);
+        let remote = Identity::random();
+        // a longer chain of less work is not worth downloading
+        let mut long = Blockchain::new();
+        extend(&mut long, 3, 0x207fffff);
+        fixture.handle(remote.version(&long));
+        assert!(matches!(fixture.peer.next(), Some(Message::Version(_))));
+        assert!(fixture.peer.next().is_none());
+        // a chain of more work is, from its tip
+        let mut heavy = Blockchain::new();
+        extend(&mut heavy, 2, 0x1e010000);
+        fixture.handle(remote.version(&heavy));
+        assert!(matches!(fixture.peer.next(), Some(Message::Version(_))));
+        match fixture.peer.next() {
+            Some(Message::GetBlocks(hashes)) => assert_eq!(hashes, vec![heavy.tip()]),
+            _ => panic!("expected block request"),
+        }
+    }
+
+    #[test]
+    fn addr_needs_version() {
+        let mut fixture = Fixture::new();
+        let listening: Vec<SocketAddr> = vec!["10.0.0.1:6000".parse().unwrap()];
+        fixture.handle(Message::Addr(listening.clone()));
+        assert!(fixture.addresses.lock().unwrap().nodes().is_empty());
+        let remote = Identity::random();
+        fixture.handle(remote.version(&Blockchain::new()));
+        fixture.handle(Message::Addr(listening.clone()));
+        assert_eq!(fixture.addresses.lock().unwrap().addresses(&remote.id()), listening);
+    }
+
+    #[test]
+    fn checkpoints_checked() {
+        let mut fixture = Fixture::new();
+        let authority = Identity::random();
+        let block: H256 = [1u8; 32].into();
+        // untrusted authorities are ignored, forged signatures penalized
+        fixture.handle(Message::SignedCheckpoint(authority.sign_checkpoint(block)));
+        assert_eq!(fixture.misbehaving_reports(), 0);
+        fixture.ctx.set_checkpoint_authority(authority.id());
+        let forged = SignedCheckpoint { authority: authority.id(), ..Identity::random().sign_checkpoint(block) };
+        fixture.handle(Message::SignedCheckpoint(forged));
+        assert_eq!(fixture.misbehaving_reports(), 1);
+        assert!(fixture.peer.next().is_none());
+        // the block of a valid checkpoint is fetched if missing
+        fixture.handle(Message::SignedCheckpoint(authority.sign_checkpoint(block)));
+        match fixture.peer.next() {
+            Some(Message::GetBlocks(hashes)) => assert_eq!(hashes, vec![block]),
+            _ => panic!("expected block request"),
+        }
+    }
+
+    #[test]
+    fn ping_answered_pong_checked() {
+        let mut fixture = Fixture::new();
+        fixture.handle(Message::Ping("alive".to_string()));
+        match fixture.peer.next() {
+            Some(Message::Pong(nonce)) => assert_eq!(nonce, "alive"),
+            _ => panic!("expected pong"),
+        }
+        fixture.peer.handle.ping(Instant::now());
+        let nonce = match fixture.peer.next() {
+            Some(Message::Ping(nonce)) => nonce,
+            _ => panic!("expected ping"),
+        };
+        fixture.handle(Message::Pong("unsolicited".to_string()));
+        assert!(fixture.peer.handle.latency().is_none());
+        fixture.handle(Message::Pong(nonce));
+        assert!(fixture.peer.handle.latency().is_some());
+    }
+
+    #[test]
+    fn unknown_blocks_requested() {
+        let mut fixture = Fixture::new();
+        let genesis = fixture.chain.lock().unwrap().tip();
+        let unknown: H256 = [1u8; 32].into();
+        fixture.handle(Message::NewBlockHashes(vec![genesis, unknown]));
+        match fixture.peer.next() {
+            Some(Message::GetBlocks(hashes)) => assert_eq!(hashes, vec![unknown]),
+            _ => panic!("expected block request"),
+        }
+    }
+
+    #[test]
+    fn known_blocks_served() {
+        let mut fixture = Fixture::new();
+        let genesis = fixture.chain.lock().unwrap().tip();
+        fixture.handle(Message::GetBlocks(vec![genesis, [1u8; 32].into()]));
+        match fixture.peer.next() {
+            Some(Message::Blocks(blocks)) => {
+                assert_eq!(blocks.iter().map(|block| block.hash()).collect::<Vec<H256>>(), vec![genesis])
+            }
+            _ => panic!("expected blocks"),
+        }
+    }
+
+    #[test]
+    fn blocks_counted_and_queued() {
+        let mut fixture = Fixture::new();
+        let genesis = fixture.chain.lock().unwrap().tip();
+        let blocks = vec![generate_random_block(&genesis), generate_random_block(&genesis)];
+        fixture.handle(Message::Blocks(blocks.clone()));
+        assert_eq!(fixture.ctx.num_blocks, 2);
+        let queued: Vec<H256> = fixture.ctx.validation_queue.try_iter().map(|candidate| candidate.block.hash()).collect();
+        assert_eq!(queued, blocks.iter().map(|block| block.hash()).collect::<Vec<H256>>());
+    }
+
+    #[test]
+    fn compact_block_of_known_block_ignored() {
+        let mut fixture = Fixture::new();
+        let genesis = {
+            let chain = fixture.chain.lock().unwrap();
+            chain.blockmap[&chain.tip()].clone()
+        };
+        fixture.handle(Message::CompactBlock(CompactBlock::new(&genesis, |_| false)));
+        assert!(fixture.peer.next().is_none());
+        assert!(fixture.ctx.validation_queue.try_recv().is_err());
+    }
+
+    #[test]
+    fn transactions_requested_and_served() {
+        let mut fixture = Fixture::new();
+        let hash: H256 = [1u8; 32].into();
+        fixture.handle(Message::NewTransactionHashes(vec![hash]));
+        match fixture.peer.next() {
+            Some(Message::GetTransactions(hashes)) => assert_eq!(hashes, vec![hash]),
+            _ => panic!("expected transaction request"),
+        }
+        // the mempool is empty
+        fixture.handle(Message::GetTransactions(vec![hash]));
+        match fixture.peer.next() {
+            Some(Message::Transactions(transactions)) => assert!(transactions.is_empty()),
+            _ => panic!("expected transactions"),
+        }
+    }
+
+    #[test]
+    fn invalid_transactions_rejected() {
+        let mut fixture = Fixture::new();
+        let genesis = fixture.chain.lock().unwrap().tip();
+        // spending outputs that do not exist
+        let block = generate_block_with_transactions(&genesis, 1);
+        let transactions: Vec<SignedTransaction> =
+            block.content.data.iter().map(|tx| SignedTransaction::clone(tx)).collect();
+        fixture.handle(Message::Transactions(transactions));
+        assert_eq!(fixture.peer.handle.message_stats().rejected, 1);
+        assert_eq!(fixture.ctx.txpool.len(), 0);
+    }
+
+    #[test]
+    fn tx_proofs_answered_and_checked() {
+        let mut fixture = Fixture::new();
+        let genesis = fixture.chain.lock().unwrap().tip();
+        fixture.handle(Message::GetTxProof([1u8; 32].into()));
+        assert!(fixture.peer.next().is_none());
+        let proof = MerkleProof { siblings: vec![], index: 0, leaf_count: 1 };
+        fixture.handle(Message::TxProof([1u8; 32].into(), genesis, proof));
+        assert_eq!(fixture.peer.handle.message_stats().rejected, 1);
+    }
+}
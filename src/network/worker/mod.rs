@@ -0,0 +1,679 @@
+mod handlers;
+
+use super::addresses::AddressBook;
+use super::block_cache::{self, BlockCache};
+use super::download::Scheduler;
+use super::identity::{Identity, NodeId};
+use super::message::{self, CompressionStats, CompressionSummary, Message, Version};
+use super::peer;
+use super::server;
+use super::validator::{self, Candidate};
+use crate::network::server::Handle as ServerHandle;
+use crossbeam::channel;
+use log::{error, info, warn};
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::clock::Clock;
+use crate::crypto::hash::H256;
+use crate::locks::OrderedMutex;
+use crate::memory::MemoryBudget;
+use crate::miner;
+use crate::transaction::{Mempool, State, TxPool};
+use crate::policy::Policy;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How often an idle worker checks the download scheduler for stalled requests.
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(1000);
+/// Misbehavior score for sending a message that cannot be decoded.
+const MALFORMED_MESSAGE_PENALTY: u32 = server::BAN_THRESHOLD;
+/// Misbehavior score for relaying an announcement with an invalid provenance signature.
+const BAD_PROVENANCE_PENALTY: u32 = server::BAN_THRESHOLD / 2;
+/// How long a worker that panicked waits before it starts over, so that a panic on every
+/// message does not spin.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct Context {
+    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    num_worker: usize,
+    server: ServerHandle,
+    chain: Arc<OrderedMutex<Blockchain>>,
+    orphan_buffer: Arc<OrderedMutex<HashMap<H256, Block>>>,
+    mempool: Arc<OrderedMutex<Mempool>>,
+    /// The mempool transactions, looked up without the mempool lock
+    txpool: Arc<TxPool>,
+    state: Arc<OrderedMutex<State>>,
+    scheduler: Arc<OrderedMutex<Scheduler>>,
+    policy: Policy,
+    identity: Arc<Identity>,
+    clock: Arc<dyn Clock>,
+    /// The Version messages the connected peers introduced themselves with
+    versions: Arc<Mutex<HashMap<SocketAddr, Version>>>,
+    addresses: Arc<Mutex<AddressBook>>,
+    /// Blocks served to peers without locking the chain, locked after it when both are
+    block_cache: Arc<Mutex<BlockCache>>,
+    /// The node whose checkpoints are trusted, if any
+    checkpoint_authority: Option<NodeId>,
+    /// Accounts the block cache and the orphan buffer
+    memory: Arc<MemoryBudget>,
+    /// Queues received blocks for the validation thread
+    validator: validator::Handle,
+    /// Drained by the validation thread once started
+    validation_queue: channel::Receiver<Candidate>,
+    compression: Arc<CompressionStats>,
+    /// Takes the pool messages, once set
+    miner: Option<miner::Handle>,
+    /// Blocks received by this worker, and the sum of their delays in milliseconds
+    num_blocks: usize,
+    delay_sum: u128,
+}
+
+pub fn new(
+    num_worker: usize,
+    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    server: &ServerHandle,
+    chain: &Arc<OrderedMutex<Blockchain>>,
+    orphan_buffer: &Arc<OrderedMutex<HashMap<H256, Block>>>,
+    mempool: &Arc<OrderedMutex<Mempool>>,
+    state: &Arc<OrderedMutex<State>>,
+    scheduler: &Arc<OrderedMutex<Scheduler>>,
+    policy: &Policy,
+    identity: &Arc<Identity>,
+    clock: &Arc<dyn Clock>,
+    addresses: &Arc<Mutex<AddressBook>>,
+) -> Context {
+    let (validator, validation_queue) = validator::queue();
+    Context {
+        msg_chan: msg_src,
+        num_worker,
+        server: server.clone(),
+        chain: Arc::clone(chain),
+        orphan_buffer: Arc::clone(orphan_buffer),
+        mempool: Arc::clone(mempool),
+        txpool: mempool.lock().unwrap().pool(),
+        state: Arc::clone(state),
+        scheduler: Arc::clone(scheduler),
+        policy: policy.clone(),
+        identity: Arc::clone(identity),
+        clock: Arc::clone(clock),
+        versions: Arc::new(Mutex::new(HashMap::new())),
+        addresses: Arc::clone(addresses),
+        block_cache: Arc::new(Mutex::new(BlockCache::new(block_cache::DEFAULT_CAPACITY))),
+        checkpoint_authority: None,
+        memory: Arc::new(MemoryBudget::unlimited()),
+        validator: validator,
+        validation_queue: validation_queue,
+        compression: Arc::new(CompressionStats::default()),
+        miner: None,
+        num_blocks: 0,
+        delay_sum: 0,
+    }
+}
+
+/// Send out the block requests the download scheduler has ready, and disconnect the peers
+/// that kept stalling, whose blocks go to other peers.
+pub(super) fn request_blocks(scheduler: &OrderedMutex<Scheduler>, server: &ServerHandle) {
+    let (requests, stalled) = {
+        let mut scheduler = scheduler.lock().unwrap();
+        (scheduler.schedule(), scheduler.take_stalled_peers())
+    };
+    for addr in stalled {
+        server.disconnect(addr);
+    }
+    for (peer, hashes) in requests {
+        peer.write(Message::GetBlocks(hashes));
+    }
+}
+
+/// Counts the worker threads still running, and how often they were restarted.
+#[derive(Clone)]
+pub struct Handle {
+    running: Arc<AtomicUsize>,
+    started: usize,
+    restarts: Arc<AtomicUsize>,
+    validator: validator::Handle,
+    compression: Arc<CompressionStats>,
+}
+
+impl Handle {
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn started(&self) -> usize {
+        self.started
+    }
+
+    /// Times a worker panicked and was started over.
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    /// Received blocks waiting for the validation thread.
+    pub fn blocks_validating(&self) -> usize {
+        self.validator.queued()
+    }
+
+    /// Bytes sent and received in compressed messages.
+    pub fn compression(&self) -> CompressionSummary {
+        self.compression.summary()
+    }
+}
+
+/// Decrements the running count when a worker thread ends, whether it returns or panics.
+struct RunningGuard(Arc<AtomicUsize>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Context {
+    /// Trust the checkpoints signed by a node, refusing reorgs past them.
+    pub fn set_checkpoint_authority(&mut self, authority: NodeId) {
+        self.checkpoint_authority = Some(authority);
+    }
+
+    /// Account the block cache and the orphan buffer against a budget shared with other pools.
+    pub fn set_memory_budget(&mut self, memory: &Arc<MemoryBudget>) {
+        self.block_cache.lock().unwrap().set_memory_budget(memory);
+        self.memory = Arc::clone(memory);
+    }
+
+    /// Hand the pool messages of peers to the miner, see `pool`.
+    pub fn set_miner(&mut self, miner: &miner::Handle) {
+        self.miner = Some(miner.clone());
+    }
+
+    /// Start the worker threads, and the thread validating the blocks they receive.
+    pub fn start(self) -> Handle {
+        validator::Context::new(
+            self.validation_queue.clone(),
+            &self.server,
+            &self.chain,
+            &self.orphan_buffer,
+            &self.mempool,
+            &self.state,
+            &self.scheduler,
+            &self.policy,
+            &self.clock,
+            &self.block_cache,
+            &self.memory,
+        )
+        .start();
+        let num_worker = self.num_worker;
+        let running = Arc::new(AtomicUsize::new(num_worker));
+        let restarts = Arc::new(AtomicUsize::new(0));
+        for i in 0..num_worker {
+            let cloned = self.clone();
+            let guard = RunningGuard(Arc::clone(&running));
+            let restarts = Arc::clone(&restarts);
+            thread::spawn(move || {
+                let _guard = guard;
+                cloned.supervise(i, &restarts);
+                warn!("Worker thread {} exited", i);
+            });
+        }
+        Handle {
+            running: running,
+            started: num_worker,
+            restarts: restarts,
+            validator: self.validator.clone(),
+            compression: Arc::clone(&self.compression),
+        }
+    }
+
+    /// Whether a peer can be asked for block bodies. Peers are assumed to until their Version
+    /// says otherwise.
+    fn serves_blocks(&self, addr: &SocketAddr) -> bool {
+        self.versions.lock().unwrap().get(addr).map_or(true, |version| version.capabilities.serves_blocks())
+    }
+
+    /// Whether a peer accepts compressed messages, which it has to say in its Version.
+    fn accepts_compression(&self, addr: &SocketAddr) -> bool {
+        self.versions.lock().unwrap().get(addr).map_or(false, |version| version.capabilities.compression)
+    }
+
+    /// Queue a serialized message of the given kind, compressed if the peer accepts it and it
+    /// gets smaller.
+    fn write_compressible(&self, peer: &peer::Handle, kind: &'static str, serialized: Vec<u8>) {
+        if self.accepts_compression(&peer.addr()) {
+            let compressed = message::compress(&serialized);
+            if compressed.len() < serialized.len() {
+                self.compression.record_sent(serialized.len(), compressed.len());
+                peer.write_serialized(kind, compressed);
+                return;
+            }
+        }
+        peer.write_serialized(kind, serialized);
+    }
+
+    /// Refuse a message of a peer, adding to its misbehavior score.
+    fn reject(&self, peer: &peer::Handle, score: u32) {
+        peer.record_rejected();
+        self.server.misbehaving(peer.addr(), score);
+    }
+
+    fn node_id(&self, addr: &SocketAddr) -> Option<NodeId> {
+        self.versions.lock().unwrap().get(addr).map(|version| version.node_id)
+    }
+
+    fn request_blocks(&self) {
+        request_blocks(&self.scheduler, &self.server);
+    }
+
+    /// Run the worker loop until the message channel closes, starting it over whenever it
+    /// panics, the message being handled lost. A panic taken holding a shared lock leaves it
+    /// poisoned, and the data behind it possibly half changed: the worker stops then rather than
+    /// panic again on every message, and the node reports itself unhealthy.
+    fn supervise(mut self, worker: usize, restarts: &AtomicUsize) {
+        while panic::catch_unwind(AssertUnwindSafe(|| self.worker_loop())).is_err() {
+            if let Some(lock) = self.poisoned_lock() {
+                error!("Worker thread {} panicked with the {} lock poisoned, stopping it", worker, lock);
+                return;
+            }
+            let total = restarts.fetch_add(1, Ordering::SeqCst) + 1;
+            error!("Worker thread {} panicked, restarting it ({} restarts so far)", worker, total);
+            thread::sleep(RESTART_DELAY);
+        }
+    }
+
+    /// The first of the locks shared with the other threads that a panic poisoned, if any.
+    fn poisoned_lock(&self) -> Option<&'static str> {
+        let locks = [
+            ("chain", self.chain.is_poisoned()),
+            ("orphan buffer", self.orphan_buffer.is_poisoned()),
+            ("scheduler", self.scheduler.is_poisoned()),
+            ("mempool", self.mempool.is_poisoned()),
+            ("state", self.state.is_poisoned()),
+            ("versions", self.versions.is_poisoned()),
+            ("address book", self.addresses.is_poisoned()),
+            ("block cache", self.block_cache.is_poisoned()),
+        ];
+        locks.iter().find(|(_, poisoned)| *poisoned).map(|(name, _)| *name)
+    }
+
+    fn worker_loop(&mut self) {
+        loop {
+            let (msg, peer) = match self.msg_chan.recv_timeout(SCHEDULE_INTERVAL) {
+                Ok(msg) => msg,
+                Err(channel::RecvTimeoutError::Timeout) => {
+                    self.request_blocks();
+                    continue;
+                }
+                Err(channel::RecvTimeoutError::Disconnected) => return,
+            };
+            self.handle(&msg, &peer);
+        }
+    }
+
+    /// Handle a message received from a peer.
+    fn handle(&mut self, bytes: &[u8], peer: &peer::Handle) {
+        if let Some(msg) = self.decode(bytes, peer) {
+            handlers::dispatch(self, peer, msg);
+        }
+    }
+
+    /// Decode a message from a peer, decompressed and with the provenance of signed
+    /// announcements checked, ready for its handler. Peers sending messages that fail to decode
+    /// are penalized.
+    fn decode(&self, bytes: &[u8], peer: &peer::Handle) -> Option<Message> {
+        let msg = match message::decode(bytes) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Malformed message from peer {}: {}", peer.addr(), e);
+                self.reject(peer, MALFORMED_MESSAGE_PENALTY);
+                return None;
+            }
+        };
+        let msg = match msg {
+            Message::Compressed(payload) => {
+                let inner = message::decompress(&payload)
+                    .and_then(|raw| message::decode(&raw).ok().map(|inner| (inner, raw.len())));
+                match inner {
+                    Some((inner, raw_len)) if inner.is_compressible() => {
+                        self.compression.record_received(raw_len, payload.len());
+                        inner
+                    }
+                    _ => {
+                        warn!("Malformed compressed message from peer {}", peer.addr());
+                        self.reject(peer, MALFORMED_MESSAGE_PENALTY);
+                        return None;
+                    }
+                }
+            }
+            msg => msg,
+        };
+        peer.record_received(msg.kind(), bytes.len());
+        match msg {
+            Message::Originated(inner, provenance) => {
+                if !provenance.verify(&inner) {
+                    warn!("Invalid provenance from peer {}", peer.addr());
+                    self.reject(peer, BAD_PROVENANCE_PENALTY);
+                    return None;
+                }
+                let via = self.node_id(&peer.addr());
+                info!("Announcement originated by node {} received from peer {} (node {:?})", provenance.origin, peer.addr(), via);
+                match *inner {
+                    Message::NewBlockHashes(_) | Message::NewTransactionHashes(_) => Some(*inner),
+                    _ => {
+                        warn!("Peer {} sent a signed message that is not an announcement", peer.addr());
+                        self.reject(peer, BAD_PROVENANCE_PENALTY);
+                        None
+                    }
+                }
+            }
+            msg => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::crypto::hash::Hashable;
+    use crate::policy::Policy;
+    use std::time::Instant;
+
+    /// A worker context on `chain`, with the rest of the node state fresh.
+    pub(super) fn context(
+        msg_rx: channel::Receiver<(Vec<u8>, peer::Handle)>,
+        server: &ServerHandle,
+        chain: &Arc<OrderedMutex<Blockchain>>,
+    ) -> Context {
+        new(
+            1,
+            msg_rx,
+            server,
+            chain,
+            &Arc::new(OrderedMutex::new(HashMap::new())),
+            &Arc::new(OrderedMutex::new(Mempool::new())),
+            &Arc::new(OrderedMutex::new(State::new())),
+            &Arc::new(OrderedMutex::new(Scheduler::new())),
+            &Policy::default(),
+            &Arc::new(Identity::random()),
+            &(Arc::new(SystemClock) as Arc<dyn Clock>),
+            &Arc::new(Mutex::new(AddressBook::default())),
+        )
+    }
+
+    #[test]
+    fn survive_garbage() {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, control_rx) = server::test::handle();
+        let (peer, peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let mut worker = context(msg_rx, &server, &Arc::new(OrderedMutex::new(Blockchain::new())));
+        msg_tx.send((vec![255u8; 7], peer.clone())).unwrap();
+        msg_tx.send((vec![], peer.clone())).unwrap();
+        let ping = bincode::serialize(&Message::Ping("alive".to_string())).unwrap();
+        msg_tx.send((ping, peer.clone())).unwrap();
+        drop(msg_tx);
+        worker.worker_loop();
+
+        // the worker kept going and answered the ping
+        let pong: Message = bincode::deserialize(&peer_rx.try_recv().unwrap()).unwrap();
+        match pong {
+            Message::Pong(nonce) => assert_eq!(nonce, "alive"),
+            _ => panic!("expected pong"),
+        }
+        // and reported the peer once for each malformed message
+        let mut reports = 0;
+        while let Ok(signal) = control_rx.try_recv() {
+            if let server::ControlSignal::Misbehaving(addr, _) = signal {
+                assert_eq!(addr, peer.addr());
+                reports += 1;
+            }
+        }
+        assert_eq!(reports, 2);
+
+        // which the peer's statistics count apart from the ping and pong
+        let stats = peer.message_stats();
+        assert_eq!(stats.rejected, 2);
+        assert_eq!(stats.received["ping"].messages, 1);
+        assert_eq!(stats.sent["pong"].messages, 1);
+        assert_eq!(stats.received.len() + stats.sent.len(), 2);
+    }
+
+    #[test]
+    fn stop_after_panic_holding_a_lock() {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, _control_rx) = server::test::handle();
+        let (peer, _peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        // a transaction indexed in a block of the longest chain the chain lost makes proof
+        // requests panic with the chain locked
+        let mut broken = Blockchain::new();
+        let genesis = broken.tip();
+        broken.blockmap.remove(&genesis);
+        broken.txindex.insert([1u8; 32].into(), vec![genesis]);
+        let chain = Arc::new(OrderedMutex::new(broken));
+        let mut worker = context(msg_rx, &server, &chain);
+        worker.num_worker = 2;
+        let handle = worker.start();
+        let proof_request = bincode::serialize(&Message::GetTxProof([1u8; 32].into())).unwrap();
+        msg_tx.send((proof_request.clone(), peer.clone())).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.running() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // the worker stopped rather than restart into the poisoned lock
+        assert!(chain.is_poisoned());
+        assert_eq!(handle.running(), 1);
+        assert_eq!(handle.restarts(), 0);
+
+        // and so does the other one on its next use of the lock
+        msg_tx.send((proof_request, peer.clone())).unwrap();
+        while handle.running() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(handle.running(), 0);
+        assert_eq!(handle.restarts(), 0);
+    }
+
+    #[test]
+    fn version_handshake() {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, control_rx) = server::test::handle();
+        let (peer, peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let mut worker = context(msg_rx, &server, &Arc::new(OrderedMutex::new(Blockchain::new())));
+        let identity = Arc::clone(&worker.identity);
+        let remote = Identity::random();
+        msg_tx.send((bincode::serialize(&remote.version(&Blockchain::new())).unwrap(), peer.clone())).unwrap();
+        // a correctly signed announcement, and one whose signature does not match
+        let signed = remote.originate(Message::NewTransactionHashes(vec![[1u8; 32].into()]));
+        msg_tx.send((bincode::serialize(&signed).unwrap(), peer.clone())).unwrap();
+        let forged = match signed {
+            Message::Originated(_, provenance) => {
+                Message::Originated(Box::new(Message::NewTransactionHashes(vec![[2u8; 32].into()])), provenance)
+            }
+            _ => unreachable!(),
+        };
+        msg_tx.send((bincode::serialize(&forged).unwrap(), peer.clone())).unwrap();
+        drop(msg_tx);
+        worker.worker_loop();
+
+        assert_eq!(worker.node_id(&peer.addr()), Some(remote.id()));
+        match bincode::deserialize(&peer_rx.try_recv().unwrap()).unwrap() {
+            Message::Version(version) => assert_eq!(version.node_id, identity.id()),
+            _ => panic!("expected version"),
+        }
+        // the valid announcement was processed like a plain one
+        match bincode::deserialize(&peer_rx.try_recv().unwrap()).unwrap() {
+            Message::GetTransactions(hashes) => assert_eq!(hashes, vec![[1u8; 32].into()]),
+            _ => panic!("expected transaction request"),
+        }
+        assert!(peer_rx.try_recv().is_err());
+        let mut reports = 0;
+        while let Ok(signal) = control_rx.try_recv() {
+            if let server::ControlSignal::Misbehaving(..) = signal {
+                reports += 1;
+            }
+        }
+        assert_eq!(reports, 1);
+    }
+
+    #[test]
+    fn light_peer_not_asked_for_blocks() {
+        use super::message::Capabilities;
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, _control_rx) = server::test::handle();
+        let (light, light_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let (full, full_rx) = peer::test::handle("127.0.0.1:6002".parse().unwrap());
+        let mut worker = context(msg_rx, &server, &Arc::new(OrderedMutex::new(Blockchain::new())));
+        let remote = Identity::random();
+        let light_version = match remote.version(&Blockchain::new()) {
+            Message::Version(version) => {
+                Message::Version(Version { capabilities: Capabilities { light: true, ..version.capabilities }, ..version })
+            }
+            _ => unreachable!(),
+        };
+        msg_tx.send((bincode::serialize(&light_version).unwrap(), light.clone())).unwrap();
+        msg_tx.send((bincode::serialize(&Identity::random().version(&Blockchain::new())).unwrap(), full.clone())).unwrap();
+        let announce = |hash: u8| bincode::serialize(&Message::NewBlockHashes(vec![[hash; 32].into()])).unwrap();
+        msg_tx.send((announce(1), light.clone())).unwrap();
+        msg_tx.send((announce(2), full.clone())).unwrap();
+        drop(msg_tx);
+        worker.worker_loop();
+
+        let requests = |rx: &mio_extras::channel::Receiver<Vec<u8>>| {
+            let mut requests: Vec<Vec<H256>> = Vec::new();
+            while let Ok(msg) = rx.try_recv() {
+                if let Message::GetBlocks(hashes) = bincode::deserialize(&msg).unwrap() {
+                    requests.push(hashes);
+                }
+            }
+            requests
+        };
+        assert!(requests(&light_rx).is_empty());
+        assert_eq!(requests(&full_rx), vec![vec![[2u8; 32].into()]]);
+    }
+
+    #[test]
+    fn blocks_queued_for_validation() {
+        use crate::block::test::generate_random_block;
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, _control_rx) = server::test::handle();
+        let (peer, _peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let chain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let mut worker = context(msg_rx, &server, &chain);
+        let tip = chain.lock().unwrap().tip();
+        let blocks = vec![generate_random_block(&tip), generate_random_block(&tip)];
+        msg_tx.send((bincode::serialize(&Message::Blocks(blocks.clone())).unwrap(), peer.clone())).unwrap();
+        drop(msg_tx);
+        worker.worker_loop();
+
+        // the worker left the blocks to the validation thread, in order
+        assert_eq!(worker.validator.queued(), 2);
+        let queued: Vec<H256> = worker.validation_queue.try_iter().map(|candidate| candidate.block.hash()).collect();
+        assert_eq!(queued, blocks.iter().map(|block| block.hash()).collect::<Vec<H256>>());
+        assert_eq!(chain.lock().unwrap().height(), 0);
+    }
+
+    #[test]
+    fn compressed_blocks_unwrapped() {
+        use crate::block::test::generate_random_block;
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, control_rx) = server::test::handle();
+        let (peer, _peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let chain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let mut worker = context(msg_rx, &server, &chain);
+        let tip = chain.lock().unwrap().tip();
+        let blocks = vec![generate_random_block(&tip), generate_random_block(&tip)];
+        let serialized = bincode::serialize(&Message::Blocks(blocks.clone())).unwrap();
+        msg_tx.send((message::compress(&serialized), peer.clone())).unwrap();
+        // only bulk messages may come compressed
+        let ping = bincode::serialize(&Message::Ping("alive".to_string())).unwrap();
+        msg_tx.send((message::compress(&ping), peer.clone())).unwrap();
+        drop(msg_tx);
+        worker.worker_loop();
+
+        assert_eq!(worker.validator.queued(), 2);
+        assert_eq!(worker.compression.summary().received_raw, serialized.len() as u64);
+        let mut reports = 0;
+        while let Ok(signal) = control_rx.try_recv() {
+            if let server::ControlSignal::Misbehaving(..) = signal {
+                reports += 1;
+            }
+        }
+        assert_eq!(reports, 1);
+    }
+
+    #[test]
+    fn compact_blocks_rebuilt_or_fetched() {
+        use super::message::CompactBlock;
+        use crate::block::test::generate_block_with_transactions;
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server, _control_rx) = server::test::handle();
+        let (peer, peer_rx) = peer::test::handle("127.0.0.1:6001".parse().unwrap());
+        let chain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let mut worker = context(msg_rx, &server, &chain);
+        let tip = chain.lock().unwrap().tip();
+        // every transaction sent whole, and none, which the empty mempool cannot make up for
+        let complete = generate_block_with_transactions(&tip, 2);
+        let incomplete = generate_block_with_transactions(&tip, 2);
+        let compact = |block: &Block, relayed: bool| {
+            bincode::serialize(&Message::CompactBlock(CompactBlock::new(block, |_| relayed))).unwrap()
+        };
+        msg_tx.send((compact(&complete, false), peer.clone())).unwrap();
+        msg_tx.send((compact(&incomplete, true), peer.clone())).unwrap();
+        drop(msg_tx);
+        worker.worker_loop();
+
+        let queued: Vec<H256> = worker.validation_queue.try_iter().map(|candidate| candidate.block.hash()).collect();
+        assert_eq!(queued, vec![complete.hash()]);
+        match bincode::deserialize(&peer_rx.try_recv().unwrap()).unwrap() {
+            Message::GetBlocks(hashes) => assert_eq!(hashes, vec![incomplete.hash()]),
+            _ => panic!("expected block request"),
+        }
+    }
+
+    /// Handle the messages queued for a worker, without waiting for more.
+    fn handle_queued(worker: &mut Context) {
+        while let Ok((msg, peer)) = worker.msg_chan.try_recv() {
+            worker.handle(&msg, &peer);
+        }
+    }
+
+    #[test]
+    fn sync_over_memory_link() {
+        use crate::block::test::generate_random_block;
+        use super::peer::test::link;
+        let node = |chain: &Arc<OrderedMutex<Blockchain>>| {
+            let (msg_tx, msg_rx) = channel::unbounded();
+            let (server, _control_rx) = server::test::handle();
+            let worker = context(msg_rx, &server, chain);
+            (worker, msg_tx)
+        };
+        let ahead_chain = Arc::new(OrderedMutex::new(Blockchain::new()));
+        let genesis = ahead_chain.lock().unwrap().tip();
+        let first = generate_random_block(&genesis);
+        let second = generate_random_block(&first.hash());
+        ahead_chain.lock().unwrap().insert(&first);
+        ahead_chain.lock().unwrap().insert(&second);
+        let (mut ahead, ahead_tx) = node(&ahead_chain);
+        let (mut behind, behind_tx) = node(&Arc::new(OrderedMutex::new(Blockchain::new())));
+        let (to_ahead, to_behind) = link("127.0.0.1:6001".parse().unwrap(), "127.0.0.1:6002".parse().unwrap());
+
+        // the node ahead announces its blocks, the other asks for them and gets them
+        to_behind.handle.write(Message::NewBlockHashes(vec![first.hash(), second.hash()]));
+        assert_eq!(to_behind.relay(&behind_tx, &to_ahead), 1);
+        handle_queued(&mut behind);
+        assert_eq!(to_ahead.relay(&ahead_tx, &to_behind), 1);
+        handle_queued(&mut ahead);
+        assert_eq!(to_behind.relay(&behind_tx, &to_ahead), 1);
+        handle_queued(&mut behind);
+
+        let queued: Vec<H256> = behind.validation_queue.try_iter().map(|candidate| candidate.block.hash()).collect();
+        assert_eq!(queued, vec![first.hash(), second.hash()]);
+        assert!(to_ahead.sent().is_empty());
+        assert_eq!(to_ahead.handle.message_stats().received["blocks"].messages, 1);
+    }
+}
@@ -0,0 +1,735 @@
+//! A full node: the chain, the mempool, the wallet, the P2P server and its workers, the miner and
+//! the API server, wired together and started. The binary is a command line in front of
+//! `Node::builder()`, and tests and tools embed nodes in-process the same way. What a node takes
+//! from its environment, its block storage, its clock and its randomness, can be swapped for
+//! simulations and deterministic tests.
+
+use crate::amount::Amount;
+use crate::api::{self, Server as ApiServer};
+use crate::blockchain::Blockchain;
+use crate::chainparams;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::address::address_of;
+use crate::crypto::hash::{H160, Hashable};
+use crate::crypto::signer::{ExternalSigner, Signer};
+use crate::forks::ForkMonitor;
+use crate::locks::OrderedMutex;
+use crate::memory::MemoryBudget;
+use crate::miner;
+use crate::network::addresses::{self, AddressBook, Family};
+use crate::network::banlist;
+use crate::network::download::Scheduler;
+use crate::network::identity::{Identity, NodeId};
+use crate::network::message::Message;
+use crate::network::peer;
+use crate::network::server::{self, Outbound};
+use crate::network::worker;
+use crate::policy::Policy;
+use crate::storage::{BlockFiles, BlockStore};
+use crate::transaction::{Mempool, State};
+use crate::wallet::{self, CoinSelection, Wallet};
+use crossbeam::channel;
+use log::{debug, error, info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often `Node::run` writes the address book to the data directory.
+const PEERS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the demo transaction is put into the mempool, see `Builder::demo_transactions`.
+const DEMO_TRANSACTION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The settings of a node, defaulting to those of the command line. Build it with
+/// `Node::builder()`, once the network is selected.
+pub struct Builder {
+    p2p_addrs: Vec<SocketAddr>,
+    api_addr: Option<SocketAddr>,
+    known_peers: Vec<String>,
+    prefer_family: Option<Family>,
+    outbound: Outbound,
+    p2p_workers: usize,
+    identity: Option<Identity>,
+    data_dir: Option<PathBuf>,
+    sign_announcements: bool,
+    policy: Policy,
+    coin_selection: CoinSelection,
+    external_signer: Option<String>,
+    mining_address: Option<H160>,
+    serve_pool: bool,
+    join_pool: Option<SocketAddr>,
+    maturity: usize,
+    finality_depth: usize,
+    own_tx_quota: usize,
+    template_refresh: Duration,
+    checkpoint_authority: Option<NodeId>,
+    checkpoint_interval: Duration,
+    min_peers: usize,
+    max_memory: Option<usize>,
+    storage: Option<Arc<dyn BlockStore>>,
+    clock: Arc<dyn Clock>,
+    rng: Option<StdRng>,
+    demo_transactions: bool,
+}
+
+impl Builder {
+    fn new() -> Self {
+        let params = chainparams::current();
+        Builder {
+            p2p_addrs: vec![SocketAddr::from(([127, 0, 0, 1], params.default_p2p_port))],
+            api_addr: Some(SocketAddr::from(([127, 0, 0, 1], params.default_api_port))),
+            known_peers: vec![],
+            prefer_family: None,
+            outbound: Outbound::default(),
+            p2p_workers: 4,
+            identity: None,
+            data_dir: None,
+            sign_announcements: false,
+            policy: Policy::default(),
+            coin_selection: CoinSelection::default(),
+            external_signer: None,
+            mining_address: None,
+            serve_pool: false,
+            join_pool: None,
+            maturity: 1,
+            finality_depth: 6,
+            own_tx_quota: 0,
+            template_refresh: Duration::from_millis(1000),
+            checkpoint_authority: None,
+            checkpoint_interval: Duration::from_secs(30),
+            min_peers: 1,
+            max_memory: None,
+            storage: None,
+            clock: Arc::new(SystemClock),
+            rng: None,
+            demo_transactions: false,
+        }
+    }
+
+    /// Listen for peers at these addresses. Port 0 picks a free port, see `Node::p2p_addrs`.
+    pub fn p2p(mut self, addrs: &[SocketAddr]) -> Self {
+        self.p2p_addrs = addrs.to_vec();
+        self
+    }
+
+    /// Serve the API at this address, or not at all. Port 0 picks a free port, see
+    /// `Node::api_addr`.
+    pub fn api(mut self, addr: Option<SocketAddr>) -> Self {
+        self.api_addr = addr;
+        self
+    }
+
+    /// Connect to these peers at start, addresses or host names with a port, retrying until
+    /// they answer.
+    pub fn connect(mut self, peers: &[String]) -> Self {
+        self.known_peers = peers.to_vec();
+        self
+    }
+
+    /// Try the addresses of this family first when a peer has addresses of both.
+    pub fn prefer_family(mut self, family: Option<Family>) -> Self {
+        self.prefer_family = family;
+        self
+    }
+
+    pub fn outbound(mut self, outbound: Outbound) -> Self {
+        self.outbound = outbound;
+        self
+    }
+
+    pub fn p2p_workers(mut self, p2p_workers: usize) -> Self {
+        self.p2p_workers = p2p_workers;
+        self
+    }
+
+    /// The identity of the node, a fresh one if not given, derived from the generator of `rng`
+    /// if there is one.
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Store the blocks, the peers, the bans, the wallet labels and keys in this directory, loading
+    /// what a previous run stored there. Without it the node keeps everything in memory, and
+    /// does not store its blocks unless given a `storage`.
+    pub fn data_dir(mut self, dir: &Path) -> Self {
+        self.data_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Sign the announcements of the blocks and transactions created by the node.
+    pub fn sign_announcements(mut self, sign: bool) -> Self {
+        self.sign_announcements = sign;
+        self
+    }
+
+    /// The relay policy, also followed by the wallet and the miner.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn coin_selection(mut self, coin_selection: CoinSelection) -> Self {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    /// Add to the wallet the key of an external signer, an `http://` URL or a command line, see
+    /// `ExternalSigner`.
+    pub fn external_signer(mut self, spec: Option<&str>) -> Self {
+        self.external_signer = spec.map(String::from);
+        self
+    }
+
+    /// Pay the rewards of the mined blocks to this address, or else to the first key the wallet
+    /// generated, saved in the data directory. Without a data directory or a seeded `rng`, that
+    /// key is lost when the node stops. See `miner::Handle::set_payout` to change it later.
+    pub fn mining_address(mut self, address: Option<H160>) -> Self {
+        self.mining_address = address;
+        self
+    }
+
+    /// Hand out mining jobs to the peers asking for them, see `pool`.
+    pub fn pool(mut self, serve: bool) -> Self {
+        self.serve_pool = serve;
+        self
+    }
+
+    /// Mine the jobs of the pool at this address instead of blocks of our own, see `pool`.
+    pub fn join_pool(mut self, pool: Option<SocketAddr>) -> Self {
+        self.join_pool = pool;
+        self
+    }
+
+    /// Confirmations an output needs before the wallet spends it.
+    pub fn maturity(mut self, maturity: usize) -> Self {
+        self.maturity = maturity;
+        self
+    }
+
+    /// Confirmations after which transactions are reported final.
+    pub fn finality_depth(mut self, depth: usize) -> Self {
+        self.finality_depth = depth;
+        self
+    }
+
+    /// Bytes of each mined block reserved for the wallet's own transactions.
+    pub fn own_tx_quota(mut self, quota: usize) -> Self {
+        self.own_tx_quota = quota;
+        self
+    }
+
+    /// Bytes the mempool, the orphan pools and the block cache may hold together before
+    /// evicting their least valuable entries, unlimited with `None`.
+    pub fn max_memory(mut self, bytes: Option<usize>) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    /// How often the miner checks the mempool for transactions paying better than its block.
+    pub fn template_refresh(mut self, interval: Duration) -> Self {
+        self.template_refresh = interval;
+        self
+    }
+
+    /// Trust the checkpoints signed by this node, and sign them every `interval` if it is this
+    /// node.
+    pub fn checkpoint_authority(mut self, authority: Option<NodeId>, interval: Duration) -> Self {
+        self.checkpoint_authority = authority;
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    /// Peers required for `/readyz` to report the node ready.
+    pub fn min_peers(mut self, min_peers: usize) -> Self {
+        self.min_peers = min_peers;
+        self
+    }
+
+    /// Keep the blocks in this store rather than in the block files of the data directory.
+    pub fn storage(mut self, storage: Arc<dyn BlockStore>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// The clock of the chain, the mempool, the workers and the miner, the system's by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Draw the miner nonces, the wallet's coin selection and the identity if none is given
+    /// from this generator, so that a seeded node runs the same every time. Without it they are
+    /// drawn from the system's entropy.
+    pub fn rng(mut self, rng: StdRng) -> Self {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// Every 10 seconds, put a transaction spending the ICO output into the mempool and
+    /// announce it, for demonstrations.
+    pub fn demo_transactions(mut self, enabled: bool) -> Self {
+        self.demo_transactions = enabled;
+        self
+    }
+
+    /// Load what the data directory and the storage hold, and start the threads of the node.
+    pub fn start(mut self) -> io::Result<Node> {
+        // everything random is drawn from the generator in the same order on every run
+        let identity = match (self.identity.take(), &mut self.rng) {
+            (Some(identity), _) => identity,
+            (None, Some(rng)) => Identity::from_seed(&rng.gen()),
+            (None, None) => Identity::random(),
+        };
+        let miner_seed = self.rng.as_mut().map(|rng| rng.gen::<u64>());
+        let wallet_rng = self.rng.as_mut().map(|rng| StdRng::seed_from_u64(rng.gen()));
+        let identity = Arc::new(identity);
+        info!("Node id {}", identity.id());
+        let signer = if self.sign_announcements {
+            Some(identity.clone())
+        } else {
+            None
+        };
+
+        // create channels between server and worker
+        let (msg_tx, msg_rx) = channel::unbounded();
+
+        // start the p2p server
+        let (mut server_ctx, server) = server::new(&self.p2p_addrs, self.outbound, msg_tx, signer)?;
+        let p2p_addrs = server_ctx.local_addrs();
+        let scheduler_lock = Arc::new(OrderedMutex::new(Scheduler::new()));
+        server_ctx.set_scheduler(&scheduler_lock);
+        // keep out the peers banned by the previous run
+        if let Some(dir) = &self.data_dir {
+            let path = dir.join(banlist::BANS_FILE);
+            match server_ctx.load_bans(path.clone()) {
+                Ok(count) => info!("Loaded {} bans from {}", count, path.display()),
+                Err(e) => warn!("Error loading bans from {}, starting without them: {}", path.display(), e),
+            }
+        }
+        server_ctx.start()?;
+
+        let mut the_wallet = Wallet::new();
+        the_wallet.set_coin_selection(self.coin_selection);
+        the_wallet.set_maturity(self.maturity);
+        if let Some(rng) = wallet_rng {
+            the_wallet.set_rng(rng);
+        }
+        // the demo transactions spend the ICO output
+        the_wallet.add_key(Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap());
+        if let Some(spec) = &self.external_signer {
+            let signer = ExternalSigner::new(spec)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot reach external signer {}: {}", spec, e)))?;
+            info!("Wallet signing with the external key of {}", address_of(&signer.public_key_bytes()));
+            the_wallet.add_signer(Box::new(signer));
+        }
+        if let Some(dir) = &self.data_dir {
+            let labels_path = dir.join(wallet::LABELS_FILE);
+            the_wallet.load_labels(&labels_path).map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot load wallet labels from {}: {}", labels_path.display(), e))
+            })?;
+            let keys_path = dir.join(wallet::KEYS_FILE);
+            the_wallet.load_keys(&keys_path).map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot load wallet keys from {}: {}", keys_path.display(), e))
+            })?;
+        }
+        let payout = match self.mining_address {
+            Some(address) => address,
+            None => the_wallet
+                .first_address()
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot save the mining key: {}", e)))?,
+        };
+        info!("Mining rewards go to {}", payout);
+        let wallet_lock = Arc::new(OrderedMutex::new(the_wallet));
+        let clock = self.clock.clone();
+        let mut the_chain = Blockchain::new();
+        the_chain.set_finality_depth(self.finality_depth);
+        the_chain.subscribe(wallet_lock.clone());
+        let fork_monitor = Arc::new(Mutex::new(ForkMonitor::new()));
+        the_chain.subscribe(fork_monitor.clone());
+        let mut the_state = State::new();
+        let storage = match (self.storage.take(), &self.data_dir) {
+            (Some(storage), _) => Some(storage),
+            (None, Some(dir)) => {
+                let block_files = BlockFiles::open(&dir.join("blocks")).map_err(|e| {
+                    io::Error::new(e.kind(), format!("cannot open block files in {}: {}", dir.display(), e))
+                })?;
+                Some(Arc::new(Mutex::new(block_files)) as Arc<dyn BlockStore>)
+            }
+            (None, None) => None,
+        };
+        if let Some(storage) = storage {
+            // set before loading, forks of the stored blocks may be deeper than the state keeps
+            the_chain.set_undo_store(storage.clone());
+            let stats = storage.replay(&mut the_chain, &mut the_state, &*clock).map_err(|e| {
+                io::Error::new(e.kind(), format!("cannot load the stored blocks, try reindexing: {}", e))
+            })?;
+            info!("Loaded {} stored blocks, height {}", stats.connected, the_chain.height());
+            // subscribe after loading, the loaded blocks are already stored
+            the_chain.subscribe(storage);
+        }
+        let chain_lock = Arc::new(OrderedMutex::new(the_chain));
+        let buffer_lock = Arc::new(OrderedMutex::new(HashMap::new()));
+        let memory = Arc::new(match self.max_memory {
+            Some(bytes) => MemoryBudget::new(bytes),
+            None => MemoryBudget::unlimited(),
+        });
+        let mut the_mempool = Mempool::with_clock(&clock);
+        the_mempool.set_memory_budget(&memory);
+        the_mempool.subscribe(wallet_lock.clone());
+        let mempool_lock = Arc::new(OrderedMutex::new(the_mempool));
+        let state_lock = Arc::new(OrderedMutex::new(the_state));
+
+        // load the peers saved by the previous run
+        let mut address_book = AddressBook::new(self.prefer_family);
+        let peers_path = self.data_dir.as_ref().map(|dir| dir.join(addresses::PEERS_FILE));
+        if let Some(path) = &peers_path {
+            match address_book.load(path) {
+                Ok(count) => info!("Loaded {} peers from {}", count, path.display()),
+                Err(e) => warn!("Error loading peers from {}, starting without them: {}", path.display(), e),
+            }
+        }
+        let address_book = Arc::new(Mutex::new(address_book));
+
+        // the miner, started after the workers, which hand it the pool messages
+        let (miner_ctx, miner) = miner::new(
+            &server,
+            &chain_lock,
+            &mempool_lock,
+            &state_lock,
+            &self.policy,
+            &wallet_lock,
+            self.own_tx_quota,
+            self.template_refresh,
+            miner_seed,
+            &clock,
+        );
+        miner.set_payout(payout);
+        if self.serve_pool {
+            miner.serve_pool();
+        }
+        chain_lock.lock().unwrap().subscribe(Arc::new(miner.clone()));
+
+        // start the workers
+        let mut worker_ctx = worker::new(
+            self.p2p_workers,
+            msg_rx,
+            &server,
+            &chain_lock,
+            &buffer_lock,
+            &mempool_lock,
+            &state_lock,
+            &scheduler_lock,
+            &self.policy,
+            &identity,
+            &clock,
+            &address_book,
+        );
+        worker_ctx.set_memory_budget(&memory);
+        worker_ctx.set_miner(&miner);
+        // federated checkpoints
+        if let Some(authority) = self.checkpoint_authority {
+            worker_ctx.set_checkpoint_authority(authority);
+        }
+        let workers = worker_ctx.start();
+        if self.checkpoint_authority == Some(identity.id()) {
+            sign_checkpoints(&chain_lock, &server, &identity, self.checkpoint_interval);
+        }
+
+        if self.demo_transactions {
+            generate_demo_transactions(&mempool_lock, &state_lock, &self.policy, &server);
+        }
+
+        // start the miner
+        miner_ctx.start();
+
+        // connect to known peers, retrying until they answer, then to the saved ones, once
+        let saved_peers: Vec<Vec<SocketAddr>> = {
+            let address_book = address_book.lock().unwrap();
+            address_book.nodes().iter().map(|node| address_book.addresses(node)).collect()
+        };
+        if !self.known_peers.is_empty() || !saved_peers.is_empty() {
+            connect_peers(&server, &identity, &chain_lock, self.known_peers, saved_peers, self.prefer_family);
+        }
+
+        // start the API server
+        let api_addr = match self.api_addr {
+            Some(addr) => {
+                let readiness = api::Readiness {
+                    min_peers: self.min_peers,
+                    data_dir: self.data_dir.as_ref().map(|dir| dir.join("blocks")),
+                };
+                let addr = ApiServer::start(
+                    addr,
+                    &miner,
+                    &server,
+                    &chain_lock,
+                    &mempool_lock,
+                    &state_lock,
+                    &self.policy,
+                    &wallet_lock,
+                    &scheduler_lock,
+                    &workers,
+                    &readiness,
+                    &fork_monitor,
+                )?;
+                Some(addr)
+            }
+            None => None,
+        };
+
+        let node = Node {
+            identity: identity,
+            blockchain: chain_lock,
+            mempool: mempool_lock,
+            state: state_lock,
+            wallet: wallet_lock,
+            network: server,
+            workers: workers,
+            miner: miner,
+            forks: fork_monitor,
+            address_book: address_book,
+            peers_path: peers_path,
+            p2p_addrs: p2p_addrs,
+            api_addr: api_addr,
+        };
+        if let Some(pool) = self.join_pool {
+            node.join_pool(pool)?;
+        }
+        Ok(node)
+    }
+}
+
+/// A running node. Its threads run until the process exits.
+pub struct Node {
+    identity: Arc<Identity>,
+    blockchain: Arc<OrderedMutex<Blockchain>>,
+    mempool: Arc<OrderedMutex<Mempool>>,
+    state: Arc<OrderedMutex<State>>,
+    wallet: Arc<OrderedMutex<Wallet>>,
+    network: server::Handle,
+    workers: worker::Handle,
+    miner: miner::Handle,
+    forks: Arc<Mutex<ForkMonitor>>,
+    address_book: Arc<Mutex<AddressBook>>,
+    /// Where the address book is saved, if anywhere
+    peers_path: Option<PathBuf>,
+    p2p_addrs: Vec<SocketAddr>,
+    api_addr: Option<SocketAddr>,
+}
+
+impl Node {
+    /// The settings of a node on the selected network, see `chainparams::select`.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub fn identity(&self) -> &Arc<Identity> {
+        &self.identity
+    }
+
+    pub fn blockchain(&self) -> &Arc<OrderedMutex<Blockchain>> {
+        &self.blockchain
+    }
+
+    pub fn mempool(&self) -> &Arc<OrderedMutex<Mempool>> {
+        &self.mempool
+    }
+
+    pub fn state(&self) -> &Arc<OrderedMutex<State>> {
+        &self.state
+    }
+
+    pub fn wallet(&self) -> &Arc<OrderedMutex<Wallet>> {
+        &self.wallet
+    }
+
+    pub fn network(&self) -> &server::Handle {
+        &self.network
+    }
+
+    pub fn workers(&self) -> &worker::Handle {
+        &self.workers
+    }
+
+    pub fn miner(&self) -> &miner::Handle {
+        &self.miner
+    }
+
+    pub fn forks(&self) -> &Arc<Mutex<ForkMonitor>> {
+        &self.forks
+    }
+
+    /// The addresses the P2P server listens at.
+    pub fn p2p_addrs(&self) -> &[SocketAddr] {
+        &self.p2p_addrs
+    }
+
+    /// The address the API is served at, if it is.
+    pub fn api_addr(&self) -> Option<SocketAddr> {
+        self.api_addr
+    }
+
+    /// Connect to a peer and introduce the node to it.
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.introduce(addr)?;
+        Ok(())
+    }
+
+    /// Connect to a pool node and mine its jobs instead of blocks of our own.
+    pub fn join_pool(&self, addr: SocketAddr) -> io::Result<()> {
+        let peer = self.introduce(addr)?;
+        self.miner.join_pool(peer);
+        Ok(())
+    }
+
+    fn introduce(&self, addr: SocketAddr) -> io::Result<peer::Handle> {
+        let peer = self.network.connect(addr)?;
+        let version = self.identity.version(&self.blockchain.lock().unwrap());
+        peer.write(version);
+        Ok(peer)
+    }
+
+    /// Write the address book to the data directory, if there is one.
+    pub fn save_peers(&self) {
+        if let Some(path) = &self.peers_path {
+            if let Err(e) = self.address_book.lock().unwrap().save(path) {
+                warn!("Error saving peers to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Keep the process running, saving the address book as it goes since the node has no
+    /// shutdown hook.
+    pub fn run(&self) -> ! {
+        loop {
+            thread::sleep(PEERS_SAVE_INTERVAL);
+            self.save_peers();
+        }
+    }
+}
+
+/// Sign the tip as a checkpoint every `interval`, and broadcast it.
+fn sign_checkpoints(
+    chain: &Arc<OrderedMutex<Blockchain>>,
+    server: &server::Handle,
+    identity: &Arc<Identity>,
+    interval: Duration,
+) {
+    info!("Signing checkpoints every {} seconds", interval.as_secs());
+    let chain = chain.clone();
+    let server = server.clone();
+    let identity = identity.clone();
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        // signed again while the tip stays, for the nodes that missed it
+        let checkpoint = {
+            let mut chain = chain.lock().unwrap();
+            let tip = chain.tip();
+            chain.set_checkpoint(&tip);
+            identity.sign_checkpoint(tip)
+        };
+        debug!("Signed checkpoint at block {}", checkpoint.block);
+        server.broadcast(Message::SignedCheckpoint(checkpoint));
+    });
+}
+
+/// Connect to the known peers, retrying each until it answers, then to the saved ones not
+/// connected yet, once.
+fn connect_peers(
+    server: &server::Handle,
+    identity: &Arc<Identity>,
+    chain: &Arc<OrderedMutex<Blockchain>>,
+    known_peers: Vec<String>,
+    saved_peers: Vec<Vec<SocketAddr>>,
+    prefer_family: Option<Family>,
+) {
+    let server = server.clone();
+    let identity = identity.clone();
+    let chain = chain.clone();
+    thread::spawn(move || {
+        let connect = |addrs: &[SocketAddr]| {
+            addrs.iter().any(|addr| match server.connect(*addr) {
+                Ok(peer) => {
+                    info!("Connected to outgoing peer {}", addr);
+                    let version = identity.version(&chain.lock().unwrap());
+                    peer.write(version);
+                    true
+                }
+                Err(e) => {
+                    error!("Error connecting to peer {}: {}", addr, e);
+                    false
+                }
+            })
+        };
+        let mut connected = vec![];
+        for peer in known_peers {
+            // a host name may resolve to addresses of both families
+            let mut addrs: Vec<SocketAddr> = match peer.to_socket_addrs() {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    error!("Error parsing peer address {}: {}", &peer, e);
+                    continue;
+                }
+            };
+            addresses::sort_by_preference(&mut addrs, prefer_family);
+            while !connect(&addrs) {
+                error!("Error connecting to peer {}, retrying in one second", &peer);
+                thread::sleep(Duration::from_millis(1000));
+            }
+            connected.extend(addrs);
+        }
+        for addrs in saved_peers {
+            if !addrs.iter().any(|addr| connected.contains(addr)) {
+                connect(&addrs);
+            }
+        }
+    });
+}
+
+/// Pay 10000 from the ICO key to a fixed key every `DEMO_TRANSACTION_INTERVAL`, with a wallet of
+/// its own and through the checks of the mempool, and announce the transaction.
+fn generate_demo_transactions(
+    mempool: &Arc<OrderedMutex<Mempool>>,
+    state: &Arc<OrderedMutex<State>>,
+    policy: &Policy,
+    server: &server::Handle,
+) {
+    let mempool = mempool.clone();
+    let state = state.clone();
+    let policy = policy.clone();
+    let server = server.clone();
+    let recipient = address_of(Ed25519KeyPair::from_seed_unchecked(&[255u8; 32]).unwrap().public_key().as_ref());
+    let value = Amount::from(10000);
+    // the wallet only tracks the transactions it made, so that it does not spend their inputs
+    // again: it pays out of their change once confirmed
+    let mut wallet = Wallet::new();
+    wallet.add_key(Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap());
+    thread::spawn(move || loop {
+        thread::sleep(DEMO_TRANSACTION_INTERVAL);
+
+        let accepted = {
+            let mut mempool = mempool.lock().unwrap();
+            let state = state.lock().unwrap();
+            wallet
+                .create_transaction(recipient, value, &state, &policy)
+                .map_err(|e| e.to_string())
+                .and_then(|tx| mempool.accept(&tx, &state, &policy).map(|_| tx).map_err(|e| e.to_string()))
+        };
+        match accepted {
+            Ok(tx) => {
+                wallet.add_transaction(&tx);
+                info!("New demo transaction {} paying {} to {}", tx.hash(), value, recipient);
+                server.announce(Message::NewTransactionHashes(vec![tx.hash()]));
+            }
+            Err(e) => debug!("No demo transaction this time: {}", e),
+        }
+    });
+}
@@ -0,0 +1,127 @@
+use crate::amount::Amount;
+use crate::transaction::{self, SignedTransaction};
+use std::convert::TryFrom;
+
+/// Local relay rules for unconfirmed transactions. Unlike the consensus rules in `validation`,
+/// these only decide what enters our mempool and are never applied to transactions in blocks.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Minimum fee per 1000 bytes of serialized transaction.
+    pub min_fee_rate: u64,
+    /// Maximum serialized size of a transaction in bytes.
+    pub max_tx_size: usize,
+    /// Outputs worth less than this are rejected.
+    pub dust_limit: Amount,
+    /// Maximum number of inputs of a transaction.
+    pub max_inputs: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            min_fee_rate: 0,
+            max_tx_size: 1024,
+            dust_limit: Amount::from(1),
+            max_inputs: 16,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PolicyError {
+    NonStandardVersion,
+    TooLarge,
+    TooManyInputs,
+    Dust,
+    FeeTooLow,
+}
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            PolicyError::NonStandardVersion => "non-standard transaction version",
+            PolicyError::TooLarge => "transaction too large",
+            PolicyError::TooManyInputs => "too many inputs",
+            PolicyError::Dust => "dust output",
+            PolicyError::FeeTooLow => "fee rate too low",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl Policy {
+    /// The lowest fee relayed for a transaction of `size` serialized bytes.
+    pub fn min_fee(&self, size: usize) -> Amount {
+        let fee = ((self.min_fee_rate as u128) * (size as u128) + 999) / 1000;
+        Amount::from(u64::try_from(fee).unwrap_or(u64::MAX))
+    }
+
+    pub fn is_dust(&self, value: Amount) -> bool {
+        value < self.dust_limit
+    }
+
+    /// Check a consensus-valid transaction paying `fee` against the relay rules.
+    pub fn check(&self, tx: &SignedTransaction, fee: Amount) -> Result<(), PolicyError> {
+        if tx.transaction.version != transaction::VERSION {
+            return Err(PolicyError::NonStandardVersion);
+        }
+        let size = tx.serialized_size();
+        if size > self.max_tx_size {
+            return Err(PolicyError::TooLarge);
+        }
+        if tx.transaction.input.len() > self.max_inputs {
+            return Err(PolicyError::TooManyInputs);
+        }
+        if tx.transaction.output.iter().any(|txout| self.is_dust(txout.value)) {
+            return Err(PolicyError::Dust);
+        }
+        if fee < self.min_fee(size) {
+            return Err(PolicyError::FeeTooLow);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TxIn, TxOut, Witness};
+    use crate::crypto::signer::Algorithm;
+
+    fn generate_transaction(inputs: usize, value: u64) -> SignedTransaction {
+        let input = (0..inputs).map(|i| TxIn { previous_output: [0u8; 32].into(), index: i as u8 }).collect();
+        let output = vec![TxOut { recipient: [0u8; 20].into(), value: value.into() }];
+        let tx = Transaction { version: transaction::VERSION, input: input, output: output };
+        SignedTransaction { transaction: tx, algorithm: Algorithm::Ed25519, witness: Witness::Whole { public_key: vec![0; 32], signature: vec![0; 64] } }
+    }
+
+    #[test]
+    fn standard() {
+        let policy = Policy::default();
+        assert_eq!(policy.check(&generate_transaction(1, 100), Amount::ZERO), Ok(()));
+    }
+
+    #[test]
+    fn non_standard() {
+        let policy = Policy { min_fee_rate: 1000, ..Policy::default() };
+        let tx = generate_transaction(1, 100);
+        let size = tx.serialized_size() as u64;
+        assert_eq!(policy.check(&tx, (size - 1).into()), Err(PolicyError::FeeTooLow));
+        let size = Amount::from(size);
+        assert_eq!(policy.check(&tx, size), Ok(()));
+        assert_eq!(policy.check(&generate_transaction(1, 0), size), Err(PolicyError::Dust));
+        assert_eq!(policy.check(&generate_transaction(17, 100), Amount::from(size.as_u64() * 2)), Err(PolicyError::TooManyInputs));
+        let mut tx = generate_transaction(1, 100);
+        tx.transaction.version = 2;
+        assert_eq!(policy.check(&tx, size), Err(PolicyError::NonStandardVersion));
+    }
+
+    #[test]
+    fn min_fee_rounds_up() {
+        let policy = Policy { min_fee_rate: 1000, ..Policy::default() };
+        assert_eq!(policy.min_fee(150), 150.into());
+        let policy = Policy { min_fee_rate: 10, ..Policy::default() };
+        assert_eq!(policy.min_fee(150), 2.into());
+        assert_eq!(policy.min_fee(0), Amount::ZERO);
+    }
+}
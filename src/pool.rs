@@ -0,0 +1,309 @@
+//! Pooled mining, for studying how mining power concentrates: member nodes mine the blocks of a
+//! pool node instead of their own. The pool hands each member a job, the header of its next
+//! block with a range of extra nonces of the member's own, so that members do not repeat each
+//! other's work. A member sends back the nonces of every header meeting the share target,
+//! `2^SHARE_SHIFT` times easier than the block target, and the shares count its contribution.
+//! The pool connects the block when a share also meets the block target. The rewards go to the
+//! payout address of the pool; splitting them among the members, out of the share counts of
+//! `/pool/stats`, is left to the experiment.
+//!
+//! A member asks for work with `Message::GetPoolWork`, the pool answers with `PoolWork` and
+//! sends a new job on every new tip, and the member submits `PoolShare`s.
+
+use crate::block::{Block, Header};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::u256::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+
+/// Shares are `2^SHARE_SHIFT` times easier to find than blocks.
+pub const SHARE_SHIFT: u32 = 4;
+/// Extra nonces of each member. The first range is left to the pool's own miner.
+pub const EXTRA_NONCE_RANGE: u32 = 1 << 16;
+/// Jobs whose shares are accepted: the current one, and the ones before it that members may
+/// still be working on when a new one is sent.
+const RECENT_JOBS: usize = 3;
+
+/// Work handed to a member: a block header to find nonces for, with extra nonces from
+/// `extra_nonces.0` up to, not including, `extra_nonces.1`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoolJob {
+    pub id: u64,
+    pub header: Header,
+    pub extra_nonces: (u32, u32),
+    pub share_target: H256,
+}
+
+impl PoolJob {
+    /// The header of the job with these nonces.
+    pub fn header(&self, nonce: u32, extra_nonce: u32) -> Header {
+        Header { nonce: nonce, extra_nonce: extra_nonce, ..self.header.clone() }
+    }
+}
+
+/// The nonces of a header of a job meeting the share target.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolShare {
+    pub job: u64,
+    pub nonce: u32,
+    pub extra_nonce: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShareError {
+    /// A share from a peer that did not ask for work.
+    NotMember,
+    /// A share of a job that is not recent, or was never sent.
+    UnknownJob,
+    /// An extra nonce outside the range of the member.
+    OutOfRange,
+    /// A header not meeting the share target.
+    AboveTarget,
+    /// A share submitted before.
+    Duplicate,
+}
+
+impl ShareError {
+    /// Whether an honest member could not have sent the share. Shares of a job replaced in the
+    /// meantime, or sent after leaving, can.
+    pub fn is_invalid(&self) -> bool {
+        match self {
+            ShareError::NotMember | ShareError::UnknownJob => false,
+            ShareError::OutOfRange | ShareError::AboveTarget | ShareError::Duplicate => true,
+        }
+    }
+}
+
+impl std::fmt::Display for ShareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            ShareError::NotMember => "not a pool member",
+            ShareError::UnknownJob => "unknown job",
+            ShareError::OutOfRange => "extra nonce out of range",
+            ShareError::AboveTarget => "above the share target",
+            ShareError::Duplicate => "duplicate share",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// The contribution of a member, as served by `/pool/stats`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemberStats {
+    pub addr: SocketAddr,
+    pub shares: u64,
+    /// Shares that were also blocks
+    pub blocks: u64,
+    pub rejected: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The current job, `None` before the first member joined
+    pub job: Option<u64>,
+    pub members: Vec<MemberStats>,
+}
+
+struct Member {
+    /// Index of the range of extra nonces of the member
+    slot: u32,
+    stats: MemberStats,
+}
+
+struct Job {
+    id: u64,
+    /// The block the job completes, nonces aside
+    block: Block,
+    /// Hashes of the headers of the shares accepted
+    shares: HashSet<H256>,
+}
+
+/// The target of shares for blocks of `target`.
+pub fn share_target(target: &H256) -> H256 {
+    let target = U256::from(*target);
+    if target.bits() + SHARE_SHIFT > 256 {
+        U256::MAX.into()
+    } else {
+        (target << SHARE_SHIFT).into()
+    }
+}
+
+/// The members of a pool and the jobs they work on, kept by the miner of the pool node.
+#[derive(Default)]
+pub struct Coordinator {
+    members: HashMap<SocketAddr, Member>,
+    /// Most recent last
+    jobs: VecDeque<Job>,
+    next_id: u64,
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        Coordinator::default()
+    }
+
+    /// Add a member, which keeps its range if it already is one. Returns false once every range
+    /// of extra nonces is taken.
+    pub fn join(&mut self, addr: SocketAddr) -> bool {
+        if self.members.contains_key(&addr) {
+            return true;
+        }
+        let taken: HashSet<u32> = self.members.values().map(|member| member.slot).collect();
+        match (1..u32::MAX / EXTRA_NONCE_RANGE).find(|slot| !taken.contains(slot)) {
+            Some(slot) => {
+                let stats = MemberStats { addr: addr, shares: 0, blocks: 0, rejected: 0 };
+                self.members.insert(addr, Member { slot: slot, stats: stats });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a member, freeing its range of extra nonces.
+    pub fn leave(&mut self, addr: &SocketAddr) {
+        self.members.remove(addr);
+    }
+
+    pub fn members(&self) -> Vec<SocketAddr> {
+        self.members.keys().cloned().collect()
+    }
+
+    /// Start a job completing `block`, whose nonces the members fill. Returns its id.
+    pub fn new_job(&mut self, block: Block) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.push_back(Job { id: id, block: block, shares: HashSet::new() });
+        if self.jobs.len() > RECENT_JOBS {
+            self.jobs.pop_front();
+        }
+        id
+    }
+
+    /// The block of the current job, to check whether it is still worth mining.
+    pub fn current(&self) -> Option<&Block> {
+        self.jobs.back().map(|job| &job.block)
+    }
+
+    /// The current job of a member.
+    pub fn job(&self, addr: &SocketAddr) -> Option<PoolJob> {
+        let member = self.members.get(addr)?;
+        let job = self.jobs.back()?;
+        let start = member.slot * EXTRA_NONCE_RANGE;
+        Some(PoolJob {
+            id: job.id,
+            header: job.block.header.clone(),
+            extra_nonces: (start, start + EXTRA_NONCE_RANGE),
+            share_target: share_target(&job.block.header.target()),
+        })
+    }
+
+    /// Count a share of a member. Returns the block if the share meets the block target.
+    pub fn submit(&mut self, addr: &SocketAddr, share: PoolShare) -> Result<Option<Block>, ShareError> {
+        let member = self.members.get_mut(addr).ok_or(ShareError::NotMember)?;
+        let result = match self.jobs.iter_mut().find(|job| job.id == share.job) {
+            None => Err(ShareError::UnknownJob),
+            Some(job) => {
+                let start = member.slot * EXTRA_NONCE_RANGE;
+                let header = Header { nonce: share.nonce, extra_nonce: share.extra_nonce, ..job.block.header.clone() };
+                let hash = header.hash();
+                if share.extra_nonce < start || share.extra_nonce - start >= EXTRA_NONCE_RANGE {
+                    Err(ShareError::OutOfRange)
+                } else if hash > share_target(&header.target()) {
+                    Err(ShareError::AboveTarget)
+                } else if !job.shares.insert(hash) {
+                    Err(ShareError::Duplicate)
+                } else if header.meets_target() {
+                    Ok(Some(Block { header: header, content: job.block.content.clone() }))
+                } else {
+                    Ok(None)
+                }
+            }
+        };
+        match &result {
+            Ok(block) => {
+                member.stats.shares += 1;
+                member.stats.blocks += block.is_some() as u64;
+            }
+            Err(_) => member.stats.rejected += 1,
+        }
+        result
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let mut members: Vec<MemberStats> = self.members.values().map(|member| member.stats.clone()).collect();
+        members.sort_by_key(|member| member.addr);
+        PoolStats { job: self.jobs.back().map(|job| job.id), members: members }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    /// A header of `job` with a hash between the block target and `limit`.
+    fn find(job: &PoolJob, limit: &H256, block: bool) -> PoolShare {
+        for nonce in 0.. {
+            let header = job.header(nonce, job.extra_nonces.0);
+            if header.hash() <= *limit && header.meets_target() == block {
+                return PoolShare { job: job.id, nonce: nonce, extra_nonce: job.extra_nonces.0 };
+            }
+        }
+        unreachable!()
+    }
+
+    /// `Coordinator::submit`, with the hash of the block found.
+    fn submit(pool: &mut Coordinator, addr: &SocketAddr, share: PoolShare) -> Result<Option<H256>, ShareError> {
+        pool.submit(addr, share).map(|block| block.map(|block| block.hash()))
+    }
+
+    #[test]
+    fn shares() {
+        let mut block = generate_random_block(&[0u8; 32].into());
+        // one hash in 64 is a block, one in 4 a share
+        block.header.bits = H256::from(U256::MAX >> 6).to_compact();
+        let (alice, bob): (SocketAddr, SocketAddr) = ("127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap());
+        let mut pool = Coordinator::new();
+        assert!(pool.join(alice));
+        assert!(pool.join(bob));
+        assert!(pool.job(&alice).is_none());
+        let id = pool.new_job(block.clone());
+
+        // disjoint work, with an easier target for shares
+        let (job, other) = (pool.job(&alice).unwrap(), pool.job(&bob).unwrap());
+        assert_eq!(job.id, id);
+        assert_eq!(job.extra_nonces.1, other.extra_nonces.0);
+        assert!(job.extra_nonces.0 >= EXTRA_NONCE_RANGE);
+        assert!(job.share_target > job.header.target());
+
+        let share = find(&job, &job.share_target, false);
+        assert_eq!(submit(&mut pool, &alice, share), Ok(None));
+        assert_eq!(submit(&mut pool, &alice, share), Err(ShareError::Duplicate));
+        assert!(ShareError::Duplicate.is_invalid());
+        assert_eq!(submit(&mut pool, &bob, share), Err(ShareError::OutOfRange));
+        let weak = (0..).map(|nonce| job.header(nonce, job.extra_nonces.0)).find(|header| header.hash() > job.share_target).unwrap();
+        let weak = PoolShare { job: id, nonce: weak.nonce, extra_nonce: weak.extra_nonce };
+        assert_eq!(submit(&mut pool, &alice, weak), Err(ShareError::AboveTarget));
+        assert_eq!(submit(&mut pool, &"127.0.0.1:3".parse().unwrap(), share), Err(ShareError::NotMember));
+
+        let found = pool.submit(&alice, find(&job, &job.header.target(), true)).unwrap().unwrap();
+        assert!(found.header.meets_target());
+        assert_eq!(found.header.merkle_root, block.header.merkle_root);
+
+        // shares of old jobs are accepted for a while
+        for _ in 0..RECENT_JOBS {
+            pool.new_job(block.clone());
+        }
+        assert_eq!(submit(&mut pool, &bob, find(&other, &other.share_target, false)), Err(ShareError::UnknownJob));
+        let stats = pool.stats();
+        assert_eq!(stats.job, Some(id + RECENT_JOBS as u64));
+        assert_eq!(stats.members[0], MemberStats { addr: alice, shares: 2, blocks: 1, rejected: 2 });
+        assert_eq!(stats.members[1], MemberStats { addr: bob, shares: 0, blocks: 0, rejected: 2 });
+
+        // the range of a member that left goes to the next one
+        pool.leave(&alice);
+        assert!(pool.join("127.0.0.1:3".parse().unwrap()));
+        assert_eq!(pool.job(&"127.0.0.1:3".parse().unwrap()).unwrap().extra_nonces, job.extra_nonces);
+    }
+}
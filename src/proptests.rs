@@ -0,0 +1,161 @@
+//! Property tests for block validation and the UTXO state.
+
+use crate::amount::Amount;
+use crate::block::{self, Block, Content, Header};
+use crate::crypto::hash::{H160, H256, Hashable, Hashed};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::{self, SignedTransaction, State, Transaction, TxIn, TxOut};
+use crate::validation::{self, TxError};
+use crate::crypto::address::address_of;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+fn h256() -> impl Strategy<Value = H256> {
+    any::<[u8; 32]>().prop_map(H256::from)
+}
+
+fn h160() -> impl Strategy<Value = H160> {
+    any::<[u8; 20]>().prop_map(H160::from)
+}
+
+fn tx_in() -> impl Strategy<Value = TxIn> {
+    (h256(), any::<u8>()).prop_map(|(previous_output, index)| TxIn { previous_output, index })
+}
+
+fn tx_out() -> impl Strategy<Value = TxOut> {
+    (h160(), 0..1_000_000u64).prop_map(|(recipient, value)| TxOut { recipient, value: value.into() })
+}
+
+fn transaction() -> impl Strategy<Value = Transaction> {
+    (vec(tx_in(), 0..4), vec(tx_out(), 0..4)).prop_map(|(input, output)| Transaction {
+        version: transaction::VERSION,
+        input,
+        output,
+    })
+}
+
+/// A transaction signed by a key derived from the seed, which does not necessarily own its inputs.
+fn signed_transaction() -> impl Strategy<Value = SignedTransaction> {
+    (transaction(), any::<[u8; 32]>()).prop_map(|(tx, seed)| sign(tx, &seed))
+}
+
+fn header() -> impl Strategy<Value = Header> {
+    (h256(), any::<u64>(), any::<u32>(), any::<u32>(), any::<u32>(), any::<u64>(), h256()).prop_map(
+        |(parent, height, nonce, extra_nonce, bits, timestamp, merkle_root)| Header {
+            version: block::VERSION,
+            parent,
+            height,
+            nonce,
+            extra_nonce,
+            bits,
+            timestamp: timestamp as u128,
+            merkle_root,
+        },
+    )
+}
+
+fn block() -> impl Strategy<Value = Block> {
+    (header(), vec(signed_transaction(), 0..8)).prop_map(|(mut header, data)| {
+        let data: Vec<Hashed<SignedTransaction>> = data.into_iter().map(Hashed::new).collect();
+        header.merkle_root = MerkleTree::new(&data).root();
+        Block { header, content: Content { data } }
+    })
+}
+
+fn sign(tx: Transaction, seed: &[u8; 32]) -> SignedTransaction {
+    let key = Ed25519KeyPair::from_seed_unchecked(seed).unwrap();
+    SignedTransaction::new(tx, &key)
+}
+
+/// A state with a handful of outputs owned by the key derived from the seed, together with a
+/// valid transaction spending all of them, and the seed.
+fn funded_spend() -> impl Strategy<Value = (State, SignedTransaction, [u8; 32])> {
+    (any::<[u8; 32]>(), vec((h256(), 1..1_000_000u64), 1..6), vec(tx_out(), 1..4)).prop_map(
+        |(seed, funds, outputs)| {
+            let key = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+            let owner = address_of(key.public_key().as_ref());
+            let mut state = State::default();
+            let mut input = Vec::new();
+            let mut total = 0;
+            for (hash, value) in funds {
+                state.insert_utxo((hash, 0), (value.into(), owner));
+                input.push(TxIn { previous_output: hash, index: 0 });
+                total += value;
+            }
+            // split the inputs among the outputs, leaving the rest as fee
+            let share = total / outputs.len() as u64;
+            let output = outputs
+                .into_iter()
+                .map(|txout| TxOut { recipient: txout.recipient, value: txout.value.min(share.into()) })
+                .collect();
+            let tx = Transaction { version: transaction::VERSION, input, output };
+            (state, sign(tx, &seed), seed)
+        },
+    )
+}
+
+proptest! {
+    #[test]
+    fn apply_then_rollback_restores_state(
+        blk in block(),
+        extra in vec((h256(), any::<u8>(), 0..1_000_000u64, h160()), 0..16),
+    ) {
+        let mut state = State::default();
+        // make some of the block's inputs spendable
+        for tx in &blk.content.data {
+            for txin in tx.transaction.input.iter().step_by(2) {
+                state.insert_utxo((txin.previous_output, txin.index), (1.into(), [0u8; 20].into()));
+            }
+        }
+        for (hash, index, value, recipient) in extra {
+            state.insert_utxo((hash, index), (value.into(), recipient));
+        }
+        let before = state.clone();
+        let undo = state.apply_block(&blk);
+        state.rollback_block(&blk, &undo);
+        prop_assert_eq!(state, before);
+    }
+
+    #[test]
+    fn validation_is_deterministic(blk in block(), (state, spend, _) in funded_spend()) {
+        for tx in blk.content.data.iter().map(|tx| &**tx).chain(std::iter::once(&spend)) {
+            let first = validation::check_transaction(tx, &state);
+            let second = validation::check_transaction(tx, &state.clone());
+            prop_assert_eq!(first, second);
+        }
+        prop_assert_eq!(
+            validation::check_header_version(&blk.header, 1),
+            validation::check_header_version(&blk.header.clone(), 1)
+        );
+        prop_assert_eq!(blk.hash(), blk.clone().hash());
+    }
+
+    #[test]
+    fn valid_spend_accepted((state, spend, _) in funded_spend()) {
+        prop_assert!(validation::check_transaction(&spend, &state).is_ok());
+    }
+
+    #[test]
+    fn tampered_spend_rejected((state, mut spend, _) in funded_spend(), extra in 1..1_000u64) {
+        let value = spend.transaction.output[0].value;
+        spend.transaction.output[0].value = value.checked_add(extra.into()).unwrap();
+        prop_assert_eq!(validation::check_transaction(&spend, &state), Err(TxError::BadSignature));
+    }
+
+    #[test]
+    fn overspend_rejected((state, spend, seed) in funded_spend()) {
+        let mut tx = spend.transaction.clone();
+        let total = Amount::checked_sum(tx.input.iter().map(|txin| state.utxo[&(txin.previous_output, 0)].0)).unwrap();
+        tx.output[0].value = total.checked_add(1.into()).unwrap();
+        let resigned = sign(tx, &seed);
+        prop_assert_eq!(validation::check_transaction(&resigned, &state), Err(TxError::Overspend));
+    }
+
+    #[test]
+    fn missing_input_rejected((state, spend, _) in funded_spend()) {
+        let empty = State::default();
+        prop_assert_eq!(validation::check_transaction(&spend, &empty), Err(TxError::MissingInput));
+        prop_assert!(validation::check_transaction(&spend, &state).is_ok());
+    }
+}
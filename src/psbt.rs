@@ -0,0 +1,264 @@
+//! Partially signed transactions, after Bitcoin's PSBT: a transaction spending outputs of
+//! several owners, passed around for each of them to sign the inputs they own, then combined
+//! and finalized into a transaction signed per input.
+//!
+//! Outputs are owned by a single key, so a spend needs several signers when its inputs belong
+//! to different keys, possibly held by different nodes. The spent outputs travel with the
+//! transaction, so that each signer sees the values and owners of what it signs without
+//! looking them up.
+
+use crate::amount::Amount;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::signer::{Algorithm, Signer};
+use crate::transaction::{self, InputSignature, SigHash, SignedTransaction, Transaction};
+use crate::validation;
+use crate::crypto::address::address_of;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// An input of a partially signed transaction: the output it spends and, once its owner signed
+/// it, the signature.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PsbtInput {
+    pub value: Amount,
+    pub owner: H160,
+    pub signature: Option<InputSignature>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    pub transaction: Transaction,
+    /// One entry per input of the transaction, in order
+    pub inputs: Vec<PsbtInput>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PsbtError {
+    /// A transaction without inputs or outputs.
+    Empty,
+    /// An input spending an output that does not exist.
+    MissingInput,
+    /// Outputs worth more than the inputs.
+    Overspend,
+    /// Combining partially signed versions of different transactions.
+    DifferentTransaction,
+    /// Finalizing with inputs not signed yet.
+    Incomplete,
+    /// A signature that does not match its input, see `validation::check_transaction`.
+    Invalid(validation::TxError),
+    /// Not the encoding of a partially signed transaction.
+    Undecodable,
+    /// A key held by a device that did not sign.
+    SignerFailed,
+}
+
+impl std::fmt::Display for PsbtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PsbtError::Empty => write!(f, "no inputs or no outputs"),
+            PsbtError::MissingInput => write!(f, "input does not exist"),
+            PsbtError::Overspend => write!(f, "outputs exceed inputs"),
+            PsbtError::DifferentTransaction => write!(f, "not the same transaction"),
+            PsbtError::Incomplete => write!(f, "inputs left to sign"),
+            PsbtError::Invalid(e) => write!(f, "invalid signature: {}", e),
+            PsbtError::Undecodable => write!(f, "undecodable partially signed transaction"),
+            PsbtError::SignerFailed => write!(f, "signer failed"),
+        }
+    }
+}
+
+impl Psbt {
+    /// Start a partially signed transaction, with `spent` giving the output each input spends,
+    /// from the UTXO set and the mempool.
+    pub fn new<F>(transaction: Transaction, spent: F) -> Result<Self, PsbtError>
+    where
+        F: Fn(&(H256, u8)) -> Option<(Amount, H160)>,
+    {
+        if transaction.input.is_empty() || transaction.output.is_empty() {
+            return Err(PsbtError::Empty);
+        }
+        let mut inputs = Vec::with_capacity(transaction.input.len());
+        for txin in &transaction.input {
+            let (value, owner) = spent(&(txin.previous_output, txin.index)).ok_or(PsbtError::MissingInput)?;
+            inputs.push(PsbtInput { value: value, owner: owner, signature: None });
+        }
+        let psbt = Psbt { transaction: transaction, inputs: inputs };
+        psbt.fee().ok_or(PsbtError::Overspend)?;
+        Ok(psbt)
+    }
+
+    /// The hash of the unsigned transaction, the same for every partially signed version.
+    pub fn id(&self) -> H256 {
+        self.transaction.hash()
+    }
+
+    /// The fee the transaction pays, `None` if it overspends.
+    pub fn fee(&self) -> Option<Amount> {
+        let input = Amount::checked_sum(self.inputs.iter().map(|input| input.value))?;
+        let output = Amount::checked_sum(self.transaction.output.iter().map(|txout| txout.value))?;
+        input.checked_sub(output)
+    }
+
+    /// Sign the unsigned inputs owned by the signer. Returns the number of inputs signed, or an
+    /// error if the signer failed, keeping the inputs it signed before.
+    pub fn sign(&mut self, signer: &dyn Signer, sighash: SigHash) -> Result<usize, PsbtError> {
+        let owner = address_of(&signer.public_key_bytes());
+        let mut signed = 0;
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].owner != owner || self.inputs[index].signature.is_some() {
+                continue;
+            }
+            match transaction::sign_input(&self.transaction, index, sighash, signer) {
+                Ok(Some(signature)) => {
+                    self.inputs[index].signature = Some(signature);
+                    signed += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Error signing input {} of {}: {}", index, self.id(), e);
+                    return Err(PsbtError::SignerFailed);
+                }
+            }
+        }
+        Ok(signed)
+    }
+
+    /// Add the signatures of another partially signed version of the same transaction.
+    pub fn combine(&mut self, other: &Psbt) -> Result<(), PsbtError> {
+        if other.id() != self.id() || other.inputs.len() != self.inputs.len() {
+            return Err(PsbtError::DifferentTransaction);
+        }
+        for (input, theirs) in self.inputs.iter_mut().zip(&other.inputs) {
+            if input.signature.is_none() {
+                input.signature = theirs.signature.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether every input is signed.
+    pub fn is_complete(&self) -> bool {
+        self.inputs.iter().all(|input| input.signature.is_some())
+    }
+
+    /// The transaction signed per input, checked against the outputs it spends.
+    pub fn finalize(&self) -> Result<SignedTransaction, PsbtError> {
+        let signatures: Vec<InputSignature> =
+            self.inputs.iter().map(|input| input.signature.clone()).collect::<Option<_>>().ok_or(PsbtError::Incomplete)?;
+        let signed = SignedTransaction::with_input_signatures(self.transaction.clone(), Algorithm::Ed25519, &signatures);
+        let mut state = transaction::State::default();
+        for (txin, input) in self.transaction.input.iter().zip(&self.inputs) {
+            state.insert_utxo((txin.previous_output, txin.index), (input.value, input.owner));
+        }
+        validation::check_transaction(&signed, &state).map_err(PsbtError::Invalid)?;
+        Ok(signed)
+    }
+
+    /// Hex-encoded serialization, the form exchanged between signers.
+    pub fn to_hex(&self) -> String {
+        hex::encode(bincode::serialize(self).unwrap())
+    }
+
+    pub fn from_hex(encoded: &str) -> Result<Self, PsbtError> {
+        let raw = hex::decode(encoded).map_err(|_| PsbtError::Undecodable)?;
+        let psbt: Psbt = bincode::deserialize(&raw).map_err(|_| PsbtError::Undecodable)?;
+        if psbt.inputs.len() != psbt.transaction.input.len() {
+            return Err(PsbtError::Undecodable);
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::{key, owner};
+    use crate::transaction::{TxIn, TxOut, Witness};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn two_signers() {
+        let alice = key(1);
+        let bob = key(2);
+        let owners = [owner(&alice), owner(&bob)];
+        let spent = |outpoint: &(H256, u8)| match outpoint.0.as_ref()[0] {
+            1 => Some((Amount::from(100), owners[0])),
+            2 => Some((Amount::from(50), owners[1])),
+            _ => None,
+        };
+        let tx = Transaction {
+            version: transaction::VERSION,
+            input: vec![
+                TxIn { previous_output: [1u8; 32].into(), index: 0 },
+                TxIn { previous_output: [2u8; 32].into(), index: 0 },
+            ],
+            output: vec![TxOut { recipient: [3u8; 20].into(), value: 140.into() }],
+        };
+        let mut missing = tx.clone();
+        missing.input[1].previous_output = [9u8; 32].into();
+        assert_eq!(Psbt::new(missing, spent).err(), Some(PsbtError::MissingInput));
+        let mut overspend = tx.clone();
+        overspend.output[0].value = 151.into();
+        assert_eq!(Psbt::new(overspend, spent).err(), Some(PsbtError::Overspend));
+
+        let created = Psbt::new(tx, spent).unwrap();
+        assert_eq!(created.fee(), Some(10.into()));
+        // each signer gets its own copy, and signs only its input
+        let mut from_alice = Psbt::from_hex(&created.to_hex()).unwrap();
+        assert_eq!(from_alice.sign(&alice, SigHash::All), Ok(1));
+        assert_eq!(from_alice.sign(&alice, SigHash::All), Ok(0));
+        assert_eq!(from_alice.finalize().err(), Some(PsbtError::Incomplete));
+        let mut from_bob = created.clone();
+        assert_eq!(from_bob.sign(&bob, SigHash::All), Ok(1));
+
+        let mut combined = created.clone();
+        combined.combine(&from_alice).unwrap();
+        assert!(!combined.is_complete());
+        combined.combine(&from_bob).unwrap();
+        assert!(combined.is_complete());
+        let signed = combined.finalize().unwrap();
+        assert!(matches!(signed.witness, Witness::PerInput(_)));
+        assert_eq!(signed.transaction.hash(), created.id());
+
+        // signatures of another transaction do not combine, and bad ones do not finalize
+        let mut other = created.clone();
+        other.transaction.output[0].value = 130.into();
+        assert_eq!(other.combine(&from_alice), Err(PsbtError::DifferentTransaction));
+        let mut forged = combined.clone();
+        forged.inputs[1].signature = forged.inputs[0].signature.clone();
+        assert_eq!(forged.finalize().err(), Some(PsbtError::Invalid(validation::TxError::BadSignature)));
+        assert_eq!(Psbt::from_hex("00").err(), Some(PsbtError::Undecodable));
+    }
+
+    /// Alice's key, held by a device that stopped answering.
+    struct Unplugged(Ed25519KeyPair);
+
+    impl Signer for Unplugged {
+        fn algorithm(&self) -> Algorithm {
+            Algorithm::Ed25519
+        }
+
+        fn public_key_bytes(&self) -> Vec<u8> {
+            self.0.public_key().as_ref().to_vec()
+        }
+
+        fn sign(&self, _message: &[u8]) -> std::io::Result<Vec<u8>> {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no answer"))
+        }
+    }
+
+    #[test]
+    fn signer_failed() {
+        let alice = Unplugged(key(1));
+        let owner = address_of(&alice.public_key_bytes());
+        let tx = Transaction {
+            version: transaction::VERSION,
+            input: vec![TxIn { previous_output: [1u8; 32].into(), index: 0 }],
+            output: vec![TxOut { recipient: [3u8; 20].into(), value: 90.into() }],
+        };
+        let mut psbt = Psbt::new(tx, |_| Some((Amount::from(100), owner))).unwrap();
+        assert_eq!(psbt.sign(&alice, SigHash::All), Err(PsbtError::SignerFailed));
+        assert!(psbt.inputs[0].signature.is_none());
+        assert!(!psbt.is_complete());
+    }
+}
@@ -0,0 +1,126 @@
+//! A hash map split into independently locked shards, so that threads working on different
+//! keys rarely wait for each other.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLock;
+
+/// Shards of the maps created with `Default`.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// A map whose entries are spread over shards by key hash, each behind its own lock. Values
+/// are returned by clone, so that no lock is held once a call returns; wrap large values in an
+/// `Arc`. Reads of the whole map, like `len` or `values`, visit the shards one at a time and
+/// may miss concurrent changes.
+pub struct ShardedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0, "a sharded map needs at least one shard");
+        ShardedMap { shards: (0..shards).map(|_| RwLock::new(HashMap::new())).collect(), hasher: RandomState::new() }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// Insert an entry, returning the value it replaced.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.read().unwrap().is_empty())
+    }
+
+    /// All entries, in no particular order.
+    pub fn entries(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+    {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.read().unwrap().iter().map(|(key, value)| (key.clone(), value.clone())));
+        }
+        entries
+    }
+
+    pub fn keys(&self) -> Vec<K>
+    where
+        K: Clone,
+    {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            keys.extend(shard.read().unwrap().keys().cloned());
+        }
+        keys
+    }
+
+    pub fn values(&self) -> Vec<V> {
+        let mut values = Vec::new();
+        for shard in &self.shards {
+            values.extend(shard.read().unwrap().values().cloned());
+        }
+        values
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        ShardedMap::new(DEFAULT_SHARDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn concurrent_inserts() {
+        let map: Arc<ShardedMap<u32, u32>> = Arc::new(ShardedMap::new(4));
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        map.insert(t * 100 + i, i);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(map.len(), 400);
+        assert_eq!(map.get(&250), Some(50));
+        assert_eq!(map.insert(250, 7), Some(50));
+        assert_eq!(map.remove(&250), Some(7));
+        assert!(!map.contains_key(&250));
+        assert_eq!(map.keys().len(), 399);
+        assert_eq!(map.entries().len(), 399);
+        assert_eq!(map.values().iter().filter(|v| **v == 0).count(), 4);
+        assert!(ShardedMap::<u32, u32>::default().is_empty());
+    }
+}
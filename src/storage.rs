@@ -0,0 +1,790 @@
+//! Archival block storage in append-only flat files, in the spirit of Bitcoin's blk*.dat.
+//!
+//! Blocks are appended as records of `MAGIC`, the little-endian u32 length of the payload, and
+//! the bincode-encoded block, to `blkNNNNN.dat` files that are rolled over once they reach a
+//! size limit. Where each block lives is kept in an append-only index log, `blocks.idx`, which
+//! can always be rebuilt by scanning the block files.
+//!
+//! The undo data of the blocks applied to the UTXO set, the outputs each of their transactions
+//! spent, is appended to `undo.dat` in records of the same format, holding the bincode-encoded
+//! block hash and undo data. A later record for the same block replaces an earlier one.
+//!
+//! The longest chain can be exported to a single file of block records, without the genesis
+//! block and in height order, and imported into another node, see `export_chain`.
+//!
+//! A node keeps its blocks through a `BlockStore`: the block files, or `MemoryStore` for nodes
+//! that do not outlive the process.
+
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainListener};
+use crate::chainstate;
+use crate::clock::Clock;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::{State, Undo};
+use crate::validation::{self, BlockError};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Marks the start of each record in a block file.
+pub const MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+/// Size at which a new block file is started.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 128 * 1024 * 1024;
+const HEADER_SIZE: u64 = 8;
+const INDEX_FILE: &str = "blocks.idx";
+const UNDO_FILE: &str = "undo.dat";
+
+/// Location of a block in the block files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockPos {
+    /// Number of the block file.
+    pub file: u32,
+    /// Offset of the record, which starts with `MAGIC`.
+    pub offset: u64,
+    /// Length of the encoded block.
+    pub length: u32,
+}
+
+pub struct BlockFiles {
+    dir: PathBuf,
+    max_file_size: u64,
+    index: HashMap<H256, BlockPos>,
+    index_log: File,
+    /// Number and size of the file being appended to.
+    current: u32,
+    current_size: u64,
+    /// Offset and length of the latest undo record of each block.
+    undo_index: HashMap<H256, (u64, u32)>,
+    undo_log: File,
+}
+
+fn file_path(dir: &Path, file: u32) -> PathBuf {
+    dir.join(format!("blk{:05}.dat", file))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A record of `MAGIC`, the length of the payload and the payload.
+fn record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE as usize + payload.len());
+    record.extend_from_slice(&MAGIC);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Read the next record, `None` at the end of the input.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if header[..4] != MAGIC {
+        return Err(invalid_data("bad record magic"));
+    }
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&header[4..]);
+    let mut payload = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Index the undo log, cutting off a record cut short by a crash or anything unreadable after
+/// the last good record, so that appending resumes at a record boundary.
+fn open_undo_log(path: &Path) -> io::Result<(HashMap<H256, (u64, u32)>, File)> {
+    let mut index = HashMap::new();
+    let mut end = 0;
+    if path.exists() {
+        let mut reader = BufReader::new(File::open(path)?);
+        loop {
+            let mut header = [0u8; HEADER_SIZE as usize];
+            if reader.read_exact(&mut header).is_err() || header[..4] != MAGIC {
+                break;
+            }
+            let mut length = [0u8; 4];
+            length.copy_from_slice(&header[4..]);
+            let length = u32::from_le_bytes(length);
+            let mut payload = vec![0u8; length as usize];
+            if reader.read_exact(&mut payload).is_err() {
+                break;
+            }
+            match bincode::deserialize::<(H256, Vec<Undo>)>(&payload) {
+                Ok((hash, _)) => index.insert(hash, (end, length)),
+                Err(_) => break,
+            };
+            end += HEADER_SIZE + length as u64;
+        }
+        if fs::metadata(path)?.len() > end {
+            warn!("Dropping the damaged end of {}", path.display());
+            OpenOptions::new().write(true).open(path)?.set_len(end)?;
+        }
+    }
+    let log = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok((index, log))
+}
+
+impl BlockFiles {
+    /// Open the block files in the directory, creating it if needed.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        Self::with_max_file_size(dir, DEFAULT_MAX_FILE_SIZE)
+    }
+
+    pub fn with_max_file_size(dir: &Path, max_file_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let mut index = HashMap::new();
+        if index_path.exists() {
+            let mut reader = BufReader::new(File::open(&index_path)?);
+            // a record cut short by a crash is dropped, its block is found again by a reindex
+            while let Ok((hash, pos)) = bincode::deserialize_from::<_, (H256, BlockPos)>(&mut reader) {
+                index.insert(hash, pos);
+            }
+        }
+        let index_log = OpenOptions::new().create(true).append(true).open(&index_path)?;
+        let mut current = 0;
+        while file_path(dir, current + 1).exists() {
+            current += 1;
+        }
+        let current_size = match fs::metadata(file_path(dir, current)) {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+        let (undo_index, undo_log) = open_undo_log(&dir.join(UNDO_FILE))?;
+        Ok(BlockFiles {
+            dir: dir.to_path_buf(),
+            max_file_size: max_file_size,
+            index: index,
+            index_log: index_log,
+            current: current,
+            current_size: current_size,
+            undo_index: undo_index,
+            undo_log: undo_log,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    pub fn position(&self, hash: &H256) -> Option<BlockPos> {
+        self.index.get(hash).cloned()
+    }
+
+    /// Append a block, unless it is already stored. Returns where the block is.
+    pub fn append(&mut self, block: &Block) -> io::Result<BlockPos> {
+        let hash = block.hash();
+        if let Some(pos) = self.index.get(&hash) {
+            return Ok(*pos);
+        }
+        let payload = bincode::serialize(block).unwrap();
+        let record_size = HEADER_SIZE + payload.len() as u64;
+        if self.current_size > 0 && self.current_size + record_size > self.max_file_size {
+            self.current += 1;
+            self.current_size = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path(&self.dir, self.current))?;
+        file.write_all(&record(&payload))?;
+        file.flush()?;
+        let pos = BlockPos {
+            file: self.current,
+            offset: self.current_size,
+            length: payload.len() as u32,
+        };
+        self.current_size += record_size;
+        self.index_log.write_all(&bincode::serialize(&(hash, pos)).unwrap())?;
+        self.index.insert(hash, pos);
+        Ok(pos)
+    }
+
+    /// Read a block at a known position.
+    pub fn read(&self, pos: &BlockPos) -> io::Result<Block> {
+        let mut file = File::open(file_path(&self.dir, pos.file))?;
+        file.seek(SeekFrom::Start(pos.offset))?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid_data("bad record magic"));
+        }
+        let mut payload = vec![0u8; pos.length as usize];
+        file.read_exact(&mut payload)?;
+        bincode::deserialize(&payload).map_err(|_| invalid_data("undecodable block"))
+    }
+
+    /// Read a stored block by hash.
+    pub fn get(&self, hash: &H256) -> io::Result<Option<Block>> {
+        match self.index.get(hash) {
+            Some(pos) => self.read(pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the undo data of a block, replacing any stored before.
+    pub fn append_undo(&mut self, hash: &H256, undo: &[Undo]) -> io::Result<()> {
+        let payload = bincode::serialize(&(hash, undo)).unwrap();
+        let offset = self.undo_log.metadata()?.len();
+        self.undo_log.write_all(&record(&payload))?;
+        self.undo_log.flush()?;
+        self.undo_index.insert(*hash, (offset, payload.len() as u32));
+        Ok(())
+    }
+
+    /// Read the undo data of a block.
+    pub fn undo(&self, hash: &H256) -> io::Result<Option<Vec<Undo>>> {
+        let (offset, length) = match self.undo_index.get(hash) {
+            Some(pos) => *pos,
+            None => return Ok(None),
+        };
+        let mut file = File::open(self.dir.join(UNDO_FILE))?;
+        file.seek(SeekFrom::Start(offset + HEADER_SIZE))?;
+        let mut payload = vec![0u8; length as usize];
+        file.read_exact(&mut payload)?;
+        let (_, undo): (H256, Vec<Undo>) =
+            bincode::deserialize(&payload).map_err(|_| invalid_data("undecodable undo data"))?;
+        Ok(Some(undo))
+    }
+
+    /// Scan all block files in append order, without using the index.
+    pub fn iter(&self) -> BlockFileIter {
+        BlockFileIter {
+            dir: self.dir.clone(),
+            file: 0,
+            reader: None,
+            offset: 0,
+        }
+    }
+
+    /// Rebuild the index by scanning the block files, and rewrite the index log. A record cut
+    /// short at the end of the last file is cut off, so that appending resumes at a record
+    /// boundary. Returns the number of blocks found.
+    pub fn reindex(&mut self) -> io::Result<usize> {
+        let mut positions = Vec::new();
+        let mut end = 0;
+        for item in self.iter() {
+            let (pos, block) = item?;
+            if pos.file == self.current {
+                end = pos.offset + HEADER_SIZE + pos.length as u64;
+            }
+            positions.push((block.hash(), pos));
+        }
+        let current_path = file_path(&self.dir, self.current);
+        if current_path.exists() && end < self.current_size {
+            OpenOptions::new().write(true).open(&current_path)?.set_len(end)?;
+        }
+        self.current_size = end;
+
+        let index_path = self.dir.join(INDEX_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut log = Vec::new();
+        for entry in &positions {
+            log.extend_from_slice(&bincode::serialize(entry).unwrap());
+        }
+        fs::write(&tmp_path, &log)?;
+        fs::rename(&tmp_path, &index_path)?;
+        self.index_log = OpenOptions::new().append(true).open(&index_path)?;
+        self.index = positions.into_iter().collect();
+        Ok(self.index.len())
+    }
+}
+
+/// Outcome of `replay`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub connected: usize,
+    pub invalid: usize,
+    /// Blocks whose parent is not stored, or was invalid.
+    pub orphaned: usize,
+    /// Blocks connected whose stored undo data was missing or disagreed with the replayed one,
+    /// and was rewritten.
+    pub undo_rewritten: usize,
+}
+
+/// Rebuild a chain, its transaction index and the UTXO set by feeding all stored blocks through
+/// validation in the order they were stored. Blocks stored before their parent are held back
+/// until the parent is connected. The stored undo data of each block connected is checked
+/// against the replayed one, and rewritten where it is missing or wrong.
+pub fn replay(files: &mut BlockFiles, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock) -> io::Result<ReplayStats> {
+    let blocks = files.iter().map(|item| item.map(|(_, block)| block));
+    connect_stored(blocks, chain, state, clock, |hash, undo| repair_undo(files, hash, undo))
+}
+
+/// Rewrite the stored undo data of a block unless it matches the replayed one. Returns whether
+/// it was rewritten.
+fn repair_undo(files: &mut BlockFiles, hash: &H256, undo: &[Undo]) -> io::Result<bool> {
+    if files.undo(hash)?.as_ref().map(|stored| stored.as_slice()) == Some(undo) {
+        return Ok(false);
+    }
+    files.append_undo(hash, undo)?;
+    Ok(true)
+}
+
+/// Connect the stored blocks like `replay`, but leave the files untouched, so that what is
+/// wrong with them can still be found by `verify::verify_chain`.
+pub fn load(files: &BlockFiles, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock) -> io::Result<ReplayStats> {
+    connect_stored(files.iter().map(|item| item.map(|(_, block)| block)), chain, state, clock, |_, _| Ok(false))
+}
+
+/// Connect blocks in the order they were stored, holding back those stored before their parent
+/// until the parent is connected. The undo data of each block connected goes to `check_undo`,
+/// which returns whether it rewrote the stored one.
+fn connect_stored<I, F>(blocks: I, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock, mut check_undo: F) -> io::Result<ReplayStats>
+where
+    I: IntoIterator<Item = io::Result<Block>>,
+    F: FnMut(&H256, &[Undo]) -> io::Result<bool>,
+{
+    let mut stats = ReplayStats::default();
+    let mut pending: HashMap<H256, Vec<Block>> = HashMap::new();
+    for block in blocks {
+        let mut queue = vec![block?];
+        while let Some(block) = queue.pop() {
+            let hash = block.hash();
+            if chain.blockmap.contains_key(&hash) {
+                continue;
+            }
+            match validation::check_block(&block, chain, state, clock) {
+                Ok(()) => {
+                    for hash in chainstate::insert_block(&block, chain, state).connected {
+                        // kept by the state until the next move, see `chainstate::follow_tip`
+                        if let Some(undo) = state.block_undo(&hash) {
+                            if check_undo(&hash, undo)? {
+                                stats.undo_rewritten += 1;
+                            }
+                        }
+                    }
+                    stats.connected += 1;
+                    if let Some(children) = pending.remove(&hash) {
+                        queue.extend(children);
+                    }
+                }
+                Err(BlockError::UnknownParent) => {
+                    pending.entry(block.header.parent).or_insert_with(Vec::new).push(block);
+                }
+                Err(e) => {
+                    warn!("Stored block {} is invalid: {}", hash, e);
+                    stats.invalid += 1;
+                }
+            }
+        }
+    }
+    stats.orphaned = pending.values().map(|blocks| blocks.len()).sum();
+    Ok(stats)
+}
+
+/// Write the blocks of the longest chain above the genesis block, in height order, as block
+/// file records. Returns the number of blocks written.
+pub fn export_chain<W: Write>(chain: &Blockchain, writer: &mut W) -> io::Result<usize> {
+    let height = chain.height();
+    for hash in (1..=height).filter_map(|height| chain.hash_at_height(height)) {
+        writer.write_all(&record(&bincode::serialize(&chain.blockmap[&hash]).unwrap()))?;
+    }
+    writer.flush()?;
+    Ok(height)
+}
+
+/// Outcome of `import_chain`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportStats {
+    pub imported: usize,
+    /// Blocks the chain already had.
+    pub known: usize,
+}
+
+/// Feed the blocks written by `export_chain` through validation into a chain. Import stops at
+/// the first block that cannot be connected, keeping the blocks before it.
+pub fn import_chain<R: Read>(reader: &mut R, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock) -> io::Result<ImportStats> {
+    let mut stats = ImportStats::default();
+    while let Some(payload) = read_record(reader)? {
+        let block: Block = bincode::deserialize(&payload).map_err(|_| invalid_data("undecodable block"))?;
+        let hash = block.hash();
+        if chain.blockmap.contains_key(&hash) {
+            stats.known += 1;
+            continue;
+        }
+        if let Err(e) = validation::check_block(&block, chain, state, clock) {
+            let msg = format!("block {} after {} imported blocks is invalid: {}", hash, stats.imported, e);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        chainstate::insert_block(&block, chain, state);
+        stats.imported += 1;
+    }
+    Ok(stats)
+}
+
+/// Iterator over the blocks of the block files, see `BlockFiles::iter`. A record cut short at
+/// the end of a file ends the scan of that file.
+pub struct BlockFileIter {
+    dir: PathBuf,
+    file: u32,
+    reader: Option<BufReader<File>>,
+    offset: u64,
+}
+
+impl Iterator for BlockFileIter {
+    type Item = io::Result<(BlockPos, Block)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.reader.is_none() {
+                let path = file_path(&self.dir, self.file);
+                if !path.exists() {
+                    return None;
+                }
+                match File::open(path) {
+                    Ok(f) => self.reader = Some(BufReader::new(f)),
+                    Err(e) => return Some(Err(e)),
+                }
+                self.offset = 0;
+            }
+            let reader = self.reader.as_mut().unwrap();
+            let mut header = [0u8; HEADER_SIZE as usize];
+            let mut payload = Vec::new();
+            if reader.read_exact(&mut header).is_ok() {
+                if header[..4] != MAGIC {
+                    // stop scanning instead of returning the same error forever
+                    self.reader = None;
+                    self.file = u32::MAX;
+                    return Some(Err(invalid_data("bad record magic")));
+                }
+                let mut length = [0u8; 4];
+                length.copy_from_slice(&header[4..]);
+                payload.resize(u32::from_le_bytes(length) as usize, 0);
+            }
+            if payload.is_empty() || reader.read_exact(&mut payload).is_err() {
+                // end of this file, continue with the next one
+                self.reader = None;
+                self.file += 1;
+                continue;
+            }
+            let pos = BlockPos {
+                file: self.file,
+                offset: self.offset,
+                length: payload.len() as u32,
+            };
+            self.offset += HEADER_SIZE + payload.len() as u64;
+            return match bincode::deserialize(&payload) {
+                Ok(block) => Some(Ok((pos, block))),
+                Err(_) => Some(Err(invalid_data("undecodable block"))),
+            };
+        }
+    }
+}
+
+/// Where a node keeps the blocks it inserts and their undo data, to connect them again at start.
+/// As a chain listener it stores every block inserted into the chain it is subscribed to.
+pub trait BlockStore: ChainListener {
+    /// Connect the stored blocks to a chain and its UTXO set, repairing the stored undo data of
+    /// the longest chain, see `replay`.
+    fn replay(&self, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock) -> io::Result<ReplayStats>;
+
+    /// The undo data stored for a block, see `Blockchain::set_undo_store`.
+    fn load_undo(&self, hash: &H256) -> io::Result<Option<Vec<Undo>>>;
+}
+
+impl ChainListener for Mutex<BlockFiles> {
+    fn on_block_inserted(&self, block: &Block) {
+        if let Err(e) = self.lock().unwrap().append(block) {
+            error!("Error storing block {}: {}", block.hash(), e);
+        }
+    }
+
+    fn on_block_undo(&self, block: &Block, undo: &[Undo]) {
+        if let Err(e) = self.lock().unwrap().append_undo(&block.hash(), undo) {
+            error!("Error storing undo data of block {}: {}", block.hash(), e);
+        }
+    }
+}
+
+impl BlockStore for Mutex<BlockFiles> {
+    fn replay(&self, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock) -> io::Result<ReplayStats> {
+        // locked for each access only, the chain may read undo data back through `load_undo`
+        let blocks = self.lock().unwrap().iter().map(|item| item.map(|(_, block)| block));
+        connect_stored(blocks, chain, state, clock, |hash, undo| repair_undo(&mut self.lock().unwrap(), hash, undo))
+    }
+
+    fn load_undo(&self, hash: &H256) -> io::Result<Option<Vec<Undo>>> {
+        self.lock().unwrap().undo(hash)
+    }
+}
+
+/// Blocks kept in memory, so that simulations and tests can restart a node within the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<MemoryBlocks>,
+}
+
+#[derive(Default)]
+struct MemoryBlocks {
+    /// The hashes of the blocks in the order they were stored
+    order: Vec<H256>,
+    blocks: HashMap<H256, Block>,
+    undo: HashMap<H256, Vec<Undo>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<Block> {
+        self.inner.lock().unwrap().blocks.get(hash).cloned()
+    }
+
+    pub fn undo(&self, hash: &H256) -> Option<Vec<Undo>> {
+        self.inner.lock().unwrap().undo.get(hash).cloned()
+    }
+}
+
+impl ChainListener for MemoryStore {
+    fn on_block_inserted(&self, block: &Block) {
+        let mut inner = self.inner.lock().unwrap();
+        let hash = block.hash();
+        if !inner.blocks.contains_key(&hash) {
+            inner.order.push(hash);
+            inner.blocks.insert(hash, block.clone());
+        }
+    }
+
+    fn on_block_undo(&self, block: &Block, undo: &[Undo]) {
+        self.inner.lock().unwrap().undo.insert(block.hash(), undo.to_vec());
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn replay(&self, chain: &mut Blockchain, state: &mut State, clock: &dyn Clock) -> io::Result<ReplayStats> {
+        let blocks: Vec<io::Result<Block>> = {
+            let inner = self.inner.lock().unwrap();
+            inner.order.iter().map(|hash| Ok(inner.blocks[hash].clone())).collect()
+        };
+        // locked for each access only, the chain may read undo data back through `load_undo`
+        connect_stored(blocks, chain, state, clock, |hash, undo| {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.undo.get(hash).map(|stored| stored.as_slice()) == Some(undo) {
+                return Ok(false);
+            }
+            inner.undo.insert(*hash, undo.to_vec());
+            Ok(true)
+        })
+    }
+
+    fn load_undo(&self, hash: &H256) -> io::Result<Option<Vec<Undo>>> {
+        Ok(self.undo(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::clock::SystemClock;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockfiles-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn append_and_read() {
+        let dir = temp_dir("append");
+        // small files, so that blocks are spread over several of them
+        let mut files = BlockFiles::with_max_file_size(&dir, 300).unwrap();
+        let mut blocks = Vec::new();
+        let mut parent: H256 = [0u8; 32].into();
+        for _ in 0..5 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            files.append(&block).unwrap();
+            blocks.push(block);
+        }
+        let again = files.append(&blocks[0]).unwrap();
+        assert_eq!(files.position(&blocks[0].hash()), Some(again));
+        assert_eq!(files.len(), 5);
+        assert!(files.position(&blocks[4].hash()).unwrap().file > 0);
+        for block in &blocks {
+            assert_eq!(files.get(&block.hash()).unwrap().unwrap().hash(), block.hash());
+        }
+
+        // scanning finds the blocks in order, where the index says they are
+        let scanned: Vec<(BlockPos, Block)> = files.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(scanned.len(), 5);
+        for ((pos, block), expected) in scanned.iter().zip(&blocks) {
+            assert_eq!(block.hash(), expected.hash());
+            assert_eq!(files.position(&block.hash()), Some(*pos));
+        }
+
+        // reopening restores the index and keeps appending to the last file
+        drop(files);
+        let mut files = BlockFiles::with_max_file_size(&dir, 300).unwrap();
+        assert_eq!(files.len(), 5);
+        let block = generate_random_block(&parent);
+        let pos = files.append(&block).unwrap();
+        assert!(pos.file >= scanned[4].0.file);
+        assert_eq!(files.iter().count(), 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reindex_and_replay() {
+        let dir = temp_dir("reindex");
+        let mut files = BlockFiles::open(&dir).unwrap();
+        let genesis = Blockchain::new().tip();
+        // both fail the proof of work of the genesis block, the second cannot be connected
+        let invalid = generate_random_block(&genesis);
+        files.append(&invalid).unwrap();
+        files.append(&generate_random_block(&invalid.hash())).unwrap();
+        let len = fs::metadata(file_path(&dir, 0)).unwrap().len();
+        files.append(&generate_random_block(&genesis)).unwrap();
+        drop(files);
+        // lose the index, and the end of the last block
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+        let path = file_path(&dir, 0);
+        let full = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(full - 3).unwrap();
+
+        let mut files = BlockFiles::open(&dir).unwrap();
+        assert_eq!(files.len(), 0);
+        assert_eq!(files.reindex().unwrap(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(files.get(&invalid.hash()).unwrap().unwrap().hash(), invalid.hash());
+        assert_eq!(BlockFiles::open(&dir).unwrap().len(), 2);
+
+        let mut chain = Blockchain::new();
+        let mut state = State::new();
+        let stats = replay(&mut files, &mut chain, &mut state, &SystemClock).unwrap();
+        assert_eq!(stats, ReplayStats { connected: 0, invalid: 1, orphaned: 1, undo_rewritten: 0 });
+        assert_eq!(chain.tip(), genesis);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryStore::new();
+        let genesis = Blockchain::new().tip();
+        // the orphan is stored before its parent, which fails the proof of work
+        let invalid = generate_random_block(&genesis);
+        let orphan = generate_random_block(&invalid.hash());
+        store.on_block_inserted(&orphan);
+        store.on_block_inserted(&invalid);
+        store.on_block_inserted(&orphan);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(&invalid.hash()).unwrap().hash(), invalid.hash());
+        let undo: Vec<Undo> = vec![vec![(([3u8; 32].into(), 0), (10.into(), [4u8; 20].into(), None))]];
+        store.on_block_undo(&orphan, &undo);
+        assert_eq!(store.undo(&orphan.hash()), Some(undo));
+
+        let mut chain = Blockchain::new();
+        let mut state = State::new();
+        let stats = store.replay(&mut chain, &mut state, &SystemClock).unwrap();
+        assert_eq!(stats, ReplayStats { connected: 0, invalid: 1, orphaned: 1, undo_rewritten: 0 });
+        assert_eq!(chain.tip(), genesis);
+    }
+
+    #[test]
+    fn undo_log() {
+        let dir = temp_dir("undo");
+        let mut files = BlockFiles::open(&dir).unwrap();
+        let first: H256 = [1u8; 32].into();
+        let second: H256 = [2u8; 32].into();
+        let undo: Vec<Undo> = vec![vec![(([3u8; 32].into(), 0), (10.into(), [4u8; 20].into(), None))], vec![]];
+        files.append_undo(&first, &undo).unwrap();
+        files.append_undo(&second, &[]).unwrap();
+        files.append_undo(&second, &undo).unwrap();
+        assert_eq!(files.undo(&first).unwrap(), Some(undo.clone()));
+        assert_eq!(files.undo(&second).unwrap(), Some(undo.clone()));
+        assert_eq!(files.undo(&[5u8; 32].into()).unwrap(), None);
+
+        // a record cut short is dropped when reopening, and appending continues after the others
+        drop(files);
+        let path = dir.join(UNDO_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+        let mut files = BlockFiles::open(&dir).unwrap();
+        assert_eq!(files.undo(&second).unwrap(), Some(Vec::new()));
+        files.append_undo(&first, &[]).unwrap();
+        let files = BlockFiles::open(&dir).unwrap();
+        assert_eq!(files.undo(&first).unwrap(), Some(Vec::new()));
+        assert_eq!(files.undo(&second).unwrap(), Some(Vec::new()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_record() {
+        let dir = temp_dir("truncated");
+        let mut files = BlockFiles::open(&dir).unwrap();
+        let first = generate_random_block(&[0u8; 32].into());
+        files.append(&first).unwrap();
+        files.append(&generate_random_block(&first.hash())).unwrap();
+        // simulate a crash in the middle of writing the second block
+        let path = file_path(&dir, 0);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+        let scanned: Vec<_> = files.iter().collect();
+        assert_eq!(scanned.len(), 1);
+        assert_eq!(scanned[0].as_ref().unwrap().1.hash(), first.hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_and_import() {
+        let mut chain = Blockchain::new();
+        let mut parent = chain.tip();
+        let mut blocks = Vec::new();
+        for _ in 0..3 {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            chain.insert(&block);
+            blocks.push(block);
+        }
+        // a fork block is left out
+        chain.insert(&generate_random_block(&blocks[0].hash()));
+        let mut exported = Vec::new();
+        assert_eq!(export_chain(&chain, &mut exported).unwrap(), 3);
+        let mut reader = &exported[..];
+        for block in &blocks {
+            let payload = read_record(&mut reader).unwrap().unwrap();
+            assert_eq!(bincode::deserialize::<Block>(&payload).unwrap().hash(), block.hash());
+        }
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+
+        // a chain that has the blocks skips them
+        let mut state = State::new();
+        let stats = import_chain(&mut &exported[..], &mut chain, &mut state, &SystemClock).unwrap();
+        assert_eq!(stats, ImportStats { imported: 0, known: 3 });
+
+        // the random blocks fail the proof of work, and import stops at the first one
+        let mut fresh = Blockchain::new();
+        let e = import_chain(&mut &exported[..], &mut fresh, &mut state, &SystemClock).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fresh.height(), 0);
+
+        // a truncated file is an error rather than a shorter chain
+        let truncated = &exported[..exported.len() - 1];
+        assert!(import_chain(&mut &truncated[..], &mut chain, &mut state, &SystemClock).is_err());
+    }
+}
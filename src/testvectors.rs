@@ -0,0 +1,106 @@
+//! Golden serializations and hashes of the consensus objects. A change to one of these tests
+//! means the encoding of blocks or transactions changed, and with it the hashes the network
+//! agrees on: such a change needs a new protocol version, not new vectors.
+
+use crate::amount::Amount;
+use crate::block::{self, Block, Content, Header};
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable, Hashed};
+use crate::transaction::{self, SignedTransaction, Transaction, TxIn, TxOut, Witness};
+use ring::signature::Ed25519KeyPair;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+fn header() -> Header {
+    Header {
+        version: block::VERSION,
+        parent: [1u8; 32].into(),
+        height: 2,
+        nonce: 3,
+        extra_nonce: 4,
+        bits: 0x1e010000,
+        timestamp: 5,
+        merkle_root: [6u8; 32].into(),
+    }
+}
+
+fn transaction() -> Transaction {
+    Transaction {
+        version: transaction::VERSION,
+        input: vec![TxIn { previous_output: [7u8; 32].into(), index: 1 }],
+        output: vec![TxOut { recipient: [8u8; 20].into(), value: Amount::from(50) }],
+    }
+}
+
+/// Signed with a fixed key; Ed25519 signatures are deterministic.
+fn signed_transaction() -> SignedTransaction {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[9u8; 32]).unwrap();
+    SignedTransaction::new(transaction(), &key)
+}
+
+/// Check the serialization of a value against the golden bytes, and that decoding them gives
+/// back a value that serializes the same.
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T, golden: &[u8]) {
+    let serialized = bincode::serialize(value).unwrap();
+    assert_eq!(hex::encode(&serialized), hex::encode(golden));
+    let decoded: T = bincode::deserialize(golden).unwrap();
+    assert_eq!(bincode::serialize(&decoded).unwrap(), serialized);
+}
+
+#[test]
+fn header_vector() {
+    round_trip(
+        &header(),
+        &hex!("010000000101010101010101010101010101010101010101010101010101010101010101020000000000000003000000040000000000011e050000000000000000000000000000000606060606060606060606060606060606060606060606060606060606060606"),
+    );
+    let hash: H256 = hex!("1817f0a0a1de59d05b94bfca05978db676f9cb00ea087e3da63917624268f12b").into();
+    assert_eq!(header().hash(), hash);
+}
+
+#[test]
+fn transaction_vector() {
+    round_trip(
+        &transaction(),
+        &hex!("010000000100000000000000070707070707070707070707070707070707070707070707070707070707070701010000000000000008080808080808080808080808080808080808083200000000000000"),
+    );
+    let hash: H256 = hex!("e330a9a769a5e15c396537f7955ccf40f6e6a3a894f7b32ac48d79d0499f14f2").into();
+    assert_eq!(transaction().hash(), hash);
+    assert_eq!(
+        transaction::signing_hash(&transaction()).as_ref(),
+        &hex!("e46b82033f64cdbf1bb7c7c36facb85f969a6c5510f1ef278b7bf08d800a6323")[..]
+    );
+}
+
+#[test]
+fn signed_transaction_vector() {
+    let signed = signed_transaction();
+    round_trip(
+        &signed,
+        &hex!("01000000010000000000000007070707070707070707070707070707070707070707070707070707070707070101000000000000000808080808080808080808080808080808080808320000000000000000000000000000002000000000000000fd1724385aa0c75b64fb78cd602fa1d991fdebf76b13c58ed702eac835e9f618400000000000000011c2b960e5ec3f062db359510e07c7008e1a7002678567b2311d52a83c9cd50be49e42ed17f1dd8a4b0e398d5ab6038b7c21245de3ae9cc840228ef42d93840d"),
+    );
+    let hash: H256 = hex!("2f91262cea17d045b2d09003e7aea715a9e50758d103e0f7def78cc82d9d4364").into();
+    assert_eq!(signed.hash(), hash);
+    let message = transaction::signing_hash(&signed.transaction);
+    match &signed.witness {
+        Witness::Whole { public_key, signature } => assert!(signed.algorithm.verify(public_key, message.as_ref(), signature)),
+        witness => panic!("unexpected witness {:?}", witness),
+    }
+}
+
+#[test]
+fn genesis_vector() {
+    let chain = Blockchain::new();
+    let genesis: &Block = &chain.blockmap[&chain.tip()];
+    round_trip(
+        genesis,
+        &hex!("010000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000011e0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"),
+    );
+    let hash: H256 = hex!("f6358835696a11b6fd1c319e9e559d2e5cc2ddd016ff4d773926fe1907247bc0").into();
+    assert_eq!(genesis.hash(), hash);
+    assert_eq!(genesis.hash(), genesis.header.hash());
+    // a block is its header followed by its transactions
+    let block = Block { header: header(), content: Content { data: vec![Hashed::new(signed_transaction())] } };
+    let mut expected = bincode::serialize(&header()).unwrap();
+    expected.extend_from_slice(&bincode::serialize(&vec![signed_transaction()]).unwrap());
+    assert_eq!(bincode::serialize(&block).unwrap(), expected);
+}
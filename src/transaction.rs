@@ -2,22 +2,103 @@ extern crate rand;
 use serde::{Serialize,Deserialize};
 use ring::digest;
 use ring::signature::{self, Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
-use crate::crypto::hash::{H160, H256, Hashable};
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::ChainListener;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::hash::{H160, H256, Hashable, Hashed};
+use crate::crypto::signer::{Algorithm, Signer};
+use crate::crypto::u256::U256;
+use crate::memory::{MemoryBudget, Pool};
+use crate::policy::{Policy, PolicyError};
+use crate::validation::{self, TxError};
+use log::debug;
+use rayon::prelude::*;
 use std::convert::TryInto;
-use std::collections::{HashSet, HashMap};
+use std::io;
+use crate::sharded::ShardedMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashSet, HashMap};
+use std::sync::{Arc, RwLock};
 
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct State {
-    pub utxo: HashMap<(H256, u8), (u64, H160)>,
+    /// Change through `insert_utxo`, `apply` and `rollback`, which keep the commitment in sync
+    pub utxo: HashMap<(H256, u8), Utxo>,
+    /// Sum of the hashes of all UTXO entries, modulo 2^256
+    commitment: U256,
+    /// The commitment right after each block was applied
+    block_commitments: HashMap<H256, H256>,
+    /// Outputs spent by each block of the longest chain, to disconnect it in a reorg, with its
+    /// height. Only the last `UNDO_DEPTH` blocks are kept past `prune_undo`.
+    block_undo: HashMap<H256, (u64, Vec<Undo>)>,
+    /// Sum of the UTXO values, wide enough not to overflow whatever the entries
+    utxo_value: u128,
+    /// Entries by number of decimal digits of their value, see `UtxoStats::histogram`
+    utxo_histogram: [usize; VALUE_BUCKETS],
+    /// Entries paying each address, only addresses with at least one
+    utxo_addresses: HashMap<H160, usize>,
+    /// Height of the last block connected, see `connect_block`
+    height: u64,
 }
 
+/// Buckets of the UTXO value histogram, one per number of decimal digits of a `u64`, and one for
+/// the value 0.
+const VALUE_BUCKETS: usize = 21;
+
+fn value_bucket(value: Amount) -> usize {
+    let mut value = value.as_u64();
+    let mut digits = 0;
+    while value > 0 {
+        value /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+/// UTXO entries with a value in `min..=max`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ValueBucket {
+    pub min: u64,
+    pub max: u64,
+    pub count: usize,
+}
+
+/// The UTXO set at a glance, as served by `/stats/utxo`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UtxoStats {
+    pub count: usize,
+    /// Sum of the values, `None` past `Amount::MAX_MONEY`
+    pub total_value: Option<Amount>,
+    /// Entries by order of magnitude of their value, only the buckets with entries
+    pub histogram: Vec<ValueBucket>,
+    pub addresses: usize,
+}
+
+/// Value of the output granted by the ICO in `State::new`, the coins in existence before any
+/// block reward.
+pub const ICO_VALUE: Amount = Amount::new(10000);
+
+/// Blocks at the tip of the longest chain whose undo data the state keeps when the chain has an
+/// undo store. Deeper reorgs disconnect blocks with the undo data the store keeps, see
+/// `Blockchain::stored_undo`.
+pub const UNDO_DEPTH: u64 = 100;
+
+/// An entry of the UTXO set: the value and the recipient of the output, and the height of the
+/// coinbase that created it, `None` for the outputs of other transactions.
+pub type Utxo = (Amount, H160, Option<u64>);
+
+/// The outputs spent by a transaction, needed to roll it back.
+pub type Undo = Vec<((H256, u8), Utxo)>;
+
 impl State {
     pub fn new() -> Self {
         use crate::crypto::key_pair;
-        let mut utxo = HashMap::new();
+        let mut state = State::default();
         let bytes32 = [0u8; 32];
         let tx_hash: H256 = bytes32.into();
         let output_idx: u8 = 0;
-        let value: u64 = 10000;
+        let value = ICO_VALUE;
         let seed = [0u8; 32];
         let key = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
         let public_key = key.public_key();
@@ -25,69 +106,931 @@ impl State {
         let recipient: H160 = pb_hash.to_addr().into();
         let init_key = (tx_hash, output_idx);
         let init_val = (value, recipient);
-        utxo.insert(init_key, init_val);
-        println!("ICO completed. {:?} coins are granted to {:?}", value, recipient);
-        State { utxo: utxo }
+        state.insert_utxo(init_key, init_val);
+        println!("ICO completed. {} coins are granted to {}", value, recipient);
+        state
+    }
+
+    /// Add an unspent output of a transaction other than a coinbase, replacing any entry with
+    /// the same key.
+    pub fn insert_utxo(&mut self, key: (H256, u8), (value, recipient): (Amount, H160)) {
+        self.insert_entry(key, (value, recipient, None));
+    }
+
+    fn insert_entry(&mut self, key: (H256, u8), val: Utxo) {
+        if let Some(old) = self.utxo.insert(key, val) {
+            self.commitment = self.commitment.overflowing_sub(entry_hash(&key, &old)).0;
+            self.uncount_entry(&old);
+        }
+        self.commitment = self.commitment.overflowing_add(entry_hash(&key, &val)).0;
+        self.count_entry(&val);
     }
 
-    pub fn update(&mut self, transaction: &SignedTransaction) {
+    fn remove_utxo(&mut self, key: &(H256, u8)) -> Option<Utxo> {
+        let val = self.utxo.remove(key)?;
+        self.commitment = self.commitment.overflowing_sub(entry_hash(key, &val)).0;
+        self.uncount_entry(&val);
+        Some(val)
+    }
+
+    fn count_entry(&mut self, (value, recipient, _): &Utxo) {
+        self.utxo_value += value.as_u64() as u128;
+        self.utxo_histogram[value_bucket(*value)] += 1;
+        *self.utxo_addresses.entry(*recipient).or_insert(0) += 1;
+    }
+
+    fn uncount_entry(&mut self, (value, recipient, _): &Utxo) {
+        self.utxo_value -= value.as_u64() as u128;
+        self.utxo_histogram[value_bucket(*value)] -= 1;
+        if let Some(count) = self.utxo_addresses.get_mut(recipient) {
+            *count -= 1;
+            if *count == 0 {
+                self.utxo_addresses.remove(recipient);
+            }
+        }
+    }
+
+    pub fn update(&mut self, transaction: &Hashed<SignedTransaction>) {
         println!("Before state update");
         for (key, val) in self.utxo.iter() {
             println!("key: {:?}, val: {:?}", key, val);
         }
-        let tx = transaction.transaction.clone();
-        let input = tx.input;
-        let output = tx.output;
-        for txin in input {
-            let key = (txin.previous_output, txin.index);
-            self.utxo.remove(&key);
-        }
-        let mut idx = 0;
-        for txout in output {
-            let tx_hash = transaction.hash();
-            self.utxo.insert((tx_hash, idx), (txout.value, txout.recipient));
-            idx += 1;
-        }
+        self.apply(transaction);
         println!("After state update");
         for (key, val) in self.utxo.iter() {
             println!("key: {:?}, val: {:?}", key, val);
         }
 
     }
+
+    /// Spend the inputs and create the outputs of a transaction. Returns what is needed to roll
+    /// it back.
+    pub fn apply(&mut self, transaction: &Hashed<SignedTransaction>) -> Undo {
+        let tx = &transaction.transaction;
+        let mut undo = Vec::new();
+        for txin in &tx.input {
+            let key = (txin.previous_output, txin.index);
+            if let Some(val) = self.remove_utxo(&key) {
+                undo.push((key, val));
+            }
+        }
+        let tx_hash = transaction.hash();
+        let coinbase_height = tx.coinbase_height();
+        for (idx, txout) in tx.output.iter().enumerate() {
+            self.insert_entry((tx_hash, idx as u8), (txout.value, txout.recipient, coinbase_height));
+        }
+        undo
+    }
+
+    /// Undo `apply` of a transaction, which must be the last one applied.
+    pub fn rollback(&mut self, transaction: &Hashed<SignedTransaction>, undo: &Undo) {
+        let tx_hash = transaction.hash();
+        for idx in 0..transaction.transaction.output.len() {
+            self.remove_utxo(&(tx_hash, idx as u8));
+        }
+        for (key, val) in undo {
+            self.insert_entry(*key, *val);
+        }
+    }
+
+    /// The sum of all unspent outputs, `None` past `Amount::MAX_MONEY`.
+    pub fn supply(&self) -> Option<Amount> {
+        if self.utxo_value > Amount::MAX_MONEY.as_u64() as u128 {
+            return None;
+        }
+        Some(Amount::from(self.utxo_value as u64))
+    }
+
+    /// Statistics of the UTXO set, kept up to date with every change rather than computed by
+    /// going through the set.
+    pub fn utxo_stats(&self) -> UtxoStats {
+        let histogram = self
+            .utxo_histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(digits, count)| match digits {
+                0 => ValueBucket { min: 0, max: 0, count: *count },
+                _ => ValueBucket {
+                    min: 10u64.pow(digits as u32 - 1),
+                    max: 10u64.checked_pow(digits as u32).map_or(u64::MAX, |bound| bound - 1),
+                    count: *count,
+                },
+            })
+            .collect();
+        UtxoStats {
+            count: self.utxo.len(),
+            total_value: self.supply(),
+            histogram: histogram,
+            addresses: self.utxo_addresses.len(),
+        }
+    }
+
+    /// Apply all transactions of a block in order.
+    pub fn apply_block(&mut self, block: &Block) -> Vec<Undo> {
+        block.content.data.iter().map(|tx| self.apply(tx)).collect()
+    }
+
+    /// Undo `apply_block`, which must be the last block applied.
+    pub fn rollback_block(&mut self, block: &Block, undo: &[Undo]) {
+        for (tx, tx_undo) in block.content.data.iter().zip(undo.iter()).rev() {
+            self.rollback(tx, tx_undo);
+        }
+    }
+
+    /// Height of the last block connected, 0 before any.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Apply a block joining the longest chain, and keep what it spent until it is disconnected.
+    pub fn connect_block(&mut self, block: &Block) -> Vec<Undo> {
+        let hash = block.hash();
+        let undo = self.apply_block(block);
+        self.height = block.header.height;
+        self.commit_block(hash);
+        self.block_undo.insert(hash, (self.height, undo.clone()));
+        undo
+    }
+
+    /// Drop the undo data of the blocks more than `UNDO_DEPTH` below the last one connected, for
+    /// when it can be read back from where it was stored.
+    pub fn prune_undo(&mut self) {
+        let height = self.height;
+        self.block_undo.retain(|_, (connected, _)| connected.saturating_add(UNDO_DEPTH) > height);
+    }
+
+    /// Roll back a block leaving the longest chain, which must be the last block connected.
+    /// Returns false, leaving the state untouched, if the block was not connected or its undo
+    /// data was pruned, see `disconnect_block_with`.
+    pub fn disconnect_block(&mut self, block: &Block) -> bool {
+        match self.block_undo.remove(&block.hash()) {
+            Some((_, undo)) => {
+                self.disconnect_block_with(block, &undo);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like `disconnect_block`, with undo data read back from where it was stored.
+    pub fn disconnect_block_with(&mut self, block: &Block, undo: &[Undo]) {
+        self.rollback_block(block, undo);
+        self.height = block.header.height.saturating_sub(1);
+    }
+
+    /// The outputs spent by a block connected, one entry per transaction, unless pruned.
+    pub fn block_undo(&self, block_hash: &H256) -> Option<&[Undo]> {
+        self.block_undo.get(block_hash).map(|(_, undo)| undo.as_slice())
+    }
+
+    /// A commitment to the UTXO set that does not depend on the order of insertion, so that
+    /// nodes can compare their states. It is updated with every change instead of rehashing the
+    /// whole set. Being additive, it is only meant for comparing honest nodes: colliding sets can
+    /// be crafted, so verifying snapshots from untrusted peers would need a stronger set hash.
+    pub fn commitment(&self) -> H256 {
+        self.commitment.into()
+    }
+
+    /// Record the current commitment as the one after applying the block.
+    pub fn commit_block(&mut self, block_hash: H256) {
+        self.block_commitments.insert(block_hash, self.commitment());
+    }
+
+    /// The commitment recorded by `commit_block`.
+    pub fn block_commitment(&self, block_hash: &H256) -> Option<H256> {
+        self.block_commitments.get(block_hash).cloned()
+    }
+}
+
+fn entry_hash(key: &(H256, u8), val: &Utxo) -> U256 {
+    let entry: H256 = digest::digest(&digest::SHA256, &bincode::serialize(&(key, val)).unwrap()).into();
+    entry.into()
+}
+
+/// Reasons a transaction is not accepted into the mempool.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AcceptError {
+    Duplicate,
+    /// Some inputs are unknown, the transaction is held until they show up.
+    Orphan,
+    /// An input is already spent by another transaction of the mempool.
+    Conflict,
+    Invalid(TxError),
+    Policy(PolicyError),
+    /// The mempool is at its share of the memory budget, and pays more than the transaction.
+    MempoolFull,
+}
+
+impl std::fmt::Display for AcceptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AcceptError::Duplicate => write!(f, "transaction already known"),
+            AcceptError::Orphan => write!(f, "inputs not found, held as orphan"),
+            AcceptError::Conflict => write!(f, "input already spent in the mempool"),
+            AcceptError::Invalid(e) => write!(f, "consensus check failed: {}", e),
+            AcceptError::Policy(e) => write!(f, "policy check failed: {}", e),
+            AcceptError::MempoolFull => write!(f, "mempool full, fee rate too low"),
+        }
+    }
+}
+
+/// Bookkeeping for a transaction waiting in the mempool.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryInfo {
+    /// Milliseconds since the UNIX epoch.
+    pub time_added: u128,
+    /// Only known for transactions that went through `Mempool::accept`.
+    pub fee: Option<Amount>,
+}
+
+/// Maximum number of orphan transactions held, further orphans are dropped.
+pub const MAX_ORPHANS: usize = 100;
+/// Milliseconds after which an orphan transaction is dropped if its inputs did not show up.
+pub const ORPHAN_EXPIRY: u128 = 20 * 60 * 1000;
+
+/// The transactions of a mempool and their bookkeeping, shared with the threads that only
+/// look transactions up, so that they do not wait for the mempool lock. Only the mempool
+/// changes it, so a transaction found here has passed `Mempool::accept` at some point, but
+/// may be gone by the time it is used.
+#[derive(Default)]
+pub struct TxPool {
+    transactions: ShardedMap<H256, Arc<Hashed<SignedTransaction>>>,
+    info: ShardedMap<H256, EntryInfo>,
+    /// Transactions with a known fee by decreasing fee rate, locked apart from the rest
+    by_fee_rate: RwLock<BTreeSet<(Reverse<u64>, H256)>>,
+}
+
+impl TxPool {
+    pub fn get(&self, tx_hash: &H256) -> Option<Arc<Hashed<SignedTransaction>>> {
+        self.transactions.get(tx_hash)
+    }
+
+    pub fn contains(&self, tx_hash: &H256) -> bool {
+        self.transactions.contains_key(tx_hash)
+    }
+
+    pub fn info(&self, tx_hash: &H256) -> Option<EntryInfo> {
+        self.info.get(tx_hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn hashes(&self) -> Vec<H256> {
+        self.transactions.keys()
+    }
+
+    pub fn transactions(&self) -> Vec<Arc<Hashed<SignedTransaction>>> {
+        self.transactions.values()
+    }
+
+    /// Hashes of the transactions with a known fee, highest fee rate first.
+    pub fn by_fee_rate(&self) -> Vec<H256> {
+        self.by_fee_rate.read().unwrap().iter().map(|(_, hash)| *hash).collect()
+    }
+
+    /// Transactions added at or after `since`, in milliseconds since the UNIX epoch, paying at
+    /// least `min_fee_rate`, at most `limit` of them. They come by decreasing fee rate, followed
+    /// by those of unknown fee, oldest first, which a fee rate filter leaves out.
+    pub fn select(
+        &self,
+        min_fee_rate: Option<u64>,
+        since: Option<u128>,
+        limit: usize,
+    ) -> Vec<(Arc<Hashed<SignedTransaction>>, EntryInfo)> {
+        let by_fee_rate: Vec<H256> = self
+            .by_fee_rate
+            .read()
+            .unwrap()
+            .iter()
+            .take_while(|(Reverse(rate), _)| min_fee_rate.map_or(true, |min| *rate >= min))
+            .map(|(_, hash)| *hash)
+            .collect();
+        let mut unknown_fee: Vec<(H256, EntryInfo)> = Vec::new();
+        if min_fee_rate.is_none() {
+            unknown_fee = self.info.entries().into_iter().filter(|(_, info)| info.fee.is_none()).collect();
+            unknown_fee.sort_by_key(|(hash, info)| (info.time_added, *hash));
+        }
+        by_fee_rate
+            .into_iter()
+            .filter_map(|hash| Some((hash, self.info.get(&hash)?)))
+            .chain(unknown_fee)
+            .filter(|(_, info)| since.map_or(true, |since| info.time_added >= since))
+            // transactions removed meanwhile are skipped
+            .filter_map(|(hash, info)| Some((self.transactions.get(&hash)?, info)))
+            .take(limit)
+            .collect()
+    }
+
+    /// The transaction least worth keeping: the lowest fee rate, or without a known fee rate,
+    /// the oldest.
+    fn cheapest(&self) -> Option<H256> {
+        if let Some((_, hash)) = self.by_fee_rate.read().unwrap().iter().next_back() {
+            return Some(*hash);
+        }
+        self.info.entries().into_iter().min_by_key(|(hash, info)| (info.time_added, *hash)).map(|(hash, _)| hash)
+    }
+
+    fn fee_rate(transaction: &Hashed<SignedTransaction>, fee: Amount) -> u64 {
+        fee.per_kilobyte(transaction.serialized_size())
+    }
+
+    fn insert(&self, transaction: Hashed<SignedTransaction>, info: EntryInfo) -> Arc<Hashed<SignedTransaction>> {
+        let tx_hash = transaction.hash();
+        if let Some(fee) = info.fee {
+            let rate = TxPool::fee_rate(&transaction, fee);
+            self.by_fee_rate.write().unwrap().insert((Reverse(rate), tx_hash));
+        }
+        let transaction = Arc::new(transaction);
+        self.info.insert(tx_hash, info);
+        self.transactions.insert(tx_hash, Arc::clone(&transaction));
+        transaction
+    }
+
+    fn remove(&self, tx_hash: &H256) -> Option<Arc<Hashed<SignedTransaction>>> {
+        let transaction = self.transactions.remove(tx_hash)?;
+        if let Some(fee) = self.info.remove(tx_hash).and_then(|info| info.fee) {
+            let rate = TxPool::fee_rate(&transaction, fee);
+            self.by_fee_rate.write().unwrap().remove(&(Reverse(rate), *tx_hash));
+        }
+        Some(transaction)
+    }
 }
 
 pub struct Mempool {
-    pub txmap: HashMap<H256, SignedTransaction>,
-    pub txset: HashSet<H256>,
+    /// Shared with readers through `pool`, changed only by the mempool
+    txpool: Arc<TxPool>,
+    /// The mempool transaction spending each output
+    spent: HashMap<(H256, u8), H256>,
+    /// Transactions spending outputs not in the UTXO set yet, with the time they arrived
+    orphans: HashMap<H256, (Hashed<SignedTransaction>, u128)>,
+    /// Orphans waiting for each missing parent transaction
+    orphans_by_parent: HashMap<H256, HashSet<H256>>,
+    listeners: Vec<Arc<dyn ChainListener>>,
+    clock: Arc<dyn Clock>,
+    /// Accounts the transactions and the orphans, unlimited unless `set_memory_budget`
+    memory: Arc<MemoryBudget>,
 }
 
 impl Mempool {
     pub fn new() -> Self {
-        let mut txmap = HashMap::new();
-        let mut txset = HashSet::new();
-        Mempool { txmap: txmap, txset: txset }
+        Mempool::with_clock(&(Arc::new(SystemClock) as Arc<dyn Clock>))
+    }
+
+    /// Create a mempool that dates its entries with the given clock.
+    pub fn with_clock(clock: &Arc<dyn Clock>) -> Self {
+        Mempool {
+            txpool: Arc::new(TxPool::default()),
+            spent: HashMap::new(),
+            orphans: HashMap::new(),
+            orphans_by_parent: HashMap::new(),
+            listeners: Vec::new(),
+            clock: Arc::clone(clock),
+            memory: Arc::new(MemoryBudget::unlimited()),
+        }
+    }
+
+    /// Account the transactions and the orphans against a budget shared with other pools,
+    /// evicting the least valuable when it is exceeded. Set it before any transaction enters.
+    pub fn set_memory_budget(&mut self, memory: &Arc<MemoryBudget>) {
+        self.memory = Arc::clone(memory);
+    }
+
+    pub fn memory_budget(&self) -> Arc<MemoryBudget> {
+        Arc::clone(&self.memory)
+    }
+
+    /// The transactions of the mempool, for threads that look them up without holding the
+    /// mempool lock.
+    pub fn pool(&self) -> Arc<TxPool> {
+        Arc::clone(&self.txpool)
+    }
+
+    pub fn get(&self, tx_hash: &H256) -> Option<Arc<Hashed<SignedTransaction>>> {
+        self.txpool.get(tx_hash)
+    }
+
+    pub fn contains(&self, tx_hash: &H256) -> bool {
+        self.txpool.contains(tx_hash)
+    }
+
+    pub fn info(&self, tx_hash: &H256) -> Option<EntryInfo> {
+        self.txpool.info(tx_hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.txpool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txpool.is_empty()
+    }
+
+    pub fn hashes(&self) -> Vec<H256> {
+        self.txpool.hashes()
+    }
+
+    pub fn transactions(&self) -> Vec<Arc<Hashed<SignedTransaction>>> {
+        self.txpool.transactions()
+    }
+
+    /// Register a listener for transactions entering the mempool.
+    pub fn subscribe(&mut self, listener: Arc<dyn ChainListener>) {
+        self.listeners.push(listener);
     }
 
     pub fn insert(&mut self, transaction: &SignedTransaction) {
+        self.insert_with_fee(Hashed::new(transaction.clone()), None);
+    }
+
+    /// Insert a transaction, unless known. Returns false if it was evicted right away, paying
+    /// less than the transactions already in when the memory budget is exceeded.
+    fn insert_with_fee(&mut self, transaction: Hashed<SignedTransaction>, fee: Option<Amount>) -> bool {
         let tx_hash: H256 = transaction.hash();
-        if self.txset.contains(&tx_hash) {
-            return;
+        if self.txpool.contains(&tx_hash) {
+            return true;
+        }
+        let time_added = self.clock.now();
+        for txin in &transaction.transaction.input {
+            self.spent.insert((txin.previous_output, txin.index), tx_hash);
+        }
+        self.memory.add(Pool::Mempool, transaction.serialized_size());
+        let transaction = self.txpool.insert(transaction, EntryInfo { time_added: time_added, fee: fee });
+        self.trim();
+        if !self.txpool.contains(&tx_hash) {
+            return false;
+        }
+        for listener in &self.listeners {
+            listener.on_tx_accepted(&transaction);
         }
-        self.txmap.insert(tx_hash, transaction.clone());
-        self.txset.insert(tx_hash);
+        true
+    }
+
+    /// Evict the transactions of lowest fee rate, with the transactions spending their outputs,
+    /// while the mempool is over its share of the memory budget.
+    fn trim(&mut self) {
+        while self.memory.over_budget(Pool::Mempool) {
+            let cheapest = match self.txpool.cheapest() {
+                Some(hash) => hash,
+                None => break,
+            };
+            let mut evicted = self.descendants(&cheapest);
+            evicted.insert(cheapest);
+            debug!("Mempool over its memory budget, evicting {} transactions", evicted.len());
+            for hash in evicted {
+                if self.remove_hash(&hash) {
+                    self.memory.evicted(Pool::Mempool);
+                }
+            }
+        }
+    }
+
+    /// Validate a transaction against the consensus rules and the relay policy, and insert it
+    /// if it passes both. Returns the hash of the accepted transaction. Outputs of mempool
+    /// transactions can be spent before they are confirmed. A transaction spending unknown
+    /// outputs is kept as an orphan, and retried by `retry_orphans` once its parents show up.
+    pub fn accept(&mut self, transaction: &SignedTransaction, state: &State, policy: &Policy) -> Result<H256, AcceptError> {
+        self.expire_orphans();
+        let transaction = Hashed::new(transaction.clone());
+        let time_added = self.clock.now();
+        self.accept_hashed(transaction, time_added, state, policy)
+    }
+
+    /// Put back a transaction of a block that left the longest chain, checked like `accept`.
+    /// A transaction spending unknown outputs is dropped rather than held as an orphan: they
+    /// were spent or never created on the new longest chain.
+    pub fn restore(&mut self, transaction: &SignedTransaction, state: &State, policy: &Policy) -> Result<H256, AcceptError> {
+        let transaction = Hashed::new(transaction.clone());
+        let tx_hash: H256 = transaction.hash();
+        let fee = self.check_hashed(&transaction, state, policy)?;
+        if !self.insert_with_fee(transaction, Some(fee)) {
+            return Err(AcceptError::MempoolFull);
+        }
+        Ok(tx_hash)
+    }
+
+    fn accept_hashed(
+        &mut self,
+        transaction: Hashed<SignedTransaction>,
+        time_added: u128,
+        state: &State,
+        policy: &Policy,
+    ) -> Result<H256, AcceptError> {
+        let tx_hash: H256 = transaction.hash();
+        let fee = match self.check_hashed(&transaction, state, policy) {
+            Ok(fee) => fee,
+            Err(AcceptError::Invalid(TxError::MissingInput)) => {
+                self.add_orphan(transaction, time_added, state);
+                return Err(AcceptError::Orphan);
+            }
+            Err(e) => return Err(e),
+        };
+        if !self.insert_with_fee(transaction, Some(fee)) {
+            return Err(AcceptError::MempoolFull);
+        }
+        Ok(tx_hash)
+    }
+
+    /// Run the checks of `accept` without changing the mempool, and return the fee the
+    /// transaction pays. Unknown inputs are reported as `MissingInput` rather than held.
+    pub fn check(&self, transaction: &SignedTransaction, state: &State, policy: &Policy) -> Result<Amount, AcceptError> {
+        self.check_hashed(&Hashed::new(transaction.clone()), state, policy)
     }
 
-    pub fn remove(&mut self, transaction: &SignedTransaction) {
+    fn check_hashed(&self, transaction: &Hashed<SignedTransaction>, state: &State, policy: &Policy) -> Result<Amount, AcceptError> {
         let tx_hash: H256 = transaction.hash();
-        if self.txmap.contains_key(&tx_hash) {
-            self.txmap.remove(&tx_hash);
+        if self.txpool.contains(&tx_hash) || self.orphans.contains_key(&tx_hash) {
+            return Err(AcceptError::Duplicate);
+        }
+        if transaction.transaction.input.iter().any(|txin| self.spent.contains_key(&(txin.previous_output, txin.index))) {
+            return Err(AcceptError::Conflict);
+        }
+        let fee = validation::check_transaction(transaction, &self.spent_outputs(transaction, state))
+            .map_err(AcceptError::Invalid)?;
+        // the transaction could be mined in the next block at the earliest
+        validation::check_maturity(transaction, state, state.height() + 1).map_err(AcceptError::Invalid)?;
+        policy.check(transaction, fee).map_err(AcceptError::Policy)?;
+        Ok(fee)
+    }
+
+    /// An output in the UTXO set, or created by a mempool transaction.
+    pub fn find_output(&self, outpoint: &(H256, u8), state: &State) -> Option<(Amount, H160)> {
+        if let Some((value, recipient, _)) = state.utxo.get(outpoint) {
+            return Some((*value, *recipient));
+        }
+        let transaction = self.txpool.get(&outpoint.0)?;
+        let txout = transaction.transaction.output.get(outpoint.1 as usize)?;
+        Some((txout.value, txout.recipient))
+    }
+
+    /// The outputs a transaction spends, as a state to check it against.
+    fn spent_outputs(&self, transaction: &SignedTransaction, state: &State) -> State {
+        let mut view = State::default();
+        for txin in &transaction.transaction.input {
+            let outpoint = (txin.previous_output, txin.index);
+            if let Some(val) = self.find_output(&outpoint, state) {
+                view.insert_utxo(outpoint, val);
+            }
+        }
+        view
+    }
+
+    /// The mempool transactions a transaction spends outputs of.
+    pub fn parents(&self, tx_hash: &H256) -> Vec<H256> {
+        let mut parents: Vec<H256> = match self.txpool.get(tx_hash) {
+            Some(transaction) => transaction
+                .transaction
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .filter(|parent| self.txpool.contains(parent))
+                .collect(),
+            None => return Vec::new(),
+        };
+        parents.sort();
+        parents.dedup();
+        parents
+    }
+
+    /// The mempool transactions spending outputs of a transaction.
+    pub fn children(&self, tx_hash: &H256) -> Vec<H256> {
+        let mut children: Vec<H256> = match self.txpool.get(tx_hash) {
+            Some(transaction) => (0..transaction.transaction.output.len())
+                .filter_map(|idx| self.spent.get(&(*tx_hash, idx as u8)).cloned())
+                .collect(),
+            None => return Vec::new(),
+        };
+        children.sort();
+        children.dedup();
+        children
+    }
+
+    /// The transactions spending outputs of a transaction, directly or not, in no particular
+    /// order.
+    pub fn descendants(&self, tx_hash: &H256) -> HashSet<H256> {
+        let mut descendants = HashSet::new();
+        let mut queue = vec![*tx_hash];
+        while let Some(hash) = queue.pop() {
+            for child in self.children(&hash) {
+                if descendants.insert(child) {
+                    queue.push(child);
+                }
+            }
+        }
+        descendants
+    }
+
+    /// `check` each transaction, as if those before it in the batch had been accepted: spending
+    /// an input twice within the batch is a conflict. Outputs created within the batch are not
+    /// available to later transactions.
+    pub fn check_batch(&self, transactions: &[SignedTransaction], state: &State, policy: &Policy) -> Vec<Result<Amount, AcceptError>> {
+        let mut spent = HashSet::new();
+        let mut seen = HashSet::new();
+        transactions
+            .iter()
+            .map(|transaction| {
+                let transaction = Hashed::new(transaction.clone());
+                if !seen.insert(transaction.hash()) {
+                    return Err(AcceptError::Duplicate);
+                }
+                let inputs: Vec<(H256, u8)> = transaction.transaction.input.iter().map(|txin| (txin.previous_output, txin.index)).collect();
+                if inputs.iter().any(|input| spent.contains(input)) {
+                    return Err(AcceptError::Conflict);
+                }
+                let fee = self.check_hashed(&transaction, state, policy)?;
+                spent.extend(inputs);
+                Ok(fee)
+            })
+            .collect()
+    }
+
+    fn add_orphan(&mut self, transaction: Hashed<SignedTransaction>, time_added: u128, state: &State) {
+        if self.orphans.len() >= MAX_ORPHANS {
+            debug!("Orphan pool full, dropping transaction {}", transaction.hash());
+            return;
+        }
+        let tx_hash = transaction.hash();
+        for txin in &transaction.transaction.input {
+            if !state.utxo.contains_key(&(txin.previous_output, txin.index)) {
+                self.orphans_by_parent.entry(txin.previous_output).or_insert_with(HashSet::new).insert(tx_hash);
+            }
+        }
+        self.memory.add(Pool::OrphanTransactions, transaction.serialized_size());
+        self.orphans.insert(tx_hash, (transaction, time_added));
+        // the oldest orphans are the least likely to see their inputs
+        while self.memory.over_budget(Pool::OrphanTransactions) {
+            let oldest = match self.orphans.iter().min_by_key(|(hash, (_, time_added))| (*time_added, **hash)) {
+                Some((hash, _)) => *hash,
+                None => break,
+            };
+            debug!("Orphan pool over its memory budget, dropping transaction {}", oldest);
+            self.remove_orphan(&oldest);
+            self.memory.evicted(Pool::OrphanTransactions);
+        }
+    }
+
+    fn remove_orphan(&mut self, tx_hash: &H256) -> Option<(Hashed<SignedTransaction>, u128)> {
+        let (transaction, time_added) = self.orphans.remove(tx_hash)?;
+        self.memory.remove(Pool::OrphanTransactions, transaction.serialized_size());
+        for txin in &transaction.transaction.input {
+            if let Some(waiting) = self.orphans_by_parent.get_mut(&txin.previous_output) {
+                waiting.remove(tx_hash);
+                if waiting.is_empty() {
+                    self.orphans_by_parent.remove(&txin.previous_output);
+                }
+            }
+        }
+        Some((transaction, time_added))
+    }
+
+    /// Drop the orphans older than `ORPHAN_EXPIRY`.
+    fn expire_orphans(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, (_, time_added))| now.saturating_sub(*time_added) > ORPHAN_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove_orphan(&hash);
+        }
+    }
+
+    /// Retry the orphans spending outputs of the given transactions, which just arrived in a
+    /// block or in the mempool. Orphans still missing inputs stay in the pool. Returns the
+    /// hashes of the transactions accepted into the mempool.
+    pub fn retry_orphans(&mut self, parents: &[H256], state: &State, policy: &Policy) -> Vec<H256> {
+        self.expire_orphans();
+        let mut accepted = Vec::new();
+        let mut queue = parents.to_vec();
+        while let Some(parent) = queue.pop() {
+            let waiting = match self.orphans_by_parent.get(&parent) {
+                Some(waiting) => waiting.iter().cloned().collect::<Vec<H256>>(),
+                None => continue,
+            };
+            for hash in waiting {
+                let (transaction, time_added) = match self.remove_orphan(&hash) {
+                    Some(orphan) => orphan,
+                    None => continue,
+                };
+                match self.accept_hashed(transaction, time_added, state, policy) {
+                    Ok(hash) => {
+                        accepted.push(hash);
+                        queue.push(hash);
+                    }
+                    Err(AcceptError::Orphan) => {}
+                    Err(e) => debug!("Orphan transaction {} rejected: {}", hash, e),
+                }
+            }
+        }
+        accepted
+    }
+
+    /// Number of orphan transactions held.
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// The mempool transaction spending an output.
+    pub fn spender(&self, outpoint: &(H256, u8)) -> Option<H256> {
+        self.spent.get(outpoint).cloned()
+    }
+
+    pub fn remove(&mut self, transaction: &Hashed<SignedTransaction>) {
+        self.remove_hash(&transaction.hash());
+    }
+
+    fn remove_hash(&mut self, tx_hash: &H256) -> bool {
+        let transaction = match self.txpool.remove(tx_hash) {
+            Some(transaction) => transaction,
+            None => return false,
+        };
+        self.memory.remove(Pool::Mempool, transaction.serialized_size());
+        for txin in &transaction.transaction.input {
+            let outpoint = (txin.previous_output, txin.index);
+            if self.spent.get(&outpoint) == Some(tx_hash) {
+                self.spent.remove(&outpoint);
+            }
+        }
+        true
+    }
+
+    /// Remove transactions found invalid by a `Revalidation`, with the transactions spending
+    /// their outputs. Returns how many were still in the mempool.
+    pub fn evict(&mut self, hashes: &[H256]) -> usize {
+        let mut evicted: HashSet<H256> = HashSet::new();
+        for hash in hashes {
+            evicted.extend(self.descendants(hash));
+            evicted.insert(*hash);
+        }
+        evicted.iter().filter(|hash| self.remove_hash(hash)).count()
+    }
+}
+
+/// A check of the mempool transactions against the UTXO set after blocks connected, which may
+/// have spent their inputs. Taking the snapshot only shares the transactions and copies the
+/// outputs they spend, so the locks on the mempool and the state are held briefly, and the
+/// signatures are checked in parallel without holding any lock. Transactions spending outputs of invalid
+/// ones are left to `Mempool::evict`.
+pub struct Revalidation {
+    transactions: Vec<Arc<Hashed<SignedTransaction>>>,
+    /// The spent outputs found in the UTXO set or created by mempool transactions
+    utxos: Vec<((H256, u8), (Amount, H160))>,
+    /// The transactions spending coinbase outputs no longer mature, after a reorg
+    immature: Vec<H256>,
+}
+
+impl Revalidation {
+    pub fn new(mempool: &Mempool, state: &State) -> Self {
+        let transactions = mempool.transactions();
+        let utxos = transactions
+            .iter()
+            .flat_map(|tx| tx.transaction.input.iter())
+            .filter_map(|txin| {
+                let key = (txin.previous_output, txin.index);
+                mempool.find_output(&key, state).map(|val| (key, val))
+            })
+            .collect();
+        let immature = transactions
+            .iter()
+            .filter(|tx| validation::check_maturity(tx, state, state.height() + 1).is_err())
+            .map(|tx| tx.hash())
+            .collect();
+        Revalidation { transactions: transactions, utxos: utxos, immature: immature }
+    }
+
+    /// The hashes of the transactions no longer valid.
+    pub fn run(self) -> Vec<H256> {
+        let mut state = State::default();
+        for (key, val) in self.utxos {
+            state.insert_utxo(key, val);
         }
+        let immature = &self.immature;
+        self.transactions
+            .par_iter()
+            .filter(|tx| immature.contains(&tx.hash()) || validation::check_transaction(tx, &state).is_err())
+            .map(|tx| tx.hash())
+            .collect()
     }
 }
 
+/// A transaction signed as a whole by the owner of all its inputs, or signed per input.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
+    /// The scheme of the public keys and the signatures
+    pub algorithm: Algorithm,
+    pub witness: Witness,
+}
+
+/// The signatures of a transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Witness {
+    /// One signature of the whole transaction, by the owner of all its inputs
+    Whole { public_key: Vec<u8>, signature: Vec<u8> },
+    /// One signature per input, in input order, see `sign_input`
+    PerInput(Vec<InputSignature>),
+    /// No signature, as for a coinbase, which spends no output
+    Unsigned,
+}
+
+impl Default for Witness {
+    fn default() -> Self {
+        Witness::Unsigned
+    }
+}
+
+impl SignedTransaction {
+    /// Sign a transaction with a key held in memory.
+    pub fn new(transaction: Transaction, key: &Ed25519KeyPair) -> Self {
+        SignedTransaction::try_new(transaction, key).expect("keys held in memory always sign")
+    }
+
+    /// Sign a transaction with a key of any supported scheme, failing if the key is held by a
+    /// device that does not answer.
+    pub fn try_new(transaction: Transaction, signer: &dyn Signer) -> io::Result<Self> {
+        let signature = signer.sign(signing_hash(&transaction).as_ref())?;
+        Ok(SignedTransaction {
+            transaction: transaction,
+            algorithm: signer.algorithm(),
+            witness: Witness::Whole { public_key: signer.public_key_bytes(), signature: signature },
+        })
+    }
+
+    /// Wrap a coinbase, which spends no output and so carries no signature.
+    pub fn coinbase(transaction: Transaction) -> Self {
+        SignedTransaction {
+            transaction: transaction,
+            algorithm: Algorithm::default(),
+            witness: Witness::Unsigned,
+        }
+    }
+
+    /// A transaction carrying one signature per input, in input order, see `sign_input`.
+    pub fn with_input_signatures(transaction: Transaction, algorithm: Algorithm, signatures: &[InputSignature]) -> Self {
+        SignedTransaction {
+            transaction: transaction,
+            algorithm: algorithm,
+            witness: Witness::PerInput(signatures.to_vec()),
+        }
+    }
+
+    /// The public keys that signed the transaction, none for a coinbase.
+    pub fn signers(&self) -> Vec<Vec<u8>> {
+        match &self.witness {
+            Witness::Whole { public_key, .. } => vec![public_key.clone()],
+            Witness::PerInput(signatures) => signatures.iter().map(|signature| signature.public_key.clone()).collect(),
+            Witness::Unsigned => Vec::new(),
+        }
+    }
+
+    /// The length of the encoding, counted against the size limits and by fee rates. Computed
+    /// on every call: `Hashed` computes it once for the transactions of the mempool and blocks.
+    pub fn serialized_size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+}
+
+/// What the signature of an input commits to, after Bitcoin's SIGHASH flags: all the outputs,
+/// or with `Single` only the output at the index of the input, and all the inputs, or with
+/// ANYONECANPAY only the input itself. The other parts of the transaction can then be changed
+/// without invalidating the signature, so that several owners can build a transaction together.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SigHash {
+    All,
+    Single,
+    AllAnyoneCanPay,
+    SingleAnyoneCanPay,
+}
+
+impl SigHash {
+    pub fn anyone_can_pay(self) -> bool {
+        match self {
+            SigHash::All | SigHash::Single => false,
+            SigHash::AllAnyoneCanPay | SigHash::SingleAnyoneCanPay => true,
+        }
+    }
+
+    pub fn single(self) -> bool {
+        match self {
+            SigHash::All | SigHash::AllAnyoneCanPay => false,
+            SigHash::Single | SigHash::SingleAnyoneCanPay => true,
+        }
+    }
+}
+
+/// The signature of one input by the owner of the output it spends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputSignature {
+    pub sighash: SigHash,
     pub public_key: Vec<u8>,
     pub signature: Vec<u8>,
 }
@@ -99,12 +1042,47 @@ impl Hashable for SignedTransaction {
     }
 }
 
+/// Version of the transactions produced by this node.
+pub const VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
+    pub version: u32,
     pub input: Vec<TxIn>,
     pub output: Vec<TxOut>,
 }
 
+/// Index of the input of a coinbase. The previous output it names starts with 24 zero bytes,
+/// which no transaction hash does in practice.
+const COINBASE_INDEX: u8 = u8::MAX;
+
+impl Transaction {
+    /// The coinbase of the block at `height`, creating the block reward. Its only input spends
+    /// nothing but commits to the height, so that coinbases of different blocks paying the same
+    /// outputs have different hashes.
+    pub fn coinbase(height: u64, output: Vec<TxOut>) -> Self {
+        let mut previous_output = [0u8; 32];
+        previous_output[24..].copy_from_slice(&height.to_be_bytes());
+        let input = TxIn { previous_output: previous_output.into(), index: COINBASE_INDEX };
+        Transaction { version: VERSION, input: vec![input], output: output }
+    }
+
+    /// The height a coinbase commits to, `None` if the transaction is not a coinbase.
+    pub fn coinbase_height(&self) -> Option<u64> {
+        match self.input.as_slice() {
+            [txin] if txin.index == COINBASE_INDEX => {
+                let bytes: [u8; 32] = txin.previous_output.into();
+                if bytes[..24].iter().all(|b| *b == 0) {
+                    Some(u64::from_be_bytes(bytes[24..].try_into().unwrap()))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
 impl Hashable for Transaction {
     fn hash(&self) -> H256 {
         let m = bincode::serialize(&self).unwrap();
@@ -121,29 +1099,69 @@ pub struct TxIn {
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct TxOut {
     pub recipient: H160,
-    pub value: u64,
+    pub value: Amount,
+}
+
+/// The message signed for a transaction, its double SHA-256
+pub fn signing_hash(t: &Transaction) -> digest::Digest {
+    let m = bincode::serialize(&t).unwrap();
+    digest::digest(&digest::SHA256, digest::digest(&digest::SHA256, m.as_ref()).as_ref())
+}
+
+/// The message signed for the input at `index` under `sighash`: the double SHA-256 of the parts
+/// of the transaction it commits to, the index and the mode. `None` if there is no such input,
+/// or no output at the index of a `Single` input.
+pub fn input_signing_hash(t: &Transaction, index: usize, sighash: SigHash) -> Option<digest::Digest> {
+    let input = if sighash.anyone_can_pay() {
+        vec![t.input.get(index)?.clone()]
+    } else if index < t.input.len() {
+        t.input.clone()
+    } else {
+        return None;
+    };
+    let output = if sighash.single() {
+        vec![t.output.get(index)?.clone()]
+    } else {
+        t.output.clone()
+    };
+    let committed = Transaction { version: t.version, input: input, output: output };
+    let m = bincode::serialize(&(committed, index as u32, sighash)).unwrap();
+    Some(digest::digest(&digest::SHA256, digest::digest(&digest::SHA256, m.as_ref()).as_ref()))
+}
+
+/// Sign the input at `index`, see `input_signing_hash`. Returns `None` if the input cannot be
+/// signed with this sighash, and an error if the signer fails.
+pub fn sign_input(t: &Transaction, index: usize, sighash: SigHash, signer: &dyn Signer) -> io::Result<Option<InputSignature>> {
+    let message = match input_signing_hash(t, index, sighash) {
+        Some(message) => message,
+        None => return Ok(None),
+    };
+    Ok(Some(InputSignature {
+        sighash: sighash,
+        public_key: signer.public_key_bytes(),
+        signature: signer.sign(message.as_ref())?,
+    }))
 }
 
 /// Create digital signature of a transaction
 pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
-    let m = bincode::serialize(&t).unwrap();
-    let txid = digest::digest(&digest::SHA256, digest::digest(&digest::SHA256, m.as_ref()).as_ref());
+    let txid = signing_hash(t);
     let sig = key.sign(txid.as_ref());
     return sig;
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
 pub fn verify(t: &Transaction, public_key: &<Ed25519KeyPair as KeyPair>::PublicKey, signature: &Signature) -> bool {
-    let m = bincode::serialize(&t).unwrap();
-    let txid = digest::digest(&digest::SHA256, digest::digest(&digest::SHA256, m.as_ref()).as_ref());
+    let txid = signing_hash(t);
     let public_key_ = signature::UnparsedPublicKey::new(&signature::ED25519, public_key.as_ref());
     let ret = public_key_.verify(txid.as_ref(), signature.as_ref()).is_ok();
     return ret;
 }
 
 #[cfg(any(test, test_utilities))]
-mod tests {
+pub mod tests {
     use super::*;
+    use crate::crypto::address::address_of;
     use crate::crypto::key_pair;
 
     pub fn generate_random_transaction() -> Transaction {
@@ -155,7 +1173,7 @@ mod tests {
         let pb_hash: H256 = digest::digest(&digest::SHA256, public_key.as_ref()).into();
         let recipient: H160 = pb_hash.to_addr().into();
         let value: u64 = rng.gen();
-        let tx_out = TxOut { recipient: recipient, value: value };
+        let tx_out = TxOut { recipient: recipient, value: value.into() };
 
         let rand_num: u8 = rng.gen();
         let previous_output: H256 = [rand_num; 32].into();
@@ -164,10 +1182,302 @@ mod tests {
 
         let inputs = vec![tx_in];
         let outputs = vec![tx_out];
-        let tx = Transaction { input: inputs, output: outputs };
+        let tx = Transaction { version: VERSION, input: inputs, output: outputs };
         return tx;
     }
 
+    /// Spend the first output of a transaction with `key`, paying `value` to `recipient`.
+    pub fn spend(key: &Ed25519KeyPair, previous_output: H256, recipient: H160, value: u64) -> SignedTransaction {
+        let input = TxIn { previous_output: previous_output, index: 0 };
+        let output = TxOut { recipient: recipient, value: value.into() };
+        SignedTransaction::new(Transaction { version: VERSION, input: vec![input], output: vec![output] }, key)
+    }
+
+    /// The key of seed `[seed; 32]`.
+    pub fn key(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    /// The address of the outputs `key` spends.
+    pub fn owner(key: &Ed25519KeyPair) -> H160 {
+        address_of(key.public_key().as_ref())
+    }
+
+    /// A state with `value` coins of `owner` in output `[i; 32]:0` for each `i` of `outputs`.
+    pub fn funded(owner: H160, outputs: &[u8], value: u64) -> State {
+        let mut state = State::default();
+        for i in outputs {
+            state.insert_utxo(([*i; 32].into(), 0), (value.into(), owner));
+        }
+        state
+    }
+
+    struct Counter(std::sync::Mutex<usize>);
+
+    impl ChainListener for Counter {
+        fn on_tx_accepted(&self, _transaction: &SignedTransaction) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn mempool_notifies_listeners() {
+        let mut mempool = Mempool::new();
+        let counter = Arc::new(Counter(std::sync::Mutex::new(0)));
+        mempool.subscribe(counter.clone());
+        let tx = SignedTransaction { transaction: generate_random_transaction(), algorithm: Algorithm::Ed25519, witness: Witness::Unsigned };
+        mempool.insert(&tx);
+        mempool.insert(&tx);
+        assert_eq!(*counter.0.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn state_commitment() {
+        let mut state = State::new();
+        let mut other = State::new();
+        assert_eq!(state.commitment(), other.commitment());
+        let recipient: H160 = [1u8; 20].into();
+        for i in 0..10u8 {
+            state.insert_utxo(([i; 32].into(), i), ((i as u64).into(), recipient));
+        }
+        for i in (0..10u8).rev() {
+            other.insert_utxo(([i; 32].into(), i), ((i as u64).into(), recipient));
+        }
+        assert_eq!(state.commitment(), other.commitment());
+        other.insert_utxo(([0u8; 32].into(), 0), (1.into(), recipient));
+        assert_ne!(state.commitment(), other.commitment());
+
+        // spending and creating outputs gives the same commitment as building the set afresh
+        let tx = Hashed::new(SignedTransaction {
+            transaction: Transaction {
+                version: VERSION,
+                input: vec![TxIn { previous_output: [3u8; 32].into(), index: 3 }],
+                output: vec![TxOut { recipient: recipient, value: 3.into() }],
+            },
+            algorithm: Algorithm::Ed25519,
+            witness: Witness::Unsigned,
+        });
+        let before = state.commitment();
+        let undo = state.apply(&tx);
+        let mut fresh = State::default();
+        for (key, val) in &state.utxo {
+            fresh.insert_entry(*key, *val);
+        }
+        assert_eq!(state.commitment(), fresh.commitment());
+        state.rollback(&tx, &undo);
+        assert_eq!(state.commitment(), before);
+    }
+
+    #[test]
+    fn utxo_stats() {
+        let mut state = State::default();
+        let alice: H160 = [1u8; 20].into();
+        let bob: H160 = [2u8; 20].into();
+        state.insert_utxo(([1u8; 32].into(), 0), (5.into(), alice));
+        state.insert_utxo(([1u8; 32].into(), 1), (50.into(), alice));
+        state.insert_utxo(([2u8; 32].into(), 0), (70.into(), bob));
+        let stats = state.utxo_stats();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.total_value, Some(125.into()));
+        assert_eq!(stats.addresses, 2);
+        assert_eq!(stats.histogram, vec![
+            ValueBucket { min: 1, max: 9, count: 1 },
+            ValueBucket { min: 10, max: 99, count: 2 },
+        ]);
+
+        // replacing and spending entries keeps the counters in step with the set
+        state.insert_utxo(([2u8; 32].into(), 0), (0.into(), alice));
+        let tx = Hashed::new(SignedTransaction {
+            transaction: Transaction {
+                version: VERSION,
+                input: vec![TxIn { previous_output: [1u8; 32].into(), index: 1 }],
+                output: vec![TxOut { recipient: bob, value: u64::MAX.into() }],
+            },
+            algorithm: Algorithm::Ed25519,
+            witness: Witness::Unsigned,
+        });
+        let undo = state.apply(&tx);
+        let stats = state.utxo_stats();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.total_value, None);
+        assert_eq!(stats.histogram, vec![
+            ValueBucket { min: 0, max: 0, count: 1 },
+            ValueBucket { min: 1, max: 9, count: 1 },
+            ValueBucket { min: 10_000_000_000_000_000_000, max: u64::MAX, count: 1 },
+        ]);
+        state.rollback(&tx, &undo);
+        let stats = state.utxo_stats();
+        assert_eq!(stats.total_value, Some(55.into()));
+        assert_eq!(stats.addresses, 1);
+    }
+
+    #[test]
+    fn orphan_pool() {
+        use crate::clock::MockClock;
+        use std::time::Duration;
+        let key = key(0);
+        let owner = owner(&key);
+        let mut state = State::new();
+        let policy = Policy::default();
+        let clock = Arc::new(MockClock::new(0));
+        let mut mempool = Mempool::with_clock(&(clock.clone() as Arc<dyn Clock>));
+
+        // the parent spends the initial output, the child arrives first
+        let parent = Hashed::new(spend(&key, [0u8; 32].into(), owner, 10000));
+        let child = spend(&key, parent.hash(), owner, 9000);
+        assert_eq!(mempool.accept(&child, &state, &policy), Err(AcceptError::Orphan));
+        assert_eq!(mempool.accept(&child, &state, &policy), Err(AcceptError::Duplicate));
+        assert_eq!(mempool.orphan_count(), 1);
+        assert!(mempool.retry_orphans(&[[9u8; 32].into()], &state, &policy).is_empty());
+
+        // once the parent is confirmed the child enters the mempool
+        state.apply(&parent);
+        assert_eq!(mempool.retry_orphans(&[parent.hash()], &state, &policy), vec![child.hash()]);
+        assert_eq!(mempool.orphan_count(), 0);
+        assert!(mempool.contains(&child.hash()));
+
+        // orphans whose parents never show up expire
+        let orphan = spend(&key, [7u8; 32].into(), owner, 1);
+        assert_eq!(mempool.accept(&orphan, &state, &policy), Err(AcceptError::Orphan));
+        clock.advance(Duration::from_millis(ORPHAN_EXPIRY as u64 + 1));
+        assert_eq!(mempool.accept(&orphan, &state, &policy), Err(AcceptError::Orphan));
+        assert_eq!(mempool.orphan_count(), 1);
+        clock.advance(Duration::from_millis(ORPHAN_EXPIRY as u64 + 1));
+        assert!(mempool.retry_orphans(&[], &state, &policy).is_empty());
+        assert_eq!(mempool.orphan_count(), 0);
+    }
+
+    #[test]
+    fn memory_budget_evicts_lowest_fee_rate() {
+        let key = key(0);
+        let state = funded(owner(&key), &[1, 2, 3, 4], 10000);
+        let policy = Policy::default();
+        let size = spend(&key, [1u8; 32].into(), [9u8; 20].into(), 9000).serialized_size();
+        // room for two transactions
+        let memory = Arc::new(MemoryBudget::new(size * 5 / 2));
+        let mut mempool = Mempool::new();
+        mempool.set_memory_budget(&memory);
+
+        let low = mempool.accept(&spend(&key, [1u8; 32].into(), [9u8; 20].into(), 9000), &state, &policy).unwrap();
+        let high = mempool.accept(&spend(&key, [2u8; 32].into(), [9u8; 20].into(), 7000), &state, &policy).unwrap();
+        assert_eq!(mempool.accept(&spend(&key, [3u8; 32].into(), [9u8; 20].into(), 9500), &state, &policy), Err(AcceptError::MempoolFull));
+        assert_eq!(mempool.len(), 2);
+        // a better paying transaction takes the place of the cheapest one
+        let middle = mempool.accept(&spend(&key, [4u8; 32].into(), [9u8; 20].into(), 8000), &state, &policy).unwrap();
+        assert!(!mempool.contains(&low));
+        assert!(mempool.contains(&high) && mempool.contains(&middle));
+        assert_eq!(memory.usage(Pool::Mempool), 2 * size);
+        assert_eq!(memory.stats().pools[Pool::Mempool as usize].evicted, 2);
+    }
+
+    #[test]
+    fn revalidation() {
+        let key = key(0);
+        let mut state = funded(owner(&key), &[1, 2], 10);
+        let policy = Policy::default();
+        let mut mempool = Mempool::new();
+        let first = mempool.accept(&spend(&key, [1u8; 32].into(), [9u8; 20].into(), 10), &state, &policy).unwrap();
+        let second = mempool.accept(&spend(&key, [2u8; 32].into(), [9u8; 20].into(), 10), &state, &policy).unwrap();
+        assert_eq!(mempool.accept(&spend(&key, [1u8; 32].into(), [9u8; 20].into(), 9), &state, &policy), Err(AcceptError::Conflict));
+        assert_eq!(mempool.spender(&([1u8; 32].into(), 0)), Some(first));
+
+        // a block spends the input of the first transaction another way
+        let block_tx = Hashed::new(spend(&key, [1u8; 32].into(), [9u8; 20].into(), 8));
+        state.apply(&block_tx);
+        let invalid = Revalidation::new(&mempool, &state).run();
+        assert_eq!(invalid, vec![first]);
+        assert_eq!(mempool.evict(&invalid), 1);
+        assert_eq!(mempool.evict(&invalid), 0);
+        assert!(mempool.contains(&second));
+        assert_eq!(mempool.spender(&([1u8; 32].into(), 0)), None);
+    }
+
+    #[test]
+    fn unconfirmed_chain() {
+        let key = key(0);
+        let owner = owner(&key);
+        let mut state = funded(owner, &[1], 10);
+        let policy = Policy::default();
+        let mut mempool = Mempool::new();
+        let parent = mempool.accept(&spend(&key, [1u8; 32].into(), owner, 10), &state, &policy).unwrap();
+        let child = mempool.accept(&spend(&key, parent, owner, 8), &state, &policy).unwrap();
+        let grandchild = mempool.accept(&spend(&key, child, owner, 5), &state, &policy).unwrap();
+        assert_eq!(mempool.info(&child).unwrap().fee, Some(2.into()));
+        assert_eq!(mempool.parents(&child), vec![parent]);
+        assert_eq!(mempool.children(&child), vec![grandchild]);
+        assert_eq!(mempool.descendants(&parent), vec![child, grandchild].into_iter().collect());
+
+        // confirming the parent leaves its descendants valid
+        let parent_tx = (*mempool.get(&parent).unwrap()).clone();
+        mempool.remove(&parent_tx);
+        let undo = state.apply(&parent_tx);
+        assert!(mempool.parents(&child).is_empty());
+        assert!(Revalidation::new(&mempool, &state).run().is_empty());
+
+        // a conflicting block evicts the descendants of the transaction it invalidates
+        state.rollback(&parent_tx, &undo);
+        state.apply(&Hashed::new(spend(&key, [1u8; 32].into(), owner, 9)));
+        assert_eq!(Revalidation::new(&mempool, &state).run(), vec![child]);
+        assert_eq!(mempool.evict(&[child]), 2);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn select_from_pool() {
+        use crate::clock::MockClock;
+        use std::time::Duration;
+        let key = key(0);
+        let state = funded(owner(&key), &[1, 2, 3], 1000);
+        let policy = Policy::default();
+        let clock = Arc::new(MockClock::new(0));
+        let mut mempool = Mempool::with_clock(&(clock.clone() as Arc<dyn Clock>));
+        let low = mempool.accept(&spend(&key, [1u8; 32].into(), [9u8; 20].into(), 990), &state, &policy).unwrap();
+        clock.advance(Duration::from_millis(10));
+        let high = mempool.accept(&spend(&key, [2u8; 32].into(), [9u8; 20].into(), 900), &state, &policy).unwrap();
+        let unknown = Hashed::new(spend(&key, [3u8; 32].into(), [9u8; 20].into(), 1000));
+        mempool.insert(&unknown);
+        let pool = mempool.pool();
+        let hashes = |selected: Vec<(Arc<Hashed<SignedTransaction>>, EntryInfo)>| -> Vec<H256> {
+            selected.iter().map(|(tx, _)| tx.hash()).collect()
+        };
+
+        assert_eq!(hashes(pool.select(None, None, 10)), vec![high, low, unknown.hash()]);
+        assert_eq!(hashes(pool.select(None, None, 1)), vec![high]);
+        assert_eq!(hashes(pool.select(None, Some(10), 10)), vec![high, unknown.hash()]);
+        let size = mempool.get(&high).unwrap().serialized_size();
+        let high_rate = Amount::from(100).per_kilobyte(size);
+        assert_eq!(hashes(pool.select(Some(high_rate), None, 10)), vec![high]);
+        assert_eq!(hashes(pool.select(Some(0), None, 10)), vec![high, low]);
+    }
+
+    #[test]
+    fn check_batch() {
+        let key = key(0);
+        let state = funded(owner(&key), &[1, 2], 10);
+        let policy = Policy::default();
+        let mut mempool = Mempool::new();
+        let pooled = spend(&key, [2u8; 32].into(), [9u8; 20].into(), 10);
+        mempool.accept(&pooled, &state, &policy).unwrap();
+
+        let valid = spend(&key, [1u8; 32].into(), [9u8; 20].into(), 7);
+        let results = mempool.check_batch(
+            &[valid.clone(), valid.clone(), spend(&key, [1u8; 32].into(), [9u8; 20].into(), 6), pooled, spend(&key, [3u8; 32].into(), [9u8; 20].into(), 1)],
+            &state,
+            &policy,
+        );
+        assert_eq!(results, vec![
+            Ok(3.into()),
+            Err(AcceptError::Duplicate),
+            Err(AcceptError::Conflict),
+            Err(AcceptError::Duplicate),
+            Err(AcceptError::Invalid(TxError::MissingInput)),
+        ]);
+        // nothing was added
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.orphan_count(), 0);
+        assert_eq!(mempool.check(&valid, &state, &policy), Ok(3.into()));
+    }
+
     #[test]
     fn sign_verify() {
         let t = generate_random_transaction();
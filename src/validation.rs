@@ -0,0 +1,673 @@
+use crate::amount::Amount;
+use crate::block::{Block, Header};
+use crate::blockchain::Blockchain;
+use crate::clock::Clock;
+use crate::crypto::address::address_of;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::transaction::{self, SignedTransaction, State, Transaction, Witness};
+use ring::digest;
+use std::collections::HashSet;
+
+/// A consensus upgrade. Starting at `height`, blocks and transactions must carry at least the
+/// given versions. Blocks below the activation height are validated under the old rules, and
+/// versions above the required minimum are always accepted, so upgrades work like soft forks.
+pub struct Deployment {
+    pub name: &'static str,
+    pub height: usize,
+    pub block_version: u32,
+    pub tx_version: u32,
+}
+
+/// All deployments, ordered by activation height.
+pub const DEPLOYMENTS: &[Deployment] = &[
+    Deployment { name: "genesis", height: 0, block_version: 1, tx_version: 1 },
+];
+
+/// The latest deployment active at the given height.
+pub fn active_deployment(height: usize) -> &'static Deployment {
+    let mut active = &DEPLOYMENTS[0];
+    for deployment in DEPLOYMENTS {
+        if deployment.height <= height {
+            active = deployment;
+        }
+    }
+    return active;
+}
+
+/// Check the header version of a block at the given height.
+pub fn check_header_version(header: &Header, height: usize) -> bool {
+    header.version >= active_deployment(height).block_version
+}
+
+/// Check the version of a transaction included in a block at the given height.
+pub fn check_transaction_version(tx: &Transaction, height: usize) -> bool {
+    tx.version >= active_deployment(height).tx_version
+}
+
+/// Check that a header carries the height following its parent's.
+pub fn check_header_height(header: &Header, parent: &Header) -> bool {
+    header.height == parent.height + 1
+}
+
+/// How far ahead of the local clock a block timestamp may be, in milliseconds. Allows for clock
+/// skew between nodes.
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Check that a block is not timestamped too far in the future.
+pub fn check_timestamp(header: &Header, clock: &dyn Clock) -> bool {
+    header.timestamp <= clock.now() + MAX_FUTURE_BLOCK_TIME
+}
+
+pub fn check_block_weight(block: &Block) -> bool {
+    block.weight() <= MAX_BLOCK_WEIGHT
+}
+
+/// Most inputs a transaction may spend.
+pub const MAX_TX_INPUTS: usize = 1000;
+/// Most outputs a transaction may create. Inputs refer to outputs by a `u8` index, so further
+/// outputs could never be spent.
+pub const MAX_TX_OUTPUTS: usize = 256;
+/// Largest serialized transaction, in bytes.
+pub const MAX_TX_SIZE: usize = 100_000;
+/// Heaviest block, see `Block::weight`.
+pub const MAX_BLOCK_WEIGHT: usize = 1_000_000;
+
+/// Reasons a transaction violates the consensus rules.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TxError {
+    BadSignature,
+    MissingInput,
+    WrongOwner,
+    Overspend,
+    TooManyInputs,
+    TooManyOutputs,
+    TooLarge,
+    /// An output, or the sum of the inputs or outputs, exceeds `Amount::MAX_MONEY`.
+    ValueOutOfRange,
+    /// An input spends the output of a coinbase less than `COINBASE_MATURITY` blocks deep.
+    ImmatureCoinbase,
+    /// Two inputs spend the same output, which would count its value twice.
+    DuplicateInput,
+}
+
+impl std::fmt::Display for TxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            TxError::BadSignature => "invalid signature",
+            TxError::MissingInput => "input does not exist",
+            TxError::WrongOwner => "input is not owned by the signer",
+            TxError::Overspend => "outputs exceed inputs",
+            TxError::TooManyInputs => "too many inputs",
+            TxError::TooManyOutputs => "too many outputs",
+            TxError::TooLarge => "transaction too large",
+            TxError::ValueOutOfRange => "value out of range",
+            TxError::ImmatureCoinbase => "spends an immature coinbase",
+            TxError::DuplicateInput => "spends an output twice",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// Check the limits on the shape of a transaction, which do not depend on the UTXO set.
+/// Returns the sum of the outputs.
+pub fn check_transaction_limits(signed: &SignedTransaction) -> Result<Amount, TxError> {
+    let tx = &signed.transaction;
+    if tx.input.len() > MAX_TX_INPUTS {
+        return Err(TxError::TooManyInputs);
+    }
+    if tx.output.len() > MAX_TX_OUTPUTS {
+        return Err(TxError::TooManyOutputs);
+    }
+    if signed.serialized_size() > MAX_TX_SIZE {
+        return Err(TxError::TooLarge);
+    }
+    let mut spent = HashSet::with_capacity(tx.input.len());
+    if !tx.input.iter().all(|txin| spent.insert((txin.previous_output, txin.index))) {
+        return Err(TxError::DuplicateInput);
+    }
+    Amount::checked_sum(tx.output.iter().map(|txout| txout.value)).ok_or(TxError::ValueOutOfRange)
+}
+
+/// Blocks a coinbase must be buried under before its outputs can be spent, so that a reorg
+/// dropping the coinbase does not invalidate a chain of payments made with the reward.
+pub const COINBASE_MATURITY: u64 = 100;
+
+/// Whether an output can be spent in a block at `height`: that of a coinbase `COINBASE_MATURITY`
+/// blocks after it, the others, and those not in the set, right away.
+pub fn is_mature(state: &State, outpoint: &(H256, u8), height: u64) -> bool {
+    match state.utxo.get(outpoint) {
+        Some((_, _, Some(created))) => height >= created.saturating_add(COINBASE_MATURITY),
+        _ => true,
+    }
+}
+
+/// Check that a transaction in a block at `height` spends no immature coinbase output.
+pub fn check_maturity(signed: &SignedTransaction, state: &State, height: u64) -> Result<(), TxError> {
+    if signed.transaction.input.iter().all(|txin| is_mature(state, &(txin.previous_output, txin.index), height)) {
+        Ok(())
+    } else {
+        Err(TxError::ImmatureCoinbase)
+    }
+}
+
+/// Check a transaction against the consensus rules and the current UTXO set. Every input must
+/// be owned by the signer of the transaction, or by the signer of the input if the transaction
+/// is signed per input. Returns the fee paid by the transaction.
+pub fn check_transaction(signed: &SignedTransaction, state: &State) -> Result<Amount, TxError> {
+    let output_amount = check_transaction_limits(signed)?;
+    let tx = &signed.transaction;
+    // the owner of each input
+    let owners: Vec<H160> = match &signed.witness {
+        Witness::Whole { public_key, signature } => {
+            let txid = transaction::signing_hash(tx);
+            if !signed.algorithm.verify(public_key, txid.as_ref(), signature) {
+                return Err(TxError::BadSignature);
+            }
+            vec![address_of(public_key); tx.input.len()]
+        }
+        Witness::PerInput(signatures) => {
+            if signatures.len() != tx.input.len() {
+                return Err(TxError::BadSignature);
+            }
+            let mut owners = Vec::with_capacity(signatures.len());
+            for (index, input) in signatures.iter().enumerate() {
+                let message = transaction::input_signing_hash(tx, index, input.sighash).ok_or(TxError::BadSignature)?;
+                if !signed.algorithm.verify(&input.public_key, message.as_ref(), &input.signature) {
+                    return Err(TxError::BadSignature);
+                }
+                owners.push(address_of(&input.public_key));
+            }
+            owners
+        }
+        Witness::Unsigned => return Err(TxError::BadSignature),
+    };
+    let mut input_amount = Amount::ZERO;
+    for (txin, owner) in tx.input.iter().zip(owners) {
+        match state.utxo.get(&(txin.previous_output, txin.index)) {
+            Some((value, recipient, _)) => {
+                if *recipient != owner {
+                    return Err(TxError::WrongOwner);
+                }
+                input_amount = input_amount.checked_add(*value).ok_or(TxError::ValueOutOfRange)?;
+            }
+            None => return Err(TxError::MissingInput),
+        }
+    }
+    input_amount.checked_sub(output_amount).ok_or(TxError::Overspend)
+}
+
+/// New coins the coinbase of the first blocks may claim, on top of the fees of the block.
+pub const INITIAL_SUBSIDY: Amount = Amount::new(50);
+/// Blocks between two halvings of the subsidy.
+pub const SUBSIDY_HALVING_INTERVAL: usize = 210_000;
+
+/// New coins the coinbase of the block at `height` may claim. The genesis block has none.
+pub fn block_subsidy(height: usize) -> Amount {
+    let halvings = height / SUBSIDY_HALVING_INTERVAL;
+    if height == 0 || halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from(INITIAL_SUBSIDY.as_u64() >> halvings)
+}
+
+/// Most coins that can exist once the block at `height` is connected: the ICO and the
+/// subsidies of the blocks up to `height`. Fees only move coins around, so the UTXO set sums
+/// to less whenever miners leave part of the subsidy and fees unclaimed.
+pub fn scheduled_supply(height: usize) -> Amount {
+    let mut supply = transaction::ICO_VALUE.as_u64();
+    // the first height of each halving period, the genesis block having no subsidy
+    let mut start = 1;
+    while start <= height {
+        let subsidy = block_subsidy(start).as_u64();
+        if subsidy == 0 {
+            break;
+        }
+        let end = (start / SUBSIDY_HALVING_INTERVAL + 1) * SUBSIDY_HALVING_INTERVAL;
+        supply += subsidy * (std::cmp::min(height, end - 1) + 1 - start) as u64;
+        start = end;
+    }
+    Amount::from(supply)
+}
+
+/// Reasons a block violates the consensus rules.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BlockError {
+    UnknownParent,
+    BadDifficulty,
+    BadProofOfWork,
+    TimeTooNew,
+    BadHeight,
+    BadVersion,
+    BadTransactionVersion,
+    BadTransaction(TxError),
+    /// The first transaction looks like a coinbase but commits to another height, or is signed.
+    BadCoinbase,
+    /// The coinbase claims more than the subsidy and the fees of the block.
+    ExcessiveReward,
+    /// The block weighs more than `MAX_BLOCK_WEIGHT`.
+    TooLarge,
+    /// The Merkle root of the header does not commit to the transactions of the block.
+    BadMerkleRoot,
+    /// A transaction appears twice, which the Merkle root cannot tell from the same block
+    /// without the repetition when it is the last one, see `MerkleTree`.
+    DuplicateTransaction,
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockError::UnknownParent => write!(f, "parent block is unknown"),
+            BlockError::BadDifficulty => write!(f, "difficulty differs from the parent"),
+            BlockError::BadProofOfWork => write!(f, "hash does not meet the target"),
+            BlockError::TimeTooNew => write!(f, "timestamp is too far in the future"),
+            BlockError::BadHeight => write!(f, "height does not follow the parent's"),
+            BlockError::BadVersion => write!(f, "block version is outdated"),
+            BlockError::BadTransactionVersion => write!(f, "transaction version is outdated"),
+            BlockError::BadTransaction(e) => write!(f, "invalid transaction: {}", e),
+            BlockError::BadCoinbase => write!(f, "malformed coinbase"),
+            BlockError::ExcessiveReward => write!(f, "coinbase claims more than the subsidy and fees"),
+            BlockError::TooLarge => write!(f, "block exceeds the weight limit"),
+            BlockError::BadMerkleRoot => write!(f, "merkle root does not match the transactions"),
+            BlockError::DuplicateTransaction => write!(f, "transaction included twice"),
+        }
+    }
+}
+
+/// Check a block against the consensus rules, its parent in the chain and the local time, and
+/// its transactions against the current UTXO set if it extends the longest chain. The
+/// transactions of a block on a fork are checked by `chainstate` against the UTXO set of their
+/// own branch, if and when it becomes the longest.
+pub fn check_block(block: &Block, chain: &Blockchain, state: &State, clock: &dyn Clock) -> Result<(), BlockError> {
+    let parent = match chain.blockmap.get(&block.header.parent) {
+        Some(parent) => parent,
+        None => return Err(BlockError::UnknownParent),
+    };
+    if block.header.bits != parent.header.bits {
+        return Err(BlockError::BadDifficulty);
+    }
+    if block.hash() > block.header.target() {
+        return Err(BlockError::BadProofOfWork);
+    }
+    if !check_timestamp(&block.header, clock) {
+        return Err(BlockError::TimeTooNew);
+    }
+    if !check_block_weight(block) {
+        return Err(BlockError::TooLarge);
+    }
+    let mut hashes = HashSet::with_capacity(block.content.data.len());
+    if !block.content.data.iter().all(|tx| hashes.insert(tx.hash())) {
+        return Err(BlockError::DuplicateTransaction);
+    }
+    if MerkleTree::new(&block.content.data).root() != block.header.merkle_root {
+        return Err(BlockError::BadMerkleRoot);
+    }
+    // the parent's height was checked when it was connected, the genesis block has height 0
+    if !check_header_height(&block.header, &parent.header) {
+        return Err(BlockError::BadHeight);
+    }
+    if !check_header_version(&block.header, block.header.height as usize) {
+        return Err(BlockError::BadVersion);
+    }
+    if block.header.parent == chain.tip() {
+        check_block_transactions(block, state)?;
+    }
+    Ok(())
+}
+
+/// Check the transactions of a block against the UTXO set of the chain ending at its parent.
+pub fn check_block_transactions(block: &Block, state: &State) -> Result<(), BlockError> {
+    let height = block.header.height as usize;
+    // the outputs the block spends, updated transaction by transaction so that a transaction
+    // can spend the outputs of an earlier one, and no output is spent twice
+    let mut view = State::default();
+    for txin in block.content.data.iter().flat_map(|tx| tx.transaction.input.iter()) {
+        let key = (txin.previous_output, txin.index);
+        if let Some((value, recipient, _)) = state.utxo.get(&key) {
+            view.insert_utxo(key, (*value, *recipient));
+        }
+    }
+    // the coinbase, if any, comes first; its outputs cannot be spent in the same block, and a
+    // coinbase anywhere else fails as spending a missing input
+    let (coinbase, transactions) = match block.content.data.split_first() {
+        Some((first, rest)) if first.transaction.coinbase_height().is_some() => (Some(first), rest),
+        _ => (None, &block.content.data[..]),
+    };
+    let mut fees = Amount::ZERO;
+    for transaction in transactions {
+        if !check_transaction_version(&transaction.transaction, height) {
+            return Err(BlockError::BadTransactionVersion);
+        }
+        let fee = check_transaction(transaction, &view).map_err(BlockError::BadTransaction)?;
+        check_maturity(transaction, state, height as u64).map_err(BlockError::BadTransaction)?;
+        fees = fees.checked_add(fee).ok_or(BlockError::BadTransaction(TxError::ValueOutOfRange))?;
+        view.apply(transaction);
+    }
+    if let Some(coinbase) = coinbase {
+        if !check_transaction_version(&coinbase.transaction, height) {
+            return Err(BlockError::BadTransactionVersion);
+        }
+        check_coinbase(coinbase, height, fees)?;
+    }
+    Ok(())
+}
+
+/// Check the coinbase of the block at `height`, whose other transactions pay `fees`.
+pub fn check_coinbase(coinbase: &SignedTransaction, height: usize, fees: Amount) -> Result<(), BlockError> {
+    if coinbase.transaction.coinbase_height() != Some(height as u64) || coinbase.witness != Witness::Unsigned {
+        return Err(BlockError::BadCoinbase);
+    }
+    let claimed = check_transaction_limits(coinbase).map_err(BlockError::BadTransaction)?;
+    match block_subsidy(height).checked_add(fees) {
+        Some(reward) if claimed <= reward => Ok(()),
+        _ => Err(BlockError::ExcessiveReward),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::H256;
+    use crate::transaction::tests::{key, owner};
+
+    #[test]
+    fn header_version() {
+        let parent: H256 = [0u8; 32].into();
+        let mut block = generate_random_block(&parent);
+        assert!(check_header_version(&block.header, 1));
+        block.header.version = 0;
+        assert!(!check_header_version(&block.header, 1));
+        block.header.version = 2;
+        assert!(check_header_version(&block.header, 1));
+    }
+
+    #[test]
+    fn header_height() {
+        let genesis = Blockchain::new();
+        let parent = &genesis.blockmap[&genesis.tip()].header;
+        let mut block = generate_random_block(&genesis.tip());
+        assert!(!check_header_height(&block.header, parent));
+        block.header.height = 1;
+        assert!(check_header_height(&block.header, parent));
+        let child = generate_random_block(&block.hash());
+        assert!(!check_header_height(&child.header, &block.header));
+    }
+
+    #[test]
+    fn future_timestamp() {
+        use crate::clock::MockClock;
+        use std::time::Duration;
+        let parent: H256 = [0u8; 32].into();
+        let block = generate_random_block(&parent);
+        let clock = MockClock::new(block.header.timestamp);
+        assert!(check_timestamp(&block.header, &clock));
+        // a node whose clock lags by more than the allowance rejects the block
+        clock.set(block.header.timestamp - MAX_FUTURE_BLOCK_TIME);
+        assert!(check_timestamp(&block.header, &clock));
+        clock.set(block.header.timestamp - MAX_FUTURE_BLOCK_TIME - 1);
+        assert!(!check_timestamp(&block.header, &clock));
+        clock.advance(Duration::from_millis(1));
+        assert!(check_timestamp(&block.header, &clock));
+    }
+
+    #[test]
+    fn spend_genesis_output() {
+        use crate::transaction::{TxIn, TxOut};
+        use ring::signature::KeyPair;
+        let state = State::new();
+        let other = key(1);
+        let key = key(0);
+        let input = TxIn { previous_output: [0u8; 32].into(), index: 0 };
+        let output = TxOut { recipient: [1u8; 20].into(), value: 9000.into() };
+        let tx = Transaction { version: transaction::VERSION, input: vec![input], output: vec![output] };
+        let mut signed = SignedTransaction::new(tx, &key);
+        assert_eq!(check_transaction(&signed, &state), Ok(1000.into()));
+        signed.transaction.output[0].value = 20000.into();
+        assert_eq!(check_transaction(&signed, &state), Err(TxError::BadSignature));
+        signed.witness = Witness::Whole {
+            public_key: other.public_key().as_ref().to_vec(),
+            signature: transaction::sign(&signed.transaction, &other).as_ref().to_vec(),
+        };
+        assert_eq!(check_transaction(&signed, &state), Err(TxError::WrongOwner));
+    }
+
+    #[test]
+    fn duplicate_input() {
+        use crate::transaction::{TxIn, TxOut};
+        let state = State::new();
+        let key = key(0);
+        // the genesis output listed twice would fund twice its value
+        let input = TxIn { previous_output: [0u8; 32].into(), index: 0 };
+        let output = TxOut { recipient: [1u8; 20].into(), value: 20000.into() };
+        let tx = Transaction { version: transaction::VERSION, input: vec![input.clone(), input], output: vec![output] };
+        let signed = SignedTransaction::new(tx, &key);
+        assert_eq!(check_transaction_limits(&signed), Err(TxError::DuplicateInput));
+        assert_eq!(check_transaction(&signed, &state), Err(TxError::DuplicateInput));
+    }
+
+    #[test]
+    fn input_signature_modes() {
+        use crate::crypto::signer::Algorithm;
+        use crate::transaction::{sign_input, SigHash, TxIn, TxOut};
+        use ring::signature::KeyPair;
+        let alice = key(1);
+        let bob = key(2);
+        let mut state = State::default();
+        state.insert_utxo(([1u8; 32].into(), 0), (100.into(), owner(&alice)));
+        state.insert_utxo(([2u8; 32].into(), 0), (50.into(), owner(&bob)));
+        let signed = |tx: &Transaction, signatures: &[_]| {
+            SignedTransaction::with_input_signatures(tx.clone(), Algorithm::Ed25519, signatures)
+        };
+
+        // alice offers her coin for an output paying her, whatever else the transaction does
+        let mut tx = Transaction {
+            version: transaction::VERSION,
+            input: vec![TxIn { previous_output: [1u8; 32].into(), index: 0 }],
+            output: vec![TxOut { recipient: [3u8; 20].into(), value: 90.into() }],
+        };
+        let offer = sign_input(&tx, 0, SigHash::SingleAnyoneCanPay, &alice).unwrap().unwrap();
+        let committed_to_all = sign_input(&tx, 0, SigHash::All, &alice).unwrap().unwrap();
+        assert_eq!(check_transaction(&signed(&tx, &[offer.clone()]), &state), Ok(10.into()));
+
+        // bob adds his input and output, signing the whole transaction
+        tx.input.push(TxIn { previous_output: [2u8; 32].into(), index: 0 });
+        tx.output.push(TxOut { recipient: [4u8; 20].into(), value: 45.into() });
+        let accept = sign_input(&tx, 1, SigHash::All, &bob).unwrap().unwrap();
+        let joint = signed(&tx, &[offer.clone(), accept.clone()]);
+        assert_eq!(check_transaction(&joint, &state), Ok(15.into()));
+        assert_eq!(joint.signers(), vec![alice.public_key().as_ref().to_vec(), bob.public_key().as_ref().to_vec()]);
+        assert_eq!(joint.witness, Witness::PerInput(vec![offer.clone(), accept.clone()]));
+        assert_eq!(check_transaction(&signed(&tx, &[committed_to_all, accept.clone()]), &state), Err(TxError::BadSignature));
+
+        // bob's output is his to change, alice's is not
+        let mut changed = tx.clone();
+        changed.output[1].value = 40.into();
+        assert_eq!(check_transaction(&signed(&changed, &[offer.clone(), accept.clone()]), &state), Err(TxError::BadSignature));
+        let accept_changed = sign_input(&changed, 1, SigHash::All, &bob).unwrap().unwrap();
+        assert_eq!(check_transaction(&signed(&changed, &[offer.clone(), accept_changed]), &state), Ok(20.into()));
+        changed.output[0].value = 80.into();
+        let accept_changed = sign_input(&changed, 1, SigHash::All, &bob).unwrap().unwrap();
+        assert_eq!(check_transaction(&signed(&changed, &[offer.clone(), accept_changed]), &state), Err(TxError::BadSignature));
+
+        // each input needs a signature by its owner
+        let stolen = sign_input(&tx, 0, SigHash::All, &bob).unwrap().unwrap();
+        assert_eq!(check_transaction(&signed(&tx, &[stolen, accept.clone()]), &state), Err(TxError::WrongOwner));
+        assert_eq!(check_transaction(&signed(&tx, &[offer.clone()]), &state), Err(TxError::BadSignature));
+        // signatures cut short do not decode, rather than fail validation
+        let encoded = bincode::serialize(&joint).unwrap();
+        assert!(bincode::deserialize::<SignedTransaction>(&encoded[..encoded.len() - 10]).is_err());
+
+        // a single input signs no output when there is none at its index
+        tx.output.truncate(1);
+        assert!(sign_input(&tx, 1, SigHash::Single, &bob).unwrap().is_none());
+        assert!(sign_input(&tx, 2, SigHash::All, &bob).unwrap().is_none());
+    }
+
+    #[test]
+    fn transaction_limits() {
+        use crate::transaction::{TxIn, TxOut};
+        let key = key(0);
+        let owner = owner(&key);
+        let recipient: H160 = [1u8; 20].into();
+        let spend = |inputs: usize, outputs: Vec<u64>| {
+            let input = (0..inputs).map(|i| TxIn { previous_output: [(i % 256) as u8; 32].into(), index: (i / 256) as u8 }).collect();
+            let output = outputs.into_iter().map(|value| TxOut { recipient: recipient, value: value.into() }).collect();
+            let tx = Transaction { version: transaction::VERSION, input: input, output: output };
+            SignedTransaction::new(tx, &key)
+        };
+        let max = Amount::MAX_MONEY.as_u64();
+        let mut state = State::default();
+        state.insert_utxo(([0u8; 32].into(), 0), (Amount::MAX_MONEY, owner));
+        state.insert_utxo(([1u8; 32].into(), 0), (Amount::MAX_MONEY, owner));
+
+        assert_eq!(check_transaction(&spend(1, vec![max]), &state), Ok(Amount::ZERO));
+        assert_eq!(check_transaction(&spend(1, vec![max + 1]), &state), Err(TxError::ValueOutOfRange));
+        // outputs summing past u64::MAX
+        assert_eq!(
+            check_transaction(&spend(1, vec![u64::MAX, 2]), &state),
+            Err(TxError::ValueOutOfRange)
+        );
+        assert_eq!(check_transaction(&spend(1, vec![max, 1]), &state), Err(TxError::ValueOutOfRange));
+        // inputs summing past the range
+        assert_eq!(check_transaction(&spend(2, vec![1]), &state), Err(TxError::ValueOutOfRange));
+
+        assert_eq!(check_transaction_limits(&spend(MAX_TX_INPUTS + 1, vec![1])), Err(TxError::TooManyInputs));
+        assert_eq!(check_transaction_limits(&spend(1, vec![1; MAX_TX_OUTPUTS + 1])), Err(TxError::TooManyOutputs));
+        assert_eq!(check_transaction_limits(&spend(1, vec![1; MAX_TX_OUTPUTS])), Ok(Amount::from(MAX_TX_OUTPUTS as u64)));
+        let mut large = spend(1, vec![1]);
+        large.witness = Witness::Whole { public_key: vec![0u8; MAX_TX_SIZE], signature: vec![] };
+        assert_eq!(check_transaction_limits(&large), Err(TxError::TooLarge));
+    }
+
+    #[test]
+    fn block_weight() {
+        use crate::block::test::generate_block_with_transactions;
+        use crate::crypto::hash::Hashed;
+        let mut block = generate_block_with_transactions(&H256::default(), 3);
+        assert_eq!(block.weight(), bincode::serialize(&block).unwrap().len());
+        assert!(check_block_weight(&block));
+        let mut heavy = (*block.content.data[0]).clone();
+        heavy.witness = Witness::Whole { public_key: vec![0u8; MAX_BLOCK_WEIGHT], signature: vec![] };
+        block.content.data.push(Hashed::new(heavy));
+        assert_eq!(block.weight(), bincode::serialize(&block).unwrap().len());
+        assert!(!check_block_weight(&block));
+    }
+
+    #[test]
+    fn merkle_root_commits_to_transactions() {
+        use crate::block::test::generate_block_with_transactions;
+        use crate::chainparams::REGTEST;
+        use crate::clock::SystemClock;
+        let chain = Blockchain::with_params(&REGTEST);
+        let genesis = &chain.blockmap[&chain.tip()].header;
+        let mut block = generate_block_with_transactions(&chain.tip(), 3);
+        block.header.height = 1;
+        block.header.bits = genesis.bits;
+        block.header.timestamp = genesis.timestamp + 1;
+        while !block.header.meets_target() {
+            block.header.nonce += 1;
+        }
+        // the header passes, the transactions do not: the first spends the ICO output of another key
+        let stolen = Err(BlockError::BadTransaction(TxError::WrongOwner));
+        assert_eq!(check_block(&block, &chain, &State::new(), &SystemClock), stolen);
+
+        // other transactions under the same header
+        let mut substituted = block.clone();
+        substituted.content.data.pop();
+        assert_eq!(check_block(&substituted, &chain, &State::new(), &SystemClock), Err(BlockError::BadMerkleRoot));
+        // repeating the last transaction keeps the root
+        let mut repeated = block.clone();
+        repeated.content.data.push(block.content.data[2].clone());
+        assert_eq!(MerkleTree::new(&repeated.content.data).root(), block.header.merkle_root);
+        assert_eq!(check_block(&repeated, &chain, &State::new(), &SystemClock), Err(BlockError::DuplicateTransaction));
+    }
+
+    #[test]
+    fn subsidy_schedule() {
+        let interval = SUBSIDY_HALVING_INTERVAL;
+        assert_eq!(block_subsidy(0), Amount::ZERO);
+        assert_eq!(block_subsidy(1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(interval - 1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(interval), Amount::from(25));
+        assert_eq!(block_subsidy(64 * interval), Amount::ZERO);
+
+        let ico = transaction::ICO_VALUE.as_u64();
+        assert_eq!(scheduled_supply(0).as_u64(), ico);
+        assert_eq!(scheduled_supply(3).as_u64(), ico + 150);
+        assert_eq!(scheduled_supply(interval).as_u64(), ico + 50 * (interval as u64 - 1) + 25);
+        // the sum of the subsidies one by one, across the halvings
+        let mut supply = ico;
+        for height in 1..=3 * interval + 5 {
+            supply += block_subsidy(height).as_u64();
+        }
+        assert_eq!(scheduled_supply(3 * interval + 5).as_u64(), supply);
+        assert!(scheduled_supply(usize::MAX).is_valid());
+    }
+
+    #[test]
+    fn coinbase_reward() {
+        use crate::transaction::TxOut;
+        let pay = |value: u64| vec![TxOut { recipient: [1u8; 20].into(), value: value.into() }];
+        let coinbase = SignedTransaction::coinbase(Transaction::coinbase(5, pay(60)));
+        assert_eq!(coinbase.transaction.coinbase_height(), Some(5));
+        assert_eq!(Transaction::default().coinbase_height(), None);
+
+        assert_eq!(check_coinbase(&coinbase, 5, 10.into()), Ok(()));
+        assert_eq!(check_coinbase(&coinbase, 5, 9.into()), Err(BlockError::ExcessiveReward));
+        assert_eq!(check_coinbase(&coinbase, 6, 10.into()), Err(BlockError::BadCoinbase));
+        // claiming less is allowed, the rest is never created
+        let modest = SignedTransaction::coinbase(Transaction::coinbase(5, pay(1)));
+        assert_eq!(check_coinbase(&modest, 5, Amount::ZERO), Ok(()));
+        let mut signed = coinbase.clone();
+        signed.witness = Witness::Whole { public_key: vec![0u8; 32], signature: vec![0u8; 64] };
+        assert_eq!(check_coinbase(&signed, 5, 10.into()), Err(BlockError::BadCoinbase));
+        // relayed on its own, a coinbase fails like any unsigned transaction
+        assert_eq!(check_transaction(&coinbase, &State::new()), Err(TxError::BadSignature));
+    }
+
+    #[test]
+    fn coinbase_maturity() {
+        use crate::block::test::generate_random_block;
+        use crate::crypto::hash::Hashed;
+        use crate::policy::Policy;
+        use crate::transaction::{AcceptError, Mempool, TxIn, TxOut};
+        let key = key(0);
+        let owner = owner(&key);
+        let coinbase = Hashed::new(SignedTransaction::coinbase(Transaction::coinbase(
+            5,
+            vec![TxOut { recipient: owner, value: 50.into() }],
+        )));
+        let mut block = generate_random_block(&H256::default());
+        block.header.height = 5;
+        block.content.data = vec![coinbase.clone()];
+        let mut state = State::default();
+        state.connect_block(&block);
+        assert_eq!(state.height(), 5);
+        assert_eq!(state.utxo[&(coinbase.hash(), 0)], (50.into(), owner, Some(5)));
+
+        let input = TxIn { previous_output: coinbase.hash(), index: 0 };
+        let output = TxOut { recipient: [1u8; 20].into(), value: 40.into() };
+        let spend = SignedTransaction::new(Transaction { version: transaction::VERSION, input: vec![input], output: vec![output] }, &key);
+        assert_eq!(check_transaction(&spend, &state), Ok(10.into()));
+        assert_eq!(check_maturity(&spend, &state, 5 + COINBASE_MATURITY - 1), Err(TxError::ImmatureCoinbase));
+        assert_eq!(check_maturity(&spend, &state, 5 + COINBASE_MATURITY), Ok(()));
+        // the mempool takes the next block's height
+        let mut mempool = Mempool::new();
+        assert_eq!(mempool.accept(&spend, &state, &Policy::default()), Err(AcceptError::Invalid(TxError::ImmatureCoinbase)));
+
+        // other outputs are mature right away, and the coinbase is forgotten when disconnected
+        assert!(is_mature(&state, &(H256::default(), 0), 0));
+        assert!(state.disconnect_block(&block));
+        assert_eq!(state.height(), 4);
+        assert!(state.utxo.is_empty());
+    }
+
+    #[test]
+    fn transaction_version() {
+        let mut tx = Transaction::default();
+        assert!(!check_transaction_version(&tx, 1));
+        tx.version = crate::transaction::VERSION;
+        assert!(check_transaction_version(&tx, 1));
+    }
+}
@@ -0,0 +1,220 @@
+//! Re-validation of the last blocks of the stored chain, in the spirit of bitcoind's
+//! verifychain. The UTXO set is rolled back over the blocks with the undo data read from disk,
+//! then the blocks are checked and applied again, and every step is compared with the block
+//! files, the indices and the commitments recorded when the blocks were first connected.
+
+use crate::blockchain::Blockchain;
+use crate::clock::Clock;
+use crate::crypto::hash::{H256, Hashable};
+use crate::storage::BlockFiles;
+use crate::transaction::{State, Undo};
+use crate::validation::{self, BlockError};
+
+/// Ways a block of the longest chain disagrees with what is stored about it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Issue {
+    /// The block index has no entry for the block.
+    NotStored,
+    /// The block or its undo data could not be read.
+    Unreadable(String),
+    /// The block index points at the record of another block.
+    WrongRecord,
+    BadMerkleRoot,
+    /// The block fails validation against the UTXO set it was connected to.
+    Invalid(BlockError),
+    MissingUndo,
+    /// The stored undo data is not what applying the block spends.
+    WrongUndo,
+    /// The UTXO set after applying the block differs from the one recorded when it was
+    /// connected.
+    WrongCommitment,
+    /// A transaction of the block is missing from the transaction index.
+    NotInTxIndex(H256),
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Issue::NotStored => write!(f, "missing from the block index"),
+            Issue::Unreadable(e) => write!(f, "unreadable: {}", e),
+            Issue::WrongRecord => write!(f, "the block index points at another block"),
+            Issue::BadMerkleRoot => write!(f, "merkle root does not match the transactions"),
+            Issue::Invalid(e) => write!(f, "invalid: {}", e),
+            Issue::MissingUndo => write!(f, "no undo data stored"),
+            Issue::WrongUndo => write!(f, "stored undo data differs from the outputs the block spends"),
+            Issue::WrongCommitment => write!(f, "UTXO commitment differs from the one recorded"),
+            Issue::NotInTxIndex(tx) => write!(f, "transaction {} missing from the transaction index", tx),
+        }
+    }
+}
+
+pub struct Problem {
+    pub height: usize,
+    pub block: H256,
+    pub issue: Issue,
+}
+
+pub struct Report {
+    pub tip: H256,
+    pub height: usize,
+    /// Blocks checked below and including the tip
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, height: usize, block: H256, issue: Issue) {
+        self.problems.push(Problem { height: height, block: block, issue: issue });
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "Block {} at height {}: {}", problem.block, problem.height, problem.issue)?;
+        }
+        writeln!(f, "Checked the last {} blocks up to tip {} at height {}", self.checked, self.tip, self.height)?;
+        write!(f, "{}", if self.is_consistent() { "Consistent" } else { "Inconsistent" })
+    }
+}
+
+/// Re-validate the last `depth` blocks of the longest chain, the genesis block aside, against
+/// the block files they were loaded from and the UTXO set at the tip. Neither is changed.
+pub fn verify_chain(files: &BlockFiles, chain: &Blockchain, state: &State, clock: &dyn Clock, depth: usize) -> Report {
+    let height = chain.height();
+    let depth = depth.min(height);
+    let mut report = Report { tip: chain.tip(), height: height, checked: depth, problems: Vec::new() };
+    let heights = height + 1 - depth..=height;
+    let mut state = state.clone();
+
+    // roll back to below the lowest block checked, with the undo data as stored
+    let mut stored_undo: Vec<Option<Vec<Undo>>> = Vec::new();
+    for height in heights.clone().rev() {
+        let hash = chain.hash_at_height(height).unwrap();
+        let block = &chain.blockmap[&hash];
+        match files.get(&hash) {
+            Ok(Some(stored)) if stored.hash() != hash => report.add(height, hash, Issue::WrongRecord),
+            Ok(Some(_)) => {}
+            Ok(None) => report.add(height, hash, Issue::NotStored),
+            Err(e) => report.add(height, hash, Issue::Unreadable(e.to_string())),
+        }
+        let undo = match files.undo(&hash) {
+            Ok(Some(undo)) => Some(undo),
+            Ok(None) => {
+                report.add(height, hash, Issue::MissingUndo);
+                None
+            }
+            Err(e) => {
+                report.add(height, hash, Issue::Unreadable(e.to_string()));
+                None
+            }
+        };
+        // carry on with the undo data kept in memory, to check the blocks below
+        let memory_undo = state.block_undo(&hash).map(|undo| undo.to_vec()).unwrap_or_default();
+        state.rollback_block(block, undo.as_ref().unwrap_or(&memory_undo));
+        stored_undo.push(undo);
+    }
+
+    // then check and apply the blocks again
+    for (height, undo) in heights.zip(stored_undo.into_iter().rev()) {
+        let hash = chain.hash_at_height(height).unwrap();
+        let block = &chain.blockmap[&hash];
+        // covers the Merkle root, the proof of work and the signatures
+        let checked = validation::check_block(block, chain, &state, clock)
+            .and_then(|()| validation::check_block_transactions(block, &state));
+        match checked {
+            Ok(()) => {}
+            Err(BlockError::BadMerkleRoot) => report.add(height, hash, Issue::BadMerkleRoot),
+            Err(e) => report.add(height, hash, Issue::Invalid(e)),
+        }
+        for transaction in &block.content.data {
+            let tx_hash = transaction.hash();
+            if !chain.txindex.get(&tx_hash).map_or(false, |blocks| blocks.contains(&hash)) {
+                report.add(height, hash, Issue::NotInTxIndex(tx_hash));
+            }
+        }
+        let applied = state.apply_block(block);
+        if undo.map_or(false, |undo| undo != applied) {
+            report.add(height, hash, Issue::WrongUndo);
+        }
+        if state.block_commitment(&hash) != Some(state.commitment()) {
+            report.add(height, hash, Issue::WrongCommitment);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Content, Header};
+    use crate::chainparams;
+    use crate::chainstate;
+    use crate::clock::SystemClock;
+    use crate::crypto::hash::{H160, Hashed};
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::{self, SignedTransaction, Transaction, TxIn, TxOut};
+    use ring::signature::Ed25519KeyPair;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    /// A block on the tip of a regtest chain.
+    fn mine(chain: &Blockchain, transactions: Vec<Hashed<SignedTransaction>>) -> Block {
+        let parent = &chain.blockmap[&chain.tip()].header;
+        let mut block = Block {
+            header: Header {
+                parent: parent.hash(),
+                height: parent.height + 1,
+                timestamp: parent.timestamp + 1,
+                merkle_root: MerkleTree::new(&transactions).root(),
+                ..parent.clone()
+            },
+            content: Content { data: transactions },
+        };
+        while !block.header.meets_target() {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    #[test]
+    fn verify_stored_chain() {
+        let dir = std::env::temp_dir().join(format!("verify-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let files = Arc::new(Mutex::new(BlockFiles::open(&dir).unwrap()));
+        let mut chain = Blockchain::with_params(&chainparams::REGTEST);
+        chain.subscribe(files.clone());
+        let mut state = State::new();
+        let recipient: H160 = [1u8; 20].into();
+        let coinbase = Transaction::coinbase(1, vec![TxOut { recipient: recipient, value: validation::block_subsidy(1) }]);
+        let first = mine(&chain, vec![Hashed::new(SignedTransaction::coinbase(coinbase))]);
+        chainstate::insert_block(&first, &mut chain, &mut state);
+        // spend the ICO output, so that the block has undo data
+        let ico = Transaction {
+            version: transaction::VERSION,
+            input: vec![TxIn { previous_output: [0u8; 32].into(), index: 0 }],
+            output: vec![TxOut { recipient: recipient, value: transaction::ICO_VALUE }],
+        };
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0u8; 32]).unwrap();
+        let second = mine(&chain, vec![Hashed::new(SignedTransaction::new(ico, &key))]);
+        chainstate::insert_block(&second, &mut chain, &mut state);
+
+        let report = verify_chain(&files.lock().unwrap(), &chain, &state, &SystemClock, 10);
+        assert_eq!(report.checked, 2);
+        assert!(report.is_consistent(), "{}", report);
+
+        // undo data that does not give back the ICO output leaves the block nothing to spend,
+        // though the UTXO set ends up the same
+        files.lock().unwrap().append_undo(&second.hash(), &[vec![]]).unwrap();
+        let report = verify_chain(&files.lock().unwrap(), &chain, &state, &SystemClock, 1);
+        assert_eq!(report.checked, 1);
+        let issues: Vec<Issue> = report.problems.iter().map(|problem| problem.issue.clone()).collect();
+        assert_eq!(issues, vec![Issue::Invalid(BlockError::BadTransaction(validation::TxError::MissingInput))]);
+        assert!(report.problems.iter().all(|problem| problem.block == second.hash() && problem.height == 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1116 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, ChainListener, Finality};
+use crate::crypto::address::address_of;
+use crate::crypto::hash::{H160, H256, Hashable};
+use crate::locks::OrderedMutex;
+use crate::policy::Policy;
+use crate::psbt::{Psbt, PsbtError};
+use crate::crypto::signer::{Algorithm, Signer};
+use crate::transaction::{self, InputSignature, SigHash, SignedTransaction, State, Transaction, TxIn, TxOut, Witness};
+use crate::validation;
+use log::warn;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{FromEntropy, Rng};
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File in the data directory holding the wallet labels.
+pub const LABELS_FILE: &str = "wallet-labels.dat";
+/// File in the data directory holding the seeds of the keys the wallet generated.
+pub const KEYS_FILE: &str = "wallet-keys.dat";
+/// Longest label, in bytes.
+pub const MAX_LABEL_LENGTH: usize = 256;
+/// Most subsets of coins branch and bound explores before giving up on an exact match.
+const MAX_BRANCH_AND_BOUND_TRIES: usize = 100_000;
+
+/// How the wallet picks the coins a payment spends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// The largest coins until the payment is covered: the fewest inputs.
+    LargestFirst,
+    /// A set of coins matching the payment closely enough to need no change output, falling
+    /// back to largest-first if there is none.
+    BranchAndBound,
+    /// Random coins until the payment is covered, then more random coins that bring the change
+    /// closer to the payment value, so that change outputs stay useful for later payments.
+    RandomImprove,
+}
+
+impl Default for CoinSelection {
+    fn default() -> Self {
+        CoinSelection::LargestFirst
+    }
+}
+
+impl std::str::FromStr for CoinSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest-first" => Ok(CoinSelection::LargestFirst),
+            "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+            "random-improve" => Ok(CoinSelection::RandomImprove),
+            _ => Err(format!(
+                "unknown coin selection {}, expected largest-first, branch-and-bound or random-improve",
+                s
+            )),
+        }
+    }
+}
+
+/// An unspent output of ours and its value.
+type Coin = ((H256, u8), Amount);
+
+/// Serialized sizes of the transactions signed by a key, or signed per input. Signatures have
+/// a fixed length, so sizes only depend on the numbers of inputs and outputs, and are known
+/// without asking a signer.
+struct TxSizes {
+    base: usize,
+    input: usize,
+    output: usize,
+}
+
+impl TxSizes {
+    fn new(signer: &dyn Signer) -> Self {
+        let empty = SignedTransaction {
+            transaction: Transaction { version: transaction::VERSION, input: vec![], output: vec![] },
+            algorithm: signer.algorithm(),
+            witness: Witness::Whole {
+                public_key: signer.public_key_bytes(),
+                signature: vec![0; signer.algorithm().signature_len()],
+            },
+        };
+        TxSizes {
+            base: empty.serialized_size(),
+            input: bincode::serialize(&TxIn::default()).unwrap().len(),
+            output: bincode::serialize(&TxOut::default()).unwrap().len(),
+        }
+    }
+
+    /// Sizes of the transactions finalized out of a partially signed transaction, with one
+    /// Ed25519 signature per input.
+    fn per_input() -> Self {
+        let empty = Transaction { version: transaction::VERSION, input: vec![], output: vec![] };
+        let signature = InputSignature {
+            sighash: SigHash::All,
+            public_key: vec![0; Algorithm::Ed25519.public_key_len()],
+            signature: vec![0; Algorithm::Ed25519.signature_len()],
+        };
+        TxSizes {
+            base: SignedTransaction::with_input_signatures(empty, Algorithm::Ed25519, &[]).serialized_size(),
+            input: bincode::serialize(&TxIn::default()).unwrap().len() + bincode::serialize(&signature).unwrap().len(),
+            output: bincode::serialize(&TxOut::default()).unwrap().len(),
+        }
+    }
+
+    fn size(&self, inputs: usize, outputs: usize) -> usize {
+        self.base + inputs * self.input + outputs * self.output
+    }
+}
+
+/// What the coins selected for a payment must cover.
+struct Target<'a> {
+    value: Amount,
+    /// Outputs of the payment, not counting change
+    outputs: usize,
+    sizes: TxSizes,
+    policy: &'a Policy,
+}
+
+impl<'a> Target<'a> {
+    /// Value that `inputs` coins must add up to, for the payment and its fee without change.
+    /// `None` past `Amount::MAX_MONEY`, which no coins can cover.
+    fn needed(&self, inputs: usize) -> Option<Amount> {
+        self.value.checked_add(self.policy.min_fee(self.sizes.size(inputs, self.outputs)))
+    }
+
+    /// Whether `inputs` coins worth `total` cover the payment and its fee.
+    fn covers(&self, total: Amount, inputs: usize) -> bool {
+        self.needed(inputs).map_or(false, |needed| total >= needed)
+    }
+
+    /// Excess over `needed` from which a change output is created, see `pay`.
+    fn change_threshold(&self, inputs: usize) -> Amount {
+        let change_fee = self
+            .policy
+            .min_fee(self.sizes.size(inputs, self.outputs + 1))
+            .checked_sub(self.policy.min_fee(self.sizes.size(inputs, self.outputs)))
+            .unwrap_or(Amount::ZERO);
+        change_fee.checked_add(self.policy.dust_limit).unwrap_or(Amount::MAX_MONEY)
+    }
+}
+
+/// The largest coins, `coins` being sorted by decreasing value.
+fn largest_first(coins: &[Coin], target: &Target) -> Option<Vec<Coin>> {
+    let mut selected = Vec::new();
+    let mut total = Amount::ZERO;
+    for coin in coins.iter().take(target.policy.max_inputs) {
+        selected.push(*coin);
+        total = total.checked_add(coin.1)?;
+        if target.covers(total, selected.len()) {
+            return Some(selected);
+        }
+    }
+    None
+}
+
+/// Coins whose excess over the payment stays below the change threshold, searched depth first
+/// over `coins` sorted by decreasing value. Gives up on coins adding up to more than
+/// `Amount::MAX_MONEY`, so that the sums of the search cannot fail.
+fn branch_and_bound(coins: &[Coin], target: &Target) -> Option<Vec<Coin>> {
+    fn search(
+        coins: &[Coin],
+        target: &Target,
+        selected: &mut Vec<Coin>,
+        total: Amount,
+        remaining: Amount,
+        tries: &mut usize,
+    ) -> bool {
+        *tries += 1;
+        let n = selected.len();
+        if n > 0 && target.covers(total, n) {
+            // more coins would only add to the excess
+            let excess = total.checked_sub(target.needed(n).unwrap()).unwrap();
+            return excess < target.change_threshold(n);
+        }
+        if coins.is_empty()
+            || n == target.policy.max_inputs
+            || !target.covers(total.checked_add(remaining).unwrap(), n + 1)
+            || *tries > MAX_BRANCH_AND_BOUND_TRIES
+        {
+            return false;
+        }
+        let (coin, rest) = (coins[0], &coins[1..]);
+        let remaining = remaining.checked_sub(coin.1).unwrap();
+        selected.push(coin);
+        if search(rest, target, selected, total.checked_add(coin.1).unwrap(), remaining, tries) {
+            return true;
+        }
+        selected.pop();
+        search(rest, target, selected, total, remaining, tries)
+    }
+
+    let mut selected = Vec::new();
+    let remaining = Amount::checked_sum(coins.iter().map(|coin| coin.1))?;
+    if search(coins, target, &mut selected, Amount::ZERO, remaining, &mut 0) {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+/// Random coins until the payment is covered, then random coins bringing the change closer to
+/// the payment value without exceeding twice that value.
+fn random_improve<R: Rng>(coins: &[Coin], target: &Target, rng: &mut R) -> Option<Vec<Coin>> {
+    let mut order: Vec<Coin> = coins.to_vec();
+    order.shuffle(rng);
+    let mut order = order.into_iter();
+    let mut selected = Vec::new();
+    let mut total = Amount::ZERO;
+    while selected.is_empty() || !target.covers(total, selected.len()) {
+        if selected.len() == target.policy.max_inputs {
+            return None;
+        }
+        let coin = order.next()?;
+        selected.push(coin);
+        total = total.checked_add(coin.1)?;
+    }
+    for coin in order {
+        let n = selected.len();
+        if n == target.policy.max_inputs {
+            break;
+        }
+        let (ideal, limit, with_coin) = match (
+            target.needed(n + 1).and_then(|needed| needed.checked_add(target.value)),
+            total.checked_add(coin.1),
+        ) {
+            (Some(ideal), Some(with_coin)) => (ideal, ideal.checked_add(target.value), with_coin),
+            _ => continue,
+        };
+        let distance = |value: Amount| value.as_u64().abs_diff(ideal.as_u64());
+        if distance(with_coin) < distance(total) && limit.map_or(true, |limit| with_coin <= limit) {
+            selected.push(coin);
+            total = with_coin;
+        }
+    }
+    Some(selected)
+}
+
+/// The outputs of a transaction spending `inputs` coins worth `total`, if they cover the
+/// payments and the fee: the payments, and change to `change_address` unless it is below the
+/// dust limit, left to the fee then instead of creating an output the policy would reject.
+fn pay(
+    inputs: usize,
+    total: Amount,
+    payments: &[TxOut],
+    change_address: H160,
+    sizes: &TxSizes,
+    policy: &Policy,
+) -> Option<Vec<TxOut>> {
+    let excess = total.checked_sub(Amount::checked_sum(payments.iter().map(|output| output.value))?)?;
+    let change = excess.checked_sub(policy.min_fee(sizes.size(inputs, payments.len() + 1)));
+    match change {
+        Some(change) if !policy.is_dust(change) => {
+            let mut output = payments.to_vec();
+            output.push(TxOut { recipient: change_address, value: change });
+            Some(output)
+        }
+        _ if excess >= policy.min_fee(sizes.size(inputs, payments.len())) => Some(payments.to_vec()),
+        _ => None,
+    }
+}
+
+/// Where a wallet transaction stands with respect to the longest chain.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum TxState {
+    /// Not in the longest chain (yet, or anymore after a reorg).
+    Pending,
+    Confirmed { block: H256, height: usize },
+    /// One of its inputs was spent by another transaction in the longest chain.
+    Conflicted { by: H256 },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WalletError {
+    /// The payment itself is below the dust limit of the relay policy.
+    Dust,
+    /// No key, or no watch-only address, has enough unspent outputs to pay the value and the
+    /// minimum relay fee.
+    InsufficientFunds,
+    /// A transaction without any payment.
+    NoPayment,
+    /// The key paying is held by a device that did not sign.
+    SignerFailed,
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            WalletError::Dust => "payment below the dust limit",
+            WalletError::InsufficientFunds => "insufficient funds",
+            WalletError::NoPayment => "no payment",
+            WalletError::SignerFailed => "signer failed",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+pub struct WalletTx {
+    pub transaction: SignedTransaction,
+    pub state: TxState,
+}
+
+/// Memos the operator attached to addresses and transactions.
+#[derive(Serialize, Deserialize, Default)]
+struct Labels {
+    addresses: HashMap<H160, String>,
+    transactions: HashMap<H256, String>,
+}
+
+/// One of our keys, shared so it can sign once the wallet is released: an external signer may
+/// take seconds to answer.
+pub type Key = Arc<dyn Signer + Send + Sync>;
+
+/// A transaction built by the wallet, to be signed by the key whose outputs it spends.
+pub struct Funded {
+    pub transaction: Transaction,
+    key: Key,
+}
+
+impl Funded {
+    pub fn sign(self) -> Result<SignedTransaction, WalletError> {
+        SignedTransaction::try_new(self.transaction, self.key.as_ref()).map_err(|e| {
+            warn!("Error signing transaction: {}", e);
+            WalletError::SignerFailed
+        })
+    }
+}
+
+/// Sign the inputs of a partially signed transaction owned by some keys, see `Wallet::keys`.
+/// Returns the number of inputs signed.
+pub fn sign_psbt(keys: &[Key], psbt: &mut Psbt, sighash: SigHash) -> Result<usize, PsbtError> {
+    let mut signed = 0;
+    for key in keys {
+        signed += psbt.sign(key.as_ref(), sighash)?;
+    }
+    Ok(signed)
+}
+
+pub struct Wallet {
+    /// Our keys, held by the node or by external signers
+    keys: Vec<Key>,
+    addresses: HashSet<H160>,
+    /// Addresses tracked without their keys, spent through partially signed transactions
+    watched: Vec<H160>,
+    pub transactions: HashMap<H256, WalletTx>,
+    labels: Labels,
+    /// Where the labels are saved on every change, if set
+    labels_path: Option<PathBuf>,
+    /// Seeds of the keys out of `new_address`, in order
+    seeds: Vec<[u8; 32]>,
+    /// Where the seeds are saved on every new address, if set
+    keys_path: Option<PathBuf>,
+    coin_selection: CoinSelection,
+    /// Confirmations an output needs before the wallet spends it
+    maturity: usize,
+    /// Height of the longest chain, as of the last block connected or disconnected
+    height: usize,
+    /// Picks the coins of random-improve
+    rng: Mutex<StdRng>,
+}
+
+fn set_label<K: std::hash::Hash + Eq>(labels: &mut HashMap<K, String>, key: K, label: &str) {
+    if label.is_empty() {
+        labels.remove(&key);
+    } else {
+        labels.insert(key, label.to_string());
+    }
+}
+
+impl Wallet {
+    pub fn new() -> Self {
+        Wallet {
+            keys: Vec::new(),
+            addresses: HashSet::new(),
+            watched: Vec::new(),
+            transactions: HashMap::new(),
+            labels: Labels::default(),
+            labels_path: None,
+            seeds: Vec::new(),
+            keys_path: None,
+            coin_selection: CoinSelection::default(),
+            maturity: 0,
+            height: 0,
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    pub fn set_coin_selection(&mut self, coin_selection: CoinSelection) {
+        self.coin_selection = coin_selection;
+    }
+
+    /// Pick coins with this generator, seeded for coin selection to be reproducible.
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = Mutex::new(rng);
+    }
+
+    /// Only spend outputs with at least `maturity` confirmations. Outputs of transactions the
+    /// wallet does not know, such as the ICO, count as mature.
+    pub fn set_maturity(&mut self, maturity: usize) {
+        self.maturity = maturity;
+    }
+
+    /// Whether the outputs of a transaction have enough confirmations to be spent.
+    fn is_mature(&self, hash: &H256) -> bool {
+        match self.transactions.get(hash).map(|wtx| wtx.state) {
+            Some(TxState::Confirmed { height, .. }) => self.height + 1 - height >= self.maturity,
+            Some(_) => self.maturity == 0,
+            None => true,
+        }
+    }
+
+    /// Load the labels saved to `path`, if it exists, and save them there from now on.
+    pub fn load_labels(&mut self, path: &Path) -> io::Result<()> {
+        if path.exists() {
+            self.labels = bincode::deserialize(&fs::read(path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        self.labels_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    fn save_labels(&self) -> io::Result<()> {
+        if let Some(path) = &self.labels_path {
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, &bincode::serialize(&self.labels).unwrap())?;
+            fs::rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+
+    /// Label an address, which need not be ours. An empty label removes it.
+    pub fn set_address_label(&mut self, address: H160, label: &str) -> io::Result<()> {
+        set_label(&mut self.labels.addresses, address, label);
+        self.save_labels()
+    }
+
+    /// Label a transaction, which need not be known yet. An empty label removes it.
+    pub fn set_tx_label(&mut self, hash: H256, label: &str) -> io::Result<()> {
+        set_label(&mut self.labels.transactions, hash, label);
+        self.save_labels()
+    }
+
+    pub fn address_label(&self, address: &H160) -> Option<&str> {
+        self.labels.addresses.get(address).map(|label| label.as_str())
+    }
+
+    pub fn tx_label(&self, hash: &H256) -> Option<&str> {
+        self.labels.transactions.get(hash).map(|label| label.as_str())
+    }
+
+    /// Add a key, unless the wallet has it already. Payments it received before are only known
+    /// after a `rescan`.
+    pub fn add_key(&mut self, key: Ed25519KeyPair) {
+        self.add_signer(Box::new(key));
+    }
+
+    /// Like `add_key`, for a key the wallet only asks for signatures, like an
+    /// `ExternalSigner`.
+    pub fn add_signer(&mut self, signer: Box<dyn Signer + Send + Sync>) {
+        if self.addresses.insert(address_of(&signer.public_key_bytes())) {
+            self.keys.push(Arc::from(signer));
+        }
+    }
+
+    /// Add a new key, out of the generator of the wallet, and return its address. The key is
+    /// saved before its address is handed out, if the keys are saved.
+    pub fn new_address(&mut self) -> io::Result<H160> {
+        let seed: [u8; 32] = self.rng.lock().unwrap().gen();
+        self.seeds.push(seed);
+        if let Err(e) = self.save_keys() {
+            self.seeds.pop();
+            return Err(e);
+        }
+        let key = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let address = address_of(key.public_key().as_ref());
+        self.add_key(key);
+        Ok(address)
+    }
+
+    /// The address of the first key out of `new_address`, added if there is none yet, so that
+    /// the mining rewards go to the same key across restarts.
+    pub fn first_address(&mut self) -> io::Result<H160> {
+        match self.seeds.first() {
+            Some(seed) => Ok(address_of(Ed25519KeyPair::from_seed_unchecked(seed).unwrap().public_key().as_ref())),
+            None => self.new_address(),
+        }
+    }
+
+    /// Add the keys saved to `path` by `new_address`, if it exists, and save the new ones there
+    /// from now on.
+    pub fn load_keys(&mut self, path: &Path) -> io::Result<()> {
+        if path.exists() {
+            let seeds: Vec<[u8; 32]> = bincode::deserialize(&fs::read(path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            for seed in &seeds {
+                self.add_key(Ed25519KeyPair::from_seed_unchecked(seed).unwrap());
+            }
+            self.seeds.extend(seeds);
+        }
+        self.keys_path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    fn save_keys(&self) -> io::Result<()> {
+        if let Some(path) = &self.keys_path {
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, &bincode::serialize(&self.seeds).unwrap())?;
+            fs::rename(&tmp_path, path)?;
+        }
+        Ok(())
+    }
+
+    /// Track the outputs and transactions of an address whose key is held elsewhere, unless
+    /// the wallet does already. Like for `add_key`, its past is only known after a `rescan`.
+    pub fn add_watch_only(&mut self, address: H160) {
+        if !self.tracks(&address) {
+            self.watched.push(address);
+        }
+    }
+
+    /// Our keys, to sign with once the wallet is released.
+    pub fn keys(&self) -> Vec<Key> {
+        self.keys.clone()
+    }
+
+    /// The addresses of our keys.
+    pub fn addresses(&self) -> Vec<H160> {
+        self.addresses.iter().cloned().collect()
+    }
+
+    pub fn watch_only_addresses(&self) -> &[H160] {
+        &self.watched
+    }
+
+    /// Whether an address is one of our keys or watch-only.
+    pub fn tracks(&self, address: &H160) -> bool {
+        self.addresses.contains(address) || self.watched.contains(address)
+    }
+
+    /// The value of the unspent outputs of each address of a key, then of each watch-only
+    /// address, immature ones included.
+    pub fn balances(&self, state: &State) -> Vec<(H160, Amount)> {
+        let mut balances: HashMap<H160, Amount> = HashMap::new();
+        for (value, owner, _) in state.utxo.values() {
+            if self.tracks(owner) {
+                let balance = balances.entry(*owner).or_insert(Amount::ZERO);
+                *balance = balance.checked_add(*value).unwrap_or(Amount::MAX_MONEY);
+            }
+        }
+        self.addresses
+            .iter()
+            .chain(&self.watched)
+            .map(|address| (*address, balances.get(address).cloned().unwrap_or(Amount::ZERO)))
+            .collect()
+    }
+
+    /// Whether a transaction pays to or is signed by one of our keys or watch-only addresses.
+    pub fn is_mine(&self, transaction: &SignedTransaction) -> bool {
+        transaction.signers().iter().any(|signer| self.tracks(&address_of(signer)))
+            || transaction.transaction.output.iter().any(|txout| self.tracks(&txout.recipient))
+    }
+
+    /// Whether a transaction, or one of its inputs, is signed by one of our keys.
+    pub fn is_from_me(&self, transaction: &SignedTransaction) -> bool {
+        transaction.signers().iter().any(|signer| self.addresses.contains(&address_of(signer)))
+    }
+
+    /// Start tracking a transaction that is not in the chain yet.
+    pub fn add_transaction(&mut self, transaction: &SignedTransaction) {
+        self.transactions.entry(transaction.hash()).or_insert(WalletTx {
+            transaction: transaction.clone(),
+            state: TxState::Pending,
+        });
+    }
+
+    /// Build and sign a transaction paying `value` to `recipient` out of the outputs of a single
+    /// key, chosen with the coin selection of the wallet among the mature ones, paying the
+    /// minimum relay fee of the policy. Change below the dust limit is left to the fee instead of creating an output the
+    /// policy would reject.
+    pub fn create_transaction(
+        &self,
+        recipient: H160,
+        value: Amount,
+        state: &State,
+        policy: &Policy,
+    ) -> Result<SignedTransaction, WalletError> {
+        self.create_transaction_many(&[(recipient, value)], state, policy)
+    }
+
+    /// Like `create_transaction`, with one output per payment, in order, and one change output.
+    pub fn create_transaction_many(
+        &self,
+        payments: &[(H160, Amount)],
+        state: &State,
+        policy: &Policy,
+    ) -> Result<SignedTransaction, WalletError> {
+        self.fund_transaction_many(payments, state, policy)?.sign()
+    }
+
+    /// Like `create_transaction_many`, left unsigned, so that a key held by a device signs it
+    /// without the wallet and the state locked.
+    pub fn fund_transaction_many(
+        &self,
+        payments: &[(H160, Amount)],
+        state: &State,
+        policy: &Policy,
+    ) -> Result<Funded, WalletError> {
+        let (value, payments) = Self::payments(payments, policy)?;
+        let pending = self.pending_spends();
+        for key in &self.keys {
+            let address = address_of(&key.public_key_bytes());
+            let target = Target { value: value, outputs: payments.len(), sizes: TxSizes::new(key.as_ref()), policy: policy };
+            if let Some((input, output)) = self.select(address, &target, &payments, state, &pending) {
+                let tx = Transaction { version: transaction::VERSION, input: input, output: output };
+                return Ok(Funded { transaction: tx, key: key.clone() });
+            }
+        }
+        Err(WalletError::InsufficientFunds)
+    }
+
+    /// Like `create_transaction_many`, out of the outputs of a single watch-only address, left
+    /// for its key holder to sign. The fee is counted for the transaction it finalizes into.
+    pub fn create_psbt(&self, payments: &[(H160, Amount)], state: &State, policy: &Policy) -> Result<Psbt, WalletError> {
+        let (value, payments) = Self::payments(payments, policy)?;
+        let pending = self.pending_spends();
+        for address in &self.watched {
+            let target = Target { value: value, outputs: payments.len(), sizes: TxSizes::per_input(), policy: policy };
+            if let Some((input, output)) = self.select(*address, &target, &payments, state, &pending) {
+                let tx = Transaction { version: transaction::VERSION, input: input, output: output };
+                return Ok(Psbt::new(tx, |outpoint| state.utxo.get(outpoint).map(|(value, owner, _)| (*value, *owner))).unwrap());
+            }
+        }
+        Err(WalletError::InsufficientFunds)
+    }
+
+    /// The outputs paying `payments`, and their total value.
+    fn payments(payments: &[(H160, Amount)], policy: &Policy) -> Result<(Amount, Vec<TxOut>), WalletError> {
+        if payments.is_empty() {
+            return Err(WalletError::NoPayment);
+        }
+        if payments.iter().any(|(_, value)| policy.is_dust(*value)) {
+            return Err(WalletError::Dust);
+        }
+        let value = Amount::checked_sum(payments.iter().map(|(_, value)| *value)).ok_or(WalletError::InsufficientFunds)?;
+        let outputs = payments.iter().map(|(recipient, value)| TxOut { recipient: *recipient, value: *value }).collect();
+        Ok((value, outputs))
+    }
+
+    /// Outputs already spent by our transactions waiting for a block.
+    fn pending_spends(&self) -> HashSet<(H256, u8)> {
+        self.transactions
+            .values()
+            .filter(|wtx| wtx.state == TxState::Pending)
+            .flat_map(|wtx| wtx.transaction.transaction.input.iter().map(|txin| (txin.previous_output, txin.index)))
+            .collect()
+    }
+
+    /// The inputs and outputs of a transaction paying `target` out of the mature outputs of
+    /// `address`, chosen with the coin selection of the wallet, change going back to `address`.
+    fn select(
+        &self,
+        address: H160,
+        target: &Target,
+        payments: &[TxOut],
+        state: &State,
+        pending: &HashSet<(H256, u8)>,
+    ) -> Option<(Vec<TxIn>, Vec<TxOut>)> {
+        let mut coins: Vec<Coin> = state
+            .utxo
+            .iter()
+            .filter(|(outpoint, (_, owner, _))| {
+                *owner == address
+                    && !pending.contains(outpoint)
+                    && self.is_mature(&outpoint.0)
+                    && validation::is_mature(state, outpoint, state.height() + 1)
+            })
+            .map(|(outpoint, (coin_value, _, _))| (*outpoint, *coin_value))
+            .collect();
+        coins.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let selected = match self.coin_selection {
+            CoinSelection::LargestFirst => largest_first(&coins, target),
+            CoinSelection::BranchAndBound => branch_and_bound(&coins, target).or_else(|| largest_first(&coins, target)),
+            CoinSelection::RandomImprove => random_improve(&coins, target, &mut *self.rng.lock().unwrap()),
+        }?;
+        let input: Vec<TxIn> =
+            selected.iter().map(|(outpoint, _)| TxIn { previous_output: outpoint.0, index: outpoint.1 }).collect();
+        let total = Amount::checked_sum(selected.iter().map(|coin| coin.1))?;
+        let output = pay(input.len(), total, payments, address, &target.sizes, target.policy)?;
+        Some((input, output))
+    }
+
+    /// Confirmations of a transaction, counted like `Blockchain::confirmations` for its block:
+    /// 0 while pending, and minus the confirmations of the conflicting transaction when
+    /// conflicted.
+    pub fn confirmations(&self, hash: &H256, chain: &Blockchain) -> Option<i64> {
+        let confirmations = match self.transactions.get(hash)?.state {
+            TxState::Confirmed { block, .. } => chain.confirmations(&block).unwrap_or(0),
+            TxState::Conflicted { by } => chain
+                .find_transaction(&by)
+                .and_then(|block| chain.confirmations(&block))
+                .map_or(0, |c| -c),
+            TxState::Pending => 0,
+        };
+        Some(confirmations)
+    }
+
+    /// Finality of the block confirming a transaction, `Pending` unless confirmed.
+    pub fn finality(&self, hash: &H256, chain: &Blockchain) -> Option<Finality> {
+        let finality = match self.transactions.get(hash)?.state {
+            TxState::Confirmed { block, .. } => chain.finality(&block),
+            _ => Finality::Pending,
+        };
+        Some(finality)
+    }
+
+    /// Go through the blocks of the longest chain from height `from` to the tip as if they were
+    /// connected again, to find the transactions of a key added since. The outputs of the key
+    /// are in the UTXO set anyway; the rescan brings its history, and the confirmations its
+    /// outputs need to be spent. There is no address index, so every block is read. Returns the
+    /// number of transactions found.
+    pub fn rescan(&mut self, chain: &Blockchain, from: usize) -> usize {
+        let known = self.transactions.len();
+        for height in from..=chain.height() {
+            let hash = chain.hash_at_height(height).unwrap();
+            self.block_connected(&chain.blockmap[&hash], height);
+        }
+        self.transactions.len() - known
+    }
+
+    pub fn block_connected(&mut self, block: &Block, height: usize) {
+        let block_hash = block.hash();
+        self.height = height;
+        let mut spent = HashMap::new();
+        for transaction in &block.content.data {
+            let hash = transaction.hash();
+            for txin in &transaction.transaction.input {
+                spent.insert((txin.previous_output, txin.index), hash);
+            }
+            if self.is_mine(transaction) {
+                self.add_transaction(transaction);
+                self.transactions.get_mut(&hash).unwrap().state = TxState::Confirmed { block: block_hash, height: height };
+            }
+        }
+        for (hash, wtx) in self.transactions.iter_mut() {
+            if wtx.state != TxState::Pending {
+                continue;
+            }
+            for txin in &wtx.transaction.transaction.input {
+                if let Some(by) = spent.get(&(txin.previous_output, txin.index)) {
+                    if by != hash {
+                        wtx.state = TxState::Conflicted { by: *by };
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn block_disconnected(&mut self, block: &Block, height: usize) {
+        let block_hash = block.hash();
+        self.height = height - 1;
+        let removed: HashSet<H256> = block.content.data.iter().map(|tx| tx.hash()).collect();
+        for wtx in self.transactions.values_mut() {
+            match wtx.state {
+                TxState::Confirmed { block, .. } if block == block_hash => {
+                    wtx.state = TxState::Pending;
+                }
+                TxState::Conflicted { by } if removed.contains(&by) => {
+                    wtx.state = TxState::Pending;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl ChainListener for OrderedMutex<Wallet> {
+    fn on_block_connected(&self, block: &Block, height: usize) {
+        self.lock().unwrap().block_connected(block, height);
+    }
+
+    fn on_block_disconnected(&self, block: &Block, height: usize) {
+        self.lock().unwrap().block_disconnected(block, height);
+    }
+
+    fn on_tx_accepted(&self, transaction: &SignedTransaction) {
+        let mut wallet = self.lock().unwrap();
+        if wallet.is_mine(transaction) {
+            wallet.add_transaction(transaction);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashed;
+    use crate::policy::PolicyError;
+    use crate::validation;
+    use crate::transaction::tests::{key, owner, spend};
+
+    #[test]
+    fn confirm_and_reorg() {
+        let mut wallet = Wallet::new();
+        wallet.add_key(key(1));
+        let key = key(1);
+        let tx = spend(&key, [0u8; 32].into(), [9u8; 20].into(), 1);
+        let mut chain = Blockchain::new();
+        wallet.add_transaction(&tx);
+        assert_eq!(wallet.confirmations(&tx.hash(), &chain), Some(0));
+
+        let mut block = generate_random_block(&chain.tip());
+        block.content.data.push(Hashed::new(tx.clone()));
+        chain.insert(&block);
+        wallet.block_connected(&block, 1);
+        for _ in 0..2 {
+            chain.insert(&generate_random_block(&chain.tip()));
+        }
+        assert_eq!(wallet.confirmations(&tx.hash(), &chain), Some(3));
+
+        // left behind by a longer fork, before and after the wallet hears about it
+        let mut fork = generate_random_block(&block.header.parent);
+        for _ in 0..4 {
+            chain.insert(&fork);
+            fork = generate_random_block(&fork.hash());
+        }
+        assert_eq!(wallet.confirmations(&tx.hash(), &chain), Some(-1));
+        wallet.block_disconnected(&block, 1);
+        assert_eq!(wallet.transactions[&tx.hash()].state, TxState::Pending);
+        assert_eq!(wallet.confirmations(&tx.hash(), &chain), Some(0));
+    }
+
+    #[test]
+    fn conflict() {
+        let mut wallet = Wallet::new();
+        wallet.add_key(key(1));
+        let key = key(1);
+        let ours = spend(&key, [0u8; 32].into(), [9u8; 20].into(), 1);
+        let theirs = spend(&key, [0u8; 32].into(), [8u8; 20].into(), 1);
+        wallet.add_transaction(&ours);
+
+        let mut chain = Blockchain::new();
+        let mut block = generate_random_block(&chain.tip());
+        block.content.data.push(Hashed::new(theirs.clone()));
+        chain.insert(&block);
+        wallet.block_connected(&block, 1);
+        assert_eq!(wallet.transactions[&ours.hash()].state, TxState::Conflicted { by: theirs.hash() });
+        chain.insert(&generate_random_block(&block.hash()));
+        assert_eq!(wallet.confirmations(&ours.hash(), &chain), Some(-2));
+
+        wallet.block_disconnected(&block, 1);
+        assert_eq!(wallet.transactions[&ours.hash()].state, TxState::Pending);
+    }
+
+    #[test]
+    fn create_transaction() {
+        let mut wallet = Wallet::new();
+        let key = key(1);
+        let address = owner(&key);
+        wallet.add_key(key);
+        let mut state = State::default();
+        state.insert_utxo(([0u8; 32].into(), 0), (1000.into(), address));
+        state.insert_utxo(([1u8; 32].into(), 0), (300.into(), address));
+        let policy = Policy { min_fee_rate: 100, dust_limit: 10.into(), ..Policy::default() };
+        let recipient: H160 = [9u8; 20].into();
+
+        assert_eq!(wallet.create_transaction(recipient, 9.into(), &state, &policy).err(), Some(WalletError::Dust));
+        assert_eq!(wallet.create_transaction(recipient, 1300.into(), &state, &policy).err(), Some(WalletError::InsufficientFunds));
+
+        // both outputs needed, the change goes back to the wallet
+        let tx = wallet.create_transaction(recipient, 1100.into(), &state, &policy).unwrap();
+        let fee = validation::check_transaction(&tx, &state).unwrap();
+        assert_eq!(policy.check(&tx, fee), Ok(()));
+        assert_eq!(tx.transaction.input.len(), 2);
+        assert_eq!(tx.transaction.output[1].recipient, address);
+
+        // change below the dust limit is left to the fee
+        let size = tx.serialized_size();
+        let tx = wallet.create_transaction(recipient, Amount::from(1000 - policy.min_fee(size).as_u64() - 5), &state, &policy).unwrap();
+        let fee = validation::check_transaction(&tx, &state).unwrap();
+        assert_eq!(policy.check(&tx, fee), Ok(()));
+        assert_eq!(tx.transaction.output.len(), 1);
+
+        // a zero-fee transaction would not be relayed
+        let tx = wallet.create_transaction(recipient, 1000.into(), &state, &Policy::default()).unwrap();
+        assert_eq!(
+            policy.check(&tx, validation::check_transaction(&tx, &state).unwrap()),
+            Err(PolicyError::FeeTooLow)
+        );
+    }
+
+    #[test]
+    fn create_transaction_many() {
+        let mut wallet = Wallet::new();
+        let key = key(1);
+        let address = owner(&key);
+        wallet.add_key(key);
+        let mut state = State::default();
+        state.insert_utxo(([0u8; 32].into(), 0), (1000.into(), address));
+        state.insert_utxo(([1u8; 32].into(), 0), (300.into(), address));
+        let policy = Policy { min_fee_rate: 100, dust_limit: 10.into(), ..Policy::default() };
+        let (alice, bob): (H160, H160) = ([8u8; 20].into(), [9u8; 20].into());
+
+        assert_eq!(wallet.create_transaction_many(&[], &state, &policy).err(), Some(WalletError::NoPayment));
+        assert_eq!(wallet.create_transaction_many(&[(alice, 500.into()), (bob, 9.into())], &state, &policy).err(), Some(WalletError::Dust));
+        assert_eq!(
+            wallet.create_transaction_many(&[(alice, u64::MAX.into()), (bob, 500.into())], &state, &policy).err(),
+            Some(WalletError::InsufficientFunds)
+        );
+
+        let tx = wallet.create_transaction_many(&[(alice, 700.into()), (bob, 400.into())], &state, &policy).unwrap();
+        let fee = validation::check_transaction(&tx, &state).unwrap();
+        assert_eq!(policy.check(&tx, fee), Ok(()));
+        let outputs: Vec<(H160, Amount)> = tx.transaction.output.iter().map(|output| (output.recipient, output.value)).collect();
+        assert_eq!(outputs, vec![(alice, 700.into()), (bob, 400.into()), (address, Amount::from(200 - fee.as_u64()))]);
+    }
+
+    #[test]
+    fn coin_selection() {
+        let key = key(1);
+        let address = owner(&key);
+        let mut wallet = Wallet::new();
+        wallet.add_key(key);
+        let mut state = State::default();
+        for (i, value) in [1000, 500, 300, 200, 100, 50].iter().enumerate() {
+            state.insert_utxo(([i as u8; 32].into(), 0), (Amount::from(*value), address));
+        }
+        let policy = Policy { dust_limit: 10.into(), ..Policy::default() };
+        let recipient: H160 = [9u8; 20].into();
+        let change = |tx: &SignedTransaction| tx.transaction.output.get(1).map_or(0, |output| output.value.as_u64());
+
+        // one input, most of it coming back as change
+        let tx = wallet.create_transaction(recipient, 350.into(), &state, &policy).unwrap();
+        assert_eq!(tx.transaction.input.len(), 1);
+        assert_eq!(change(&tx), 650);
+
+        // 300 + 50 pays exactly, without change
+        wallet.set_coin_selection(CoinSelection::BranchAndBound);
+        let tx = wallet.create_transaction(recipient, 350.into(), &state, &policy).unwrap();
+        assert_eq!(tx.transaction.input.len(), 2);
+        assert_eq!(tx.transaction.output.len(), 1);
+        assert_eq!(validation::check_transaction(&tx, &state), Ok(Amount::ZERO));
+        // 1000 + 300 pays 1290 and 10 of fee
+        let policy = Policy { min_fee_rate: 10, ..policy };
+        let tx = wallet.create_transaction(recipient, 1290.into(), &state, &policy).unwrap();
+        assert_eq!(tx.transaction.input.len(), 2);
+        assert_eq!(tx.transaction.output.len(), 1);
+        // no exact match for 1400, largest first then
+        let tx = wallet.create_transaction(recipient, 1400.into(), &state, &policy).unwrap();
+        assert_eq!(tx.transaction.input.len(), 2);
+        assert_eq!(change(&tx) + validation::check_transaction(&tx, &state).unwrap().as_u64(), 100);
+
+        wallet.set_coin_selection(CoinSelection::RandomImprove);
+        let tx = wallet.create_transaction(recipient, 350.into(), &state, &policy).unwrap();
+        let fee = validation::check_transaction(&tx, &state).unwrap();
+        assert_eq!(policy.check(&tx, fee), Ok(()));
+        assert_eq!(wallet.create_transaction(recipient, 2200.into(), &state, &policy).err(), Some(WalletError::InsufficientFunds));
+
+        // out of equal coins, enough for the payment, then more for change of the same value
+        let mut state = State::default();
+        for i in 0..10u8 {
+            state.insert_utxo(([i; 32].into(), 0), (100.into(), address));
+        }
+        let policy = Policy { dust_limit: 10.into(), ..Policy::default() };
+        let tx = wallet.create_transaction(recipient, 300.into(), &state, &policy).unwrap();
+        assert_eq!(tx.transaction.input.len(), 6);
+        assert_eq!(change(&tx), 300);
+        wallet.set_coin_selection(CoinSelection::LargestFirst);
+        let tx = wallet.create_transaction(recipient, 300.into(), &state, &policy).unwrap();
+        assert_eq!(tx.transaction.input.len(), 3);
+        assert_eq!(tx.transaction.output.len(), 1);
+    }
+
+    #[test]
+    fn maturity() {
+        let address = owner(&key(1));
+        let mut wallet = Wallet::new();
+        wallet.add_key(key(1));
+        wallet.set_maturity(3);
+        let payer = key(2);
+        let mut received = spend(&payer, [0u8; 32].into(), address, 1);
+        received.transaction.output[0].value = 1000.into();
+        let received = SignedTransaction::new(received.transaction, &payer);
+        let mut state = State::default();
+        state.insert_utxo((received.hash(), 0), (1000.into(), address));
+        let policy = Policy::default();
+        let recipient: H160 = [9u8; 20].into();
+
+        let chain = Blockchain::new();
+        let mut block = generate_random_block(&chain.tip());
+        block.content.data.push(Hashed::new(received));
+        wallet.block_connected(&block, 1);
+        wallet.block_connected(&generate_random_block(&block.hash()), 2);
+        assert_eq!(wallet.create_transaction(recipient, 500.into(), &state, &policy).err(), Some(WalletError::InsufficientFunds));
+        let next = generate_random_block(&block.hash());
+        wallet.block_connected(&next, 3);
+        assert!(wallet.create_transaction(recipient, 500.into(), &state, &policy).is_ok());
+        wallet.block_disconnected(&next, 3);
+        assert_eq!(wallet.create_transaction(recipient, 500.into(), &state, &policy).err(), Some(WalletError::InsufficientFunds));
+
+        // outputs the wallet did not see confirmed, like the ICO, are mature
+        state.insert_utxo(([5u8; 32].into(), 0), (1000.into(), address));
+        assert!(wallet.create_transaction(recipient, 500.into(), &state, &policy).is_ok());
+    }
+
+    #[test]
+    fn rescan() {
+        let address = owner(&key(1));
+        let payer = key(2);
+        let received = spend(&payer, [0u8; 32].into(), address, 1);
+        let mut chain = Blockchain::new();
+        let mut block = generate_random_block(&chain.tip());
+        block.content.data.push(Hashed::new(received.clone()));
+        chain.insert(&block);
+        chain.insert(&generate_random_block(&block.hash()));
+
+        // the key is imported after the payment was confirmed, twice
+        let mut wallet = Wallet::new();
+        wallet.add_key(key(1));
+        wallet.add_key(key(1));
+        assert_eq!(wallet.keys.len(), 1);
+        assert_eq!(wallet.confirmations(&received.hash(), &chain), None);
+        assert_eq!(wallet.rescan(&chain, 2), 0);
+        assert_eq!(wallet.rescan(&chain, 0), 1);
+        assert_eq!(wallet.transactions[&received.hash()].state, TxState::Confirmed { block: block.hash(), height: 1 });
+        assert_eq!(wallet.confirmations(&received.hash(), &chain), Some(2));
+        assert_eq!(wallet.rescan(&chain, 0), 0);
+    }
+
+    #[test]
+    fn watch_only() {
+        let cold = key(1);
+        let address = owner(&cold);
+        let mut wallet = Wallet::new();
+        wallet.add_watch_only(address);
+        wallet.add_watch_only(address);
+        assert_eq!(wallet.watch_only_addresses(), &[address][..]);
+        assert!(wallet.addresses().is_empty());
+        let mut state = State::default();
+        state.insert_utxo(([0u8; 32].into(), 0), (1000.into(), address));
+        state.insert_utxo(([1u8; 32].into(), 0), (300.into(), address));
+        state.insert_utxo(([2u8; 32].into(), 0), (50.into(), [9u8; 20].into()));
+        assert_eq!(wallet.balances(&state), vec![(address, 1300.into())]);
+        let policy = Policy { min_fee_rate: 100, dust_limit: 10.into(), ..Policy::default() };
+        let recipient: H160 = [9u8; 20].into();
+
+        // nothing to sign with
+        assert_eq!(wallet.create_transaction(recipient, 500.into(), &state, &policy).err(), Some(WalletError::InsufficientFunds));
+        assert_eq!(wallet.create_psbt(&[(recipient, 1300.into())], &state, &policy).err(), Some(WalletError::InsufficientFunds));
+        let mut psbt = wallet.create_psbt(&[(recipient, 1100.into())], &state, &policy).unwrap();
+        assert_eq!(psbt.inputs.len(), 2);
+        assert_eq!(psbt.transaction.output[1].recipient, address);
+        assert_eq!(sign_psbt(&wallet.keys(), &mut psbt, SigHash::All), Ok(0));
+
+        // signed by the key holder, the fee is what the finalized transaction needs
+        assert_eq!(psbt.sign(&cold, SigHash::All), Ok(2));
+        let tx = psbt.finalize().unwrap();
+        let fee = validation::check_transaction(&tx, &state).unwrap();
+        assert_eq!(fee, psbt.fee().unwrap());
+        assert_eq!(policy.check(&tx, fee), Ok(()));
+        assert_eq!(fee, policy.min_fee(tx.serialized_size()));
+        assert!(wallet.is_mine(&tx));
+        wallet.add_transaction(&tx);
+        assert_eq!(wallet.create_psbt(&[(recipient, 100.into())], &state, &policy).err(), Some(WalletError::InsufficientFunds));
+    }
+
+    #[test]
+    fn labels() {
+        let path = std::env::temp_dir().join(format!("wallet-labels-{}.dat", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut wallet = Wallet::new();
+        wallet.load_labels(&path).unwrap();
+        let address: H160 = [9u8; 20].into();
+        let hash: H256 = [1u8; 32].into();
+        wallet.set_address_label(address, "exchange").unwrap();
+        wallet.set_tx_label(hash, "scenario A").unwrap();
+        wallet.set_tx_label([2u8; 32].into(), "scenario B").unwrap();
+        wallet.set_tx_label([2u8; 32].into(), "").unwrap();
+
+        let mut reopened = Wallet::new();
+        reopened.load_labels(&path).unwrap();
+        assert_eq!(reopened.address_label(&address), Some("exchange"));
+        assert_eq!(reopened.tx_label(&hash), Some("scenario A"));
+        assert_eq!(reopened.tx_label(&[2u8; 32].into()), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keys_saved() {
+        let path = std::env::temp_dir().join(format!("wallet-keys-{}.dat", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut wallet = Wallet::new();
+        wallet.load_keys(&path).unwrap();
+        let first = wallet.first_address().unwrap();
+        assert_eq!(wallet.first_address().unwrap(), first);
+        let second = wallet.new_address().unwrap();
+
+        // the keys come back on restart, with a generator drawing other seeds
+        let mut reopened = Wallet::new();
+        reopened.load_keys(&path).unwrap();
+        assert_eq!(reopened.first_address().unwrap(), first);
+        let addresses: HashSet<H160> = reopened.addresses().into_iter().collect();
+        assert_eq!(addresses, [first, second].iter().cloned().collect());
+        fs::remove_file(&path).unwrap();
+    }
+}
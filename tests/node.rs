@@ -0,0 +1,130 @@
+//! Nodes embedded in the test process through `Node::builder()`, talking over real sockets.
+
+use bitcoin::chainparams::{self, Network};
+use bitcoin::clock::{Clock, MockClock};
+use bitcoin::storage::MemoryStore;
+use bitcoin::Node;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A regtest node listening at a free port, without API server.
+fn start_node() -> Node {
+    chainparams::select(Network::Regtest);
+    let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    Node::builder().p2p(&[any_port]).api(None).min_peers(0).p2p_workers(2).start().unwrap()
+}
+
+#[test]
+fn blocks_reach_connected_node() {
+    let miner = start_node();
+    let follower = start_node();
+    assert_ne!(miner.p2p_addrs()[0].port(), 0);
+    follower.connect(miner.p2p_addrs()[0]).unwrap();
+
+    let hashes = miner.miner().generate(3, [7u8; 20].into()).unwrap();
+    assert_eq!(hashes.len(), 3);
+    let tip = miner.blockchain().lock().unwrap().tip();
+    assert_eq!(tip, hashes[2]);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while follower.blockchain().lock().unwrap().tip() != tip {
+        assert!(Instant::now() < deadline, "the blocks did not reach the follower");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(follower.blockchain().lock().unwrap().height(), 3);
+}
+
+#[test]
+fn restart_from_memory_storage() {
+    let storage = Arc::new(MemoryStore::new());
+    let clock = Arc::new(MockClock::new(1_600_000_000_000));
+    let start = || {
+        chainparams::select(Network::Regtest);
+        let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        Node::builder()
+            .p2p(&[any_port])
+            .api(None)
+            .p2p_workers(1)
+            .storage(storage.clone())
+            .clock(clock.clone())
+            .rng(StdRng::seed_from_u64(7))
+            .start()
+            .unwrap()
+    };
+    let node = start();
+    let hashes = node.miner().generate(2, [7u8; 20].into()).unwrap();
+    assert_eq!(storage.len(), 2);
+    let block = storage.get(&hashes[1]).unwrap();
+    assert_eq!(block.header.timestamp, clock.now());
+
+    // the same seed gives the same identity, and the stored blocks are connected again
+    let restarted = start();
+    assert_eq!(restarted.identity().id(), node.identity().id());
+    assert_eq!(restarted.blockchain().lock().unwrap().tip(), hashes[1]);
+}
+
+#[test]
+fn catch_up_on_connect() {
+    let miner = start_node();
+    let hashes = miner.miner().generate(3, [7u8; 20].into()).unwrap();
+    // the blocks were mined before the connection, the Version handshake brings them
+    let follower = start_node();
+    follower.connect(miner.p2p_addrs()[0]).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while follower.blockchain().lock().unwrap().tip() != hashes[2] {
+        assert!(Instant::now() < deadline, "the follower did not catch up");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(follower.blockchain().lock().unwrap().total_work(), miner.blockchain().lock().unwrap().total_work());
+}
+
+#[test]
+fn member_mines_for_pool() {
+    chainparams::select(Network::Regtest);
+    let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let pool = Node::builder().p2p(&[any_port]).api(None).min_peers(0).p2p_workers(2).pool(true).start().unwrap();
+    let member = Node::builder()
+        .p2p(&[any_port])
+        .api(None)
+        .min_peers(0)
+        .p2p_workers(2)
+        .join_pool(Some(pool.p2p_addrs()[0]))
+        .start()
+        .unwrap();
+    // only the member mines, on the jobs of the pool
+    member.miner().start(1000);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while pool.blockchain().lock().unwrap().height() < 2 {
+        assert!(Instant::now() < deadline, "the pool found no block");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let stats = pool.miner().pool_stats().unwrap();
+    assert_eq!(stats.members.len(), 1);
+    assert!(stats.members[0].blocks >= 2);
+    assert!(stats.members[0].shares >= stats.members[0].blocks);
+    {
+        let chain = pool.blockchain().lock().unwrap();
+        let coinbase = &chain.blockmap[&chain.tip()].content.data[0];
+        assert_eq!(Some(coinbase.transaction.output[0].recipient), pool.miner().payout());
+    }
+
+    // without its pool, the member mines blocks of its own
+    member.network().disconnect(pool.p2p_addrs()[0]);
+    let pays_member = || {
+        let chain = member.blockchain().lock().unwrap();
+        let coinbase = &chain.blockmap[&chain.tip()].content.data[0];
+        Some(coinbase.transaction.output[0].recipient) == member.miner().payout()
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !pays_member() {
+        assert!(Instant::now() < deadline, "the member did not mine on its own");
+        thread::sleep(Duration::from_millis(50));
+    }
+    member.miner().exit();
+}